@@ -0,0 +1,603 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssh2::{Channel, OpenFlags, OpenType, Session};
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::connection::{self, stream, Connection, FileStat};
+use crate::encoding;
+use crate::error::AnsimpleError;
+use crate::inventory::{GlobalConfig, Host};
+use crate::runner::RunOptions;
+use crate::throttle::Throttle;
+
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// Where the agent is pushed to, relative to the login directory.
+const AGENT_DIR: &str = ".ansimple";
+// Raw bytes per write request; base64 makes them a third larger on the wire.
+const WRITE_CHUNK_SIZE: usize = 256 * 1024;
+
+// One JSON object per line from the controller to the agent.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Exec {
+        command: String,
+    },
+    Exists {
+        path: PathBuf,
+    },
+    Checksum {
+        path: PathBuf,
+    },
+    Read {
+        path: PathBuf,
+    },
+    // `content` is base64. The first chunk of a file truncates it, the
+    // following ones are appended.
+    Write {
+        path: PathBuf,
+        content: String,
+        append: bool,
+    },
+    Copy {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    Size {
+        path: PathBuf,
+    },
+    Rename {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    Stat {
+        path: PathBuf,
+    },
+}
+
+// One JSON object per line back, the first one being `Ready`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    Ready {
+        version: u32,
+    },
+    Exec {
+        stdout: String,
+        stderr: String,
+        rc: i32,
+    },
+    Exists {
+        exists: bool,
+    },
+    Checksum {
+        sha256: Option<String>,
+    },
+    Read {
+        content: String,
+    },
+    Written,
+    Copied {
+        bytes: u64,
+        sha256: String,
+    },
+    Size {
+        size: Option<u64>,
+    },
+    Renamed,
+    Stat {
+        stat: Option<FileStat>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// The remote side: answers requests from `input` on `output` until the
+// controller closes the channel.
+pub fn serve<R: BufRead, W: Write>(input: R, mut output: W) -> io::Result<()> {
+    send(
+        &mut output,
+        &Reply::Ready {
+            version: PROTOCOL_VERSION,
+        },
+    )?;
+
+    for line in input.lines() {
+        let reply = match serde_json::from_str(&line?) {
+            Ok(request) => handle(request).unwrap_or_else(|err| Reply::Error {
+                message: err.to_string(),
+            }),
+            Err(err) => Reply::Error {
+                message: format!("invalid request: {err}"),
+            },
+        };
+        send(&mut output, &reply)?;
+    }
+
+    Ok(())
+}
+
+fn send<W: Write>(output: &mut W, reply: &Reply) -> io::Result<()> {
+    serde_json::to_writer(&mut *output, reply)?;
+    output.write_all(b"\n")?;
+    output.flush()
+}
+
+fn handle(request: Request) -> io::Result<Reply> {
+    Ok(match request {
+        Request::Exec { command } => {
+            let output = Command::new("/bin/sh")
+                .arg("-c")
+                .arg(&command)
+                .stdin(Stdio::null())
+                .output()?;
+            Reply::Exec {
+                stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                rc: output.status.code().unwrap_or(-1),
+            }
+        }
+        Request::Exists { path } => Reply::Exists {
+            exists: match fs::metadata(path) {
+                Ok(_) => true,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+                Err(err) => return Err(err),
+            },
+        },
+        Request::Checksum { path } => Reply::Checksum {
+            sha256: match File::open(path) {
+                Ok(mut file) => {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut file, &mut hasher)?;
+                    Some(format!("{:x}", hasher.finalize()))
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err),
+            },
+        },
+        Request::Read { path } => Reply::Read {
+            content: encoding::b64encode(fs::read(path)?),
+        },
+        Request::Write {
+            path,
+            content,
+            append,
+        } => {
+            let content = encoding::b64decode(content).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "content is not base64")
+            })?;
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)?
+                .write_all(&content)?;
+            Reply::Written
+        }
+        Request::Copy { src, dest } => {
+            let (bytes, sha256) = stream(&mut File::open(src)?, &mut File::create(dest)?)?;
+            Reply::Copied { bytes, sha256 }
+        }
+        Request::Size { path } => Reply::Size {
+            size: match fs::metadata(path) {
+                Ok(metadata) => Some(metadata.len()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err),
+            },
+        },
+        Request::Rename { src, dest } => {
+            fs::rename(src, dest)?;
+            Reply::Renamed
+        }
+        Request::Stat { path } => Reply::Stat {
+            stat: match fs::symlink_metadata(&path) {
+                Ok(metadata) => {
+                    let target = if metadata.file_type().is_symlink() {
+                        Some(fs::read_link(&path)?.to_string_lossy().into_owned())
+                    } else {
+                        None
+                    };
+                    Some(FileStat::new(
+                        metadata.mode(),
+                        metadata.uid(),
+                        metadata.gid(),
+                        target,
+                    ))
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err),
+            },
+        },
+    })
+}
+
+// A host's agent, `None` until it is started or after it broke down.
+type Slot = Arc<Mutex<Option<Agent>>>;
+
+// The agents of a run, one per host, started by the first task on the host
+// and kept for the rest of the run.
+#[derive(Debug, Clone, Default)]
+pub struct AgentPool {
+    binary: PathBuf,
+    checksum: Arc<OnceLock<String>>,
+    agents: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+impl AgentPool {
+    // `binary` is an `ansimple-agent` built for the hosts of the run.
+    pub fn new<P: Into<PathBuf>>(binary: P) -> Self {
+        Self {
+            binary: binary.into(),
+            checksum: Arc::default(),
+            agents: Arc::default(),
+        }
+    }
+
+    pub fn connect(
+        &self,
+        host: &Host,
+        options: &RunOptions,
+        global_config: &GlobalConfig,
+    ) -> Result<Box<dyn Connection>, AnsimpleError> {
+        let slot = self
+            .agents
+            .lock()
+            .expect("agent pool lock poisoned")
+            .entry(host.address.clone())
+            .or_default()
+            .clone();
+
+        // An agent that broke down is replaced by the next task.
+        let mut agent = slot.lock().expect("agent lock poisoned");
+        if agent.is_none() {
+            let path = self.remote_path()?;
+            let session = connection::session(host, options, global_config)?;
+            *agent = Some(Agent::start(&host.address, session, &self.binary, &path)?);
+        }
+        drop(agent);
+
+        Ok(Box::new(AgentConnection {
+            host: host.address.clone(),
+            slot,
+            throttle: options.throttle.clone(),
+        }))
+    }
+
+    // The name carries the checksum of the build, so hosts that already have
+    // it are not sent it again.
+    fn remote_path(&self) -> Result<String, AnsimpleError> {
+        let checksum = match self.checksum.get() {
+            Some(checksum) => checksum,
+            None => {
+                let mut file =
+                    File::open(&self.binary).map_err(|source| self.read_error(source))?;
+                let (_, checksum) =
+                    stream(&mut file, &mut io::sink()).map_err(|source| self.read_error(source))?;
+                self.checksum.get_or_init(|| checksum)
+            }
+        };
+
+        Ok(format!("{AGENT_DIR}/agent-{}", &checksum[..16]))
+    }
+
+    fn read_error(&self, source: io::Error) -> AnsimpleError {
+        AnsimpleError::Read {
+            path: self.binary.clone(),
+            source,
+        }
+    }
+}
+
+pub struct Agent {
+    host: String,
+    channel: BufReader<Channel>,
+    // Kept for as long as the channel is in use.
+    _session: Session,
+}
+
+impl std::fmt::Debug for Agent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Agent").field("host", &self.host).finish()
+    }
+}
+
+impl Agent {
+    // Uploads the agent unless the host already has this build of it, and
+    // runs it on a channel of its own.
+    fn start(
+        host: &str,
+        session: Session,
+        binary: &Path,
+        path: &str,
+    ) -> Result<Self, AnsimpleError> {
+        push(&session, binary, path)?;
+        let mut channel = session.channel_session()?;
+        channel.exec(path)?;
+
+        let mut agent = Self {
+            host: host.to_owned(),
+            channel: BufReader::new(channel),
+            _session: session,
+        };
+        match agent.receive() {
+            Ok(Reply::Ready { version }) if version == PROTOCOL_VERSION => Ok(agent),
+            Ok(Reply::Ready { version }) => Err(agent.error(format!(
+                "protocol version {version}, expected {PROTOCOL_VERSION}"
+            ))),
+            Ok(reply) => Err(agent.error(format!("unexpected first reply {reply:?}"))),
+            Err(_) => {
+                let mut stderr = String::new();
+                let _ = agent.channel.get_mut().stderr().read_to_string(&mut stderr);
+                Err(agent.error(format!("failed to start: {}", stderr.trim())))
+            }
+        }
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Reply, AnsimpleError> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        let channel = self.channel.get_mut();
+        channel.write_all(&line)?;
+        channel.flush()?;
+
+        self.receive()
+    }
+
+    fn receive(&mut self) -> Result<Reply, AnsimpleError> {
+        let mut line = String::new();
+        if self.channel.read_line(&mut line)? == 0 {
+            return Err(self.error("exited".to_owned()));
+        }
+
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    fn error(&self, message: String) -> AnsimpleError {
+        AnsimpleError::Agent {
+            host: self.host.clone(),
+            message,
+        }
+    }
+}
+
+// Uploads under a temporary name first, so an interrupted upload never takes
+// the agent's.
+fn push(session: &Session, binary: &Path, path: &str) -> Result<(), AnsimpleError> {
+    let sftp = session.sftp()?;
+    match sftp.stat(Path::new(path)) {
+        Ok(_) => return Ok(()),
+        Err(err) if connection::is_not_found(&err) => {}
+        Err(err) => return Err(err.into()),
+    }
+    match sftp.stat(Path::new(AGENT_DIR)) {
+        Ok(_) => {}
+        Err(err) if connection::is_not_found(&err) => sftp.mkdir(Path::new(AGENT_DIR), 0o700)?,
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut local_file = File::open(binary).map_err(|source| AnsimpleError::Read {
+        path: binary.to_owned(),
+        source,
+    })?;
+    let partial = PathBuf::from(format!("{path}.partial"));
+    let mut file = sftp.open_mode(
+        &partial,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        0o755,
+        OpenType::File,
+    )?;
+    stream(&mut local_file, &mut file)?;
+    drop(file);
+    sftp.rename(&partial, Path::new(path), None)?;
+
+    Ok(())
+}
+
+// A task's handle on the agent of its host. Only file contents that cross
+// the channel pass the throttle, copies on the host do not.
+struct AgentConnection {
+    host: String,
+    slot: Slot,
+    throttle: Throttle,
+}
+
+impl AgentConnection {
+    fn call(&mut self, request: Request) -> Result<Reply, AnsimpleError> {
+        let mut slot = self.slot.lock().expect("agent lock poisoned");
+        let Some(agent) = slot.as_mut() else {
+            return Err(AnsimpleError::Agent {
+                host: self.host.clone(),
+                message: "no longer running".to_owned(),
+            });
+        };
+
+        match agent.call(&request) {
+            Ok(Reply::Error { message }) => Err(agent.error(message)),
+            Ok(reply) => Ok(reply),
+            Err(err) => {
+                *slot = None;
+                Err(err)
+            }
+        }
+    }
+
+    fn unexpected(&self, reply: Reply) -> AnsimpleError {
+        AnsimpleError::Agent {
+            host: self.host.clone(),
+            message: format!("unexpected reply {reply:?}"),
+        }
+    }
+
+    // Sends `source` in chunks, the first one truncating the file unless
+    // `append` is set.
+    fn send_file(
+        &mut self,
+        path: &Path,
+        source: &mut dyn Read,
+        append: bool,
+    ) -> Result<(u64, String), AnsimpleError> {
+        let mut source = self.throttle.reader(source);
+        let mut buffer = vec![0; WRITE_CHUNK_SIZE];
+        let mut hasher = Sha256::new();
+        let mut bytes = 0;
+        loop {
+            let read = read_chunk(&mut source, &mut buffer)?;
+            // An empty source still has to truncate the file.
+            if read > 0 || bytes == 0 {
+                match self.call(Request::Write {
+                    path: path.to_owned(),
+                    content: encoding::b64encode(&buffer[..read]),
+                    append: append || bytes > 0,
+                })? {
+                    Reply::Written => {}
+                    reply => return Err(self.unexpected(reply)),
+                }
+            }
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            bytes += read as u64;
+        }
+
+        Ok((bytes, format!("{:x}", hasher.finalize())))
+    }
+}
+
+impl Connection for AgentConnection {
+    fn exec(&mut self, command: &str) -> Result<(String, String, i32), AnsimpleError> {
+        match self.call(Request::Exec {
+            command: command.to_owned(),
+        })? {
+            Reply::Exec { stdout, stderr, rc } => Ok((stdout, stderr, rc)),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    // Commands run by the agent have no stdin of their own, so the input is
+    // staged in a file next to it.
+    fn exec_with_input(
+        &mut self,
+        command: &str,
+        input: &mut dyn Read,
+    ) -> Result<(String, String, i32), AnsimpleError> {
+        let staged = format!("{AGENT_DIR}/input-{:016x}", rand::random::<u64>());
+        self.send_file(Path::new(&staged), input, false)?;
+        self.exec(&format!(
+            "({command}) < {staged}; status=$?; rm -f {staged}; exit $status"
+        ))
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, AnsimpleError> {
+        match self.call(Request::Exists {
+            path: path.to_owned(),
+        })? {
+            Reply::Exists { exists } => Ok(exists),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    fn checksum(&mut self, path: &Path) -> Result<Option<String>, AnsimpleError> {
+        match self.call(Request::Checksum {
+            path: path.to_owned(),
+        })? {
+            Reply::Checksum { sha256 } => Ok(sha256),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, AnsimpleError> {
+        match self.call(Request::Read {
+            path: path.to_owned(),
+        })? {
+            Reply::Read { content } => {
+                let content =
+                    encoding::b64decode(&content).ok_or_else(|| AnsimpleError::Agent {
+                        host: self.host.clone(),
+                        message: "content is not base64".to_owned(),
+                    })?;
+                // It arrives in one piece, so the throttle holds back what
+                // comes after it instead.
+                self.throttle.take(content.len());
+                Ok(content)
+            }
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        source: &mut dyn Read,
+    ) -> Result<(u64, String), AnsimpleError> {
+        self.send_file(path, source, false)
+    }
+
+    fn copy(&mut self, src: &Path, dest: &Path) -> Result<(u64, String), AnsimpleError> {
+        match self.call(Request::Copy {
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+        })? {
+            Reply::Copied { bytes, sha256 } => Ok((bytes, sha256)),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    fn size(&mut self, path: &Path) -> Result<Option<u64>, AnsimpleError> {
+        match self.call(Request::Size {
+            path: path.to_owned(),
+        })? {
+            Reply::Size { size } => Ok(size),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    fn append(&mut self, path: &Path, source: &mut dyn Read) -> Result<u64, AnsimpleError> {
+        Ok(self.send_file(path, source, true)?.0)
+    }
+
+    fn rename(&mut self, src: &Path, dest: &Path) -> Result<(), AnsimpleError> {
+        match self.call(Request::Rename {
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+        })? {
+            Reply::Renamed => Ok(()),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, AnsimpleError> {
+        match self.call(Request::Stat {
+            path: path.to_owned(),
+        })? {
+            Reply::Stat { stat } => Ok(stat),
+            reply => Err(self.unexpected(reply)),
+        }
+    }
+}
+
+// Fills `buffer` as far as `source` allows, so chunks only come up short at
+// the end.
+fn read_chunk(source: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(filled)
+}
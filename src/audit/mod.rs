@@ -0,0 +1,180 @@
+use serde::Serialize;
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::inventory::Host;
+use crate::secrets;
+use crate::task::{TaskKind, TaskResult, NO_LOG_MESSAGE};
+
+pub const SYSLOG: &str = "syslog";
+
+#[derive(Debug)]
+enum Sink {
+    File(File),
+    Syslog,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    RunStarted {
+        playbook: &'a Path,
+    },
+    PlayStarted {
+        hosts: Vec<&'a str>,
+    },
+    CommandExecuted {
+        host: &'a str,
+        task: &'a str,
+        command: &'a str,
+        rc: Option<i32>,
+    },
+    FileWritten {
+        host: &'a str,
+        task: &'a str,
+        path: &'a str,
+        sha256: &'a str,
+    },
+    TaskFinished {
+        host: &'a str,
+        task: &'a str,
+        status: &'a str,
+    },
+    RunFinished,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    run_id: &'a str,
+    user: &'a str,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    sink: Arc<Mutex<Sink>>,
+    run_id: String,
+    user: String,
+}
+
+impl AuditLog {
+    pub fn open(target: &str) -> io::Result<Self> {
+        let sink = if target == SYSLOG {
+            unsafe { libc::openlog(c"ansimple".as_ptr(), libc::LOG_PID, libc::LOG_USER) };
+            Sink::Syslog
+        } else {
+            Sink::File(OpenOptions::new().create(true).append(true).open(target)?)
+        };
+
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("LOGNAME"))
+            .unwrap_or_else(|_| unsafe { libc::getuid() }.to_string());
+
+        Ok(Self {
+            sink: Arc::new(Mutex::new(sink)),
+            run_id: String::new(),
+            user,
+        })
+    }
+
+    // The log with the records of the run `run_id` is.
+    pub fn for_run(&self, run_id: &str) -> Self {
+        Self {
+            run_id: run_id.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let record = Record {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            run_id: &self.run_id,
+            user: &self.user,
+            event,
+        };
+        let line = serde_json::to_string(&record).expect("failed to serialize audit record");
+        let line = secrets::mask(&line);
+
+        let mut sink = self.sink.lock().expect("audit log lock poisoned");
+        match &mut *sink {
+            Sink::File(file) => {
+                if let Err(err) = writeln!(file, "{line}").and_then(|_| file.flush()) {
+                    eprintln!("failed to write audit log: {err}");
+                }
+            }
+            Sink::Syslog => {
+                let message = CString::new(line.replace('\0', "")).expect("nul bytes removed");
+                unsafe { libc::syslog(libc::LOG_NOTICE, c"%s".as_ptr(), message.as_ptr()) };
+            }
+        }
+    }
+
+    pub fn record_result(&self, host: &Host, result: &TaskResult, no_log: bool) {
+        self.record_effects(host, result, no_log);
+        self.record(AuditEvent::TaskFinished {
+            host: &host.address,
+            task: &kind(result).to_string(),
+            status: result.status(),
+        });
+    }
+
+    // The command `result` ran and the file it wrote, also of tasks that
+    // failed afterwards.
+    pub fn record_effects(&self, host: &Host, result: &TaskResult, no_log: bool) {
+        let kind = kind(result);
+        let task = kind.to_string();
+
+        match kind {
+            // Commands a `creates`, `removes` or `unless` probe held back
+            // have no exit status.
+            TaskKind::Shell {
+                command,
+                rc: Some(rc),
+                ..
+            } => self.record(AuditEvent::CommandExecuted {
+                host: &host.address,
+                task: &task,
+                command: if no_log { NO_LOG_MESSAGE } else { command },
+                rc: Some(*rc),
+            }),
+            // Unchanged tasks wrote no file.
+            _ if !matches!(result, TaskResult::Changed(_, _)) => {}
+            TaskKind::Copy {
+                dest: path, result, ..
+            }
+            | TaskKind::Template {
+                dest: path, result, ..
+            }
+            | TaskKind::SearchReplace { path, result, .. } => {
+                self.record(AuditEvent::FileWritten {
+                    host: &host.address,
+                    task: &task,
+                    path,
+                    sha256: result,
+                })
+            }
+            TaskKind::Shell { .. }
+            | TaskKind::Fetch { .. }
+            | TaskKind::File { .. }
+            | TaskKind::Plugin { .. }
+            | TaskKind::Package { .. }
+            | TaskKind::Service { .. }
+            | TaskKind::Git { .. }
+            | TaskKind::IncludeTasks { .. } => {}
+        }
+    }
+}
+
+fn kind(result: &TaskResult) -> &TaskKind {
+    let (TaskResult::Changed(_, kind)
+    | TaskResult::Unchanged(_, kind)
+    | TaskResult::Skipped(_, kind)
+    | TaskResult::Failed(_, kind)) = result;
+    kind
+}
@@ -0,0 +1,9 @@
+// Pushed to hosts by `ansimple --agent`, runs the operations of every task of
+// the run over one channel.
+fn main() {
+    let stdin = std::io::stdin();
+    if let Err(err) = ansimple::agent::serve(stdin.lock(), std::io::stdout().lock()) {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+}
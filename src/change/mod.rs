@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use crate::connection::Connection;
+use crate::error::AnsimpleError;
+
+// Decides whether a task still has to act on the remote host, before it does
+// anything. Every task kind describes its desired state with one of these.
+#[derive(Debug, Clone)]
+pub enum ChangeDetector {
+    // No cheap way to tell, the task always acts.
+    Always,
+    // The file at `path` must have the given SHA-256.
+    Checksum { path: PathBuf, checksum: String },
+    // The file at `path` must exist (or not).
+    Stat { path: PathBuf, exists: bool },
+    // The host is already in the desired state when `command` exits with 0.
+    Probe { command: String },
+}
+
+impl ChangeDetector {
+    pub fn needs_change(&self, connection: &mut dyn Connection) -> Result<bool, AnsimpleError> {
+        match self {
+            ChangeDetector::Always => Ok(true),
+            ChangeDetector::Checksum { path, checksum } => {
+                Ok(connection.checksum(path)?.as_ref() != Some(checksum))
+            }
+            ChangeDetector::Stat { path, exists } => Ok(connection.exists(path)? != *exists),
+            ChangeDetector::Probe { command } => Ok(connection.exec(command)?.2 != 0),
+        }
+    }
+}
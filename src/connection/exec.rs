@@ -0,0 +1,337 @@
+use indexmap::IndexMap;
+use sha2::{Digest, Sha256};
+
+use std::io::{self, Read};
+use std::path::Path;
+
+use super::{quote, Connection, FileStat};
+use crate::credentials::Password;
+use crate::encoding;
+use crate::error::AnsimpleError;
+use crate::platform::Platform;
+
+// How a task's commands run once connected, as the global config, the play,
+// the host and the task settle it, each overriding the ones before.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    pub become_root: bool,
+    // Who commands become, root when not set.
+    pub become_user: Option<String>,
+    // For sudo, when it asks for one.
+    pub become_password: Option<Password>,
+    pub environment: IndexMap<String, String>,
+}
+
+impl ExecOptions {
+    // `connection` with its commands run the way the options say. Tasks that
+    // `become` also read and write their files through commands run as the
+    // user they become, as SFTP only knows the user that connected.
+    pub fn wrap(
+        self,
+        connection: Box<dyn Connection>,
+        platform: Platform,
+    ) -> Result<Box<dyn Connection>, AnsimpleError> {
+        if !self.become_root && self.environment.is_empty() {
+            return Ok(connection);
+        }
+        let valid = |name: &str| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if let Some(name) = self.environment.keys().find(|name| !valid(name)) {
+            return Err(AnsimpleError::Config(format!(
+                "`{name}` is not a valid environment variable name"
+            )));
+        }
+        if self.become_root && platform == Platform::Windows {
+            return Err(AnsimpleError::Config(
+                "`become` needs a POSIX host".to_owned(),
+            ));
+        }
+
+        Ok(Box::new(Wrapped {
+            inner: connection,
+            options: self,
+            platform,
+        }))
+    }
+
+    fn command(&self, command: &str, platform: Platform) -> String {
+        let mut script = String::new();
+        for (name, value) in &self.environment {
+            match platform {
+                Platform::Posix => script.push_str(&format!("export {name}={}\n", quote(value))),
+                Platform::Windows => {
+                    script.push_str(&format!("$env:{name} = '{}'\n", value.replace('\'', "''")))
+                }
+            }
+        }
+        script.push_str(command);
+
+        if !self.become_root {
+            return script;
+        }
+        let user = match &self.become_user {
+            Some(user) => format!("-u {} ", quote(user)),
+            None => String::new(),
+        };
+        // Never prompts: without a password sudo cannot use, it fails.
+        let sudo = format!("sudo -n {user}-- sh -c {}", quote(&script));
+        // The password is the first line of stdin. The shell reads it rather
+        // than sudo, which would leave it to the command when it does not
+        // ask, and sudo remembers it for the command after.
+        match self.become_password {
+            Some(_) => format!(
+                "{{ IFS= read -r password; printf '%s\\n' \"$password\" | sudo -S -p '' -v; }} && {sudo}"
+            ),
+            None => sudo,
+        }
+    }
+
+    // What goes to the command's stdin before its own input.
+    fn preamble(&self) -> Option<Password> {
+        let password = self.become_password.as_ref().filter(|_| self.become_root)?;
+        Some(Password::new(format!("{}\n", password.expose())))
+    }
+}
+
+struct Wrapped {
+    inner: Box<dyn Connection>,
+    options: ExecOptions,
+    platform: Platform,
+}
+
+impl Wrapped {
+    // The stdout of `command`, which has to succeed.
+    fn output(&mut self, command: &str) -> Result<String, AnsimpleError> {
+        let (stdout, stderr, rc) = self.exec(command)?;
+        if rc != 0 {
+            return Err(AnsimpleError::Command {
+                command: command.to_owned(),
+                rc,
+                stderr: stderr.trim().to_owned(),
+            });
+        }
+
+        Ok(stdout)
+    }
+
+    // Streams `source` into `command` and returns how many bytes it took and
+    // their SHA-256.
+    fn feed(
+        &mut self,
+        command: &str,
+        source: &mut dyn Read,
+    ) -> Result<(u64, String), AnsimpleError> {
+        let mut source = Counted {
+            source,
+            hasher: Sha256::new(),
+            bytes: 0,
+        };
+        let (_, stderr, rc) = self.exec_with_input(command, &mut source)?;
+        if rc != 0 {
+            return Err(AnsimpleError::Command {
+                command: command.to_owned(),
+                rc,
+                stderr: stderr.trim().to_owned(),
+            });
+        }
+
+        Ok((source.bytes, format!("{:x}", source.hasher.finalize())))
+    }
+}
+
+// Where a file is put together before it is moved over `path`, next to it so
+// the move replaces it at once.
+fn temp_path(path: &Path) -> String {
+    format!("{}.{:016x}.tmp", path.display(), rand::random::<u64>())
+}
+
+// Moves `temp` over `path` once `command` wrote it, and removes it when that
+// failed.
+fn replace(command: &str, temp: &str, path: &Path) -> String {
+    let (temp, path) = (quote(temp), quote(&path.to_string_lossy()));
+    format!("{{ {command} && mv -f -- {temp} {path}; }} || {{ rm -f -- {temp}; exit 1; }}")
+}
+
+impl Connection for Wrapped {
+    fn exec(&mut self, command: &str) -> Result<(String, String, i32), AnsimpleError> {
+        let command = self.options.command(command, self.platform);
+        match self.options.preamble() {
+            Some(preamble) => self
+                .inner
+                .exec_with_input(&command, &mut preamble.expose().as_bytes()),
+            None => self.inner.exec(&command),
+        }
+    }
+
+    fn exec_with_input(
+        &mut self,
+        command: &str,
+        input: &mut dyn Read,
+    ) -> Result<(String, String, i32), AnsimpleError> {
+        let command = self.options.command(command, self.platform);
+        match self.options.preamble() {
+            Some(preamble) => self
+                .inner
+                .exec_with_input(&command, &mut preamble.expose().as_bytes().chain(input)),
+            None => self.inner.exec_with_input(&command, input),
+        }
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.exists(path);
+        }
+        let command = format!("test -e {}", quote(&path.to_string_lossy()));
+        match self.exec(&command)? {
+            (_, _, 0) => Ok(true),
+            (_, _, 1) => Ok(false),
+            (_, stderr, rc) => Err(AnsimpleError::Command {
+                command,
+                rc,
+                stderr: stderr.trim().to_owned(),
+            }),
+        }
+    }
+
+    fn checksum(&mut self, path: &Path) -> Result<Option<String>, AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.checksum(path);
+        }
+        let path = quote(&path.to_string_lossy());
+        let stdout = self.output(&format!("if [ -e {path} ]; then sha256sum < {path}; fi"))?;
+
+        Ok(stdout.split_whitespace().next().map(str::to_owned))
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.read(path);
+        }
+        // Commands give text, the contents pass as base64.
+        let stdout = self.output(&format!("base64 < {}", quote(&path.to_string_lossy())))?;
+        encoding::b64decode(&stdout).ok_or_else(|| {
+            AnsimpleError::Transfer(format!("{} did not read back as base64", path.display()))
+        })
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        source: &mut dyn Read,
+    ) -> Result<(u64, String), AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.write(path, source);
+        }
+        let temp = temp_path(path);
+        let command = replace(&format!("cat > {}", quote(&temp)), &temp, path);
+        self.feed(&command, source)
+    }
+
+    fn copy(&mut self, src: &Path, dest: &Path) -> Result<(u64, String), AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.copy(src, dest);
+        }
+        let temp = temp_path(dest);
+        let copy = format!("cp -- {} {}", quote(&src.to_string_lossy()), quote(&temp));
+        let dest_quoted = quote(&dest.to_string_lossy());
+        let stdout = self.output(&format!(
+            "{} && wc -c < {dest_quoted} && sha256sum < {dest_quoted}",
+            replace(&copy, &temp, dest)
+        ))?;
+
+        let mut fields = stdout.split_whitespace();
+        match (
+            fields.next().and_then(|size| size.parse().ok()),
+            fields.next(),
+        ) {
+            (Some(size), Some(checksum)) => Ok((size, checksum.to_owned())),
+            _ => Err(AnsimpleError::Transfer(format!(
+                "copying to {} did not say what it copied",
+                dest.display()
+            ))),
+        }
+    }
+
+    fn size(&mut self, path: &Path) -> Result<Option<u64>, AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.size(path);
+        }
+        let path = quote(&path.to_string_lossy());
+        let stdout = self.output(&format!("if [ -e {path} ]; then wc -c < {path}; fi"))?;
+
+        Ok(stdout.trim().parse().ok())
+    }
+
+    fn append(&mut self, path: &Path, source: &mut dyn Read) -> Result<u64, AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.append(path, source);
+        }
+        let command = format!("cat >> {}", quote(&path.to_string_lossy()));
+        Ok(self.feed(&command, source)?.0)
+    }
+
+    fn rename(&mut self, src: &Path, dest: &Path) -> Result<(), AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.rename(src, dest);
+        }
+        self.output(&format!(
+            "mv -f -- {} {}",
+            quote(&src.to_string_lossy()),
+            quote(&dest.to_string_lossy())
+        ))?;
+        Ok(())
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, AnsimpleError> {
+        if !self.options.become_root {
+            return self.inner.stat(path);
+        }
+        // The raw mode in hex, as GNU and then BSD `stat` write it, and where
+        // a symlink points on the line after.
+        let path = quote(&path.to_string_lossy());
+        let stdout = self.output(&format!(
+            "if [ -e {path} ] || [ -L {path} ]; then \
+             stat -c '%f %u %g' -- {path} 2>/dev/null || stat -f '%Xp %u %g' -- {path}; \
+             if [ -L {path} ]; then readlink -- {path}; fi; fi"
+        ))?;
+        let mut lines = stdout.lines();
+        let Some(fields) = lines.next() else {
+            return Ok(None);
+        };
+
+        let fields = fields.split_whitespace().collect::<Vec<_>>();
+        let parsed = match fields.as_slice() {
+            [mode, uid, gid] => u32::from_str_radix(mode, 16)
+                .ok()
+                .zip(uid.parse().ok())
+                .zip(gid.parse().ok()),
+            _ => None,
+        };
+        let Some(((mode, uid), gid)) = parsed else {
+            return Err(AnsimpleError::Transfer(format!(
+                "cannot make out the stat of {path}: {stdout}"
+            )));
+        };
+        let target = lines.next().map(str::to_owned);
+
+        Ok(Some(FileStat::new(mode, uid, gid, target)))
+    }
+}
+
+// Counts and hashes what passes through on its way to a command.
+struct Counted<'a> {
+    source: &'a mut dyn Read,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl Read for Counted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.source.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,143 @@
+use ssh2::{Channel, Session};
+
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use super::CHUNK_SIZE;
+use crate::error::AnsimpleError;
+use crate::inventory::Host;
+
+// How long the relay, or a command's output, waits for either side before looking again.
+const POLL_MS: i32 = 50;
+
+// The host a `proxy_jump` of `[user@]host[:port]` names. It is logged in to
+// like `host` itself, as its own user when one is given.
+pub fn jump_host(host: &Host, jump: &str) -> Result<Host, AnsimpleError> {
+    let (user, address) = match jump.rsplit_once('@') {
+        Some((user, address)) => (Some(user.to_owned()), address),
+        None => (host.user.clone(), jump),
+    };
+    let (address, port) = match address.rsplit_once(':') {
+        Some((address, port)) => {
+            let port = port.parse().map_err(|_| {
+                AnsimpleError::Config(format!(
+                    "`{jump}` is not a jump host such as user@bastion:22"
+                ))
+            })?;
+            (address, port)
+        }
+        None => (address, 22),
+    };
+    if address.is_empty() {
+        return Err(AnsimpleError::Config(format!(
+            "`{jump}` is not a jump host such as user@bastion:22"
+        )));
+    }
+
+    Ok(Host {
+        address: address.to_owned(),
+        user,
+        port: Some(port),
+        proxy_jump: None,
+        ..host.clone()
+    })
+}
+
+// A stream to `address:port` as seen from the jump host, for a session of
+// its own. A thread relays between it and a channel of `jump` for as long as
+// either side is open.
+pub fn tunnel(jump: Session, address: &str, port: u16) -> Result<UnixStream, AnsimpleError> {
+    let channel = jump.channel_direct_tcpip(address, port, None)?;
+    let (local, relayed) = UnixStream::pair()?;
+    thread::spawn(move || {
+        // The session on top notices a relay that stopped.
+        let _ = relay(&jump, channel, relayed);
+    });
+
+    Ok(local)
+}
+
+// Neither side is waited on while there is something to give the other, so
+// a full buffer on one side never keeps the other from being read.
+fn relay(jump: &Session, mut channel: Channel, mut local: UnixStream) -> io::Result<()> {
+    jump.set_blocking(false);
+    local.set_nonblocking(true)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let (mut outgoing, mut incoming) = (Vec::new(), Vec::new());
+
+    loop {
+        let mut moved = false;
+        if outgoing.is_empty() {
+            match local.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(read) => {
+                    outgoing.extend_from_slice(&buffer[..read]);
+                    moved = true;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        moved |= flush(&mut channel, &mut outgoing)?;
+
+        if incoming.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) if channel.eof() => return Ok(()),
+                Ok(read) => {
+                    incoming.extend_from_slice(&buffer[..read]);
+                    moved |= read > 0;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        moved |= flush(&mut local, &mut incoming)?;
+
+        // Local data is only waited for once the channel took what came
+        // before, and room on the local side while there is some to give it.
+        if !moved {
+            let mut local_events = 0;
+            if outgoing.is_empty() {
+                local_events |= libc::POLLIN;
+            }
+            if !incoming.is_empty() {
+                local_events |= libc::POLLOUT;
+            }
+            wait(&[
+                (jump.as_raw_fd(), libc::POLLIN),
+                (local.as_raw_fd(), local_events),
+            ]);
+        }
+    }
+}
+
+// Writes what `writer` takes of `pending` and returns whether it took any.
+pub fn flush(writer: &mut impl Write, pending: &mut Vec<u8>) -> io::Result<bool> {
+    if pending.is_empty() {
+        return Ok(false);
+    }
+    match writer.write(pending) {
+        Ok(0) => Err(io::ErrorKind::WriteZero.into()),
+        Ok(written) => {
+            pending.drain(..written);
+            Ok(true)
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn wait(fds: &[(RawFd, libc::c_short)]) {
+    let mut polled = fds
+        .iter()
+        .map(|(fd, events)| libc::pollfd {
+            fd: *fd,
+            events: *events,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    // SAFETY: `polled` is a valid array of `polled.len()` pollfds
+    unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, POLL_MS) };
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, Session};
+use tera::Value;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::encoding;
+use crate::error::AnsimpleError;
+use crate::schema::{self, Generator, Schema};
+
+// Keeps sessions opened to the same new host at once from recording its key
+// twice.
+static RECORDING: Mutex<()> = Mutex::new(());
+
+// What is done with a host whose key is not in known_hosts, as OpenSSH's
+// `StrictHostKeyChecking`. A key other than the one known always fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyChecking {
+    // It is not connected to.
+    Yes,
+    // Its key is recorded in known_hosts.
+    #[default]
+    AcceptNew,
+    // Any key is taken and nothing is recorded.
+    No,
+}
+
+impl Schema for HostKeyChecking {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["yes", "accept-new", "no"])
+    }
+}
+
+impl FromStr for HostKeyChecking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yes" => Ok(Self::Yes),
+            "accept-new" => Ok(Self::AcceptNew),
+            "no" => Ok(Self::No),
+            _ => Err(format!(
+                "invalid host key checking `{s}`, expected yes, accept-new or no"
+            )),
+        }
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+// Checks the key `host` offered on `session` against the known_hosts file at
+// `path`, in which hosts on another port than 22 are `[host]:port`.
+pub fn verify(
+    session: &Session,
+    host: &str,
+    port: u16,
+    path: Option<&Path>,
+    checking: HostKeyChecking,
+) -> Result<(), AnsimpleError> {
+    if checking == HostKeyChecking::No {
+        return Ok(());
+    }
+    let error = |reason: String| AnsimpleError::HostKey {
+        host: host.to_owned(),
+        reason,
+    };
+    let path =
+        path.ok_or_else(|| error("there is no known_hosts file to check it in".to_owned()))?;
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| error("it offered no key".to_owned()))?;
+
+    let _recording = RECORDING.lock().expect("known hosts lock poisoned");
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts
+            .read_file(path, KnownHostFileKind::OpenSSH)
+            .map_err(|err| error(format!("cannot read {}: {err}", path.display())))?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(error(format!(
+            "its key is not the one in {}, which may mean the connection is intercepted",
+            path.display()
+        ))),
+        CheckResult::NotFound if checking == HostKeyChecking::AcceptNew => {
+            record(path, host, port, key, key_type).map_err(error)
+        }
+        CheckResult::NotFound => Err(error(format!(
+            "its key is not in {}, connect with `--strict-host-key-checking accept-new` to record it",
+            path.display()
+        ))),
+        CheckResult::Failure => Err(error(format!(
+            "its key could not be checked against {}",
+            path.display()
+        ))),
+    }
+}
+
+fn record(
+    path: &Path,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    key_type: HostKeyType,
+) -> Result<(), String> {
+    let key_type = match key_type {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed255219 => "ssh-ed25519",
+        HostKeyType::Unknown => return Err("its key is of an unknown type".to_owned()),
+    };
+    let name = match port {
+        22 => host.to_owned(),
+        port => format!("[{host}]:{port}"),
+    };
+    let line = format!("{name} {key_type} {}\n", encoding::b64encode(key));
+
+    let written = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir),
+        _ => Ok(()),
+    }
+    .and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+    })
+    .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    written.map_err(|err| format!("cannot record its key in {}: {err}", path.display()))
+}
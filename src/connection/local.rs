@@ -0,0 +1,157 @@
+use sha2::{Digest, Sha256};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use super::{stream, Connection, FileStat};
+use crate::error::AnsimpleError;
+
+// This machine, as whoever runs ansimple. Commands run with `/bin/sh` in the
+// directory ansimple was started in.
+pub struct LocalConnection;
+
+impl Connection for LocalConnection {
+    fn exec(&mut self, command: &str) -> Result<(String, String, i32), AnsimpleError> {
+        let output = shell(command).stdin(Stdio::null()).output()?;
+        Ok(outcome(output))
+    }
+
+    fn exec_with_input(
+        &mut self,
+        command: &str,
+        input: &mut dyn Read,
+    ) -> Result<(String, String, i32), AnsimpleError> {
+        let mut child = shell(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = stream(input, &mut stdin);
+        drop(stdin);
+        let output = child.wait_with_output()?;
+
+        // As over SSH, a command that gave up early says more about it than
+        // the broken pipe.
+        match written {
+            Err(err) if output.status.success() => Err(err.into()),
+            _ => Ok(outcome(output)),
+        }
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, AnsimpleError> {
+        match fs::metadata(path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(source) => Err(read_error(path, source)),
+        }
+    }
+
+    fn checksum(&mut self, path: &Path) -> Result<Option<String>, AnsimpleError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(read_error(path, source)),
+        };
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher).map_err(|source| read_error(path, source))?;
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, AnsimpleError> {
+        fs::read(path).map_err(|source| read_error(path, source))
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        source: &mut dyn Read,
+    ) -> Result<(u64, String), AnsimpleError> {
+        File::create(path)
+            .and_then(|mut file| stream(source, &mut file))
+            .map_err(|source| write_error(path, source))
+    }
+
+    fn copy(&mut self, src: &Path, dest: &Path) -> Result<(u64, String), AnsimpleError> {
+        let mut file = File::open(src).map_err(|source| read_error(src, source))?;
+        File::create(dest)
+            .and_then(|mut copy| stream(&mut file, &mut copy))
+            .map_err(|source| write_error(dest, source))
+    }
+
+    fn size(&mut self, path: &Path) -> Result<Option<u64>, AnsimpleError> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(read_error(path, source)),
+        }
+    }
+
+    fn append(&mut self, path: &Path, source: &mut dyn Read) -> Result<u64, AnsimpleError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| stream(source, &mut file))
+            .map(|(bytes, _)| bytes)
+            .map_err(|source| write_error(path, source))
+    }
+
+    fn rename(&mut self, src: &Path, dest: &Path) -> Result<(), AnsimpleError> {
+        fs::rename(src, dest).map_err(|source| write_error(dest, source))
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, AnsimpleError> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(read_error(path, source)),
+        };
+        let target = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(path).map_err(|source| read_error(path, source))?;
+            Some(target.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+
+        Ok(Some(FileStat::new(
+            metadata.mode(),
+            metadata.uid(),
+            metadata.gid(),
+            target,
+        )))
+    }
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("/bin/sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+fn outcome(output: Output) -> (String, String, i32) {
+    (
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+        output.status.code().unwrap_or(-1),
+    )
+}
+
+// Files that cannot be read or written here are not a broken connection, so
+// they are not retried like one.
+fn read_error(path: &Path, source: io::Error) -> AnsimpleError {
+    AnsimpleError::Read {
+        path: path.to_owned(),
+        source,
+    }
+}
+
+fn write_error(path: &Path, source: io::Error) -> AnsimpleError {
+    AnsimpleError::Write {
+        path: path.to_owned(),
+        source,
+    }
+}
@@ -0,0 +1,762 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssh2::{BlockDirections, Channel, ErrorCode, OpenFlags, OpenType, Session, Sftp};
+use tera::Value;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::credentials::Password;
+use crate::encoding;
+use crate::error::AnsimpleError;
+use crate::inventory::{GlobalConfig, Host};
+use crate::platform::Platform;
+use crate::runner::RunOptions;
+use crate::schema::{self, Generator, Schema};
+use crate::task::{CopyMode, Mode};
+use crate::throttle::Throttle;
+
+mod exec;
+mod jump;
+mod known_hosts;
+mod local;
+mod pool;
+mod tar;
+mod workers;
+
+pub use exec::ExecOptions;
+pub use known_hosts::HostKeyChecking;
+pub use local::LocalConnection;
+pub use pool::SessionPool;
+pub use tar::Archive;
+pub use workers::{Workers, DEFAULT_WORKERS};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const LIBSSH2_FX_NO_SUCH_FILE: i32 = 2;
+// The file type bits of a mode, and the types in them.
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+// How a host is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionKind {
+    #[default]
+    Ssh,
+    // This machine, without SSH.
+    Local,
+}
+
+impl Schema for ConnectionKind {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["ssh", "local"])
+    }
+}
+
+// What is at a path on a host, with the permission bits of its mode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub kind: FileKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink { target: String },
+    Other,
+}
+
+impl FileStat {
+    // From a full mode, type bits included, and where a symlink points.
+    pub fn new(mode: u32, uid: u32, gid: u32, target: Option<String>) -> Self {
+        let kind = match (mode & S_IFMT, target) {
+            (S_IFLNK, Some(target)) => FileKind::Symlink { target },
+            (S_IFDIR, _) => FileKind::Directory,
+            (S_IFREG, _) => FileKind::File,
+            _ => FileKind::Other,
+        };
+
+        Self {
+            kind,
+            mode: mode & 0o7777,
+            uid,
+            gid,
+        }
+    }
+
+    pub fn is_symlink(mode: u32) -> bool {
+        mode & S_IFMT == S_IFLNK
+    }
+}
+
+// What tasks do on a host. Tasks never touch SSH themselves, so they run the
+// same over a session of their own and through the pushed agent.
+pub trait Connection: Send {
+    // The stdout, stderr and exit status of `command`.
+    fn exec(&mut self, command: &str) -> Result<(String, String, i32), AnsimpleError>;
+
+    // Like `exec`, with what `input` yields as the command's stdin. The
+    // output is only read once the input is used up, so the command should
+    // not have much to say before it has read all of it.
+    fn exec_with_input(
+        &mut self,
+        command: &str,
+        input: &mut dyn Read,
+    ) -> Result<(String, String, i32), AnsimpleError>;
+
+    fn exists(&mut self, path: &Path) -> Result<bool, AnsimpleError>;
+
+    // `None` when the file does not exist.
+    fn checksum(&mut self, path: &Path) -> Result<Option<String>, AnsimpleError>;
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, AnsimpleError>;
+
+    // Replaces `path` with what `source` yields and returns the number of
+    // bytes written and their SHA-256.
+    fn write(&mut self, path: &Path, source: &mut dyn Read)
+        -> Result<(u64, String), AnsimpleError>;
+
+    // Copies a file that is already on the host.
+    fn copy(&mut self, src: &Path, dest: &Path) -> Result<(u64, String), AnsimpleError>;
+
+    // `None` when the file does not exist.
+    fn size(&mut self, path: &Path) -> Result<Option<u64>, AnsimpleError>;
+
+    // Adds what `source` yields to the end of `path`, creating it if needed,
+    // and returns the number of bytes written.
+    fn append(&mut self, path: &Path, source: &mut dyn Read) -> Result<u64, AnsimpleError>;
+
+    // Moves `src` over `dest`, replacing it.
+    fn rename(&mut self, src: &Path, dest: &Path) -> Result<(), AnsimpleError>;
+
+    // What is at `path`, without following a symlink there. `None` when
+    // nothing is.
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, AnsimpleError>;
+}
+
+// Connects to `host` the way the run is configured to. Local hosts run
+// everything on this machine instead.
+pub fn open(
+    host: &Host,
+    options: &RunOptions,
+    global_config: &GlobalConfig,
+) -> Result<Box<dyn Connection>, AnsimpleError> {
+    if host.connection == Some(ConnectionKind::Local) {
+        return Ok(Box::new(LocalConnection));
+    }
+    match &options.agent {
+        Some(_) if host.platform() == Platform::Windows => Err(AnsimpleError::Agent {
+            host: host.address.clone(),
+            message: "the agent runs on POSIX hosts only".to_owned(),
+        }),
+        Some(agents) => agents.connect(host, options, global_config),
+        None => options.sessions.open(host, options, global_config),
+    }
+}
+
+// An authenticated session, trying the agent, then the key file, then the
+// password. Hosts with a `proxy_jump` are reached through a session with
+// the jump host.
+pub fn session(
+    host: &Host,
+    options: &RunOptions,
+    global_config: &GlobalConfig,
+) -> Result<Session, AnsimpleError> {
+    let Some(jump) = host
+        .proxy_jump
+        .as_ref()
+        .or(global_config.proxy_jump.as_ref())
+    else {
+        let mut session = Session::new()?;
+        session.set_tcp_stream(connect(host, options, global_config)?);
+        return authenticate(session, host, options, global_config);
+    };
+
+    let jump_host = jump::jump_host(host, jump)?;
+    let mut jump_session = Session::new()?;
+    jump_session.set_tcp_stream(connect(&jump_host, options, global_config)?);
+    let jump_session = authenticate(jump_session, &jump_host, options, global_config)?;
+    let port = host.port.or(global_config.port).unwrap_or(22);
+    let tunnel =
+        jump::tunnel(jump_session, &host.address, port).map_err(|err| AnsimpleError::Connect {
+            host: host.address.clone(),
+            source: io::Error::other(format!("through {jump}: {err}")),
+        })?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tunnel);
+
+    authenticate(session, host, options, global_config)
+}
+
+fn connect(
+    host: &Host,
+    options: &RunOptions,
+    global_config: &GlobalConfig,
+) -> Result<TcpStream, AnsimpleError> {
+    let tcp = match options.connect_to.get(&host.address) {
+        Some(address) => TcpStream::connect(address),
+        None => TcpStream::connect((
+            host.address.as_str(),
+            host.port.or(global_config.port).unwrap_or(22),
+        )),
+    }
+    .map_err(|source| AnsimpleError::Connect {
+        host: host.address.clone(),
+        source,
+    })?;
+    // Requests and replies are small, do not hold them back for coalescing.
+    tcp.set_nodelay(true)?;
+
+    Ok(tcp)
+}
+
+// The host's own password wins over the one asked for at the start.
+fn authenticate(
+    mut session: Session,
+    host: &Host,
+    options: &RunOptions,
+    global_config: &GlobalConfig,
+) -> Result<Session, AnsimpleError> {
+    let user = host.user.as_ref().unwrap_or(&global_config.user);
+    let key = host.key.as_ref().unwrap_or(&global_config.key);
+    session.handshake()?;
+    let known_hosts = global_config
+        .known_hosts
+        .clone()
+        .or_else(known_hosts::default_path);
+    known_hosts::verify(
+        &session,
+        &host.address,
+        host.port.or(global_config.port).unwrap_or(22),
+        known_hosts.as_deref(),
+        options
+            .host_key_checking
+            .or(global_config.strict_host_key_checking)
+            .unwrap_or_default(),
+    )?;
+    let agent_identity = host
+        .agent_identity
+        .as_ref()
+        .or(global_config.agent_identity.as_ref());
+    let mut auth_result = match agent_identity {
+        Some(selector) => userauth_agent_identity(&session, user, selector),
+        None => session.userauth_agent(user).map_err(Into::into),
+    };
+
+    if !session.authenticated() {
+        let passphrase = host
+            .key_passphrase
+            .as_ref()
+            .or(global_config.key_passphrase.as_ref())
+            .map(Password::expose);
+        auth_result = session
+            .userauth_pubkey_file(user, None, Path::new(&key), passphrase)
+            .map_err(Into::into);
+    }
+
+    if !session.authenticated() {
+        let password = host
+            .password
+            .as_ref()
+            .or(global_config.password.as_ref())
+            .or(options.credentials.ssh_password.as_ref());
+        if let Some(password) = password {
+            auth_result = session
+                .userauth_password(user, password.expose())
+                .map_err(Into::into);
+        }
+    }
+
+    auth_result.map_err(|err| AnsimpleError::Auth {
+        host: host.address.clone(),
+        user: user.clone(),
+        reason: err.to_string(),
+    })?;
+
+    Ok(session)
+}
+
+// Runs every operation as its own channel or SFTP request, in the shell and
+// with the paths of the host's platform. File contents pass the throttle on
+// their way in either direction.
+pub struct SshConnection {
+    session: Session,
+    sftp: Option<Sftp>,
+    platform: Platform,
+    throttle: Throttle,
+}
+
+impl SshConnection {
+    pub fn new(session: Session, platform: Platform, throttle: Throttle) -> Self {
+        Self {
+            session,
+            sftp: None,
+            platform,
+            throttle,
+        }
+    }
+
+    fn sftp(&mut self) -> Result<&Sftp, AnsimpleError> {
+        if self.sftp.is_none() {
+            self.sftp = Some(self.session.sftp()?);
+        }
+
+        Ok(self.sftp.as_ref().expect("sftp session opened"))
+    }
+}
+
+impl Connection for SshConnection {
+    fn exec(&mut self, command: &str) -> Result<(String, String, i32), AnsimpleError> {
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&self.platform.command(command))?;
+        let (stdout, stderr, _) = communicate(&self.session, &mut channel, None)?;
+        channel.wait_close()?;
+
+        Ok((stdout, stderr, channel.exit_status()?))
+    }
+
+    fn exec_with_input(
+        &mut self,
+        command: &str,
+        input: &mut dyn Read,
+    ) -> Result<(String, String, i32), AnsimpleError> {
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&self.platform.command(command))?;
+        let mut input = self.throttle.reader(input);
+        let (stdout, stderr, written) = communicate(&self.session, &mut channel, Some(&mut input))?;
+        channel.wait_close()?;
+        let rc = channel.exit_status()?;
+
+        // A command that gave up early stopped reading, what it said about
+        // it is more telling than the broken pipe.
+        match written {
+            Err(err) if rc == 0 => Err(err.into()),
+            _ => Ok((stdout, stderr, rc)),
+        }
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        match self.sftp()?.stat(&path) {
+            Ok(_) => Ok(true),
+            Err(err) if is_not_found(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn checksum(&mut self, path: &Path) -> Result<Option<String>, AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        let file = match self.sftp()?.open(&path) {
+            Ok(file) => file,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut self.throttle.reader(file), &mut hasher)?;
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        let file = self.sftp()?.open(&path)?;
+        let mut contents = Vec::new();
+        self.throttle.reader(file).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        source: &mut dyn Read,
+    ) -> Result<(u64, String), AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        let mut file = self.sftp()?.create(&path)?;
+        Ok(stream(&mut self.throttle.reader(source), &mut file)?)
+    }
+
+    fn copy(&mut self, src: &Path, dest: &Path) -> Result<(u64, String), AnsimpleError> {
+        let platform = self.platform;
+        let throttle = self.throttle.clone();
+        let sftp = self.sftp()?;
+        let remote_file = sftp.open(&platform.remote_path(src))?;
+        let mut remote_dest = sftp.create(&platform.remote_path(dest))?;
+        Ok(stream(&mut throttle.reader(remote_file), &mut remote_dest)?)
+    }
+
+    fn size(&mut self, path: &Path) -> Result<Option<u64>, AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        match self.sftp()?.stat(&path) {
+            Ok(stat) => Ok(stat.size),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn append(&mut self, path: &Path, source: &mut dyn Read) -> Result<u64, AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        let sftp = self.sftp()?;
+        let mut file = sftp.open_mode(
+            &path,
+            OpenFlags::WRITE | OpenFlags::CREATE,
+            0o644,
+            OpenType::File,
+        )?;
+        // Servers differ in what they make of the append flag, an explicit
+        // offset means the same to all of them.
+        let end = file.stat()?.size.unwrap_or(0);
+        file.seek(SeekFrom::Start(end))?;
+        let (bytes, _) = stream(&mut self.throttle.reader(source), &mut file)?;
+        Ok(bytes)
+    }
+
+    // SFTP version 3 does not replace files on rename.
+    fn rename(&mut self, src: &Path, dest: &Path) -> Result<(), AnsimpleError> {
+        let platform = self.platform;
+        let sftp = self.sftp()?;
+        let dest = platform.remote_path(dest);
+        match sftp.unlink(&dest) {
+            Ok(()) => {}
+            Err(err) if is_not_found(&err) => {}
+            Err(err) => return Err(err.into()),
+        }
+        sftp.rename(&platform.remote_path(src), &dest, None)?;
+        Ok(())
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, AnsimpleError> {
+        let path = self.platform.remote_path(path);
+        let sftp = self.sftp()?;
+        let stat = match sftp.lstat(&path) {
+            Ok(stat) => stat,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mode = stat.perm.unwrap_or_default();
+        let target = if FileStat::is_symlink(mode) {
+            Some(sftp.readlink(&path)?.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+
+        Ok(Some(FileStat::new(
+            mode,
+            stat.uid.unwrap_or_default(),
+            stat.gid.unwrap_or_default(),
+            target,
+        )))
+    }
+}
+
+// Where `upload` keeps what it sent until the file is complete.
+pub fn partial_path(dest: &Path) -> PathBuf {
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+// Uploads `src` to `dest` by way of `<dest>.partial`, so an interrupted upload
+// never leaves a truncated `dest` behind. A partial file from an earlier
+// attempt is continued where it ends, provided it matches the start of `src`.
+// `progress` is told how many bytes of `src` are on the host so far. Returns
+// the number of bytes sent and the SHA-256 of `src`.
+pub fn upload(
+    connection: &mut dyn Connection,
+    src: &Path,
+    dest: &Path,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(u64, String), AnsimpleError> {
+    let read_error = |source| AnsimpleError::Read {
+        path: src.to_owned(),
+        source,
+    };
+    let mut file = File::open(src).map_err(read_error)?;
+    let total = file.metadata().map_err(read_error)?.len();
+    let partial = partial_path(dest);
+
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    if let Some(size) = connection.size(&partial)? {
+        if size > 0 && size <= total {
+            io::copy(&mut (&mut file).take(size), &mut hasher).map_err(read_error)?;
+            let prefix = format!("{:x}", hasher.clone().finalize());
+            if connection.checksum(&partial)?.as_deref() == Some(prefix.as_str()) {
+                offset = size;
+            } else {
+                hasher = Sha256::new();
+                file.seek(SeekFrom::Start(0)).map_err(read_error)?;
+            }
+        }
+    }
+    progress(offset);
+
+    let mut source = Progress {
+        file,
+        hasher: &mut hasher,
+        bytes: offset,
+        progress,
+    };
+    let sent = if offset > 0 {
+        connection.append(&partial, &mut source)?
+    } else {
+        connection.write(&partial, &mut source)?.0
+    };
+    connection.rename(&partial, dest)?;
+
+    Ok((sent, format!("{:x}", hasher.finalize())))
+}
+
+// Unpacks `archive`, a local directory, into `dest` from a single tar stream,
+// which spares trees of small files a round trip per file. The host's `tar`
+// unpacks it and `sha256sum` vouches for every file afterwards. Files keep
+// their local modes with `preserve`, or all get the one given. `progress`
+// is told how much of the stream went out so far. Returns the size of the
+// stream and a SHA-256 over the checksums of all files.
+pub fn upload_tree(
+    connection: &mut dyn Connection,
+    mut archive: Archive,
+    dest: &Path,
+    mode: Option<CopyMode>,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(u64, String), AnsimpleError> {
+    let quoted = quote(&dest.to_string_lossy());
+    let (flags, chmod) = match mode {
+        None => ("-xof", String::new()),
+        Some(CopyMode::Preserve) => ("-xpof", String::new()),
+        Some(CopyMode::Bits(Mode(bits))) => (
+            "-xof",
+            format!(" && find . -type f -exec chmod {bits:04o} {{}} +"),
+        ),
+    };
+    let command = format!(
+        "mkdir -p {quoted} && cd {quoted} && tar {flags} -{chmod} && find . -type f -exec sha256sum {{}} +"
+    );
+
+    progress(0);
+    let mut hasher = Sha256::new();
+    let mut source = Progress {
+        file: &mut archive,
+        hasher: &mut hasher,
+        bytes: 0,
+        progress,
+    };
+    let (stdout, stderr, rc) = connection.exec_with_input(&command, &mut source)?;
+    let sent = source.bytes;
+    if rc != 0 {
+        return Err(AnsimpleError::Transfer(format!(
+            "unpacking into {} exited with {rc}: {}",
+            dest.display(),
+            stderr.trim()
+        )));
+    }
+
+    let unpacked: HashMap<String, String> = tar::parse_sha256sum(&stdout).collect();
+    let mut manifest = Sha256::new();
+    for (name, checksum) in archive.checksums() {
+        match unpacked.get(name) {
+            Some(unpacked) if unpacked == checksum => {
+                manifest.update(format!("{checksum}  {name}\n"));
+            }
+            Some(_) => {
+                return Err(AnsimpleError::Transfer(format!(
+                    "{name} in {} does not match the local file",
+                    dest.display()
+                )))
+            }
+            None => {
+                return Err(AnsimpleError::Transfer(format!(
+                    "{name} is missing from {} after unpacking",
+                    dest.display()
+                )))
+            }
+        }
+    }
+
+    Ok((sent, format!("{:x}", manifest.finalize())))
+}
+
+// Single quotes `value` for a POSIX shell.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Hashes and counts what is read locally on its way to the host.
+struct Progress<'a, R> {
+    file: R,
+    hasher: &'a mut Sha256,
+    bytes: u64,
+    progress: &'a mut dyn FnMut(u64),
+}
+
+impl<R: Read> Read for Progress<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.bytes += read as u64;
+        (self.progress)(self.bytes);
+        Ok(read)
+    }
+}
+
+// What a command says on stdout and stderr, taken from both as it comes so
+// a full window on one never keeps the other from being read. `input` goes
+// to its stdin meanwhile, and the last part is whether all of it got there.
+fn communicate(
+    session: &Session,
+    channel: &mut Channel,
+    input: Option<&mut dyn Read>,
+) -> io::Result<(String, String, io::Result<()>)> {
+    session.set_blocking(false);
+    let output = pump(session, channel, input);
+    session.set_blocking(true);
+    let (stdout, stderr, written) = output?;
+
+    let text = |bytes| {
+        String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    };
+    Ok((text(stdout)?, text(stderr)?, written))
+}
+
+fn pump(
+    session: &Session,
+    channel: &mut Channel,
+    mut input: Option<&mut dyn Read>,
+) -> io::Result<(Vec<u8>, Vec<u8>, io::Result<()>)> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let (mut stdout, mut stderr, mut pending) = (Vec::new(), Vec::new(), Vec::new());
+    let mut written = Ok(());
+    let mut closing = input.is_some();
+
+    loop {
+        let mut moved = false;
+        if let Some(reader) = input.as_mut().filter(|_| pending.is_empty()) {
+            match reader.read(&mut buffer) {
+                Ok(0) => input = None,
+                Ok(read) => pending.extend_from_slice(&buffer[..read]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+            moved = true;
+        }
+        match jump::flush(channel, &mut pending) {
+            Ok(flushed) => moved |= flushed,
+            // The command stopped reading, what it says is still wanted.
+            Err(err) => {
+                (input, closing, written) = (None, false, Err(err));
+                pending.clear();
+            }
+        }
+        if closing && input.is_none() && pending.is_empty() {
+            match channel.send_eof().map_err(io::Error::from) {
+                Ok(()) => (closing, moved) = (false, true),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => (closing, written) = (false, Err(err)),
+            }
+        }
+
+        let taken = take(channel, &mut stdout, &mut buffer)?
+            | take(&mut channel.stderr(), &mut stderr, &mut buffer)?;
+        if !taken && channel.eof() {
+            break;
+        }
+        if !(moved || taken) {
+            let mut events = libc::POLLIN;
+            if matches!(
+                session.block_directions(),
+                BlockDirections::Outbound | BlockDirections::Both
+            ) {
+                events |= libc::POLLOUT;
+            }
+            jump::wait(&[(session.as_raw_fd(), events)]);
+        }
+    }
+
+    if written.is_ok() && (closing || !pending.is_empty()) {
+        written = Err(io::ErrorKind::BrokenPipe.into());
+    }
+    Ok((stdout, stderr, written))
+}
+
+// Appends what `reader` has at hand to `output` and returns whether it had any.
+fn take(reader: &mut impl Read, output: &mut Vec<u8>, buffer: &mut [u8]) -> io::Result<bool> {
+    match reader.read(buffer) {
+        Ok(read) => {
+            output.extend_from_slice(&buffer[..read]);
+            Ok(read > 0)
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// Copies in fixed-size chunks, hashing along the way, so memory use does not
+// grow with the size of the file.
+pub fn stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<(u64, String)> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        bytes += read as u64;
+    }
+    writer.flush()?;
+
+    Ok((bytes, format!("{:x}", hasher.finalize())))
+}
+
+pub fn is_not_found(err: &ssh2::Error) -> bool {
+    err.code() == ErrorCode::SFTP(LIBSSH2_FX_NO_SUCH_FILE)
+}
+
+// Offers only the agent identity whose comment or `SHA256:` fingerprint
+// matches, so servers never see (and count) attempts with unrelated keys.
+fn userauth_agent_identity(
+    session: &Session,
+    user: &str,
+    selector: &str,
+) -> Result<(), AnsimpleError> {
+    let mut agent = session.agent()?;
+    agent.connect()?;
+    agent.list_identities()?;
+
+    let identity = agent
+        .identities()?
+        .into_iter()
+        .find(|identity| {
+            identity.comment() == selector
+                || fingerprint(identity.blob()) == selector.trim_end_matches('=')
+        })
+        .ok_or_else(|| AnsimpleError::AgentIdentity(selector.to_owned()))?;
+
+    agent.userauth(user, &identity)?;
+    Ok(())
+}
+
+fn fingerprint(blob: &[u8]) -> String {
+    let digest = Sha256::digest(blob);
+    format!(
+        "SHA256:{}",
+        encoding::b64encode(digest).trim_end_matches('=')
+    )
+}
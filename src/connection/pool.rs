@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::{session, Connection, FileStat, SshConnection};
+use crate::error::AnsimpleError;
+use crate::inventory::{GlobalConfig, Host};
+use crate::runner::RunOptions;
+
+// The SSH sessions of a run, opened by the first task on a host and handed
+// from task to task after that, so each host is only connected to once.
+// Tasks that run at the same time on a host each get a session of their own.
+#[derive(Clone, Default)]
+pub struct SessionPool {
+    idle: Arc<Mutex<HashMap<String, Vec<SshConnection>>>>,
+}
+
+impl fmt::Debug for SessionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let idle = self.idle.lock().expect("session pool lock poisoned");
+        f.debug_struct("SessionPool")
+            .field("idle", &idle.values().map(Vec::len).sum::<usize>())
+            .finish()
+    }
+}
+
+impl SessionPool {
+    // A connection to `host` over an idle session, or a new one. The session
+    // goes back to the pool when the connection is dropped.
+    pub fn open(
+        &self,
+        host: &Host,
+        options: &RunOptions,
+        global_config: &GlobalConfig,
+    ) -> Result<Box<dyn Connection>, AnsimpleError> {
+        // Sessions are only shared between tasks that log in the same way.
+        let key = format!(
+            "{}@{}:{}",
+            host.user.as_ref().unwrap_or(&global_config.user),
+            host.address,
+            host.port.or(global_config.port).unwrap_or(22)
+        );
+        let idle = self
+            .idle
+            .lock()
+            .expect("session pool lock poisoned")
+            .get_mut(&key)
+            .and_then(Vec::pop);
+        let reused = idle.is_some();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => connect(host, options, global_config)?,
+        };
+        // The session may have served a task with a bandwidth of its own, or
+        // been opened before facts told which platform the host runs.
+        connection.throttle = options.throttle.clone();
+        connection.platform = host.platform();
+
+        Ok(Box::new(Pooled {
+            connection: Some(connection),
+            reused,
+            key,
+            pool: self.clone(),
+            host: host.clone(),
+            options: options.clone(),
+            global_config: global_config.clone(),
+        }))
+    }
+}
+
+fn connect(
+    host: &Host,
+    options: &RunOptions,
+    global_config: &GlobalConfig,
+) -> Result<SshConnection, AnsimpleError> {
+    Ok(SshConnection::new(
+        session(host, options, global_config)?,
+        host.platform(),
+        options.throttle.clone(),
+    ))
+}
+
+// A session taken from the pool. One that failed on the way is not given
+// back, so the next task connects again.
+struct Pooled {
+    // `None` once the session broke down.
+    connection: Option<SshConnection>,
+    // Whether the session sat in the pool and has not been used since.
+    reused: bool,
+    key: String,
+    pool: SessionPool,
+    host: Host,
+    options: RunOptions,
+    global_config: GlobalConfig,
+}
+
+impl Pooled {
+    fn connection(&mut self) -> Result<&mut SshConnection, AnsimpleError> {
+        if self.connection.is_none() {
+            self.connection = Some(connect(&self.host, &self.options, &self.global_config)?);
+        }
+
+        Ok(self.connection.as_mut().expect("connection opened"))
+    }
+
+    // Runs `operation`, once more on a new session when an idle one turns
+    // out to have been dropped by the host in the meantime.
+    fn retried<T>(
+        &mut self,
+        mut operation: impl FnMut(&mut SshConnection) -> Result<T, AnsimpleError>,
+    ) -> Result<T, AnsimpleError> {
+        let reused = std::mem::take(&mut self.reused);
+        match self.tracked(&mut operation) {
+            Err(err) if reused && err.is_transport() => self.tracked(operation),
+            result => result,
+        }
+    }
+
+    // Like `retried` for operations that stream their input and cannot be
+    // started over, with an idle session checked before it is relied on.
+    fn streamed<T>(
+        &mut self,
+        operation: impl FnOnce(&mut SshConnection) -> Result<T, AnsimpleError>,
+    ) -> Result<T, AnsimpleError> {
+        if self.reused {
+            self.retried(|connection| connection.exists(Path::new(".")))?;
+        }
+        self.tracked(operation)
+    }
+
+    // Runs `operation` and lets go of the session should it fail on the way.
+    fn tracked<T>(
+        &mut self,
+        operation: impl FnOnce(&mut SshConnection) -> Result<T, AnsimpleError>,
+    ) -> Result<T, AnsimpleError> {
+        self.reused = false;
+        let result = operation(self.connection()?);
+        if matches!(&result, Err(err) if err.is_transport()) {
+            self.connection = None;
+        }
+
+        result
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if let Ok(mut idle) = self.pool.idle.lock() {
+                idle.entry(self.key.clone()).or_default().push(connection);
+            }
+        }
+    }
+}
+
+impl Connection for Pooled {
+    fn exec(&mut self, command: &str) -> Result<(String, String, i32), AnsimpleError> {
+        self.retried(|connection| connection.exec(command))
+    }
+
+    fn exec_with_input(
+        &mut self,
+        command: &str,
+        input: &mut dyn Read,
+    ) -> Result<(String, String, i32), AnsimpleError> {
+        self.streamed(|connection| connection.exec_with_input(command, input))
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, AnsimpleError> {
+        self.retried(|connection| connection.exists(path))
+    }
+
+    fn checksum(&mut self, path: &Path) -> Result<Option<String>, AnsimpleError> {
+        self.retried(|connection| connection.checksum(path))
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, AnsimpleError> {
+        self.retried(|connection| connection.read(path))
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        source: &mut dyn Read,
+    ) -> Result<(u64, String), AnsimpleError> {
+        self.streamed(|connection| connection.write(path, source))
+    }
+
+    fn copy(&mut self, src: &Path, dest: &Path) -> Result<(u64, String), AnsimpleError> {
+        self.retried(|connection| connection.copy(src, dest))
+    }
+
+    fn size(&mut self, path: &Path) -> Result<Option<u64>, AnsimpleError> {
+        self.retried(|connection| connection.size(path))
+    }
+
+    fn append(&mut self, path: &Path, source: &mut dyn Read) -> Result<u64, AnsimpleError> {
+        self.streamed(|connection| connection.append(path, source))
+    }
+
+    fn rename(&mut self, src: &Path, dest: &Path) -> Result<(), AnsimpleError> {
+        self.retried(|connection| connection.rename(src, dest))
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, AnsimpleError> {
+        self.retried(|connection| connection.stat(path))
+    }
+}
@@ -0,0 +1,347 @@
+use sha2::{Digest, Sha256};
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::AnsimpleError;
+
+const BLOCK: usize = 512;
+// The largest size the 11 octal digits of a ustar header can hold.
+const MAX_HEADER_SIZE: u64 = 0o77777777777;
+
+// A local directory tree packed into a tar stream as it is read, so trees of
+// any size go out without being staged anywhere. Regular files, directories
+// and symbolic links are archived, with names relative to the root; names
+// too long for the ustar header get a pax header. Ownership is left out,
+// files belong to whoever unpacks them.
+pub struct Archive {
+    entries: Vec<Entry>,
+    next: usize,
+    // Header or padding bytes that go out before anything else.
+    pending: Vec<u8>,
+    sent: usize,
+    file: Option<Contents>,
+    finished: bool,
+    size: u64,
+    checksums: Vec<(String, String)>,
+}
+
+struct Entry {
+    path: PathBuf,
+    name: Vec<u8>,
+    kind: Kind,
+    mode: u32,
+    mtime: u64,
+    size: u64,
+}
+
+enum Kind {
+    Directory,
+    File,
+    Symlink(Vec<u8>),
+}
+
+// The file whose contents are going out.
+struct Contents {
+    file: File,
+    name: String,
+    remaining: u64,
+    padding: usize,
+    hasher: Sha256,
+}
+
+impl Archive {
+    pub fn new(root: &Path) -> Result<Self, AnsimpleError> {
+        let mut entries = Vec::new();
+        walk(root, &[], &mut entries).map_err(|source| AnsimpleError::Read {
+            path: root.to_owned(),
+            source,
+        })?;
+
+        let size = entries
+            .iter()
+            .map(|entry| header(entry).len() as u64 + padded(entry.size))
+            .sum::<u64>()
+            + 2 * BLOCK as u64;
+
+        Ok(Self {
+            entries,
+            next: 0,
+            pending: Vec::new(),
+            sent: 0,
+            file: None,
+            finished: false,
+            size,
+            checksums: Vec::new(),
+        })
+    }
+
+    // The length of the whole stream.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The name and SHA-256 of every regular file read out so far.
+    pub fn checksums(&self) -> &[(String, String)] {
+        &self.checksums
+    }
+
+    fn queue(&mut self, bytes: Vec<u8>) {
+        self.pending = bytes;
+        self.sent = 0;
+    }
+}
+
+impl Read for Archive {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.sent < self.pending.len() {
+                let count = buf.len().min(self.pending.len() - self.sent);
+                buf[..count].copy_from_slice(&self.pending[self.sent..self.sent + count]);
+                self.sent += count;
+                return Ok(count);
+            }
+
+            if let Some(contents) = &mut self.file {
+                if contents.remaining > 0 {
+                    let count = buf.len().min(contents.remaining as usize);
+                    let read = contents.file.read(&mut buf[..count])?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{} shrank while it was being archived", contents.name),
+                        ));
+                    }
+                    contents.hasher.update(&buf[..read]);
+                    contents.remaining -= read as u64;
+                    return Ok(read);
+                }
+
+                let contents = self.file.take().expect("file being archived");
+                self.checksums
+                    .push((contents.name, format!("{:x}", contents.hasher.finalize())));
+                self.queue(vec![0; contents.padding]);
+                continue;
+            }
+
+            if self.next < self.entries.len() {
+                let entry = &self.entries[self.next];
+                self.next += 1;
+                if let Kind::File = entry.kind {
+                    let file = File::open(&entry.path).map_err(|err| {
+                        io::Error::new(err.kind(), format!("{}: {err}", entry.path.display()))
+                    })?;
+                    self.file = Some(Contents {
+                        file,
+                        name: String::from_utf8_lossy(&entry.name).into_owned(),
+                        remaining: entry.size,
+                        padding: (padded(entry.size) - entry.size) as usize,
+                        hasher: Sha256::new(),
+                    });
+                }
+                let header = header(entry);
+                self.queue(header);
+                continue;
+            }
+
+            if !self.finished {
+                self.finished = true;
+                self.queue(vec![0; 2 * BLOCK]);
+                continue;
+            }
+
+            return Ok(0);
+        }
+    }
+}
+
+// Collects the entries below `dir` in a stable order, each directory before
+// what it contains.
+fn walk(dir: &Path, prefix: &[u8], entries: &mut Vec<Entry>) -> io::Result<()> {
+    let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let mut name = prefix.to_vec();
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend_from_slice(child.file_name().as_bytes());
+
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            Kind::Symlink(fs::read_link(&path)?.as_os_str().as_bytes().to_vec())
+        } else if file_type.is_dir() {
+            Kind::Directory
+        } else if file_type.is_file() {
+            Kind::File
+        } else {
+            // Sockets, fifos and devices have nothing to copy.
+            continue;
+        };
+
+        let recurse = matches!(kind, Kind::Directory);
+        entries.push(Entry {
+            path: path.clone(),
+            name: name.clone(),
+            size: if let Kind::File = kind {
+                metadata.len()
+            } else {
+                0
+            },
+            kind,
+            mode: metadata.mode() & 0o7777,
+            mtime: metadata.mtime().max(0) as u64,
+        });
+        if recurse {
+            walk(&path, &name, entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+// The header blocks of `entry`, a pax header first when the ustar one
+// cannot hold its name, link target or size.
+fn header(entry: &Entry) -> Vec<u8> {
+    let mut name = entry.name.clone();
+    if let Kind::Directory = entry.kind {
+        name.push(b'/');
+    }
+    let (typeflag, link): (u8, &[u8]) = match &entry.kind {
+        Kind::Directory => (b'5', b""),
+        Kind::File => (b'0', b""),
+        Kind::Symlink(target) => (b'2', target),
+    };
+
+    let mut records = Vec::new();
+    if name.len() > 100 {
+        pax_record(&mut records, "path", &name);
+    }
+    if link.len() > 100 {
+        pax_record(&mut records, "linkpath", link);
+    }
+    if entry.size > MAX_HEADER_SIZE {
+        pax_record(&mut records, "size", entry.size.to_string().as_bytes());
+    }
+
+    let mut header = Vec::new();
+    if !records.is_empty() {
+        let size = records.len() as u64;
+        header.extend_from_slice(&block(
+            b"././@PaxHeader",
+            b'x',
+            0o644,
+            size,
+            entry.mtime,
+            b"",
+        ));
+        header.extend_from_slice(&records);
+        header.resize(header.len() + (padded(size) - size) as usize, 0);
+    }
+    header.extend_from_slice(&block(
+        &name[..name.len().min(100)],
+        typeflag,
+        entry.mode,
+        entry.size.min(MAX_HEADER_SIZE),
+        entry.mtime,
+        &link[..link.len().min(100)],
+    ));
+
+    header
+}
+
+fn block(name: &[u8], typeflag: u8, mode: u32, size: u64, mtime: u64, link: &[u8]) -> Vec<u8> {
+    let mut block = vec![0; BLOCK];
+    block[..name.len()].copy_from_slice(name);
+    octal(&mut block[100..108], mode.into());
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    octal(&mut block[124..136], size);
+    octal(&mut block[136..148], mtime);
+    block[156] = typeflag;
+    block[157..157 + link.len()].copy_from_slice(link);
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is taken with its own field filled with spaces.
+    block[148..156].fill(b' ');
+    let checksum: u32 = block.iter().map(|byte| u32::from(*byte)).sum();
+    block[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+    block
+}
+
+// Zero-padded octal digits followed by a NUL, as much as `field` holds.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{value:0digits$o}");
+    field[..digits].copy_from_slice(&text.as_bytes()[text.len() - digits..]);
+    field[digits] = 0;
+}
+
+// A `<length> <key>=<value>\n` record, the length counting itself.
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+
+    records.extend_from_slice(format!("{length} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK as u64) * BLOCK as u64
+}
+
+// The file names sha256sum prints are escaped when they start its line with
+// a backslash.
+pub fn parse_sha256sum(output: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    output.lines().filter_map(|line| {
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (checksum, name) = line.split_once("  ")?;
+        let name = name.strip_prefix("./").unwrap_or(name);
+        let name = if escaped {
+            unescape(name)
+        } else {
+            name.to_owned()
+        };
+
+        Some((name, checksum.to_owned()))
+    })
+}
+
+fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('r')) => {
+                unescaped.push('\r');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
@@ -0,0 +1,52 @@
+use tokio::sync::Semaphore;
+use tokio::task;
+
+use std::sync::Arc;
+
+use crate::error::AnsimpleError;
+
+// How many threads of a run may wait on hosts at once unless told otherwise.
+pub const DEFAULT_WORKERS: usize = 64;
+
+// The threads SSH and SFTP calls block on, so the runtime keeps driving the
+// other hosts in the meantime. Work beyond the limit waits for a thread to
+// free up.
+#[derive(Debug, Clone)]
+pub struct Workers {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self::new(DEFAULT_WORKERS)
+    }
+}
+
+impl Workers {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(limit.clamp(1, Semaphore::MAX_PERMITS))),
+        }
+    }
+
+    // Runs `work` on a thread of its own once one is free.
+    pub async fn run<T, F>(&self, work: F) -> Result<T, AnsimpleError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("workers closed");
+        // The thread holds on to the permit, as it keeps going even when
+        // whoever waits for it gives up.
+        Ok(task::spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        })
+        .await?)
+    }
+}
@@ -0,0 +1,376 @@
+use indexmap::IndexMap;
+use serde_yaml::{Mapping, Value};
+
+use super::{key, split_words, truthy, yaml_inline};
+use crate::error::AnsimpleError;
+
+// An Ansible inventory, from its INI or YAML form, with each host's vars as
+// set on the host itself.
+#[derive(Debug, Default)]
+pub struct AnsibleInventory {
+    hosts: IndexMap<String, Mapping>,
+    groups: IndexMap<String, Group>,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    hosts: Vec<String>,
+    children: Vec<String>,
+    vars: Mapping,
+}
+
+impl AnsibleInventory {
+    pub fn parse(source: &str, notes: &mut Vec<String>) -> Result<Self, AnsimpleError> {
+        if is_ini(source) {
+            Ok(Self::parse_ini(source, notes))
+        } else {
+            let document: Value = serde_yaml::from_str(source)?;
+            let Value::Mapping(groups) = document else {
+                return Err(AnsimpleError::Config(
+                    "an inventory is a mapping of groups".to_owned(),
+                ));
+            };
+
+            let mut inventory = Self::default();
+            for (name, group) in &groups {
+                inventory.add_yaml_group(&key(name), group, notes);
+            }
+            Ok(inventory)
+        }
+    }
+
+    fn parse_ini(source: &str, notes: &mut Vec<String>) -> Self {
+        let mut inventory = Self::default();
+        let mut section = ("ungrouped".to_owned(), "hosts");
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = match header.split_once(':') {
+                    Some((group, "vars")) => (group.to_owned(), "vars"),
+                    Some((group, "children")) => (group.to_owned(), "children"),
+                    Some((group, kind)) => {
+                        notes.push(format!(
+                            "inventory: unknown section kind `[{group}:{kind}]`"
+                        ));
+                        (group.to_owned(), "ignored")
+                    }
+                    None => (header.to_owned(), "hosts"),
+                };
+                inventory.groups.entry(section.0.clone()).or_default();
+                continue;
+            }
+
+            let group = inventory.groups.entry(section.0.clone()).or_default();
+            match section.1 {
+                "vars" => {
+                    if let Some((name, value)) = line.split_once('=') {
+                        group.vars.insert(name.trim().into(), scalar(value.trim()));
+                    }
+                }
+                "children" => group.children.push(line.to_owned()),
+                "hosts" => {
+                    let mut words = split_words(line).into_iter();
+                    let Some(name) = words.next() else { continue };
+                    if name.contains('[') {
+                        notes.push(format!("inventory: host range `{name}` is not expanded"));
+                        continue;
+                    }
+                    group.hosts.push(name.clone());
+                    let vars = inventory.hosts.entry(name).or_default();
+                    for word in words {
+                        if let Some((key, value)) = word.split_once('=') {
+                            vars.insert(key.into(), scalar(value));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        inventory
+    }
+
+    fn add_yaml_group(&mut self, name: &str, group: &Value, notes: &mut Vec<String>) {
+        self.groups.entry(name.to_owned()).or_default();
+        let Value::Mapping(group) = group else {
+            return;
+        };
+
+        if let Some(Value::Mapping(hosts)) = group.get("hosts") {
+            for (host, vars) in hosts {
+                let host = key(host);
+                if host.contains('[') {
+                    notes.push(format!("inventory: host range `{host}` is not expanded"));
+                    continue;
+                }
+                let entry = self.hosts.entry(host.clone()).or_default();
+                if let Value::Mapping(vars) = vars {
+                    entry.extend(vars.clone());
+                }
+                self.groups[name].hosts.push(host);
+            }
+        }
+        if let Some(Value::Mapping(vars)) = group.get("vars") {
+            self.groups[name].vars.extend(vars.clone());
+        }
+        if let Some(Value::Mapping(children)) = group.get("children") {
+            for (child, child_group) in children {
+                let child = key(child);
+                self.groups[name].children.push(child.clone());
+                self.add_yaml_group(&child, child_group, notes);
+            }
+        }
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.hosts.contains_key(host)
+    }
+
+    pub fn host_names(&self) -> impl Iterator<Item = &String> {
+        self.hosts.keys()
+    }
+
+    pub fn group(&self, name: &str) -> Option<Vec<String>> {
+        if name == "all" {
+            return Some(self.hosts.keys().cloned().collect());
+        }
+
+        let group = self.groups.get(name)?;
+        let mut hosts = group.hosts.clone();
+        for child in &group.children {
+            for host in self.group(child).unwrap_or_default() {
+                if !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+            }
+        }
+        Some(hosts)
+    }
+
+    // What ansimple calls the host: where it is reached.
+    pub fn address(&self, host: &str) -> String {
+        match self.vars(host).get("ansible_host") {
+            Some(Value::String(address)) => address.clone(),
+            _ => host.to_owned(),
+        }
+    }
+
+    // The vars of `host` as Ansible resolves them: those of `all`, then those
+    // of every group it is in, then its own.
+    pub fn vars(&self, host: &str) -> Mapping {
+        let mut vars = Mapping::new();
+        if let Some(all) = self.groups.get("all") {
+            vars.extend(all.vars.clone());
+        }
+        for (name, group) in &self.groups {
+            if name != "all"
+                && self
+                    .group(name)
+                    .is_some_and(|hosts| hosts.iter().any(|h| h == host))
+            {
+                vars.extend(group.vars.clone());
+            }
+        }
+        if let Some(own) = self.hosts.get(host) {
+            vars.extend(own.clone());
+        }
+
+        vars
+    }
+
+    // The inventory as an ansimple host config: the user and key most hosts
+    // share go to `global_config`, other connection vars are left out.
+    pub fn to_host_config(&self, notes: &mut Vec<String>) -> Value {
+        let resolved = self
+            .hosts
+            .keys()
+            .map(|host| (host.clone(), self.vars(host)))
+            .collect::<IndexMap<String, Mapping>>();
+        let common = |name: &str| -> Option<String> {
+            let mut counts: IndexMap<String, usize> = IndexMap::new();
+            for vars in resolved.values() {
+                if let Some(Value::String(value)) = vars.get(name) {
+                    *counts.entry(value.clone()).or_default() += 1;
+                }
+            }
+            counts
+                .into_iter()
+                .max_by_key(|(_, count)| *count)
+                .map(|(value, _)| value)
+        };
+
+        let user = common("ansible_user").unwrap_or_else(|| {
+            notes.push("inventory: no `ansible_user`, `global_config.user` is a guess".to_owned());
+            "root".to_owned()
+        });
+        let key_file = common("ansible_ssh_private_key_file").unwrap_or_else(|| {
+            notes.push(
+                "inventory: no `ansible_ssh_private_key_file`, `global_config.key` is a guess"
+                    .to_owned(),
+            );
+            "~/.ssh/id_ed25519".to_owned()
+        });
+
+        let mut hosts = Vec::new();
+        for (name, vars) in &resolved {
+            let mut host = Mapping::new();
+            host.insert("address".into(), self.address(name).into());
+            let mut plain = Mapping::new();
+            for (var, value) in vars {
+                let var = key(var);
+                match var.as_str() {
+                    "ansible_host" | "ansible_python_interpreter" => {}
+                    "ansible_user" if value.as_str() != Some(user.as_str()) => {
+                        host.insert("user".into(), value.clone());
+                    }
+                    "ansible_ssh_private_key_file" if value.as_str() != Some(key_file.as_str()) => {
+                        host.insert("key".into(), value.clone());
+                    }
+                    "ansible_user" | "ansible_ssh_private_key_file" => {}
+                    "ansible_port" | "ansible_ssh_port" => match key(value).parse::<u16>() {
+                        Ok(port) => {
+                            host.insert("port".into(), port.into());
+                        }
+                        Err(_) => notes.push(format!(
+                            "inventory: {name}: `{var}: {}` is not a port",
+                            yaml_inline(value)
+                        )),
+                    },
+                    "ansible_become" => {
+                        host.insert("become".into(), truthy(value).into());
+                    }
+                    "ansible_become_user" => {
+                        host.insert("become_user".into(), value.clone());
+                    }
+                    "ansible_shell_type" if value.as_str() == Some("powershell") => {
+                        host.insert("platform".into(), "windows".into());
+                    }
+                    "ansible_connection" => match value.as_str() {
+                        Some("ssh") | Some("smart") => {}
+                        Some("local") => {
+                            host.insert("connection".into(), "local".into());
+                        }
+                        Some("winrm") | Some("psrp") => {
+                            host.insert("platform".into(), "windows".into());
+                            notes.push(format!(
+                                "inventory: {name} is reached over SSH, not {}",
+                                value.as_str().unwrap_or_default()
+                            ));
+                        }
+                        _ => notes.push(format!(
+                            "inventory: {name}: `ansible_connection: {}` is not supported",
+                            yaml_inline(value)
+                        )),
+                    },
+                    "ansible_password" | "ansible_ssh_pass" => {
+                        host.insert("password".into(), value.clone());
+                        notes.push(format!(
+                            "inventory: {name}: `{var}` is kept as `password`, encrypt it with \
+                             `!vault` or leave it out and use `--ask-pass`"
+                        ));
+                    }
+                    "ansible_ssh_common_args" | "ansible_ssh_extra_args" => {
+                        match proxy_jump(&key(value)) {
+                            Some(jump) => {
+                                host.insert("proxy_jump".into(), jump.into());
+                            }
+                            None => notes.push(format!(
+                                "inventory: {name}: only a `ProxyJump` in `{var}` is converted"
+                            )),
+                        }
+                    }
+                    _ if var.starts_with("ansible_") => {
+                        notes.push(format!("inventory: {name}: `{var}` is not supported"))
+                    }
+                    _ => {
+                        plain.insert(var.into(), value.clone());
+                    }
+                }
+            }
+            if !plain.is_empty() {
+                host.insert("vars".into(), Value::Mapping(plain));
+            }
+            hosts.push(Value::Mapping(host));
+        }
+
+        let mut global_config = Mapping::new();
+        global_config.insert("user".into(), user.into());
+        global_config.insert("key".into(), key_file.into());
+        let mut config = Mapping::new();
+        config.insert("global_config".into(), Value::Mapping(global_config));
+        config.insert("hosts".into(), Value::Sequence(hosts));
+        let groups = self.to_groups();
+        if !groups.is_empty() {
+            config.insert("groups".into(), Value::Mapping(groups));
+        }
+        Value::Mapping(config)
+    }
+
+    // The groups with their hosts by address and their children by name.
+    // Hosts reached at an `ansible_host` get a group of their own name, so
+    // plays can go on naming them as they did.
+    fn to_groups(&self) -> Mapping {
+        let mut groups = Mapping::new();
+        for (name, group) in &self.groups {
+            let members = group
+                .hosts
+                .iter()
+                .map(|host| self.address(host))
+                .chain(group.children.iter().cloned())
+                .map(Value::from)
+                .collect::<Vec<_>>();
+            if name != "all" && !members.is_empty() {
+                groups.insert(name.as_str().into(), Value::Sequence(members));
+            }
+        }
+        for name in self.hosts.keys() {
+            let address = self.address(name);
+            if address != *name && !self.groups.contains_key(name) {
+                groups.insert(name.as_str().into(), vec![Value::from(address)].into());
+            }
+        }
+
+        groups
+    }
+}
+
+// The jump host of `ssh` arguments, given with `-J` or `-o ProxyJump=`.
+fn proxy_jump(args: &str) -> Option<String> {
+    let args = args.replace(['"', '\''], " ");
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        let jump = match word {
+            "-J" => words.next(),
+            "-o" => words
+                .next()
+                .and_then(|option| option.strip_prefix("ProxyJump=")),
+            _ => word
+                .strip_prefix("-J")
+                .or_else(|| word.strip_prefix("-oProxyJump=")),
+        };
+        if let Some(jump) = jump.filter(|jump| !jump.is_empty()) {
+            return Some(jump.to_owned());
+        }
+    }
+
+    None
+}
+
+// INI inventories start with a section header or a host line, never with a
+// YAML mapping key.
+pub fn is_ini(source: &str) -> bool {
+    source
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .is_some_and(|line| line.starts_with('[') || !line.contains(':'))
+}
+
+// INI values typed the way YAML would, so `80` stays a number.
+fn scalar(value: &str) -> Value {
+    serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()))
+}
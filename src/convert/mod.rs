@@ -0,0 +1,994 @@
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+
+use std::fs;
+use std::path::Path;
+
+use crate::error::AnsimpleError;
+
+mod inventory;
+
+pub use inventory::AnsibleInventory;
+
+// Task keywords that are not the module of the task.
+const TASK_KEYWORDS: &[&str] = &[
+    "name",
+    "when",
+    "tags",
+    "register",
+    "no_log",
+    "args",
+    "vars",
+    "become",
+    "become_user",
+    "become_method",
+    "notify",
+    "ignore_errors",
+    "changed_when",
+    "failed_when",
+    "delegate_to",
+    "run_once",
+    "until",
+    "retries",
+    "delay",
+    "environment",
+    "remote_user",
+    "connection",
+    "check_mode",
+    "diff",
+    "timeout",
+    "throttle",
+    "async",
+    "poll",
+    "loop",
+    "loop_control",
+    "listen",
+    "any_errors_fatal",
+    "rescue",
+    "always",
+];
+
+// An Ansible playbook or inventory translated to ansimple, with a note for
+// everything that could not be.
+#[derive(Debug)]
+pub struct Conversion {
+    pub yaml: String,
+    pub notes: Vec<String>,
+}
+
+// Converts the playbook or inventory at `path`, telling them apart by their
+// shape. Play hosts naming groups are expanded with `inventory` when given.
+pub fn convert_file(path: &Path, inventory: Option<&Path>) -> Result<Conversion, AnsimpleError> {
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|source| AnsimpleError::Read {
+            path: path.to_owned(),
+            source,
+        })
+    };
+    let source = read(path)?;
+    let mut notes = Vec::new();
+
+    let converted = if inventory::is_ini(&source) {
+        AnsibleInventory::parse(&source, &mut notes)?.to_host_config(&mut notes)
+    } else {
+        let document: Value =
+            serde_yaml::from_str(&source).map_err(|source| AnsimpleError::Parse {
+                path: path.to_owned(),
+                source,
+            })?;
+        if is_inventory(&document) {
+            AnsibleInventory::parse(&source, &mut notes)?.to_host_config(&mut notes)
+        } else {
+            let inventory = inventory
+                .map(|path| AnsibleInventory::parse(&read(path)?, &mut notes))
+                .transpose()?;
+            convert_playbook(&document, inventory.as_ref(), &mut notes)?
+        }
+    };
+
+    Ok(Conversion {
+        yaml: serde_yaml::to_string(&converted)?,
+        notes,
+    })
+}
+
+// YAML inventories are mappings of groups, each with some of `hosts`,
+// `children` and `vars`; plays have scalar `hosts`.
+fn is_inventory(document: &Value) -> bool {
+    let Value::Mapping(groups) = document else {
+        return false;
+    };
+    groups.values().any(|group| match group {
+        Value::Mapping(group) => ["hosts", "children", "vars"]
+            .iter()
+            .any(|key| group.contains_key(*key)),
+        _ => false,
+    })
+}
+
+pub fn convert_playbook(
+    document: &Value,
+    inventory: Option<&AnsibleInventory>,
+    notes: &mut Vec<String>,
+) -> Result<Value, AnsimpleError> {
+    let plays = match document {
+        Value::Sequence(plays) => plays.clone(),
+        Value::Mapping(_) => vec![document.clone()],
+        _ => {
+            return Err(AnsimpleError::Config(
+                "a playbook is a play or a list of plays".to_owned(),
+            ))
+        }
+    };
+
+    let mut converted = Vec::new();
+    for (index, play) in plays.iter().enumerate() {
+        let at = format!("play {}", index + 1);
+        let Value::Mapping(play) = play else {
+            notes.push(format!("{at}: not a mapping, left out"));
+            continue;
+        };
+        if let Some(target) = play.get("import_playbook") {
+            notes.push(format!(
+                "{at}: `import_playbook: {}` is not converted, convert that file and add its \
+                 plays here",
+                key(target)
+            ));
+            continue;
+        }
+        converted.push(Value::Mapping(convert_play(play, &at, inventory, notes)));
+    }
+
+    Ok(match converted.len() {
+        1 => converted.remove(0),
+        _ => Value::Sequence(converted),
+    })
+}
+
+fn convert_play(
+    play: &Mapping,
+    at: &str,
+    inventory: Option<&AnsibleInventory>,
+    notes: &mut Vec<String>,
+) -> Mapping {
+    let mut converted = Mapping::new();
+    let at = match play.get("name").and_then(Value::as_str) {
+        Some(name) => {
+            converted.insert("name".into(), name.into());
+            format!("{at} '{name}'")
+        }
+        None => at.to_owned(),
+    };
+
+    let pattern = match play.get("hosts") {
+        Some(Value::Sequence(patterns)) => patterns.iter().map(key).collect::<Vec<_>>().join(":"),
+        Some(pattern) => key(pattern),
+        None => {
+            notes.push(format!("{at}: no `hosts`"));
+            String::new()
+        }
+    };
+    let hosts = expand_hosts(&pattern, inventory, &at, notes);
+    converted.insert(
+        "hosts".into(),
+        Value::Sequence(hosts.into_iter().map(Value::from).collect()),
+    );
+
+    let (mut tasks, mut handlers, mut position) = (Vec::new(), Vec::new(), 0);
+    for (keyword, value) in play {
+        match key(keyword).as_str() {
+            "name" | "hosts" => {}
+            "vars"
+            | "vars_files"
+            | "any_errors_fatal"
+            | "max_fail_percentage"
+            | "remote_user"
+            | "port"
+            | "become_user"
+            | "environment" => {
+                converted.insert(keyword.clone(), value.clone());
+            }
+            "become" => {
+                converted.insert(keyword.clone(), truthy(value).into());
+            }
+            "serial" => match value {
+                Value::Number(_) => {
+                    converted.insert(keyword.clone(), value.clone());
+                }
+                Value::String(percent) if percent.trim().ends_with('%') => {
+                    converted.insert(keyword.clone(), value.clone());
+                }
+                _ => notes.push(format!(
+                    "{at}: `serial: {}` is not supported, only a number or percentage of hosts is",
+                    yaml_inline(value)
+                )),
+            },
+            "strategy" => match value.as_str() {
+                Some("linear") | Some("free") => {
+                    converted.insert(keyword.clone(), value.clone());
+                }
+                _ => notes.push(format!(
+                    "{at}: strategy `{}` is not supported",
+                    yaml_inline(value)
+                )),
+            },
+            "gather_facts" => {
+                converted.insert(keyword.clone(), truthy(value).into());
+            }
+            "gather_subset" => {}
+            "pre_tasks" | "tasks" | "post_tasks" => {
+                if let Value::Sequence(entries) = value {
+                    convert_tasks(
+                        entries,
+                        &at,
+                        &Mapping::new(),
+                        &mut position,
+                        &mut tasks,
+                        notes,
+                    );
+                }
+            }
+            "handlers" => {
+                if let Value::Sequence(entries) = value {
+                    convert_tasks(
+                        entries,
+                        &at,
+                        &Mapping::new(),
+                        &mut position,
+                        &mut handlers,
+                        notes,
+                    );
+                }
+            }
+            "roles" => notes.push(format!(
+                "{at}: roles are not converted, add their tasks to the play"
+            )),
+            other => notes.push(format!("{at}: `{other}` is not supported")),
+        }
+    }
+
+    // Ansible runs plays in lockstep unless told otherwise.
+    if !converted.contains_key("strategy") {
+        converted.insert("strategy".into(), "linear".into());
+    }
+    if play.get("gather_facts").map(truthy) != Some(false)
+        && yaml_inline(&Value::Sequence(tasks.clone())).contains("ansible_")
+    {
+        notes.push(format!(
+            "{at}: facts are under `facts`, such as `facts.os_family`, rename the `ansible_` \
+             variables the tasks use"
+        ));
+    }
+    converted.insert("tasks".into(), Value::Sequence(tasks));
+    if !handlers.is_empty() {
+        converted.insert("handlers".into(), Value::Sequence(handlers));
+    }
+
+    converted
+}
+
+// The addresses a host pattern such as `web:&prod:!web3` stands for,
+// resolved against the inventory when there is one.
+fn expand_hosts(
+    pattern: &str,
+    inventory: Option<&AnsibleInventory>,
+    at: &str,
+    notes: &mut Vec<String>,
+) -> Vec<String> {
+    let parts = pattern
+        .split([':', ','])
+        .map(str::trim)
+        .filter(|part| !part.is_empty());
+
+    let Some(inventory) = inventory else {
+        let parts = parts.collect::<Vec<_>>();
+        if parts
+            .iter()
+            .any(|part| *part == "all" || part.contains(['*', '!', '&']))
+        {
+            notes.push(format!(
+                "{at}: hosts `{pattern}` are kept as they are, give the inventory to expand them"
+            ));
+        }
+        return parts.into_iter().map(str::to_owned).collect();
+    };
+
+    let mut hosts: Vec<String> = Vec::new();
+    for part in parts {
+        let (operator, name) = match part.chars().next() {
+            Some(operator @ ('!' | '&')) => (Some(operator), &part[1..]),
+            _ => (None, part),
+        };
+        let matched = match name {
+            "all" | "*" => inventory.group("all").unwrap_or_default(),
+            name if name.contains('*') => {
+                let glob = format!("^{}$", regex::escape(name).replace(r"\*", ".*"));
+                let glob = Regex::new(&glob).expect("escaped glob is a valid regex");
+                inventory
+                    .host_names()
+                    .filter(|host| glob.is_match(host))
+                    .cloned()
+                    .collect()
+            }
+            name => match inventory.group(name) {
+                Some(group) => group,
+                None if inventory.contains(name) => vec![name.to_owned()],
+                None => {
+                    notes.push(format!(
+                        "{at}: `{name}` is neither a host nor a group of the inventory"
+                    ));
+                    vec![name.to_owned()]
+                }
+            },
+        };
+
+        match operator {
+            Some('!') => hosts.retain(|host| !matched.contains(host)),
+            Some(_) => hosts.retain(|host| matched.contains(host)),
+            None => {
+                for host in matched {
+                    if !hosts.contains(&host) {
+                        hosts.push(host);
+                    }
+                }
+            }
+        }
+    }
+
+    hosts.iter().map(|host| inventory.address(host)).collect()
+}
+
+// Appends the converted `entries` to `tasks`. Blocks are flattened, their
+// `when` and `tags` passed on to the tasks inside.
+fn convert_tasks(
+    entries: &[Value],
+    at: &str,
+    inherited: &Mapping,
+    position: &mut usize,
+    tasks: &mut Vec<Value>,
+    notes: &mut Vec<String>,
+) {
+    for entry in entries {
+        let Value::Mapping(entry) = entry else {
+            continue;
+        };
+        *position += 1;
+        // Unnamed tasks are called after where they are in the play.
+        let name = match entry.get("name") {
+            Some(name) => key(name),
+            None if entry.contains_key("block") => format!("block {position}"),
+            None => format!("task {position}"),
+        };
+        let task_at = format!("{at}, task '{name}'");
+
+        if let Some(Value::Sequence(block)) = entry.get("block") {
+            for section in ["rescue", "always"] {
+                if entry.contains_key(section) {
+                    notes.push(format!("{task_at}: `{section}` is not converted"));
+                }
+            }
+            let mut inner = inherited.clone();
+            for keyword in ["when", "tags"] {
+                if let Some(value) = entry.get(keyword) {
+                    let merged = merge(inner.get(keyword), value);
+                    inner.insert(keyword.into(), merged);
+                }
+            }
+            convert_tasks(block, at, &inner, position, tasks, notes);
+            continue;
+        }
+
+        if let Some(task) = convert_task(entry, &name, &task_at, inherited, notes) {
+            tasks.push(task);
+        }
+    }
+}
+
+// A block's `when` or `tags` followed by those of a task inside it.
+fn merge(outer: Option<&Value>, inner: &Value) -> Value {
+    let mut merged = outer.map(as_list).unwrap_or_default();
+    merged.extend(as_list(inner));
+    Value::Sequence(merged)
+}
+
+fn as_list(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Sequence(values) => values.clone(),
+        value => vec![value.clone()],
+    }
+}
+
+fn convert_task(
+    entry: &Mapping,
+    name: &str,
+    at: &str,
+    inherited: &Mapping,
+    notes: &mut Vec<String>,
+) -> Option<Value> {
+    let Some((module, free_form)) = entry
+        .iter()
+        .map(|(keyword, value)| (key(keyword), value))
+        .find(|(keyword, _)| !is_keyword(keyword))
+    else {
+        notes.push(format!("{at}: no module, left out"));
+        return None;
+    };
+    let short = module
+        .strip_prefix("ansible.builtin.")
+        .or_else(|| module.strip_prefix("ansible.legacy."))
+        .unwrap_or(&module);
+
+    let mut args = match free_form {
+        Value::Mapping(args) => args.clone(),
+        _ => Mapping::new(),
+    };
+    if let Some(Value::Mapping(extra)) = entry.get("args") {
+        args.extend(extra.clone());
+    }
+
+    let converted = match short {
+        "shell" | "command" | "raw" => {
+            let mut command = match free_form {
+                Value::String(line) => {
+                    let (command, options) = split_free_form(line);
+                    args.extend(options);
+                    command
+                }
+                _ => match args.get("cmd").or_else(|| args.get("_raw_params")) {
+                    Some(command) => key(command),
+                    None => match args.get("argv") {
+                        Some(Value::Sequence(argv)) => argv
+                            .iter()
+                            .map(|arg| quote(&key(arg)))
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                        _ => {
+                            notes.push(format!("{at}: `{module}` without a command, left out"));
+                            return None;
+                        }
+                    },
+                },
+            };
+            if let Some(dir) = args.get("chdir") {
+                command = format!("cd {} && {command}", quote(&key(dir)));
+            }
+            let mut shell = Mapping::new();
+            shell.insert("name".into(), name.into());
+            shell.insert("command".into(), command.into());
+            for option in ["creates", "removes"] {
+                if let Some(path) = args.get(option) {
+                    shell.insert(option.into(), key(path).into());
+                }
+            }
+            note_unsupported(
+                &args,
+                &["cmd", "_raw_params", "argv", "chdir", "creates", "removes"],
+                at,
+                notes,
+            );
+            ("shell", shell)
+        }
+        "copy" | "template" => {
+            args.extend(free_form_args(free_form));
+            let (Some(src), Some(dest)) = (args.get("src"), args.get("dest")) else {
+                let missing = if args.contains_key("content") {
+                    "`content` is not supported, put it in a file to copy"
+                } else {
+                    "needs `src` and `dest`"
+                };
+                notes.push(format!("{at}: {missing}, left out"));
+                return None;
+            };
+            let src = key(src);
+            let mut copy = Mapping::new();
+            copy.insert("name".into(), name.into());
+            copy.insert("src".into(), src.clone().into());
+            copy.insert("dest".into(), key(dest).into());
+            if short == "copy" {
+                if let Some(remote_src) = args.get("remote_src") {
+                    copy.insert("remote_src".into(), truthy(remote_src).into());
+                }
+                let backup = args.get("backup").is_some_and(truthy);
+                if backup {
+                    copy.insert("backup".into(), true.into());
+                }
+                match args.get("mode").map(key).as_deref() {
+                    Some("preserve") => {
+                        copy.insert("mode".into(), "preserve".into());
+                    }
+                    Some(_) => {
+                        if let Some(mode) = octal_mode(&args, at, notes) {
+                            copy.insert("mode".into(), mode.into());
+                        }
+                    }
+                    None => {}
+                }
+                // A trailing slash copies what the directory holds, which is
+                // what a tar transfer does. Backups are only kept of files
+                // copied one by one.
+                if src.ends_with('/') && !backup {
+                    copy.insert("transfer".into(), "tar".into());
+                }
+                note_unsupported(
+                    &args,
+                    &["src", "dest", "remote_src", "backup", "mode"],
+                    at,
+                    notes,
+                );
+                ("copy", copy)
+            } else {
+                copy.insert("variables".into(), Value::Mapping(Mapping::new()));
+                copy.insert("jinja2".into(), true.into());
+                note_unsupported(&args, &["src", "dest"], at, notes);
+                ("template", copy)
+            }
+        }
+        "lineinfile" => {
+            args.extend(free_form_args(free_form));
+            let path = args
+                .get("path")
+                .or_else(|| args.get("dest"))
+                .or_else(|| args.get("name"));
+            let state = args.get("state").map(key);
+            let (Some(path), Some(regexp), Some(line)) =
+                (path, args.get("regexp"), args.get("line"))
+            else {
+                notes.push(format!(
+                    "{at}: only `lineinfile` with `path`, `regexp` and `line` is converted, left out"
+                ));
+                return None;
+            };
+            if state.as_deref() == Some("absent") {
+                notes.push(format!(
+                    "{at}: `lineinfile` with `state: absent` is not supported, left out"
+                ));
+                return None;
+            }
+            notes.push(format!(
+                "{at}: every line matching `regexp` is replaced, and `line` is not added when \
+                 none does"
+            ));
+            let mut search_replace = Mapping::new();
+            search_replace.insert("name".into(), name.into());
+            search_replace.insert("path".into(), key(path).into());
+            search_replace.insert(
+                "search".into(),
+                format!("(?m)^.*(?:{}).*$", key(regexp)).into(),
+            );
+            search_replace.insert("replace".into(), key(line).replace('$', "$$").into());
+            note_unsupported(
+                &args,
+                &["path", "dest", "name", "regexp", "line", "state"],
+                at,
+                notes,
+            );
+            ("search_replace", search_replace)
+        }
+        "fetch" => {
+            args.extend(free_form_args(free_form));
+            let (Some(src), Some(dest)) = (args.get("src"), args.get("dest")) else {
+                notes.push(format!("{at}: needs `src` and `dest`, left out"));
+                return None;
+            };
+            let mut fetch = Mapping::new();
+            fetch.insert("name".into(), name.into());
+            fetch.insert("src".into(), key(src).into());
+            fetch.insert("dest".into(), key(dest).into());
+            if let Some(flat) = args.get("flat") {
+                fetch.insert("flat".into(), truthy(flat).into());
+            }
+            note_unsupported(&args, &["src", "dest", "flat"], at, notes);
+            ("fetch", fetch)
+        }
+        "file" => {
+            args.extend(free_form_args(free_form));
+            let Some(path) = args
+                .get("path")
+                .or_else(|| args.get("dest"))
+                .or_else(|| args.get("name"))
+            else {
+                notes.push(format!("{at}: `file` without a `path`, left out"));
+                return None;
+            };
+            let mut file = Mapping::new();
+            file.insert("name".into(), name.into());
+            file.insert("path".into(), key(path).into());
+            match args.get("state").map(key).as_deref() {
+                Some(state @ ("file" | "touch" | "directory" | "absent" | "link")) => {
+                    file.insert("state".into(), state.into());
+                }
+                Some(state) => {
+                    notes.push(format!("{at}: state `{state}` is not supported, left out"));
+                    return None;
+                }
+                None => {}
+            }
+            if let Some(mode) = octal_mode(&args, at, notes) {
+                file.insert("mode".into(), mode.into());
+            }
+            for field in ["src", "owner", "group"] {
+                if let Some(value) = args.get(field) {
+                    file.insert(field.into(), key(value).into());
+                }
+            }
+            note_unsupported(
+                &args,
+                &[
+                    "path", "dest", "name", "state", "mode", "src", "owner", "group",
+                ],
+                at,
+                notes,
+            );
+            ("file", file)
+        }
+        "service" | "systemd" | "systemd_service" => {
+            args.extend(free_form_args(free_form));
+            let Some(service_name) = args.get("name").map(key) else {
+                notes.push(format!("{at}: `{module}` without a `name`, left out"));
+                return None;
+            };
+            let mut service = Mapping::new();
+            service.insert("name".into(), name.into());
+            service.insert("service".into(), service_name.into());
+            match args.get("state").map(key).as_deref() {
+                Some(state @ ("started" | "stopped" | "restarted" | "reloaded")) => {
+                    service.insert("state".into(), state.into());
+                }
+                Some(state) => notes.push(format!("{at}: state `{state}` is not supported")),
+                None => {}
+            }
+            if let Some(enabled) = args.get("enabled") {
+                service.insert("enabled".into(), truthy(enabled).into());
+            }
+            if let Some(daemon_reload) = args.get("daemon_reload") {
+                service.insert("daemon_reload".into(), truthy(daemon_reload).into());
+            }
+            if !service.contains_key("state") && !service.contains_key("enabled") {
+                notes.push(format!("{at}: `{module}` does nothing, left out"));
+                return None;
+            }
+            note_unsupported(
+                &args,
+                &["name", "state", "enabled", "daemon_reload"],
+                at,
+                notes,
+            );
+            ("service", service)
+        }
+        "package" | "apt" | "yum" | "dnf" | "pacman" | "apk" => {
+            args.extend(free_form_args(free_form));
+            let packages = match args.get("name").or_else(|| args.get("pkg")) {
+                Some(Value::Sequence(names)) => names.iter().map(key).collect::<Vec<_>>(),
+                Some(names) => key(names)
+                    .split(',')
+                    .map(|name| name.trim().to_owned())
+                    .filter(|name| !name.is_empty())
+                    .collect(),
+                None => Vec::new(),
+            };
+            if packages.is_empty() {
+                notes.push(format!("{at}: `{module}` without a `name`, left out"));
+                return None;
+            }
+
+            let mut package = Mapping::new();
+            package.insert("name".into(), name.into());
+            package.insert(
+                "packages".into(),
+                Value::Sequence(packages.into_iter().map(Value::from).collect()),
+            );
+            match args.get("state").map(key).as_deref() {
+                None | Some("present") | Some("installed") => {}
+                Some("absent") | Some("removed") => {
+                    package.insert("state".into(), "absent".into());
+                }
+                Some("latest") => {
+                    package.insert("state".into(), "latest".into());
+                }
+                Some(state) => notes.push(format!("{at}: state `{state}` is not supported")),
+            }
+            note_unsupported(&args, &["name", "pkg", "state"], at, notes);
+            ("package", package)
+        }
+        "git" => {
+            args.extend(free_form_args(free_form));
+            let (Some(repo), Some(dest)) = (args.get("repo"), args.get("dest")) else {
+                notes.push(format!("{at}: `git` without a `repo` and `dest`, left out"));
+                return None;
+            };
+            let mut git = Mapping::new();
+            git.insert("name".into(), name.into());
+            git.insert("repo".into(), key(repo).into());
+            git.insert("dest".into(), key(dest).into());
+            // Ansible's default of `HEAD` is the remote's default branch, as
+            // when no version is given here.
+            match args.get("version").map(key) {
+                Some(version) if version != "HEAD" => {
+                    git.insert("version".into(), version.into());
+                }
+                _ => {}
+            }
+            if let Some(force) = args.get("force") {
+                git.insert("force".into(), truthy(force).into());
+            }
+            note_unsupported(&args, &["repo", "dest", "version", "force"], at, notes);
+            ("git", git)
+        }
+        "include_tasks" | "import_tasks" => {
+            notes.push(format!(
+                "{at}: `{module}` is not converted, convert the included file and `include_tasks` it"
+            ));
+            return None;
+        }
+        "include_role" | "import_role" => {
+            notes.push(format!(
+                "{at}: `{module}` is not converted, add the included tasks here"
+            ));
+            return None;
+        }
+        _ => {
+            notes.push(format!(
+                "{at}: module `{module}` has no ansimple equivalent, left out"
+            ));
+            return None;
+        }
+    };
+
+    let (kind, arguments) = converted;
+    let mut task = Mapping::new();
+    task.insert(kind.into(), Value::Mapping(arguments));
+
+    let when = match (inherited.get("when"), entry.get("when")) {
+        (None, None) => None,
+        (outer, Some(inner)) => Some(merge(outer, inner)),
+        (Some(outer), None) => Some(outer.clone()),
+    };
+    if let Some(when) = when {
+        task.insert("when".into(), condition(&when).into());
+    }
+    let tags = match (inherited.get("tags"), entry.get("tags")) {
+        (None, None) => None,
+        (outer, Some(inner)) => Some(merge(outer, inner)),
+        (Some(outer), None) => Some(outer.clone()),
+    };
+    if let Some(tags) = tags {
+        task.insert(
+            "tags".into(),
+            Value::Sequence(as_list(&tags).iter().map(|tag| key(tag).into()).collect()),
+        );
+    }
+    for keyword in [
+        "register",
+        "no_log",
+        "environment",
+        "vars",
+        "become_user",
+        "delegate_to",
+    ] {
+        if let Some(value) = entry.get(keyword) {
+            task.insert(keyword.into(), value.clone());
+        }
+    }
+    if let Some(value) = entry.get("loop").or_else(|| entry.get("with_items")) {
+        task.insert("loop".into(), value.clone());
+    }
+    if let Some(value) = entry.get("become") {
+        task.insert("become".into(), truthy(value).into());
+    }
+    if let Some(value) = entry.get("ignore_errors") {
+        task.insert("ignore_errors".into(), truthy(value).into());
+    }
+    match entry.get("connection").map(key).as_deref() {
+        Some(connection @ ("local" | "ssh")) => {
+            task.insert("connection".into(), connection.into());
+        }
+        Some("smart") | None => {}
+        Some(connection) => {
+            notes.push(format!("{at}: `connection: {connection}` is not supported"))
+        }
+    }
+    match entry.get("changed_when") {
+        Some(Value::Bool(changed)) => {
+            task.insert("changed_when".into(), (*changed).into());
+        }
+        Some(changed_when) => {
+            task.insert("changed_when".into(), condition(changed_when).into());
+        }
+        None => {}
+    }
+    if let Some(until) = entry.get("until") {
+        task.insert("until".into(), condition(until).into());
+    }
+    // Counts that are templates are left to the note below.
+    let counts = ["retries", "delay"].map(|keyword| {
+        let count = entry.get(keyword).and_then(Value::as_u64);
+        if let Some(count) = count {
+            task.insert(keyword.into(), count.into());
+        }
+        count.is_some()
+    });
+    // `check_mode: false` runs the task in check runs too. Always checking is
+    // left to the note below.
+    let check_mode = entry.get("check_mode");
+    if check_mode.is_some_and(|value| !truthy(value)) {
+        task.insert("check_mode".into(), "run".into());
+    }
+    if let Some(value) = entry.get("notify") {
+        task.insert(
+            "notify".into(),
+            Value::Sequence(as_list(value).iter().map(|name| key(name).into()).collect()),
+        );
+    }
+
+    for (keyword, _) in entry {
+        let keyword = key(keyword);
+        let converted = [
+            "name",
+            "when",
+            "tags",
+            "register",
+            "no_log",
+            "args",
+            "environment",
+            "vars",
+            "become",
+            "become_user",
+            "delegate_to",
+            "connection",
+            "notify",
+            "ignore_errors",
+            "changed_when",
+            "until",
+            "loop",
+            "with_items",
+        ];
+        let always_run = keyword == "check_mode" && check_mode.is_some_and(|value| !truthy(value));
+        let count = match keyword.as_str() {
+            "retries" => counts[0],
+            "delay" => counts[1],
+            _ => false,
+        };
+        if is_keyword(&keyword) && !converted.contains(&keyword.as_str()) && !always_run && !count {
+            notes.push(format!("{at}: `{keyword}` is not supported"));
+        }
+    }
+
+    Some(Value::Mapping(task))
+}
+
+fn is_keyword(keyword: &str) -> bool {
+    TASK_KEYWORDS.contains(&keyword) || keyword.starts_with("with_")
+}
+
+// The command of a free-form `shell` line, and the options Ansible reads out
+// of it.
+fn split_free_form(line: &str) -> (String, Mapping) {
+    let option = Regex::new(r"(?:^|\s)(creates|removes|chdir|executable|warn)=(\S+)")
+        .expect("option pattern is a valid regex");
+    let mut options = Mapping::new();
+    for captures in option.captures_iter(line) {
+        options.insert(captures[1].into(), unquote(&captures[2]).into());
+    }
+    let command = option.replace_all(line, "").trim().to_owned();
+
+    (command, options)
+}
+
+// The `key=value` arguments of a free-form module line.
+fn free_form_args(free_form: &Value) -> Mapping {
+    let Value::String(line) = free_form else {
+        return Mapping::new();
+    };
+    split_words(line)
+        .into_iter()
+        .filter_map(|word| {
+            let (key, value) = word.split_once('=')?;
+            Some((key.into(), value.into()))
+        })
+        .collect()
+}
+
+fn note_unsupported(args: &Mapping, converted: &[&str], at: &str, notes: &mut Vec<String>) {
+    for (arg, _) in args {
+        let arg = key(arg);
+        if !converted.contains(&arg.as_str()) {
+            notes.push(format!("{at}: `{arg}` is not supported"));
+        }
+    }
+}
+
+// The `mode` argument when it is octal, as symbolic modes like `u+rwx` are
+// not supported.
+fn octal_mode(args: &Mapping, at: &str, notes: &mut Vec<String>) -> Option<String> {
+    let mode = args.get("mode").map(key)?;
+    let digits = mode.trim_start_matches("0o");
+    if !digits.is_empty() && digits.chars().all(|digit| ('0'..='7').contains(&digit)) {
+        Some(mode)
+    } else {
+        notes.push(format!(
+            "{at}: only octal modes are supported, not `{mode}`"
+        ));
+        None
+    }
+}
+
+// Ansible's condition lists hold when all of them do.
+fn condition(when: &Value) -> String {
+    match when {
+        Value::Sequence(conditions) if conditions.len() == 1 => condition(&conditions[0]),
+        Value::Sequence(conditions) => conditions
+            .iter()
+            .map(|condition| format!("({})", key(condition)))
+            .collect::<Vec<_>>()
+            .join(" and "),
+        condition => key(condition),
+    }
+}
+
+// Ansible's loose booleans, as in `remote_src=yes`.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Bool(value) => *value,
+        value => matches!(
+            key(value).to_lowercase().as_str(),
+            "yes" | "true" | "on" | "1" | "y"
+        ),
+    }
+}
+
+fn quote(word: &str) -> String {
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c))
+    {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+fn unquote(word: &str) -> &str {
+    word.strip_prefix(['"', '\''])
+        .and_then(|word| word.strip_suffix(['"', '\'']))
+        .unwrap_or(word)
+}
+
+// Words separated by whitespace, with quotes keeping words together.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, c) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            (None, '#') if word.is_empty() => break,
+            _ => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+fn key(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => yaml_inline(value),
+    }
+}
+
+fn yaml_inline(value: &Value) -> String {
+    serde_yaml::to_string(value)
+        .unwrap_or_default()
+        .trim_end()
+        .to_owned()
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Deserializer};
+
+use std::fmt::Debug;
+use std::io::{self, BufRead, Write};
+use std::os::fd::AsRawFd;
+
+use crate::schema::{Generator, Schema};
+use crate::secrets;
+
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: String) -> Self {
+        secrets::track(&password);
+        Self(password)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Clone for Password {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Password({})", secrets::MASK)
+    }
+}
+
+// Passwords in host configs are masked like prompted ones.
+impl<'de> Deserialize<'de> for Password {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Password::new)
+    }
+}
+
+impl Schema for Password {
+    fn schema(generator: &mut Generator) -> serde_json::Value {
+        String::schema(generator)
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        // SAFETY: only zero bytes are written, which keeps the string valid utf8
+        let bytes = unsafe { self.0.as_mut_vec() };
+        for byte in bytes.iter_mut() {
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub ssh_password: Option<Password>,
+    pub become_password: Option<Password>,
+}
+
+pub fn prompt_password(prompt: &str) -> io::Result<Password> {
+    let mut stderr = io::stderr();
+    write!(stderr, "{prompt}")?;
+    stderr.flush()?;
+
+    let stdin = io::stdin();
+    let fd = stdin.as_raw_fd();
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    let is_tty = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+    let original = termios;
+
+    if is_tty {
+        termios.c_lflag &= !libc::ECHO;
+        termios.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let mut line = String::new();
+    let result = stdin.lock().read_line(&mut line);
+
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    }
+    result?;
+
+    let password = Password::new(line.trim_end_matches(['\r', '\n']).to_owned());
+    // SAFETY: see `Password::drop`
+    unsafe { line.as_mut_vec().fill(0) };
+
+    Ok(password)
+}
@@ -0,0 +1,170 @@
+// Unified diffs of what file tasks write, for `--diff`.
+
+// Lines of context around each change.
+const CONTEXT: usize = 3;
+// Beyond this many line pairs, the changed middle of two files is shown as
+// removed and added as a whole rather than matched line by line.
+const MAX_CELLS: usize = 4_000_000;
+
+// Files larger than this are only said to differ.
+pub const MAX_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Keep,
+    Remove,
+    Add,
+}
+
+// A unified diff of `path` going from `before`, `None` when the file does not
+// exist yet, to `after`. Empty when they are the same.
+pub fn render(path: &str, before: Option<&[u8]>, after: &[u8]) -> String {
+    if before == Some(after) {
+        return String::new();
+    }
+    let (Ok(old), Ok(new)) = (
+        std::str::from_utf8(before.unwrap_or_default()),
+        std::str::from_utf8(after),
+    ) else {
+        return summary(path, "binary files differ");
+    };
+
+    // Lines keep their newline, so a missing one at the end is a change.
+    let old = old.split_inclusive('\n').collect::<Vec<&str>>();
+    let new = new.split_inclusive('\n').collect::<Vec<&str>>();
+    let ops = ops(&old, &new);
+
+    let mut output = header(path, before.is_some());
+    for (start, end) in hunks(&ops) {
+        // Where the hunk starts in either file, counting from 0.
+        let old_start = ops[..start].iter().filter(|(op, _)| *op != Op::Add).count();
+        let new_start = ops[..start]
+            .iter()
+            .filter(|(op, _)| *op != Op::Remove)
+            .count();
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|(op, _)| *op != Op::Add).count();
+        let new_len = hunk.iter().filter(|(op, _)| *op != Op::Remove).count();
+        output.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_len),
+            range(new_start, new_len)
+        ));
+
+        for (op, line) in hunk {
+            let sign = match op {
+                Op::Keep => ' ',
+                Op::Remove => '-',
+                Op::Add => '+',
+            };
+            output.push(sign);
+            output.push_str(line);
+            if !line.ends_with('\n') {
+                output.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    output
+}
+
+// In place of a diff of `path` that cannot be shown line by line.
+pub fn summary(path: &str, reason: &str) -> String {
+    format!("{}{reason}\n", header(path, true))
+}
+
+fn header(path: &str, exists: bool) -> String {
+    let before = if exists { path } else { "/dev/null" };
+    format!("--- before: {before}\n+++ after: {path}\n")
+}
+
+// Hunk ranges as diff(1) writes them, where an empty range names the line
+// before it.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        len => format!("{},{len}", start + 1),
+    }
+}
+
+// Turns `old` into `new` with as few removed and added lines as it takes.
+fn ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut ops = old[..prefix]
+        .iter()
+        .map(|line| (Op::Keep, *line))
+        .collect::<Vec<_>>();
+    if a.len().saturating_mul(b.len()) > MAX_CELLS {
+        ops.extend(a.iter().map(|line| (Op::Remove, *line)));
+        ops.extend(b.iter().map(|line| (Op::Add, *line)));
+    } else {
+        // The length of the longest common subsequence of a[i..] and b[j..].
+        let width = b.len() + 1;
+        let mut lengths = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lengths[i * width + j] = if a[i] == b[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push((Op::Keep, a[i]));
+                i += 1;
+                j += 1;
+            } else if j == b.len()
+                || (i < a.len() && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+            {
+                ops.push((Op::Remove, a[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Add, b[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| (Op::Keep, *line)),
+    );
+
+    ops
+}
+
+// The stretches of `ops` to show, as start and end indexes: every change
+// with its context, merged where the contexts touch.
+fn hunks(ops: &[(Op, &str)]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, _) in ops
+        .iter()
+        .enumerate()
+        .filter(|(_, (op, _))| *op != Op::Keep)
+    {
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + 1 + CONTEXT).min(ops.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    hunks
+}
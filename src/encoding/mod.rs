@@ -0,0 +1,71 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn b64encode<T: AsRef<[u8]>>(input: T) -> String {
+    let input = input.as_ref();
+    let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+        encoded.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        encoded.push(ALPHABET[(n >> 12) as usize & 63] as char);
+        encoded.push(if chunk.len() > 1 {
+            ALPHABET[(n >> 6) as usize & 63] as char
+        } else {
+            '='
+        });
+        encoded.push(if chunk.len() > 2 {
+            ALPHABET[n as usize & 63] as char
+        } else {
+            '='
+        });
+    }
+
+    encoded
+}
+
+pub fn b64decode<T: AsRef<[u8]>>(input: T) -> Option<Vec<u8>> {
+    let input = input
+        .as_ref()
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect::<Vec<u8>>();
+
+    if input.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(input.len() / 4 * 3);
+    for chunk in input.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|b| **b == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for (i, b) in chunk.iter().enumerate() {
+            let val = if i >= 4 - padding {
+                0
+            } else {
+                ALPHABET.iter().position(|a| a == b)? as u32
+            };
+            n = (n << 6) | val;
+        }
+
+        decoded.push((n >> 16) as u8);
+        if padding < 2 {
+            decoded.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            decoded.push(n as u8);
+        }
+    }
+
+    Some(decoded)
+}
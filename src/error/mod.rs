@@ -0,0 +1,195 @@
+use thiserror::Error;
+
+use std::error::Error as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+
+use crate::vault::VaultError;
+
+// Exit codes follow ansible: 2 when tasks failed, 4 when hosts were unreachable.
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_FAILED: i32 = 2;
+pub const EXIT_UNREACHABLE: i32 = 4;
+// As if killed by SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Debug, Error)]
+pub enum AnsimpleError {
+    #[error("failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to write {}: {source}", path.display())]
+    Write { path: PathBuf, source: io::Error },
+    #[error("failed to parse {}", located(path, source))]
+    Parse {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("invalid utf-8: {0}")]
+    Utf8(#[from] FromUtf8Error),
+    #[error(transparent)]
+    Vault(#[from] VaultError),
+    #[error("{0}")]
+    Template(String),
+    #[error("missing required variables:\n{0}")]
+    MissingVariables(String),
+    #[error("failed to connect to {host}: {source}")]
+    Connect { host: String, source: io::Error },
+    #[error("authentication failed for {user}@{host}: {reason}")]
+    Auth {
+        host: String,
+        user: String,
+        reason: String,
+    },
+    #[error("host key verification failed for {host}: {reason}")]
+    HostKey { host: String, reason: String },
+    #[error("agent on {host}: {message}")]
+    Agent { host: String, message: String },
+    #[error("no ssh agent identity matching `{0}`")]
+    AgentIdentity(String),
+    #[error(transparent)]
+    Ssh(#[from] ssh2::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error("transfer failed: {0}")]
+    Transfer(String),
+    #[error("{task}: {host} - FAILED: {source}")]
+    Task {
+        host: String,
+        task: String,
+        source: Box<AnsimpleError>,
+    },
+    #[error("{0}")]
+    Config(String),
+    #[error("`{command}` exited with {rc}: {stderr}")]
+    Command {
+        command: String,
+        rc: i32,
+        stderr: String,
+    },
+    #[error("host worker aborted: {0}")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("plugin {}: {message}", path.display())]
+    Plugin { path: PathBuf, message: String },
+    #[error("installing {role} failed: {message}")]
+    Install { role: String, message: String },
+    #[error("history database: {0}")]
+    History(String),
+    #[error("health check on {host} failed: {message}")]
+    HealthCheck { host: String, message: String },
+    #[error("`until` did not hold after {0} attempt(s)")]
+    UntilFailed(u32),
+    #[error("play aborted: {0}")]
+    Aborted(String),
+    #[error("run interrupted")]
+    Interrupted,
+    #[error("{0} test(s) failed")]
+    TestsFailed(usize),
+    #[error("{0} problem(s) found")]
+    LintFailed(usize),
+    #[error("{} host(s) failed:\n{}", failed_hosts(.0), format_failures(.0))]
+    HostsFailed(Vec<AnsimpleError>),
+}
+
+impl AnsimpleError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AnsimpleError::HostsFailed(failures) if failures.iter().all(Self::is_unreachable) => {
+                EXIT_UNREACHABLE
+            }
+            AnsimpleError::HostsFailed(_)
+            | AnsimpleError::Task { .. }
+            | AnsimpleError::Aborted(_)
+            | AnsimpleError::TestsFailed(_)
+            | AnsimpleError::LintFailed(_) => EXIT_FAILED,
+            AnsimpleError::Interrupted => EXIT_INTERRUPTED,
+            _ => EXIT_ERROR,
+        }
+    }
+
+    // The connection broke down, another one may do better.
+    pub fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            AnsimpleError::Connect { .. }
+                | AnsimpleError::Ssh(_)
+                | AnsimpleError::Io(_)
+                | AnsimpleError::Agent { .. }
+        )
+    }
+
+    // The host the error happened on, when it is about one.
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            AnsimpleError::Connect { host, .. }
+            | AnsimpleError::Auth { host, .. }
+            | AnsimpleError::HostKey { host, .. }
+            | AnsimpleError::Agent { host, .. }
+            | AnsimpleError::Task { host, .. }
+            | AnsimpleError::HealthCheck { host, .. } => Some(host),
+            _ => None,
+        }
+    }
+
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            AnsimpleError::Connect { .. }
+            | AnsimpleError::Auth { .. }
+            | AnsimpleError::HostKey { .. } => true,
+            AnsimpleError::Task { source, .. } => source.is_unreachable(),
+            _ => false,
+        }
+    }
+}
+
+// Tera reports the interesting part (e.g. the missing variable) in the
+// source chain, so flatten it into the message.
+impl From<tera::Error> for AnsimpleError {
+    fn from(err: tera::Error) -> Self {
+        let mut message = err.to_string();
+        let mut source = err.source();
+        while let Some(err) = source {
+            message.push_str(&format!(": {err}"));
+            source = err.source();
+        }
+
+        AnsimpleError::Template(message)
+    }
+}
+
+// serde_yaml ends its messages with where the error is, which reads better
+// up front, the way compilers point at a file.
+fn located(path: &Path, source: &serde_yaml::Error) -> String {
+    let message = source.to_string();
+    match source.location() {
+        Some(location) => {
+            let (line, column) = (location.line(), location.column());
+            let message = message
+                .strip_suffix(&format!(" at line {line} column {column}"))
+                .unwrap_or(&message);
+            format!("{}:{line}:{column}: {message}", path.display())
+        }
+        None => format!("{}: {message}", path.display()),
+    }
+}
+
+fn failed_hosts(failures: &[AnsimpleError]) -> usize {
+    failures
+        .iter()
+        .filter(|failure| !matches!(failure, AnsimpleError::Aborted(_)))
+        .count()
+}
+
+fn format_failures(failures: &[AnsimpleError]) -> String {
+    failures
+        .iter()
+        .map(|failure| format!("  {failure}"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
@@ -0,0 +1,168 @@
+use indexmap::IndexMap;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use std::sync::{Arc, Mutex};
+
+use crate::task::RegisteredResult;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PlayStarted {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        hosts: Vec<String>,
+    },
+    TaskStarted {
+        host: String,
+        task: String,
+    },
+    TaskResult(TaskResultEvent),
+    // How much of a file is on the host, at most once a second.
+    TransferProgress {
+        host: String,
+        task: String,
+        path: String,
+        bytes: u64,
+        total: u64,
+    },
+    TransferFinished {
+        host: String,
+        task: String,
+        path: String,
+        bytes: u64,
+        seconds: f64,
+    },
+    HostUnreachable {
+        host: String,
+        task: String,
+        error: String,
+    },
+    // An attempt of a task with `retries` or `until` that is tried again.
+    TaskRetrying {
+        host: String,
+        task: String,
+        retries_left: u32,
+    },
+    // The outcome of the play's health check on a host that finished its
+    // batch.
+    HealthCheck {
+        host: String,
+        attempts: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Recap {
+        hosts: IndexMap<String, HostStats>,
+    },
+    // Something that went wrong on the side, which the run carries on
+    // without.
+    Warning {
+        host: String,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskResultEvent {
+    pub host: String,
+    pub task: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // `None` for `no_log` tasks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Box<RegisteredResult>>,
+    // How a file task changed its file, in runs that show diffs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct HostStats {
+    pub ok: usize,
+    pub changed: usize,
+    pub failed: usize,
+    pub unreachable: usize,
+    // Left out by tags or `when`.
+    pub skipped: usize,
+    // Failed under `ignore_errors`, also counted as ok.
+    pub ignored: usize,
+}
+
+// Forwards events to a frontend and keeps the per-host counts for the recap.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    sender: UnboundedSender<Event>,
+    stats: Arc<Mutex<IndexMap<String, HostStats>>>,
+}
+
+pub fn channel() -> (EventSender, UnboundedReceiver<Event>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let sender = EventSender {
+        sender,
+        stats: Arc::default(),
+    };
+
+    (sender, receiver)
+}
+
+impl EventSender {
+    pub fn emit(&self, event: Event) {
+        {
+            let mut stats = self.stats.lock().expect("event stats lock poisoned");
+            match &event {
+                Event::PlayStarted { hosts, .. } => {
+                    for host in hosts {
+                        stats.entry(host.clone()).or_default();
+                    }
+                }
+                Event::TaskResult(result) => {
+                    let host = stats.entry(result.host.clone()).or_default();
+                    match result.status.as_str() {
+                        "failed" => host.failed += 1,
+                        "skipped" => host.skipped += 1,
+                        "changed" => {
+                            host.ok += 1;
+                            host.changed += 1;
+                        }
+                        "ignored" => {
+                            host.ok += 1;
+                            host.ignored += 1;
+                        }
+                        _ => host.ok += 1,
+                    }
+                }
+                Event::HostUnreachable { host, .. } => {
+                    stats.entry(host.clone()).or_default().unreachable += 1;
+                }
+                Event::HealthCheck {
+                    host,
+                    error: Some(_),
+                    ..
+                } => {
+                    stats.entry(host.clone()).or_default().failed += 1;
+                }
+                Event::TaskStarted { .. }
+                | Event::TaskRetrying { .. }
+                | Event::TransferProgress { .. }
+                | Event::TransferFinished { .. }
+                | Event::HealthCheck { .. }
+                | Event::Recap { .. }
+                | Event::Warning { .. } => {}
+            }
+        }
+
+        // A frontend that went away must not fail the run.
+        let _ = self.sender.send(event);
+    }
+
+    // Emits the counts collected since the previous recap, if there are any.
+    pub fn recap(&self) {
+        let hosts = std::mem::take(&mut *self.stats.lock().expect("event stats lock poisoned"));
+        if !hosts.is_empty() {
+            let _ = self.sender.send(Event::Recap { hosts });
+        }
+    }
+}
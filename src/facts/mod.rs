@@ -0,0 +1,175 @@
+use serde::Serialize;
+
+use crate::connection;
+use crate::error::AnsimpleError;
+use crate::inventory::{GlobalConfig, Host};
+use crate::platform::Platform;
+use crate::runner::RunOptions;
+
+// Prints one `key=value` line per fact. Whatever a host lacks the tools for
+// is left out rather than failing the play.
+const POSIX_SCRIPT: &str = r#"echo "system=$(uname -s)"
+echo "kernel=$(uname -r)"
+echo "architecture=$(uname -m)"
+echo "hostname=$(uname -n)"
+if [ -r /etc/os-release ]; then
+  (. /etc/os-release; echo "distribution=$ID"; echo "distribution_version=$VERSION_ID"; echo "distribution_like=$ID_LIKE")
+elif [ "$(uname -s)" = Darwin ]; then
+  echo "distribution=macos"
+  echo "distribution_version=$(sw_vers -productVersion)"
+fi
+echo "processor_count=$(getconf _NPROCESSORS_ONLN 2>/dev/null || nproc 2>/dev/null)"
+if [ -r /proc/meminfo ]; then
+  awk '/^MemTotal:/ { print "memory_mb=" int($2 / 1024) }' /proc/meminfo
+else
+  echo "memory_mb=$(( $(sysctl -n hw.memsize 2>/dev/null || echo 0) / 1048576 ))"
+fi
+if command -v ip >/dev/null 2>&1; then
+  ip -o addr show 2>/dev/null | awk '{ split($4, a, "/"); print "address=" a[1] }'
+else
+  for a in $(hostname -I 2>/dev/null); do echo "address=$a"; done
+fi
+true"#;
+
+const WINDOWS_SCRIPT: &str = r#"$os = Get-CimInstance Win32_OperatingSystem
+"system=Windows"
+"kernel=$($os.Version)"
+"architecture=$env:PROCESSOR_ARCHITECTURE"
+"hostname=$env:COMPUTERNAME"
+"distribution=$($os.Caption)"
+"distribution_version=$($os.Version)"
+"processor_count=$env:NUMBER_OF_PROCESSORS"
+"memory_mb=$([math]::Floor($os.TotalVisibleMemorySize / 1024))"
+Get-NetIPAddress | ForEach-Object { "address=$($_.IPAddress)" }"#;
+
+// What a host is, as templates see it under `facts`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Facts {
+    // `Linux`, `Darwin`, `Windows`, ...
+    pub system: String,
+    // `Debian`, `RedHat`, `Suse`, `Archlinux`, `Alpine`, `Darwin`, `Windows`,
+    // or the system when the distribution is not one of them.
+    pub os_family: String,
+    // The `ID` of `/etc/os-release`, such as `ubuntu`.
+    pub distribution: String,
+    pub distribution_version: String,
+    pub kernel: String,
+    pub hostname: String,
+    pub architecture: String,
+    // Without the loopback ones.
+    pub addresses: Vec<String>,
+    pub processor_count: Option<u64>,
+    pub memory_mb: Option<u64>,
+}
+
+impl Facts {
+    // Collects the facts of `host` over a connection of its own. Hosts the
+    // inventory gives no platform are asked again as Windows ones when the
+    // POSIX script gets no answer, as from cmd.exe.
+    pub async fn gather(
+        host: &Host,
+        options: &RunOptions,
+        global_config: &GlobalConfig,
+    ) -> Result<Self, AnsimpleError> {
+        let mut output = run(host, options, global_config).await?;
+        if host.platform.is_none() && !answered(&output) {
+            let windows = Host {
+                platform: Some(Platform::Windows),
+                ..host.clone()
+            };
+            match run(&windows, options, global_config).await {
+                Ok(tried) if answered(&tried) => output = tried,
+                _ => {}
+            }
+        }
+
+        let (stdout, stderr, rc) = output;
+        if rc != 0 {
+            return Err(AnsimpleError::Config(format!(
+                "gathering facts exited with {rc}: {}",
+                stderr.trim()
+            )));
+        }
+
+        Ok(Self::parse(&stdout))
+    }
+
+    fn parse(output: &str) -> Self {
+        let mut facts = Self::default();
+        let mut like = String::new();
+        for line in output.lines() {
+            let Some((key, value)) = line.trim_end_matches('\r').split_once('=') else {
+                continue;
+            };
+            let value = value.trim().to_owned();
+            match key {
+                "system" => facts.system = value,
+                "kernel" => facts.kernel = value,
+                "architecture" => facts.architecture = value,
+                "hostname" => facts.hostname = value,
+                "distribution" => facts.distribution = value,
+                "distribution_version" => facts.distribution_version = value,
+                "distribution_like" => like = value,
+                "processor_count" => facts.processor_count = value.parse().ok(),
+                "memory_mb" => facts.memory_mb = value.parse().ok().filter(|mb| *mb > 0),
+                "address" if !is_loopback(&value) && !facts.addresses.contains(&value) => {
+                    facts.addresses.push(value)
+                }
+                _ => {}
+            }
+        }
+        facts.os_family = os_family(&facts.system, &facts.distribution, &like);
+
+        facts
+    }
+}
+
+fn os_family(system: &str, distribution: &str, like: &str) -> String {
+    let family = std::iter::once(distribution)
+        .chain(like.split_whitespace())
+        .find_map(|id| match id {
+            "debian" | "ubuntu" => Some("Debian"),
+            "rhel" | "fedora" | "centos" | "rocky" | "almalinux" | "amzn" => Some("RedHat"),
+            "suse" | "opensuse" | "sles" => Some("Suse"),
+            "arch" => Some("Archlinux"),
+            "alpine" => Some("Alpine"),
+            _ => None,
+        });
+
+    match (family, system) {
+        (Some(family), _) => family.to_owned(),
+        (None, "Windows") => "Windows".to_owned(),
+        (None, system) => system.to_owned(),
+    }
+}
+
+fn is_loopback(address: &str) -> bool {
+    address.starts_with("127.") || address == "::1"
+}
+
+// Runs the facts script of the host's platform.
+async fn run(
+    host: &Host,
+    options: &RunOptions,
+    global_config: &GlobalConfig,
+) -> Result<(String, String, i32), AnsimpleError> {
+    let (host, global_config) = (host.clone(), global_config.clone());
+    let script = match host.platform() {
+        Platform::Posix => POSIX_SCRIPT,
+        Platform::Windows => WINDOWS_SCRIPT,
+    };
+    let workers = options.workers.clone();
+    let options = options.clone();
+    // SSH blocks, so it runs off the runtime.
+    workers
+        .run(move || connection::open(&host, &options, &global_config)?.exec(script))
+        .await?
+}
+
+fn answered((stdout, _, rc): &(String, String, i32)) -> bool {
+    *rc == 0
+        && stdout.lines().any(|line| {
+            line.strip_prefix("system=")
+                .is_some_and(|system| !system.trim().is_empty())
+        })
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tera::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+
+use crate::connection;
+use crate::error::AnsimpleError;
+use crate::inventory::{GlobalConfig, Host};
+use crate::runner::RunOptions;
+use crate::schema::{Generator, Schema};
+use crate::template::TemplateRegistry;
+
+// Must pass on every host of a batch before the next batch starts. The probe
+// is rendered with the host's variables, so it can name the host.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthCheck {
+    #[serde(flatten)]
+    pub probe: Probe,
+    // Attempts after the first one that failed.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    // Seconds between attempts.
+    #[serde(default = "default_delay")]
+    pub delay: u64,
+    // Seconds an attempt may take.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    // Fetched by the controller, passing with a 2xx status.
+    Uri(String),
+    // Run on the host, passing with exit status 0.
+    Command(String),
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_delay() -> u64 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+// The probe is one of the keys next to the attempts' settings.
+impl Schema for HealthCheck {
+    fn name() -> Option<&'static str> {
+        Some("HealthCheck")
+    }
+
+    fn schema(generator: &mut Generator) -> serde_json::Value {
+        let mut schema = generator
+            .object()
+            .optional::<String>("uri")
+            .optional::<String>("command")
+            .optional::<u32>("retries")
+            .optional::<u64>("delay")
+            .optional::<u64>("timeout")
+            .build();
+        schema["oneOf"] = json!([{ "required": ["uri"] }, { "required": ["command"] }]);
+
+        schema
+    }
+}
+
+impl HealthCheck {
+    // Probes `host` until it passes or the retries are used up. Returns the
+    // number of attempts made and the outcome of the last one.
+    pub async fn run(
+        &self,
+        host: &Host,
+        context: &Context,
+        templates: &TemplateRegistry,
+        options: &RunOptions,
+        global_config: &GlobalConfig,
+    ) -> (u32, Result<(), AnsimpleError>) {
+        let failed = |message: String| AnsimpleError::HealthCheck {
+            host: host.address.clone(),
+            message,
+        };
+        let probe = match self.probe.render(context, templates) {
+            Ok(probe) => probe,
+            Err(err) => return (0, Err(err)),
+        };
+        if let Probe::Uri(uri) = &probe {
+            if !uri.starts_with("http://") {
+                return (
+                    0,
+                    Err(failed(format!(
+                        "{uri}: only http:// URIs are fetched, check others with a `command`"
+                    ))),
+                );
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let timeout = Duration::from_secs(self.timeout);
+            let result =
+                match time::timeout(timeout, probe.attempt(host, options, global_config)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(format!("no answer within {}s", self.timeout)),
+                };
+
+            match result {
+                Ok(()) => return (attempt, Ok(())),
+                Err(message) if attempt > self.retries => return (attempt, Err(failed(message))),
+                Err(_) => time::sleep(Duration::from_secs(self.delay)).await,
+            }
+        }
+    }
+}
+
+impl Probe {
+    fn render(
+        &self,
+        context: &Context,
+        templates: &TemplateRegistry,
+    ) -> Result<Self, AnsimpleError> {
+        let value = tera::to_value(self)?;
+        let rendered = templates.render_value(&value, context, "health_check")?;
+        tera::from_value(rendered)
+            .map_err(|err| AnsimpleError::Template(format!("invalid health_check: {err}")))
+    }
+
+    async fn attempt(
+        &self,
+        host: &Host,
+        options: &RunOptions,
+        global_config: &GlobalConfig,
+    ) -> Result<(), String> {
+        match self {
+            Probe::Uri(uri) => get(uri).await,
+            Probe::Command(command) => {
+                let host = host.clone();
+                let options = options.clone();
+                let global_config = global_config.clone();
+                let command = command.clone();
+                // SSH blocks, so it runs off the runtime, where the timeout
+                // can still give up on it.
+                let output = options
+                    .workers
+                    .clone()
+                    .run(move || connection::open(&host, &options, &global_config)?.exec(&command))
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+                match output {
+                    Ok((_, _, 0)) => Ok(()),
+                    Ok((_, stderr, rc)) if stderr.trim().is_empty() => {
+                        Err(format!("exited with {rc}"))
+                    }
+                    Ok((_, stderr, rc)) => Err(format!("exited with {rc}: {}", stderr.trim())),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+        }
+    }
+}
+
+// A plain HTTP/1.1 GET that only looks at the status line.
+async fn get(uri: &str) -> Result<(), String> {
+    let rest = uri.trim_start_matches("http://");
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+    let address = match authority.rfind(':') {
+        Some(at) if !authority[at..].contains(']') => authority.to_owned(),
+        _ => format!("{authority}:80"),
+    };
+
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .map_err(|err| format!("{uri}: {err}"))?;
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: ansimple\r\nConnection: close\r\n\r\n"
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|err| format!("{uri}: {err}"))?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 1024];
+    while !response.contains(&b'\n') && response.len() < 8192 {
+        let read = stream
+            .read(&mut buffer)
+            .await
+            .map_err(|err| format!("{uri}: {err}"))?;
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..read]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default().trim();
+    match status_line.split_whitespace().nth(1).map(str::parse::<u16>) {
+        Some(Ok(status)) if (200..300).contains(&status) => Ok(()),
+        Some(Ok(_)) => Err(format!("{uri} answered `{status_line}`")),
+        _ => Err(format!("{uri} did not answer with HTTP")),
+    }
+}
@@ -0,0 +1,426 @@
+use chrono::{SecondsFormat, Utc};
+use indexmap::IndexMap;
+use tera::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+mod sqlite;
+
+use crate::error::AnsimpleError;
+use crate::events::{Event, TaskResultEvent};
+use sqlite::{Connection, Param};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id TEXT PRIMARY KEY,
+    playbook TEXT NOT NULL,
+    inventory_sha256 TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    finished_at TEXT,
+    duration_ms INTEGER,
+    status TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS results (
+    run_id TEXT NOT NULL REFERENCES runs(id),
+    host TEXT NOT NULL,
+    task TEXT NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT,
+    duration_ms INTEGER,
+    diff TEXT,
+    finished_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS results_run_id ON results (run_id);
+CREATE TABLE IF NOT EXISTS checkpoints (
+    run_id TEXT NOT NULL REFERENCES runs(id),
+    play INTEGER NOT NULL,
+    host TEXT NOT NULL,
+    next_task INTEGER NOT NULL,
+    done_sha256 TEXT NOT NULL,
+    PRIMARY KEY (run_id, play, host)
+);
+CREATE TABLE IF NOT EXISTS registered (
+    run_id TEXT NOT NULL REFERENCES runs(id),
+    play INTEGER NOT NULL,
+    host TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (run_id, play, host, name)
+);
+";
+
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    pub id: String,
+    pub playbook: String,
+    pub inventory_sha256: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub status: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResultRecord {
+    pub host: String,
+    pub task: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub diff: Option<String>,
+    pub finished_at: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl History {
+    pub fn default_path() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME")?;
+        Some(PathBuf::from(home).join(".ansimple").join("history.db"))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, AnsimpleError> {
+        let path = path.as_ref();
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(path).map_err(AnsimpleError::History)?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(AnsimpleError::History)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn start_run(
+        &self,
+        id: &str,
+        playbook: &Path,
+        inventory_sha256: &str,
+    ) -> Result<(), AnsimpleError> {
+        self.execute(
+            "INSERT INTO runs (id, playbook, inventory_sha256, started_at, status)
+             VALUES (?, ?, ?, ?, 'running')",
+            &[
+                Param::Text(id),
+                Param::Text(&playbook.to_string_lossy()),
+                Param::Text(inventory_sha256),
+                Param::Text(&now()),
+            ],
+        )
+    }
+
+    // Marks an earlier run as running again, keeping its id and results.
+    pub fn resume_run(&self, id: &str) -> Result<(), AnsimpleError> {
+        self.execute(
+            "UPDATE runs SET status = 'running', finished_at = NULL, duration_ms = NULL
+             WHERE id = ?",
+            &[Param::Text(id)],
+        )
+    }
+
+    pub fn finish_run(&self, id: &str, status: &str) -> Result<(), AnsimpleError> {
+        let now = now();
+        self.execute(
+            "UPDATE runs SET finished_at = ?1, status = ?2,
+                 duration_ms = CAST((julianday(?1) - julianday(started_at)) * 86400000 AS INTEGER)
+             WHERE id = ?3",
+            &[Param::Text(&now), Param::Text(status), Param::Text(id)],
+        )
+    }
+
+    pub fn record_result(&self, run_id: &str, result: &ResultRecord) -> Result<(), AnsimpleError> {
+        self.execute(
+            "INSERT INTO results (run_id, host, task, status, error, duration_ms, diff, finished_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            &[
+                Param::Text(run_id),
+                Param::Text(&result.host),
+                Param::Text(&result.task),
+                Param::Text(&result.status),
+                result.error.as_deref().map_or(Param::Null, Param::Text),
+                result.duration_ms.map_or(Param::Null, Param::Int),
+                result.diff.as_deref().map_or(Param::Null, Param::Text),
+                Param::Text(&result.finished_at),
+            ],
+        )
+    }
+
+    // Most recent first.
+    pub fn runs(&self, limit: usize) -> Result<Vec<RunRecord>, AnsimpleError> {
+        let rows = self.query(
+            "SELECT id, playbook, inventory_sha256, started_at, finished_at, duration_ms, status
+             FROM runs ORDER BY started_at DESC LIMIT ?",
+            &[Param::Int(limit as i64)],
+        )?;
+
+        Ok(rows.into_iter().map(run_record).collect())
+    }
+
+    pub fn run(&self, id: &str) -> Result<Option<(RunRecord, Vec<ResultRecord>)>, AnsimpleError> {
+        let Some(run) = self
+            .query(
+                "SELECT id, playbook, inventory_sha256, started_at, finished_at, duration_ms, status
+                 FROM runs WHERE id = ?",
+                &[Param::Text(id)],
+            )?
+            .into_iter()
+            .next()
+        else {
+            return Ok(None);
+        };
+
+        let results = self
+            .query(
+                "SELECT host, task, status, error, duration_ms, diff, finished_at
+                 FROM results WHERE run_id = ? ORDER BY rowid",
+                &[Param::Text(id)],
+            )?
+            .into_iter()
+            .map(|mut row| ResultRecord {
+                host: take(&mut row, 0),
+                task: take(&mut row, 1),
+                status: take(&mut row, 2),
+                error: row[3].take(),
+                duration_ms: row[4].take().and_then(|value| value.parse().ok()),
+                diff: row[5].take(),
+                finished_at: take(&mut row, 6),
+            })
+            .collect();
+
+        Ok(Some((run_record(run), results)))
+    }
+
+    // With `resume`, the checkpoint also carries the progress saved so far.
+    pub fn checkpoint(&self, run_id: &str, resume: bool) -> Result<Checkpoint, AnsimpleError> {
+        let mut progress = HashMap::new();
+        if resume {
+            let rows = self.query(
+                "SELECT play, host, next_task, done_sha256 FROM checkpoints WHERE run_id = ?",
+                &[Param::Text(run_id)],
+            )?;
+            for mut row in rows {
+                let play = take(&mut row, 0).parse().unwrap_or_default();
+                progress.insert(
+                    (play, take(&mut row, 1)),
+                    HostProgress {
+                        next_task: take(&mut row, 2).parse().unwrap_or_default(),
+                        done_sha256: take(&mut row, 3),
+                        registered: IndexMap::new(),
+                    },
+                );
+            }
+
+            let rows = self.query(
+                "SELECT play, host, name, value FROM registered WHERE run_id = ? ORDER BY rowid",
+                &[Param::Text(run_id)],
+            )?;
+            for mut row in rows {
+                let play = take(&mut row, 0).parse().unwrap_or_default();
+                let key = (play, take(&mut row, 1));
+                let value = serde_json::from_str(&take(&mut row, 3))?;
+                if let Some(host) = progress.get_mut(&key) {
+                    host.registered.insert(take(&mut row, 2), value);
+                }
+            }
+        }
+
+        Ok(Checkpoint {
+            history: self.clone(),
+            run_id: run_id.to_owned(),
+            plays: Arc::new(AtomicUsize::new(0)),
+            progress: Arc::new(progress),
+        })
+    }
+
+    pub fn recorder(&self, run_id: &str) -> Recorder {
+        Recorder {
+            history: self.clone(),
+            run_id: run_id.to_owned(),
+            started: HashMap::new(),
+        }
+    }
+
+    fn execute(&self, sql: &str, params: &[Param]) -> Result<(), AnsimpleError> {
+        self.connection
+            .lock()
+            .expect("history lock poisoned")
+            .execute(sql, params)
+            .map_err(AnsimpleError::History)
+    }
+
+    fn query(
+        &self,
+        sql: &str,
+        params: &[Param],
+    ) -> Result<Vec<Vec<Option<String>>>, AnsimpleError> {
+        self.connection
+            .lock()
+            .expect("history lock poisoned")
+            .query(sql, params)
+            .map_err(AnsimpleError::History)
+    }
+}
+
+// Turns the event stream of a run into result rows, timing each task from
+// its `TaskStarted` event.
+#[derive(Debug)]
+pub struct Recorder {
+    history: History,
+    run_id: String,
+    started: HashMap<(String, String), Instant>,
+}
+
+impl Recorder {
+    pub fn record(&mut self, event: &Event) -> Result<(), AnsimpleError> {
+        let (host, task, status, error, diff) = match event {
+            Event::TaskStarted { host, task } => {
+                self.started
+                    .insert((host.clone(), task.clone()), Instant::now());
+                return Ok(());
+            }
+            Event::TaskResult(TaskResultEvent {
+                host,
+                task,
+                status,
+                error,
+                diff,
+                ..
+            }) => (host, task, status.as_str(), error.clone(), diff.clone()),
+            Event::HostUnreachable { host, task, error } => {
+                (host, task, "unreachable", Some(error.clone()), None)
+            }
+            Event::PlayStarted { .. }
+            | Event::TaskRetrying { .. }
+            | Event::TransferProgress { .. }
+            | Event::TransferFinished { .. }
+            | Event::HealthCheck { .. }
+            | Event::Recap { .. }
+            | Event::Warning { .. } => {
+                return Ok(());
+            }
+        };
+
+        let duration_ms = self
+            .started
+            .remove(&(host.clone(), task.clone()))
+            .map(|started| started.elapsed().as_millis() as i64);
+
+        self.history.record_result(
+            &self.run_id,
+            &ResultRecord {
+                host: host.clone(),
+                task: task.clone(),
+                status: status.to_owned(),
+                error,
+                duration_ms,
+                diff,
+                finished_at: now(),
+            },
+        )
+    }
+}
+
+// How far a host got in a play: the first task it has not completed, a
+// SHA-256 of the tasks before it and the results they registered.
+#[derive(Debug, Clone)]
+pub struct HostProgress {
+    pub next_task: usize,
+    pub done_sha256: String,
+    pub registered: IndexMap<String, Value>,
+}
+
+// Saves the progress of every host after each completed task, so an
+// interrupted run can be resumed. Plays are numbered in the order they start.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    history: History,
+    run_id: String,
+    plays: Arc<AtomicUsize>,
+    progress: Arc<HashMap<(usize, String), HostProgress>>,
+}
+
+impl Checkpoint {
+    pub fn next_play(&self) -> usize {
+        self.plays.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // The progress of `host` in an earlier attempt of this run.
+    pub fn progress(&self, play: usize, host: &str) -> Option<&HostProgress> {
+        self.progress.get(&(play, host.to_owned()))
+    }
+
+    pub fn save(
+        &self,
+        play: usize,
+        host: &str,
+        next_task: usize,
+        done_sha256: &str,
+    ) -> Result<(), AnsimpleError> {
+        self.history.execute(
+            "INSERT OR REPLACE INTO checkpoints (run_id, play, host, next_task, done_sha256)
+             VALUES (?, ?, ?, ?, ?)",
+            &[
+                Param::Text(&self.run_id),
+                Param::Int(play as i64),
+                Param::Text(host),
+                Param::Int(next_task as i64),
+                Param::Text(done_sha256),
+            ],
+        )
+    }
+
+    pub fn save_registered(
+        &self,
+        play: usize,
+        host: &str,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), AnsimpleError> {
+        self.history.execute(
+            "INSERT OR REPLACE INTO registered (run_id, play, host, name, value)
+             VALUES (?, ?, ?, ?, ?)",
+            &[
+                Param::Text(&self.run_id),
+                Param::Int(play as i64),
+                Param::Text(host),
+                Param::Text(name),
+                Param::Text(&serde_json::to_string(value)?),
+            ],
+        )
+    }
+}
+
+fn run_record(mut row: Vec<Option<String>>) -> RunRecord {
+    RunRecord {
+        id: take(&mut row, 0),
+        playbook: take(&mut row, 1),
+        inventory_sha256: take(&mut row, 2),
+        started_at: take(&mut row, 3),
+        finished_at: row[4].take(),
+        duration_ms: row[5].take().and_then(|value| value.parse().ok()),
+        status: take(&mut row, 6),
+    }
+}
+
+fn take(row: &mut [Option<String>], column: usize) -> String {
+    row[column].take().unwrap_or_default()
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
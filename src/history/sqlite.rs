@@ -0,0 +1,199 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::Path;
+use std::ptr;
+
+#[repr(C)]
+struct Sqlite3 {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Sqlite3Stmt {
+    _private: [u8; 0],
+}
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x0000_0002;
+const SQLITE_OPEN_CREATE: c_int = 0x0000_0004;
+const SQLITE_OPEN_FULLMUTEX: c_int = 0x0001_0000;
+// Makes sqlite copy bound text before the call returns.
+const SQLITE_TRANSIENT: isize = -1;
+const BUSY_TIMEOUT_MS: c_int = 5000;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut Sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close(db: *mut Sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+    fn sqlite3_busy_timeout(db: *mut Sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_exec(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        bytes: c_int,
+        stmt: *mut *mut Sqlite3Stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_text(
+        stmt: *mut Sqlite3Stmt,
+        index: c_int,
+        text: *const c_char,
+        bytes: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_bind_int64(stmt: *mut Sqlite3Stmt, index: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_null(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+    fn sqlite3_step(stmt: *mut Sqlite3Stmt) -> c_int;
+    fn sqlite3_column_count(stmt: *mut Sqlite3Stmt) -> c_int;
+    fn sqlite3_column_text(stmt: *mut Sqlite3Stmt, column: c_int) -> *const c_char;
+    fn sqlite3_finalize(stmt: *mut Sqlite3Stmt) -> c_int;
+}
+
+pub enum Param<'a> {
+    Text(&'a str),
+    Int(i64),
+    Null,
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    db: *mut Sqlite3,
+}
+
+// Opened with SQLITE_OPEN_FULLMUTEX, so sqlite serializes access itself.
+unsafe impl Send for Connection {}
+
+impl Connection {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let filename =
+            CString::new(path.to_string_lossy().as_bytes()).map_err(|err| err.to_string())?;
+        let mut db = ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULLMUTEX;
+        let rc = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+        let connection = Self { db };
+        if rc != SQLITE_OK {
+            return Err(connection.error());
+        }
+
+        unsafe { sqlite3_busy_timeout(db, BUSY_TIMEOUT_MS) };
+        Ok(connection)
+    }
+
+    pub fn execute_batch(&self, sql: &str) -> Result<(), String> {
+        let sql = CString::new(sql).map_err(|err| err.to_string())?;
+        let rc = unsafe {
+            sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if rc != SQLITE_OK {
+            return Err(self.error());
+        }
+
+        Ok(())
+    }
+
+    pub fn execute(&self, sql: &str, params: &[Param]) -> Result<(), String> {
+        self.query(sql, params).map(|_| ())
+    }
+
+    // Every column is returned as text, `None` for NULL.
+    pub fn query(&self, sql: &str, params: &[Param]) -> Result<Vec<Vec<Option<String>>>, String> {
+        let statement = self.prepare(sql)?;
+        for (index, param) in params.iter().enumerate() {
+            let index = index as c_int + 1;
+            let rc = match param {
+                Param::Text(text) => unsafe {
+                    sqlite3_bind_text(
+                        statement.0,
+                        index,
+                        text.as_ptr().cast(),
+                        text.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    )
+                },
+                Param::Int(value) => unsafe { sqlite3_bind_int64(statement.0, index, *value) },
+                Param::Null => unsafe { sqlite3_bind_null(statement.0, index) },
+            };
+            if rc != SQLITE_OK {
+                return Err(self.error());
+            }
+        }
+
+        let mut rows = Vec::new();
+        loop {
+            match unsafe { sqlite3_step(statement.0) } {
+                SQLITE_ROW => {
+                    let columns = unsafe { sqlite3_column_count(statement.0) };
+                    let row = (0..columns)
+                        .map(|column| {
+                            let text = unsafe { sqlite3_column_text(statement.0, column) };
+                            (!text.is_null()).then(|| {
+                                unsafe { CStr::from_ptr(text) }
+                                    .to_string_lossy()
+                                    .into_owned()
+                            })
+                        })
+                        .collect();
+                    rows.push(row);
+                }
+                SQLITE_DONE => return Ok(rows),
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn prepare(&self, sql: &str) -> Result<Statement, String> {
+        let sql = CString::new(sql).map_err(|err| err.to_string())?;
+        let mut statement = ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_prepare_v2(self.db, sql.as_ptr(), -1, &mut statement, ptr::null_mut())
+        };
+        if rc != SQLITE_OK {
+            return Err(self.error());
+        }
+
+        Ok(Statement(statement))
+    }
+
+    fn error(&self) -> String {
+        if self.db.is_null() {
+            return "out of memory".to_owned();
+        }
+
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.db) };
+    }
+}
+
+struct Statement(*mut Sqlite3Stmt);
+
+impl Drop for Statement {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.0) };
+    }
+}
@@ -0,0 +1,48 @@
+use regex::Regex;
+
+use std::str::FromStr;
+
+use super::{Host, HostConfig};
+
+// The hosts a run is limited to, as `--limit 'web*,db01'`: addresses or group
+// names, in which `*` and `?` are wildcards.
+#[derive(Debug, Clone)]
+pub struct Limit(Vec<Regex>);
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patterns = s
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(glob)
+            .collect::<Vec<_>>();
+        if patterns.is_empty() {
+            return Err("a limit names at least one host or group".to_owned());
+        }
+
+        Ok(Self(patterns))
+    }
+}
+
+impl Limit {
+    // Whether a pattern names `host`, by its address or a group it is in.
+    pub fn allows(&self, host_config: &HostConfig, host: &Host) -> bool {
+        self.0.iter().any(|pattern| {
+            pattern.is_match(&host.address)
+                || pattern.is_match("all")
+                || host_config.groups.keys().any(|group| {
+                    pattern.is_match(group) && host_config.in_group(&host.address, group)
+                })
+        })
+    }
+}
+
+fn glob(pattern: &str) -> Regex {
+    let pattern = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{pattern}$")).expect("escaped patterns are valid")
+}
@@ -0,0 +1,321 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tera::Value;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::connection::{ConnectionKind, HostKeyChecking};
+use crate::convert::AnsibleInventory;
+use crate::credentials::Password;
+use crate::error::AnsimpleError;
+use crate::platform::Platform;
+use crate::schema::{Generator, Schema};
+use crate::throttle::Bandwidth;
+use crate::vault::{self, Vault};
+
+mod limit;
+
+pub use limit::Limit;
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    pub address: String,
+    pub user: Option<String>,
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing)]
+    pub password: Option<Password>,
+    #[serde(default, skip_serializing)]
+    pub key_passphrase: Option<Password>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_jump: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<ConnectionKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_identity: Option<String>,
+    #[serde(rename = "become", skip_serializing_if = "Option::is_none")]
+    pub become_root: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub become_user: Option<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub environment: IndexMap<String, String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: HashMap<String, Value>,
+    // Until facts find out, hosts without one are taken for POSIX ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+impl Schema for Host {
+    fn name() -> Option<&'static str> {
+        Some("Host")
+    }
+
+    fn schema(generator: &mut Generator) -> Value {
+        generator
+            .object()
+            .required::<String>("address")
+            .optional::<String>("user")
+            .optional::<String>("key")
+            .optional::<u16>("port")
+            .optional::<Password>("password")
+            .optional::<Password>("key_passphrase")
+            .optional::<String>("proxy_jump")
+            .optional::<ConnectionKind>("connection")
+            .optional::<String>("agent_identity")
+            .optional::<bool>("become")
+            .optional::<String>("become_user")
+            .optional::<IndexMap<String, String>>("environment")
+            .optional::<HashMap<String, Value>>("vars")
+            .optional::<Platform>("platform")
+            .build()
+    }
+}
+
+impl Host {
+    // This machine, for tasks delegated to `localhost` when the inventory
+    // has no such host.
+    pub fn local(address: &str) -> Self {
+        Self {
+            address: address.to_owned(),
+            user: None,
+            key: None,
+            port: None,
+            password: None,
+            key_passphrase: None,
+            proxy_jump: None,
+            connection: Some(ConnectionKind::Local),
+            agent_identity: None,
+            become_root: None,
+            become_user: None,
+            environment: IndexMap::new(),
+            vars: HashMap::new(),
+            platform: None,
+        }
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform.unwrap_or_default()
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.address)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub global_config: GlobalConfig,
+    pub hosts: Vec<Host>,
+    // Named sets of hosts plays can target, listing addresses or other
+    // groups. `all` is every host.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub groups: IndexMap<String, Vec<String>>,
+    // Vars of the hosts in a group, by group name, `all` included.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub group_vars: IndexMap<String, HashMap<String, Value>>,
+    // Vars of a host by its address, on top of those it sets itself.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub host_vars: HashMap<String, HashMap<String, Value>>,
+}
+
+impl TryFrom<PathBuf> for HostConfig {
+    type Error = AnsimpleError;
+
+    fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
+        let contents = fs::read_to_string(&value).map_err(|source| AnsimpleError::Read {
+            path: value.clone(),
+            source,
+        })?;
+        serde_yaml::from_str(&contents).map_err(|source| AnsimpleError::Parse {
+            path: value,
+            source,
+        })
+    }
+}
+
+impl TryFrom<String> for HostConfig {
+    type Error = serde_yaml::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        serde_yaml::from_str(&value)
+    }
+}
+
+impl TryFrom<Vec<u8>> for HostConfig {
+    type Error = AnsimpleError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Ok(serde_yaml::from_str(&String::from_utf8(value)?)?)
+    }
+}
+
+impl Schema for HostConfig {
+    fn name() -> Option<&'static str> {
+        Some("HostConfig")
+    }
+
+    fn schema(generator: &mut Generator) -> Value {
+        generator
+            .object()
+            .required::<GlobalConfig>("global_config")
+            .required::<Vec<Host>>("hosts")
+            .optional::<IndexMap<String, Vec<String>>>("groups")
+            .optional::<IndexMap<String, HashMap<String, Value>>>("group_vars")
+            .optional::<HashMap<String, HashMap<String, Value>>>("host_vars")
+            .build()
+    }
+}
+
+impl HostConfig {
+    pub fn load<P: AsRef<Path>>(path: P, vault: Option<&Vault>) -> Result<Self, AnsimpleError> {
+        vault::load(path, vault)
+    }
+
+    // An Ansible inventory, INI or YAML, taken over the way `ansimple
+    // convert` does. What could not be is added to `notes`.
+    pub fn load_ansible<P: AsRef<Path>>(
+        path: P,
+        vault: Option<&Vault>,
+        notes: &mut Vec<String>,
+    ) -> Result<Self, AnsimpleError> {
+        let path = path.as_ref();
+        let parse_error = |source| AnsimpleError::Parse {
+            path: path.to_owned(),
+            source,
+        };
+        let source = vault::read_to_string(path, vault)?;
+        let inventory = AnsibleInventory::parse(&source, notes).map_err(|err| match err {
+            AnsimpleError::Yaml(source) => parse_error(source),
+            err => err,
+        })?;
+
+        serde_yaml::from_value(inventory.to_host_config(notes)).map_err(parse_error)
+    }
+
+    // Every host the play `hosts` name, by address or group, in inventory
+    // order.
+    pub fn matching<'a>(&'a self, hosts: &'a [String]) -> impl Iterator<Item = &'a Host> + 'a {
+        self.hosts.iter().filter(move |host| {
+            hosts
+                .iter()
+                .any(|name| *name == host.address || self.in_group(&host.address, name))
+        })
+    }
+
+    // Whether `address` is in group `group`, directly or through the groups
+    // it lists.
+    pub fn in_group(&self, address: &str, group: &str) -> bool {
+        let mut seen = Vec::new();
+        group == "all" || self.reaches(group, address, &mut seen)
+    }
+
+    fn reaches<'a>(&'a self, group: &'a str, address: &str, seen: &mut Vec<&'a str>) -> bool {
+        // Groups that list each other are only looked into once.
+        if seen.contains(&group) {
+            return false;
+        }
+        seen.push(group);
+        self.groups.get(group).is_some_and(|members| {
+            members.iter().any(|member| {
+                member == address
+                    || (self.groups.contains_key(member) && self.reaches(member, address, seen))
+            })
+        })
+    }
+
+    // The vars of `host`: those of `all`, then those of each group it is in
+    // in the order of `group_vars`, its own and those of `host_vars`.
+    pub fn vars(&self, host: &Host) -> HashMap<String, Value> {
+        let mut vars = HashMap::new();
+        if let Some(all) = self.group_vars.get("all") {
+            vars.extend(all.clone());
+        }
+        for (group, group_vars) in &self.group_vars {
+            if group != "all" && self.in_group(&host.address, group) {
+                vars.extend(group_vars.clone());
+            }
+        }
+        vars.extend(host.vars.clone());
+        if let Some(host_vars) = self.host_vars.get(&host.address) {
+            vars.extend(host_vars.clone());
+        }
+
+        vars
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalConfig {
+    pub user: String,
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    // Tried when neither the agent nor the key let the user in. Never
+    // serialized, so it stays out of templates and the run history.
+    #[serde(default, skip_serializing)]
+    pub password: Option<Password>,
+    // What the key file is encrypted with.
+    #[serde(default, skip_serializing)]
+    pub key_passphrase: Option<Password>,
+    // A host to reach the hosts through, as `[user@]host[:port]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_jump: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_identity: Option<String>,
+    // Whether commands run as root through sudo.
+    #[serde(rename = "become", default, skip_serializing_if = "Option::is_none")]
+    pub become_root: Option<bool>,
+    // Who they run as instead, root when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub become_user: Option<String>,
+    // Set for every command run on the hosts.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub environment: IndexMap<String, String>,
+    // Shared by the transfers to all hosts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth: Option<Bandwidth>,
+    // Where each host keeps the manifest of what was uploaded to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_cache: Option<PathBuf>,
+    // The keys hosts are checked against, `~/.ssh/known_hosts` when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict_host_key_checking: Option<HostKeyChecking>,
+}
+
+impl Schema for GlobalConfig {
+    fn name() -> Option<&'static str> {
+        Some("GlobalConfig")
+    }
+
+    fn schema(generator: &mut Generator) -> Value {
+        generator
+            .object()
+            .required::<String>("user")
+            .required::<String>("key")
+            .optional::<u16>("port")
+            .optional::<Password>("password")
+            .optional::<Password>("key_passphrase")
+            .optional::<String>("proxy_jump")
+            .optional::<String>("agent_identity")
+            .optional::<bool>("become")
+            .optional::<String>("become_user")
+            .optional::<IndexMap<String, String>>("environment")
+            .optional::<Bandwidth>("max_bandwidth")
+            .optional::<PathBuf>("upload_cache")
+            .optional::<PathBuf>("known_hosts")
+            .optional::<HostKeyChecking>("strict_host_key_checking")
+            .build()
+    }
+}
@@ -0,0 +1,35 @@
+pub mod agent;
+pub mod audit;
+pub mod change;
+pub mod connection;
+pub mod convert;
+pub mod credentials;
+pub mod diff;
+mod encoding;
+pub mod error;
+pub mod events;
+pub mod facts;
+pub mod health;
+pub mod history;
+pub mod inventory;
+pub mod manifest;
+pub mod platform;
+pub mod playbook;
+pub mod plugin;
+pub mod report;
+pub mod roles;
+pub mod runner;
+pub mod scheduler;
+pub mod schema;
+pub mod secrets;
+pub mod task;
+pub mod template;
+#[cfg(feature = "test-harness")]
+pub mod testing;
+pub mod throttle;
+pub mod vault;
+
+pub use error::AnsimpleError;
+pub use inventory::HostConfig as Inventory;
+pub use playbook::Playbook;
+pub use runner::{RunOptions, RunReport, Runner};
@@ -1,44 +1,847 @@
-use clap::Parser;
+use ansimple::agent::AgentPool;
+use ansimple::audit::AuditLog;
+use ansimple::connection::{HostKeyChecking, SessionPool, Workers};
+use ansimple::convert;
+use ansimple::credentials::{prompt_password, Credentials};
+use ansimple::error::EXIT_ERROR;
+use ansimple::events::{self, Event};
+use ansimple::history::{History, Recorder, RunRecord};
+use ansimple::inventory::Limit;
+use ansimple::manifest::UploadCache;
+use ansimple::plugin::PluginRegistry;
+use ansimple::report::{HumanReporter, JsonReporter, Reporter};
+use ansimple::roles::{self, Requirements};
+use ansimple::runner::{new_run_id, Results, RunReport, TaskCursor};
+use ansimple::schema;
+use ansimple::task::sha256_hex;
+use ansimple::throttle::{Bandwidth, Throttle};
+use ansimple::vault::{self, Vault};
+use ansimple::{secrets, AnsimpleError, Inventory, RunOptions, Runner};
+use clap::{Parser, Subcommand, ValueEnum};
 use tokio::process;
+use tokio::sync::mpsc::UnboundedReceiver;
 
-mod playbook;
-mod task;
-
-use std::path::PathBuf;
-
-use self::playbook::{HostConfig, Playbook};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short = 'c', long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short = 'c', long, global = true)]
     host_config: Option<PathBuf>,
 
-    #[arg(short = 's', long)]
+    #[arg(short = 's', long, global = true)]
     host_script: Option<PathBuf>,
 
+    #[arg(
+        short = 'i',
+        long,
+        global = true,
+        conflicts_with_all = ["host_config", "host_script"]
+    )]
+    inventory: Option<PathBuf>,
+
     #[arg(short = 't', long, value_delimiter = ',')]
     tags: Option<Vec<String>>,
 
-    playbook: PathBuf,
+    #[arg(short = 'l', long)]
+    limit: Option<Limit>,
+
+    #[arg(long)]
+    start_at_task: Option<String>,
+
+    #[arg(long)]
+    step: bool,
+
+    #[arg(short = 'k', long)]
+    ask_pass: bool,
+
+    #[arg(short = 'K', long)]
+    ask_become_pass: bool,
+
+    #[arg(long, env = "ANSIMPLE_VAULT_PASSWORD_FILE", global = true)]
+    vault_password_file: Option<PathBuf>,
+
+    #[arg(long, global = true)]
+    ask_vault_pass: bool,
+
+    #[arg(long, env = "ANSIMPLE_SECRET_ENV", value_delimiter = ',')]
+    secret_env: Option<Vec<String>>,
+
+    #[arg(long, env = "ANSIMPLE_AUDIT_LOG")]
+    audit_log: Option<String>,
+
+    #[arg(long, env = "ANSIMPLE_HISTORY_DB")]
+    history_db: Option<PathBuf>,
+
+    #[arg(long)]
+    no_history: bool,
+
+    #[arg(long, env = "ANSIMPLE_PLUGIN_DIR")]
+    plugin_dir: Option<PathBuf>,
+
+    #[arg(long, env = "ANSIMPLE_AGENT")]
+    agent: Option<PathBuf>,
+
+    #[arg(long, env = "ANSIMPLE_MAX_BANDWIDTH")]
+    max_bandwidth: Option<Bandwidth>,
+
+    #[arg(long, env = "ANSIMPLE_STRICT_HOST_KEY_CHECKING")]
+    strict_host_key_checking: Option<HostKeyChecking>,
+
+    #[arg(short = 'f', long, env = "ANSIMPLE_FORKS")]
+    forks: Option<usize>,
+
+    #[arg(long)]
+    check: bool,
+
+    #[arg(long)]
+    diff: bool,
+
+    #[arg(long)]
+    syntax_check: bool,
+
+    #[arg(long)]
+    retry_failed: bool,
+
+    #[arg(long, value_enum, default_value_t = Output::Human)]
+    output: Output,
+
+    #[arg(short = 'e', long, value_parser = extra_var)]
+    extra_vars: Vec<(String, String)>,
+
+    #[arg(required = true)]
+    playbook: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "List past runs, most recent first")]
+    History {
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    #[command(about = "Show the per-host task results of a past run")]
+    Show { run_id: String },
+    #[command(
+        about = "Continue an interrupted or failed run from the first incomplete task on each host"
+    )]
+    Resume { run_id: String },
+    #[command(about = "Convert an Ansible playbook or inventory to ansimple YAML")]
+    Convert {
+        file: PathBuf,
+        #[arg(short = 'i', long)]
+        inventory: Option<PathBuf>,
+    },
+    #[command(about = "Fetch the roles listed in a requirements file from git")]
+    Install {
+        #[arg(short = 'r', long, default_value = "requirements.yml")]
+        requirements: PathBuf,
+        #[arg(short = 'p', long)]
+        roles_path: Option<PathBuf>,
+        #[arg(long)]
+        update: bool,
+    },
+    #[command(about = "Encrypt, decrypt and edit files and values with the vault")]
+    Vault {
+        #[command(subcommand)]
+        action: VaultAction,
+    },
+    #[command(about = "Check playbooks for mistakes without connecting to any host")]
+    Lint {
+        #[arg(required = true)]
+        playbooks: Vec<PathBuf>,
+    },
+    #[command(about = "Print the JSON Schema of playbooks or host configs for editors")]
+    Schema {
+        #[arg(value_enum)]
+        format: Format,
+    },
+    #[cfg(feature = "test-harness")]
+    #[command(about = "Run playbook tests against mock hosts")]
+    Test {
+        #[arg(required = true)]
+        specs: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum VaultAction {
+    #[command(about = "Encrypt files in place")]
+    Encrypt {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "Decrypt files in place")]
+    Decrypt {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "Edit an encrypted file in $EDITOR and encrypt it again")]
+    Edit { file: PathBuf },
+    #[command(about = "Print a value, or what stdin holds, as an inline !vault value")]
+    EncryptString {
+        value: Option<String>,
+        #[arg(short = 'n', long)]
+        name: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Output {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Playbook,
+    HostConfig,
 }
 
 #[tokio::main]
 async fn main() {
-    let cli = Args::parse();
+    let mut cli = Args::parse();
+    secrets::designate(cli.secret_env.clone().unwrap_or_default());
+
+    let result = match cli.command.take() {
+        Some(Command::History { limit }) => list_runs(&cli, limit),
+        Some(Command::Show { run_id }) => show_run(&cli, &run_id),
+        Some(Command::Resume { run_id }) => run(cli, Some(run_id)).await,
+        Some(Command::Convert { file, inventory }) => convert(&file, inventory.as_deref()),
+        Some(Command::Vault { action }) => run_vault(&cli, action),
+        Some(Command::Lint { playbooks }) => lint(&cli, &playbooks).await,
+        Some(Command::Schema { format }) => print_schema(format),
+        Some(Command::Install {
+            requirements,
+            roles_path,
+            update,
+        }) => install(&requirements, roles_path, update),
+        #[cfg(feature = "test-harness")]
+        Some(Command::Test { specs }) => run_tests(&specs).await,
+        None => run(cli, None).await,
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", secrets::mask(&err.to_string()));
+        std::process::exit(err.exit_code());
+    }
+}
+
+async fn run(cli: Args, resume: Option<String>) -> Result<(), AnsimpleError> {
+    if resume.is_some() && cli.check {
+        return Err(AnsimpleError::Config(
+            "a run cannot be resumed with --check".to_owned(),
+        ));
+    }
+    if resume.is_some() && cli.retry_failed {
+        return Err(AnsimpleError::Config(
+            "a run cannot be resumed with --retry-failed".to_owned(),
+        ));
+    }
+    if resume.is_some() && cli.start_at_task.is_some() {
+        return Err(AnsimpleError::Config(
+            "a run cannot be resumed with --start-at-task".to_owned(),
+        ));
+    }
+    let resumed = resume
+        .as_deref()
+        .map(|id| resumable_run(cli.history_db.clone(), id))
+        .transpose()?;
+    let run_id = resume.clone().unwrap_or_else(new_run_id);
+    let playbook = match &resumed {
+        Some((_, run)) => PathBuf::from(&run.playbook),
+        None => cli
+            .playbook
+            .clone()
+            .ok_or_else(|| AnsimpleError::Config("no playbook specified".to_owned()))?,
+    };
+    if cli.syntax_check {
+        return lint(&cli, &[playbook]).await;
+    }
+
+    let credentials = Credentials {
+        ssh_password: cli
+            .ask_pass
+            .then(|| prompt_password("SSH password: "))
+            .transpose()?,
+        become_password: cli
+            .ask_become_pass
+            .then(|| prompt_password("BECOME password: "))
+            .transpose()?,
+    };
+
+    let vault = if cli.ask_vault_pass || cli.vault_password_file.is_some() {
+        Some(vault_password(&cli, false)?)
+    } else {
+        None
+    };
+
+    let mut inventory = load_inventory(&cli, vault.as_ref()).await?;
+    let retry_file = playbook.with_extension("retry");
+    if cli.retry_failed {
+        match fs::read_to_string(&retry_file) {
+            Ok(retried) => {
+                let retried = retried.lines().map(str::trim).collect::<Vec<_>>();
+                inventory
+                    .hosts
+                    .retain(|host| retried.contains(&host.address.as_str()));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(source) => {
+                return Err(AnsimpleError::Read {
+                    path: retry_file,
+                    source,
+                })
+            }
+        }
+    }
+
+    if let Some(limit) = &cli.limit {
+        if !inventory
+            .hosts
+            .iter()
+            .any(|host| limit.allows(&inventory, host))
+        {
+            return Err(AnsimpleError::Config(
+                "--limit allows no host of the inventory".to_owned(),
+            ));
+        }
+    }
+
+    let audit = cli
+        .audit_log
+        .map(|target| AuditLog::open(&target))
+        .transpose()?;
+
+    let inventory_sha256 = sha256_hex(&serde_json::to_vec(&inventory)?);
+    let history = match resumed {
+        Some((history, run)) => {
+            if run.inventory_sha256 != inventory_sha256 {
+                eprintln!(
+                    "warning: the inventory changed since run {} started",
+                    run.id
+                );
+            }
+            history.resume_run(&run.id)?;
+            Some(history)
+        }
+        // Check runs did nothing a resumed run could build on.
+        None if cli.no_history || cli.check => None,
+        None => open_history(cli.history_db).and_then(|history| {
+            history
+                .start_run(&run_id, &playbook, &inventory_sha256)
+                .map_err(|err| eprintln!("warning: not recording run history: {err}"))
+                .ok()
+                .map(|_| history)
+        }),
+    };
+    let checkpoint = history
+        .as_ref()
+        .map(|history| history.checkpoint(&run_id, resume.is_some()))
+        .transpose()?;
+
+    let plugins = match cli.plugin_dir.or_else(PluginRegistry::default_dir) {
+        Some(dir) => PluginRegistry::load_dir(dir)?,
+        None => PluginRegistry::default(),
+    };
+
+    let reporter: Box<dyn Reporter> = match cli.output {
+        Output::Human => Box::new(HumanReporter::new()),
+        Output::Json => Box::new(JsonReporter::new(std::io::stdout())),
+    };
+    let (events, receiver) = events::channel();
+    let printer = tokio::spawn(print_events(
+        receiver,
+        reporter,
+        history.as_ref().map(|history| history.recorder(&run_id)),
+    ));
+
+    let options = RunOptions {
+        run_id: Some(run_id.clone()),
+        tags: cli.tags,
+        limit: cli.limit,
+        cursor: TaskCursor::new(cli.start_at_task, cli.step),
+        vault,
+        credentials,
+        audit,
+        events: Some(events),
+        forks: cli.forks,
+        plugins,
+        checkpoint,
+        agent: cli.agent.map(AgentPool::new),
+        connect_to: HashMap::new(),
+        throttle: cli.max_bandwidth.map(Throttle::new).unwrap_or_default(),
+        uploads: UploadCache::default(),
+        check: cli.check,
+        diff: cli.diff,
+        extra_vars: cli
+            .extra_vars
+            .iter()
+            .map(|(key, value)| (key.clone(), tera::Value::String(value.clone())))
+            .collect(),
+        sessions: SessionPool::default(),
+        workers: Workers::default(),
+        host_key_checking: cli.strict_host_key_checking,
+        results: Results::default(),
+    };
+
+    // The run gets its own task so blocking SSH calls do not keep the signal
+    // from being noticed. Hosts that were interrupted mid-task redo that task
+    // on resume.
+    let runner = Runner::new(inventory, options);
+    let path = playbook.clone();
+    let run = tokio::spawn(async move { runner.run_file(&path).await });
+    let result = tokio::select! {
+        result = run => result.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic())),
+        _ = tokio::signal::ctrl_c() => Err(AnsimpleError::Interrupted),
+    };
+    let result = result.and_then(|report| {
+        if cli.retry_failed {
+            write_retry_file(&retry_file, &report)?;
+        }
+        report.into_result()
+    });
+    // Interrupted host workers still hold the event sender.
+    if !matches!(result, Err(AnsimpleError::Interrupted)) {
+        let _ = printer.await;
+    }
 
-    let host_config = if let Some(host_script) = cli.host_script {
+    if let Some(history) = history {
+        let status = match &result {
+            Ok(()) => "ok",
+            Err(AnsimpleError::Interrupted) => "interrupted",
+            Err(err) if err.exit_code() == EXIT_ERROR => "error",
+            Err(_) => "failed",
+        };
+        if let Err(err) = history.finish_run(&run_id, status) {
+            eprintln!("warning: failed to record run history: {err}");
+        }
+        if result.is_err() {
+            eprintln!("continue the run with `ansimple resume {run_id}`");
+        }
+    }
+
+    result
+}
+
+// The hosts of --host-config, --host-script or --inventory, whichever is given.
+async fn load_inventory(cli: &Args, vault: Option<&Vault>) -> Result<Inventory, AnsimpleError> {
+    if let Some(host_script) = &cli.host_script {
         let output = process::Command::new(host_script)
             .output()
             .await
-            .expect("failed to execute host_script");
+            .map_err(|source| AnsimpleError::Read {
+                path: host_script.clone(),
+                source,
+            })?;
+        if !output.status.success() {
+            return Err(AnsimpleError::Config(format!(
+                "host_script {} exited with {}",
+                host_script.display(),
+                output.status
+            )));
+        }
 
-        HostConfig::try_from(output.stdout).expect("failed to read host_config")
+        vault::from_str(&String::from_utf8(output.stdout)?, vault)
+    } else if let Some(inventory) = &cli.inventory {
+        let mut notes = Vec::new();
+        let inventory = Inventory::load_ansible(inventory, vault, &mut notes)?;
+        for note in &notes {
+            eprintln!("warning: {note}");
+        }
+        Ok(inventory)
     } else {
-        let host_config = cli.host_config.expect("no host_config specified");
-        HostConfig::try_from(host_config).expect("failed to read host_config")
+        let host_config = cli.host_config.as_ref().ok_or_else(|| {
+            AnsimpleError::Config(
+                "one of --host-config, --host-script or --inventory is required".to_owned(),
+            )
+        })?;
+        Inventory::load(host_config, vault)
+    }
+}
+
+// Lists the hosts `report` failed on for the next run with --retry-failed,
+// every host of the run when a play was aborted. A run that failed nowhere
+// leaves nothing to retry.
+fn write_retry_file(path: &Path, report: &RunReport) -> Result<(), AnsimpleError> {
+    let failed = report
+        .hosts
+        .iter()
+        .filter(|host| report.aborted() || report.failed(host))
+        .map(|host| format!("{host}\n"))
+        .collect::<String>();
+    if failed.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(AnsimpleError::Write {
+                path: path.to_owned(),
+                source: err,
+            }),
+            _ => Ok(()),
+        };
+    }
+
+    fs::write(path, failed).map_err(|source| AnsimpleError::Write {
+        path: path.to_owned(),
+        source,
+    })?;
+    eprintln!("the failed hosts are in {}", path.display());
+    Ok(())
+}
+
+// Prints what is wrong with each playbook. Hosts are only checked when there
+// is an inventory to check them against.
+async fn lint(cli: &Args, playbooks: &[PathBuf]) -> Result<(), AnsimpleError> {
+    let vault = if cli.ask_vault_pass || cli.vault_password_file.is_some() {
+        Some(vault_password(cli, false)?)
+    } else {
+        None
     };
+    let inventory =
+        if cli.host_config.is_some() || cli.host_script.is_some() || cli.inventory.is_some() {
+            Some(load_inventory(cli, vault.as_ref()).await?)
+        } else {
+            None
+        };
+
+    let mut found = 0;
+    for playbook in playbooks {
+        let problems = ansimple::playbook::lint(playbook, inventory.as_ref(), vault.as_ref());
+        if problems.is_empty() {
+            println!("{}: ok", playbook.display());
+        }
+        for problem in &problems {
+            println!("{problem}");
+        }
+        found += problems.len();
+    }
+
+    if found > 0 {
+        return Err(AnsimpleError::LintFailed(found));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "test-harness")]
+async fn run_tests(specs: &[PathBuf]) -> Result<(), AnsimpleError> {
+    let mut failed = 0;
+    for spec in specs {
+        let report = ansimple::testing::TestCase::load(spec)?.run().await?;
+        if report.passed() {
+            println!("PASS {}", spec.display());
+        } else {
+            failed += 1;
+            println!("FAIL {}", spec.display());
+            for failure in &report.failures {
+                println!("  {}", secrets::mask(failure));
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(AnsimpleError::TestsFailed(failed));
+    }
+    Ok(())
+}
+
+// Looks up a run that did not complete, for it to continue under its id.
+fn resumable_run(path: Option<PathBuf>, id: &str) -> Result<(History, RunRecord), AnsimpleError> {
+    let path = path
+        .or_else(History::default_path)
+        .ok_or_else(|| AnsimpleError::Config("no run history to resume from".to_owned()))?;
+    let history = History::open(path)?;
+    let (run, _) = history
+        .run(id)?
+        .ok_or_else(|| AnsimpleError::Config(format!("no run with id `{id}` in the history")))?;
+    if run.status == "ok" {
+        return Err(AnsimpleError::Config(format!(
+            "run {id} completed, there is nothing to resume"
+        )));
+    }
+
+    Ok((history, run))
+}
+
+fn open_history(path: Option<PathBuf>) -> Option<History> {
+    let path = path.or_else(History::default_path)?;
+    History::open(path)
+        .map_err(|err| eprintln!("warning: not recording run history: {err}"))
+        .ok()
+}
+
+// The vault of the password file, or of a prompted password, asked twice
+// when it is to encrypt something.
+fn vault_password(cli: &Args, confirm: bool) -> Result<Vault, AnsimpleError> {
+    if let Some(path) = &cli.vault_password_file {
+        return Vault::from_password_file(path).map_err(|source| AnsimpleError::Read {
+            path: path.clone(),
+            source,
+        });
+    }
+
+    let password = prompt_password("Vault password: ")?;
+    if confirm && prompt_password("Confirm vault password: ")?.expose() != password.expose() {
+        return Err(AnsimpleError::Config(
+            "the passwords do not match".to_owned(),
+        ));
+    }
+    Ok(Vault::new(password.expose().to_owned()))
+}
+
+fn run_vault(cli: &Args, action: VaultAction) -> Result<(), AnsimpleError> {
+    match action {
+        VaultAction::Encrypt { files } => {
+            let vault = vault_password(cli, true)?;
+            for file in &files {
+                vault::encrypt_file(file, &vault)?;
+                eprintln!("encrypted {}", file.display());
+            }
+        }
+        VaultAction::Decrypt { files } => {
+            let vault = vault_password(cli, false)?;
+            for file in &files {
+                vault::decrypt_file(file, &vault)?;
+                eprintln!("decrypted {}", file.display());
+            }
+        }
+        VaultAction::Edit { file } => edit_vault(&file, &vault_password(cli, false)?)?,
+        VaultAction::EncryptString { value, name } => {
+            let vault = vault_password(cli, true)?;
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    let mut value = String::new();
+                    std::io::stdin().read_to_string(&mut value)?;
+                    value.trim_end_matches(['\r', '\n']).to_owned()
+                }
+            };
+            let encrypted = vault.encrypt_value(&value)?;
+            match name {
+                Some(name) => print!("{name}: {encrypted}"),
+                None => print!("{encrypted}"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The decrypted file is only ever in a file of its own that only the user
+// can read, removed once the editor is done with it. The file is left alone
+// when nothing changed.
+fn edit_vault(file: &Path, vault: &Vault) -> Result<(), AnsimpleError> {
+    let encrypted = fs::read_to_string(file).map_err(|source| AnsimpleError::Read {
+        path: file.to_owned(),
+        source,
+    })?;
+    if !vault::is_encrypted(&encrypted) {
+        return Err(AnsimpleError::Config(format!(
+            "{} is not encrypted, encrypt it with `ansimple vault encrypt`",
+            file.display()
+        )));
+    }
+    let contents = String::from_utf8(vault.decrypt(&encrypted)?)?;
+    let extension = file.extension().and_then(|extension| extension.to_str());
+    let name = format!(
+        "ansimple-vault-{:016x}.{}",
+        rand::random::<u64>(),
+        extension.unwrap_or("yml")
+    );
+    let temp = std::env::temp_dir().join(name);
+    let write_error = |source| AnsimpleError::Write {
+        path: temp.clone(),
+        source,
+    };
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)
+        .and_then(|mut opened| opened.write_all(contents.as_bytes()))
+        .map_err(write_error)?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let edited = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&temp)
+        .status()
+        .map_err(AnsimpleError::from)
+        .and_then(|status| {
+            if !status.success() {
+                return Err(AnsimpleError::Config(format!(
+                    "{editor} exited with {status}, {} is unchanged",
+                    file.display()
+                )));
+            }
+            fs::read_to_string(&temp).map_err(|source| AnsimpleError::Read {
+                path: temp.clone(),
+                source,
+            })
+        });
+    let _ = fs::remove_file(&temp);
+    let edited = edited?;
+
+    if edited == contents {
+        eprintln!("{} is unchanged", file.display());
+        return Ok(());
+    }
+    fs::write(file, vault.encrypt(edited.as_bytes())?).map_err(|source| AnsimpleError::Write {
+        path: file.to_owned(),
+        source,
+    })
+}
+
+// The converted YAML goes to stdout, so it can be redirected to a file, and
+// what could not be converted to stderr.
+fn convert(file: &Path, inventory: Option<&Path>) -> Result<(), AnsimpleError> {
+    let conversion = convert::convert_file(file, inventory)?;
+    print!("{}", conversion.yaml);
+    for note in &conversion.notes {
+        eprintln!("warning: {note}");
+    }
+
+    Ok(())
+}
+
+// Roles go next to the requirements file unless told otherwise, which is
+// where playbooks beside it look for them.
+fn install(
+    requirements: &Path,
+    roles_path: Option<PathBuf>,
+    update: bool,
+) -> Result<(), AnsimpleError> {
+    let roles_path = roles_path.unwrap_or_else(|| {
+        requirements
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(roles::ROLES_DIR)
+    });
+
+    for requirement in Requirements::load(requirements)?.roles {
+        let installed = requirement.install(&roles_path, update)?;
+        let version = installed.version.as_deref().unwrap_or("default branch");
+        let commit = &installed.commit[..installed.commit.len().min(12)];
+        if installed.fetched {
+            println!("{}: installed {version} ({commit})", installed.name);
+        } else {
+            println!("{}: {version} is installed ({commit})", installed.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_schema(format: Format) -> Result<(), AnsimpleError> {
+    let schema = match format {
+        Format::Playbook => schema::playbook(),
+        Format::HostConfig => schema::host_config(),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+
+    Ok(())
+}
+
+fn list_runs(cli: &Args, limit: usize) -> Result<(), AnsimpleError> {
+    let Some(history) = open_history(cli.history_db.clone()) else {
+        return Ok(());
+    };
+
+    println!(
+        "{:<24} {:<26} {:<11} {:>9}  PLAYBOOK",
+        "RUN ID", "STARTED", "STATUS", "DURATION"
+    );
+    for run in history.runs(limit)? {
+        println!(
+            "{:<24} {:<26} {:<11} {:>9}  {}",
+            run.id,
+            run.started_at,
+            run.status,
+            format_duration(run.duration_ms),
+            run.playbook
+        );
+    }
+
+    Ok(())
+}
+
+fn show_run(cli: &Args, id: &str) -> Result<(), AnsimpleError> {
+    let Some(history) = open_history(cli.history_db.clone()) else {
+        return Ok(());
+    };
+    let (run, results) = history
+        .run(id)?
+        .ok_or_else(|| AnsimpleError::Config(format!("no run with id `{id}` in the history")))?;
+
+    println!("run:       {}", run.id);
+    println!("playbook:  {}", run.playbook);
+    println!("inventory: sha256:{}", run.inventory_sha256);
+    println!(
+        "started:   {} ({}, {})",
+        run.started_at,
+        run.status,
+        format_duration(run.duration_ms)
+    );
+    println!();
+    println!("{:<20} {:<12} {:>9}  TASK", "HOST", "STATUS", "DURATION");
+    for result in results {
+        println!(
+            "{:<20} {:<12} {:>9}  {}",
+            result.host,
+            result.status,
+            format_duration(result.duration_ms),
+            result.task
+        );
+        if let Some(error) = result.error {
+            println!("{:<20} {error}", "");
+        }
+        if let Some(diff) = result.diff {
+            println!("{diff}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn print_events(
+    mut events: UnboundedReceiver<Event>,
+    mut reporter: Box<dyn Reporter>,
+    mut recorder: Option<Recorder>,
+) {
+    while let Some(event) = events.recv().await {
+        if let Some(history) = &mut recorder {
+            if let Err(err) = history.record(&event) {
+                eprintln!("warning: failed to record run history: {err}");
+                recorder = None;
+            }
+        }
+
+        reporter.report(&event);
+    }
+}
+
+// A `key=value` of `--extra-vars`.
+fn extra_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.to_owned()))
+        }
+        _ => Err(format!("expected key=value, got `{arg}`")),
+    }
+}
 
-    let mut config = Playbook::try_from(cli.playbook).expect("failed to read config");
-    config.process(host_config, cli.tags).await;
+fn format_duration(duration_ms: Option<i64>) -> String {
+    match duration_ms {
+        Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+        None => "-".to_owned(),
+    }
 }
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::connection::{self, Connection};
+use crate::error::AnsimpleError;
+use crate::inventory::Host;
+use crate::platform::Platform;
+
+const MANIFEST_VERSION: u32 = 1;
+
+// The SHA-256 and size of the files ansimple placed on each host, so content
+// a host already has is not uploaded again. Every host keeps its manifest
+// itself, which is read on first use and written back after every upload;
+// clones share what was read.
+#[derive(Debug, Clone, Default)]
+pub struct UploadCache {
+    manifests: Arc<Mutex<HashMap<String, Manifest>>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    version: u32,
+    files: BTreeMap<PathBuf, Placed>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Placed {
+    pub sha256: String,
+    pub size: u64,
+}
+
+// Where a host already has some content.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Cached {
+    // At the destination itself.
+    Here,
+    // At another path, to copy from on the host.
+    At(PathBuf),
+    Missing,
+}
+
+impl Placed {
+    pub fn of(path: &Path) -> Result<Self, AnsimpleError> {
+        let read_error = |source| AnsimpleError::Read {
+            path: path.to_owned(),
+            source,
+        };
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut File::open(path).map_err(read_error)?, &mut hasher)
+            .map_err(read_error)?;
+
+        Ok(Self {
+            sha256: format!("{:x}", hasher.finalize()),
+            size,
+        })
+    }
+}
+
+impl UploadCache {
+    // Where `host` has `content`, preferring `dest`. Recorded files are only
+    // trusted while their size still matches, so ones changed behind the
+    // cache's back are forgotten.
+    pub fn find(
+        &self,
+        host: &Host,
+        manifest: &Path,
+        connection: &mut dyn Connection,
+        dest: &Path,
+        content: &Placed,
+    ) -> Result<Cached, AnsimpleError> {
+        let mut manifests = self.manifests.lock().expect("upload cache lock poisoned");
+        let files = &mut load(&mut manifests, host, manifest, connection)?.files;
+
+        let mut candidates: Vec<PathBuf> = files
+            .iter()
+            .filter(|(_, placed)| *placed == content)
+            .map(|(path, _)| path.clone())
+            .collect();
+        // The destination first, then the others in a stable order.
+        candidates.sort_by_key(|path| path != dest);
+
+        for path in candidates {
+            if connection.size(&path)? == Some(content.size) {
+                return Ok(if path == dest {
+                    Cached::Here
+                } else {
+                    Cached::At(path)
+                });
+            }
+            files.remove(&path);
+        }
+
+        Ok(Cached::Missing)
+    }
+
+    // Notes that `dest` on `host` now holds `content` and writes the manifest
+    // back.
+    pub fn record(
+        &self,
+        host: &Host,
+        manifest: &Path,
+        connection: &mut dyn Connection,
+        dest: &Path,
+        content: Placed,
+    ) -> Result<(), AnsimpleError> {
+        let mut manifests = self.manifests.lock().expect("upload cache lock poisoned");
+        let loaded = load(&mut manifests, host, manifest, connection)?;
+        loaded.files.insert(dest.to_owned(), content);
+        let json = serde_json::to_vec_pretty(&*loaded)?;
+
+        if let Some(parent) = manifest
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            if !connection.exists(parent)? {
+                create_dir(host.platform(), connection, parent)?;
+            }
+        }
+        // Written aside and renamed, so a broken connection never leaves a
+        // truncated manifest behind.
+        let partial = connection::partial_path(manifest);
+        connection.write(&partial, &mut json.as_slice())?;
+        connection.rename(&partial, manifest)
+    }
+}
+
+// The manifest of `host`, read from the host the first time. A host without
+// one, or with one this version cannot make sense of, starts out empty.
+fn load<'a>(
+    manifests: &'a mut HashMap<String, Manifest>,
+    host: &Host,
+    path: &Path,
+    connection: &mut dyn Connection,
+) -> Result<&'a mut Manifest, AnsimpleError> {
+    if !manifests.contains_key(&host.address) {
+        let manifest = if connection.exists(path)? {
+            serde_json::from_slice::<Manifest>(&connection.read(path)?)
+                .ok()
+                .filter(|manifest| manifest.version == MANIFEST_VERSION)
+        } else {
+            None
+        };
+        manifests.insert(
+            host.address.clone(),
+            manifest.unwrap_or(Manifest {
+                version: MANIFEST_VERSION,
+                files: BTreeMap::new(),
+            }),
+        );
+    }
+
+    Ok(manifests
+        .get_mut(&host.address)
+        .expect("manifest loaded above"))
+}
+
+fn create_dir(
+    platform: Platform,
+    connection: &mut dyn Connection,
+    path: &Path,
+) -> Result<(), AnsimpleError> {
+    let path = path.to_string_lossy();
+    let command = match platform {
+        Platform::Posix => format!("mkdir -p '{}'", path.replace('\'', "'\\''")),
+        Platform::Windows => format!(
+            "New-Item -ItemType Directory -Force -Path '{}' | Out-Null",
+            path.replace('\'', "''")
+        ),
+    };
+
+    match connection.exec(&command)? {
+        (_, _, 0) => Ok(()),
+        (_, stderr, rc) => Err(AnsimpleError::Transfer(format!(
+            "creating {path} for the upload cache exited with {rc}: {}",
+            stderr.trim()
+        ))),
+    }
+}
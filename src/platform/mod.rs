@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use crate::encoding;
+use crate::schema::{self, Generator, Schema};
+
+// The operating system family of a host, which decides how commands are run,
+// how paths are spelled and which line endings text files get. Until facts
+// are gathered, the inventory says which one a host runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    #[default]
+    Posix,
+    Windows,
+}
+
+impl Schema for Platform {
+    fn schema(_: &mut Generator) -> serde_json::Value {
+        schema::names(&["posix", "windows"])
+    }
+}
+
+impl Platform {
+    pub fn newline(self) -> &'static str {
+        match self {
+            Self::Posix => "\n",
+            Self::Windows => "\r\n",
+        }
+    }
+
+    // The command line that runs `command` in the platform's shell. POSIX
+    // hosts run it as is in the login shell. Windows ones would hand it to
+    // cmd.exe, so it is passed to PowerShell encoded, which also spares it
+    // any quoting, and exits with the status of the last native command.
+    pub fn command(self, command: &str) -> Cow<'_, str> {
+        match self {
+            Self::Posix => Cow::Borrowed(command),
+            Self::Windows => {
+                let script = format!(
+                    "$ErrorActionPreference = 'Stop'\n{command}\nif ($LASTEXITCODE) {{ exit $LASTEXITCODE }}"
+                );
+                let utf16: Vec<u8> = script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+                Cow::Owned(format!(
+                    "powershell.exe -NoProfile -NonInteractive -ExecutionPolicy Bypass -EncodedCommand {}",
+                    encoding::b64encode(utf16)
+                ))
+            }
+        }
+    }
+
+    // The path SFTP expects for `path`. Windows paths may be written as
+    // `C:\dir\file` or `C:/dir/file`, the Windows SFTP server wants
+    // `/C:/dir/file`.
+    pub fn remote_path(self, path: &Path) -> Cow<'_, Path> {
+        match self {
+            Self::Posix => Cow::Borrowed(path),
+            Self::Windows => {
+                let path = path.to_string_lossy().replace('\\', "/");
+                let bytes = path.as_bytes();
+                let path = if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic()
+                {
+                    format!("/{path}")
+                } else {
+                    path
+                };
+                Cow::Owned(PathBuf::from(path))
+            }
+        }
+    }
+
+    // Gives every line of `text` the platform's line ending. Files copied as
+    // they are never pass through here.
+    pub fn text(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Posix => Cow::Borrowed(text),
+            Self::Windows => Cow::Owned(text.replace("\r\n", "\n").replace('\n', "\r\n")),
+        }
+    }
+}
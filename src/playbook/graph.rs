@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use crate::error::AnsimpleError;
+use crate::task::Task;
+
+// Groups the tasks of a play into stages whose tasks can run at the same time
+// on a host. A task with `depends_on` waits for the tasks it names only, one
+// without it waits for every task before it, so plays that never use
+// `depends_on` run one task per stage in their original order.
+pub fn stages(tasks: &[Task]) -> Result<Vec<Vec<usize>>, AnsimpleError> {
+    let mut names: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, task) in tasks.iter().enumerate() {
+        names.entry(task.to_string()).or_default().push(index);
+    }
+
+    let dependencies = tasks
+        .iter()
+        .enumerate()
+        .map(|(index, task)| match task.depends_on() {
+            None => Ok((0..index).collect()),
+            Some(depends_on) => depends_on
+                .iter()
+                .map(|name| match names.get(name).map(Vec::as_slice) {
+                    Some([dependency]) => Ok(*dependency),
+                    Some(_) => Err(AnsimpleError::Config(format!(
+                        "task '{task}' depends on '{name}', which names more than one task"
+                    ))),
+                    None => Err(AnsimpleError::Config(format!(
+                        "task '{task}' depends on unknown task '{name}'"
+                    ))),
+                })
+                .collect(),
+        })
+        .collect::<Result<Vec<Vec<usize>>, AnsimpleError>>()?;
+
+    let mut graph = Graph {
+        tasks,
+        dependencies: &dependencies,
+        levels: vec![None; tasks.len()],
+        path: Vec::new(),
+    };
+    let mut stages: Vec<Vec<usize>> = Vec::new();
+    for index in 0..tasks.len() {
+        let level = graph.level(index)?;
+        if stages.len() <= level {
+            stages.resize_with(level + 1, Vec::new);
+        }
+        stages[level].push(index);
+    }
+
+    Ok(stages)
+}
+
+struct Graph<'a> {
+    tasks: &'a [Task],
+    dependencies: &'a [Vec<usize>],
+    levels: Vec<Option<usize>>,
+    // The tasks whose level is being worked out, to catch cycles.
+    path: Vec<usize>,
+}
+
+impl Graph<'_> {
+    // The stage a task runs in: one after the last of its dependencies.
+    fn level(&mut self, index: usize) -> Result<usize, AnsimpleError> {
+        if let Some(level) = self.levels[index] {
+            return Ok(level);
+        }
+        if let Some(start) = self.path.iter().position(|task| *task == index) {
+            let cycle = self.path[start..]
+                .iter()
+                .chain([&index])
+                .map(|task| format!("'{}'", self.tasks[*task]))
+                .collect::<Vec<String>>();
+            return Err(AnsimpleError::Config(format!(
+                "tasks depend on each other in a cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        self.path.push(index);
+        let mut level = 0;
+        for dependency in &self.dependencies[index] {
+            level = level.max(self.level(*dependency)? + 1);
+        }
+        self.path.pop();
+
+        self.levels[index] = Some(level);
+        Ok(level)
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use super::{canonical, graph, Playbook, LOCALHOST};
+use crate::error::AnsimpleError;
+use crate::inventory::HostConfig;
+use crate::task::Task;
+use crate::template;
+use crate::vault::{self, Vault};
+
+// Something that would keep a playbook from running, found without
+// connecting to any host.
+#[derive(Debug)]
+pub struct Problem {
+    pub path: PathBuf,
+    // Where the play or task it is about starts, when it could be told.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.path.display(), self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+// Checks the playbook at `path` and every playbook it includes. Play hosts
+// and delegated tasks are checked against `inventory` when there is one.
+pub fn lint(path: &Path, inventory: Option<&HostConfig>, vault: Option<&Vault>) -> Vec<Problem> {
+    let mut linter = Linter {
+        inventory,
+        vault,
+        seen: Vec::new(),
+        including: Vec::new(),
+        problems: Vec::new(),
+    };
+    linter.file(path);
+
+    linter.problems
+}
+
+struct Linter<'a> {
+    inventory: Option<&'a HostConfig>,
+    vault: Option<&'a Vault>,
+    // Files already checked, so each is checked once.
+    seen: Vec<PathBuf>,
+    // The files being checked, each included by the one before.
+    including: Vec<PathBuf>,
+    problems: Vec<Problem>,
+}
+
+impl Linter<'_> {
+    fn file(&mut self, path: &Path) {
+        let file = canonical(path);
+        if self.including.contains(&file) {
+            return self.report(path, None, "the playbook includes itself".to_owned());
+        }
+        if self.seen.contains(&file) {
+            return;
+        }
+        self.seen.push(file.clone());
+
+        self.including.push(file);
+        self.playbook(path);
+        self.including.pop();
+    }
+
+    fn playbook(&mut self, path: &Path) {
+        let plays = vault::read_to_string(path, self.vault)
+            .and_then(|source| Ok((source, Playbook::load_plays(path, self.vault)?)));
+        let (source, plays) = match plays {
+            Ok(loaded) => loaded,
+            Err(AnsimpleError::Parse { source, .. }) => {
+                let line = source.location().map(|location| location.line());
+                let message = source.to_string();
+                let message = match source.location() {
+                    Some(location) => message
+                        .strip_suffix(&format!(
+                            " at line {} column {}",
+                            location.line(),
+                            location.column()
+                        ))
+                        .map(str::to_owned)
+                        .unwrap_or(message),
+                    None => message,
+                };
+                return self.report(path, line, message);
+            }
+            Err(err) => return self.report(path, None, err.to_string()),
+        };
+
+        let mut lines = Lines::new(&source);
+        for (index, play) in plays.iter().enumerate() {
+            let line = match &play.name {
+                Some(name) => lines.find(&format!("name: {name}")),
+                None => lines.find("hosts:"),
+            };
+            let at = match &play.name {
+                Some(name) => format!("play '{name}'"),
+                None => format!("play {}", index + 1),
+            };
+            self.play(path, play, &at, line, &mut lines);
+        }
+    }
+
+    fn play(
+        &mut self,
+        path: &Path,
+        play: &Playbook,
+        at: &str,
+        line: Option<usize>,
+        lines: &mut Lines,
+    ) {
+        let mut problems = Vec::new();
+        if let Some(inventory) = self.inventory {
+            for name in &play.hosts {
+                let known = name == "all"
+                    || inventory.groups.contains_key(name)
+                    || inventory.hosts.iter().any(|host| host.address == *name);
+                if !known {
+                    problems.push(format!(
+                        "{at}: `{name}` is neither a host nor a group of the inventory"
+                    ));
+                }
+            }
+        }
+        for (key, value) in play.vars.iter().flatten() {
+            if let Err(err) = template::check_value(value, &format!("vars.{key} of {at}")) {
+                problems.push(err.to_string());
+            }
+        }
+        if let Err(err) = play.load_vars_files(self.vault) {
+            problems.push(format!("{at}: {err}"));
+        }
+        for result in [play.check_notify(), graph::stages(&play.tasks).map(|_| ())] {
+            if let Err(err) = result {
+                problems.push(format!("{at}: {err}"));
+            }
+        }
+        for problem in problems {
+            self.report(path, line, problem);
+        }
+
+        for task in play.tasks.iter().chain(&play.handlers) {
+            let line = lines.find(&task.to_string());
+            self.task(path, task, play.templates_dir.as_deref(), line);
+        }
+
+        for include in play.include.iter().flatten() {
+            if let Some(when) = &include.when {
+                let location = format!("when of include {}", include.file.display());
+                if let Err(err) = template::check_condition(when, &location) {
+                    self.report(path, line, err.to_string());
+                }
+            }
+            self.file(&include.file);
+        }
+    }
+
+    fn task(
+        &mut self,
+        path: &Path,
+        task: &Task,
+        templates_dir: Option<&Path>,
+        line: Option<usize>,
+    ) {
+        for problem in task.problems(templates_dir) {
+            self.report(path, line, problem.to_string());
+        }
+
+        let (Some(inventory), Some(delegate_to)) = (self.inventory, task.delegate_to()) else {
+            return;
+        };
+        let known = delegate_to.contains("{{")
+            || LOCALHOST.contains(&delegate_to.as_str())
+            || inventory
+                .hosts
+                .iter()
+                .any(|host| host.address == *delegate_to);
+        if !known {
+            self.report(
+                path,
+                line,
+                format!("task '{task}' is delegated to `{delegate_to}`, which is not a host of the inventory"),
+            );
+        }
+    }
+
+    fn report(&mut self, path: &Path, line: Option<usize>, message: String) {
+        self.problems.push(Problem {
+            path: path.to_owned(),
+            line,
+            message,
+        });
+    }
+}
+
+// Finds where plays and tasks start by their names, in the order they come
+// in the file. Tasks of roles are not in it and are not found.
+struct Lines<'a> {
+    lines: Vec<&'a str>,
+    next: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            lines: source.lines().collect(),
+            next: 0,
+        }
+    }
+
+    // The number of the first line from the last one found on that holds
+    // `needle`.
+    fn find(&mut self, needle: &str) -> Option<usize> {
+        let index = self.lines[self.next..]
+            .iter()
+            .position(|line| line.contains(needle))?
+            + self.next;
+        self.next = index + 1;
+
+        Some(index + 1)
+    }
+}
@@ -1,158 +1,1511 @@
 use async_recursion::async_recursion;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use tera::Context;
-use tokio::task;
+use tera::{Context, Map, Value};
+use tokio::time::{self, Duration};
 
-use std::fmt::Display;
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
-use crate::task::Task;
+use crate::audit::AuditEvent;
+use crate::connection::{ConnectionKind, ExecOptions};
+use crate::error::AnsimpleError;
+use crate::events::{Event, TaskResultEvent};
+use crate::facts::Facts;
+use crate::health::HealthCheck;
+use crate::history::Checkpoint;
+use crate::inventory::{GlobalConfig, Host, HostConfig};
+use crate::platform::Platform;
+use crate::roles;
+use crate::runner::{Results, RunOptions, RunReport};
+use crate::scheduler::{Scheduler, Serial, Strategy};
+use crate::schema::{Generator, Schema};
+use crate::secrets;
+use crate::task::{
+    include_tasks, sha256_hex, CheckMode, RegisteredResult, Task, TaskKind, TaskResult,
+    NO_LOG_MESSAGE,
+};
+use crate::template::{format_names, missing_variables, TemplateRegistry};
+use crate::vault::{self, Vault};
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
-pub struct Host {
-    pub address: String,
-    pub user: Option<String>,
-    pub key: Option<String>,
-}
+mod graph;
+mod lint;
 
-impl Display for Host {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.address)
-    }
-}
+pub use lint::{lint, Problem};
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
-pub struct HostConfig {
-    pub global_config: GlobalConfig,
-    pub hosts: Vec<Host>,
-}
+// Delegated to without being in the inventory, they are this machine.
+const LOCALHOST: &[&str] = &["localhost", "127.0.0.1", "::1"];
 
-impl TryFrom<PathBuf> for HostConfig {
-    type Error = serde_yaml::Error;
+// Looked for next to the playbook when a play does not name its own.
+const TEMPLATES_DIR: &str = "templates";
 
-    fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
-        let contents = read_file(value).expect("failed to read file");
-        Self::try_from(contents)
-    }
+// How fact gathering shows up among the tasks.
+const GATHER_FACTS: &str = "gather facts";
+
+// The same file by however many paths it is reached.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
 }
 
-impl TryFrom<String> for HostConfig {
-    type Error = serde_yaml::Error;
+#[derive(Debug, Clone, Default)]
+pub struct HostVars(Arc<RwLock<HashMap<String, Map<String, Value>>>>);
 
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        serde_yaml::from_str(&value)
-    }
-}
+impl HostVars {
+    pub fn new(host_config: &HostConfig) -> Self {
+        let vars = host_config
+            .hosts
+            .iter()
+            .map(|host| {
+                let mut vars = Map::new();
+                vars.insert("address".to_owned(), Value::String(host.address.clone()));
+                if let Some(user) = &host.user {
+                    vars.insert("user".to_owned(), Value::String(user.clone()));
+                }
+                vars.extend(host_config.vars(host));
 
-impl TryFrom<Vec<u8>> for HostConfig {
-    type Error = serde_yaml::Error;
+                (host.address.clone(), vars)
+            })
+            .collect();
 
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        serde_yaml::from_str(std::str::from_utf8(&value).expect("failed to read utf8"))
+        Self(Arc::new(RwLock::new(vars)))
     }
-}
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
-pub struct GlobalConfig {
-    pub user: String,
-    pub key: String,
+    pub fn insert(&self, host: &str, key: &str, value: Value) {
+        let mut hostvars = self.0.write().expect("hostvars lock poisoned");
+        hostvars
+            .entry(host.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value);
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, Map<String, Value>> {
+        self.0.read().expect("hostvars lock poisoned").clone()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Include {
-    #[serde(flatten)]
+    // Relative to the including playbook.
     file: PathBuf,
+    // Given to every task of the included plays.
     tags: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     when: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Playbook {
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
     include: Option<Vec<Include>>,
+    // Installed under `roles/` next to the playbook, their tasks run first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<String>,
     hosts: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vars: Option<IndexMap<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vars_files: Option<Vec<PathBuf>>,
+    // What templates include, import and extend, `templates/` next to the
+    // playbook when there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    templates_dir: Option<PathBuf>,
+    // Connection defaults of the play, over the inventory's global config.
+    #[serde(skip_serializing_if = "Option::is_none")]
     local_config: Option<GlobalConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(rename = "become", skip_serializing_if = "Option::is_none")]
+    become_root: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    become_user: Option<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    environment: IndexMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    required_vars: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict_vars: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial: Option<Serial>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<Strategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    any_errors_fatal: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_fail_percentage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check: Option<HealthCheck>,
+    // Facts are gathered on each host before its first task, unless false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gather_facts: Option<bool>,
     tasks: Vec<Task>,
+    // Run at the end of the play on the hosts where a task notified them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    handlers: Vec<Task>,
+    // The playbook files the play was included through, its own last, so a
+    // file cannot include itself.
+    #[serde(skip)]
+    including: Vec<PathBuf>,
+}
+
+impl Schema for Include {
+    fn name() -> Option<&'static str> {
+        Some("Include")
+    }
+
+    fn schema(generator: &mut Generator) -> Value {
+        generator
+            .object()
+            .required::<PathBuf>("file")
+            .optional::<Vec<String>>("tags")
+            .optional::<String>("when")
+            .build()
+    }
+}
+
+impl Schema for Playbook {
+    fn name() -> Option<&'static str> {
+        Some("Play")
+    }
+
+    fn schema(generator: &mut Generator) -> Value {
+        generator
+            .object()
+            .optional::<String>("name")
+            .optional::<Vec<Include>>("include")
+            .optional::<Vec<String>>("roles")
+            .required::<Vec<String>>("hosts")
+            .optional::<IndexMap<String, Value>>("vars")
+            .optional::<Vec<PathBuf>>("vars_files")
+            .optional::<PathBuf>("templates_dir")
+            .optional::<GlobalConfig>("local_config")
+            .optional::<String>("remote_user")
+            .optional::<u16>("port")
+            .optional::<bool>("become")
+            .optional::<String>("become_user")
+            .optional::<IndexMap<String, String>>("environment")
+            .optional::<Vec<String>>("required_vars")
+            .optional::<bool>("strict_vars")
+            .optional::<Serial>("serial")
+            .optional::<Strategy>("strategy")
+            .optional::<bool>("any_errors_fatal")
+            .optional::<f64>("max_fail_percentage")
+            .optional::<HealthCheck>("health_check")
+            .optional::<bool>("gather_facts")
+            .required::<Vec<Task>>("tasks")
+            .optional::<Vec<Task>>("handlers")
+            .build()
+    }
 }
 
 impl Playbook {
+    pub fn load<P: AsRef<Path>>(path: P, vault: Option<&Vault>) -> Result<Self, AnsimpleError> {
+        let path = path.as_ref();
+        let mut play: Self = vault::load(path, vault)?;
+        play.read_from(path, vault)?;
+
+        Ok(play)
+    }
+
+    // The plays of a playbook file, which is either a single play or a list
+    // of plays to run in order.
+    pub fn load_plays<P: AsRef<Path>>(
+        path: P,
+        vault: Option<&Vault>,
+    ) -> Result<Vec<Self>, AnsimpleError> {
+        let path = path.as_ref();
+        let contents = vault::read_to_string(path, vault)?;
+        let parse_error = |err| match err {
+            AnsimpleError::Yaml(source) => AnsimpleError::Parse {
+                path: path.to_owned(),
+                source,
+            },
+            err => err,
+        };
+
+        let document: serde_yaml::Value =
+            serde_yaml::from_str(&contents).map_err(|err| parse_error(err.into()))?;
+        let mut plays: Vec<Self> = if document.is_sequence() {
+            vault::from_str(&contents, vault).map_err(parse_error)?
+        } else {
+            vault::from_str(&contents, vault)
+                .map(|play| vec![play])
+                .map_err(parse_error)?
+        };
+        for play in &mut plays {
+            play.read_from(path, vault)?;
+        }
+
+        Ok(plays)
+    }
+
+    // Completes a play read from the playbook file at `path` with what is
+    // found relative to it.
+    fn read_from(&mut self, path: &Path, vault: Option<&Vault>) -> Result<(), AnsimpleError> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for include in self.include.iter_mut().flatten() {
+            include.file = dir.join(&include.file);
+        }
+        self.tasks = include_tasks(std::mem::take(&mut self.tasks), path, vault)?;
+        self.handlers = include_tasks(std::mem::take(&mut self.handlers), path, vault)?;
+        self.add_roles(path, vault)?;
+        self.find_templates_dir(path);
+        self.including = vec![canonical(path)];
+
+        Ok(())
+    }
+
+    // Tags every task of the play has, and those of the plays it includes.
+    fn add_tags(&mut self, tags: &[String]) {
+        for task in self.tasks.iter_mut().chain(&mut self.handlers) {
+            task.add_tags(tags);
+        }
+        for include in self.include.iter_mut().flatten() {
+            include
+                .tags
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(tags);
+        }
+    }
+
+    // Puts the tasks of the play's roles before its own, in the order listed,
+    // and the roles' defaults under its vars.
+    fn add_roles(&mut self, path: &Path, vault: Option<&Vault>) -> Result<(), AnsimpleError> {
+        if self.roles.is_empty() {
+            return Ok(());
+        }
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut tasks = Vec::new();
+        let mut handlers = Vec::new();
+        let mut vars = IndexMap::new();
+        for name in std::mem::take(&mut self.roles) {
+            let role = roles::load(dir, &name, vault)?;
+            tasks.extend(role.tasks);
+            handlers.extend(role.handlers);
+            vars.extend(role.defaults);
+        }
+        tasks.append(&mut self.tasks);
+        self.tasks = tasks;
+        handlers.append(&mut self.handlers);
+        self.handlers = handlers;
+        vars.extend(self.vars.take().unwrap_or_default());
+        self.vars = Some(vars);
+
+        Ok(())
+    }
+
+    fn find_templates_dir(&mut self, path: &Path) {
+        let dir = path.parent().unwrap_or(Path::new("")).join(TEMPLATES_DIR);
+        if self.templates_dir.is_none() && dir.is_dir() {
+            self.templates_dir = Some(dir);
+        }
+    }
+
+    // Runs `plays` one after another. Hosts that failed sit out the plays
+    // after, and an aborted play ends them.
+    pub async fn process_plays(
+        plays: &mut [Playbook],
+        mut host_config: HostConfig,
+        options: RunOptions,
+    ) -> Result<RunReport, AnsimpleError> {
+        let mut report = RunReport::default();
+        for play in plays {
+            let played = play.process(host_config.clone(), options.clone()).await?;
+            host_config
+                .hosts
+                .retain(|host| !played.failed(&host.address));
+            report.merge(played);
+            if report.aborted() {
+                break;
+            }
+        }
+
+        Ok(report)
+    }
+
     #[async_recursion]
-    pub async fn process(&mut self, host_config: HostConfig, specified_tags: Option<Vec<String>>) {
+    pub async fn process(
+        &mut self,
+        mut host_config: HostConfig,
+        mut options: RunOptions,
+    ) -> Result<RunReport, AnsimpleError> {
+        let mut report = RunReport::default();
+        let file_vars = self.load_vars_files(options.vault.as_ref())?;
         if let Some(included_playbooks) = &self.include {
+            // Includes are decided before any host is, by the play's vars.
+            let templates = TemplateRegistry::new([], None, self.strict_vars.unwrap_or(false))?;
+            let mut context = Context::new();
+            self.insert_vars(&mut context, &file_vars, &options.extra_vars, &templates)?;
             for include in included_playbooks {
-                // eval when
-                let mut included_config =
-                    Playbook::try_from(include.file.clone()).expect("failed to read playbook");
-                included_config
-                    .process(host_config.clone(), specified_tags.clone())
-                    .await;
+                let location = format!("when of include {}", include.file.display());
+                if let Some(when) = &include.when {
+                    if !templates.evaluate(when, &context, &location)? {
+                        continue;
+                    }
+                }
+                let file = canonical(&include.file);
+                if self.including.contains(&file) {
+                    return Err(AnsimpleError::Config(format!(
+                        "{} includes itself through {}",
+                        include.file.display(),
+                        self.including
+                            .last()
+                            .map_or(include.file.display(), |path| path.display())
+                    )));
+                }
+                let mut plays = Playbook::load_plays(&include.file, options.vault.as_ref())?;
+                for play in &mut plays {
+                    play.including =
+                        [self.including.as_slice(), std::slice::from_ref(&file)].concat();
+                    if let Some(tags) = &include.tags {
+                        play.add_tags(tags);
+                    }
+                }
+                let included =
+                    Self::process_plays(&mut plays, host_config.clone(), options.clone()).await?;
+                host_config
+                    .hosts
+                    .retain(|host| !included.failed(&host.address));
+                report.merge(included);
+                if report.aborted() {
+                    return Ok(report);
+                }
             }
         }
 
+        // Plays before the one the run starts in are passed over, but still
+        // counted in the run history.
+        let play_number = options.checkpoint.as_ref().map(Checkpoint::next_play);
+        let Some(start) = options.cursor.start(&self.tasks).await else {
+            return Ok(report);
+        };
+
+        let global_config = self.connection_config(&host_config.global_config);
+        if let Some(bandwidth) = self
+            .local_config
+            .as_ref()
+            .and_then(|local| local.max_bandwidth)
+        {
+            options.throttle = options.throttle.and(bandwidth);
+        }
         let matching_hosts = host_config
-            .hosts
-            .iter()
-            .filter(|host| self.hosts.contains(&host.address))
+            .matching(&self.hosts)
+            .filter(|host| {
+                options
+                    .limit
+                    .as_ref()
+                    .is_none_or(|limit| limit.allows(&host_config, host))
+            })
             .collect::<Vec<&Host>>();
+        let played = matching_hosts
+            .iter()
+            .map(|host| host.address.clone())
+            .collect::<Vec<String>>();
 
-        let context = Context::new();
-        // gatcher facts
+        if let Some(audit) = &options.audit {
+            audit.record(AuditEvent::PlayStarted {
+                hosts: matching_hosts
+                    .iter()
+                    .map(|host| host.address.as_str())
+                    .collect(),
+            });
+        }
+        options.emit(Event::PlayStarted {
+            name: self.name.clone(),
+            hosts: matching_hosts
+                .iter()
+                .map(|host| host.address.clone())
+                .collect(),
+        });
 
-        let task_handles = matching_hosts
+        let hostvars = HostVars::new(&host_config);
+        let templates = TemplateRegistry::new(
+            self.tasks
+                .iter()
+                .chain(&self.handlers)
+                .filter_map(|task| task.template_source()),
+            self.templates_dir.as_deref(),
+            self.strict_vars.unwrap_or(false),
+        )?;
+        let stages = graph::stages(&self.tasks)?;
+        self.check_notify()?;
+
+        let host_contexts = matching_hosts
             .into_iter()
             .map(|host| {
-                let mut context = context.clone();
-                let playbook = self.clone();
-                let global_config = host_config.global_config.clone();
-                let local_config = playbook.local_config.clone();
-                let host = host.clone();
-                let specified_tags = specified_tags.clone();
+                let mut context = self.host_context(
+                    host,
+                    &host_config,
+                    &hostvars,
+                    &file_vars,
+                    &options.extra_vars,
+                    &templates,
+                )?;
+                let next_stage = self.resume(
+                    host,
+                    play_number,
+                    &stages,
+                    &options,
+                    &mut context,
+                    &hostvars,
+                )?;
+                Ok((host, context, next_stage))
+            })
+            .collect::<Result<Vec<(&Host, Context, usize)>, AnsimpleError>>()?;
 
-                task::spawn(async move {
-                    for mut task in playbook.tasks {
-                        if !task.when(&context) {
-                            continue;
+        if let Some(required_vars) = &self.required_vars {
+            let missing = host_contexts
+                .iter()
+                .filter_map(|(host, context, _)| {
+                    let missing = missing_variables(context, required_vars);
+                    (!missing.is_empty()).then(|| format!("  {host}: {}", format_names(&missing)))
+                })
+                .collect::<Vec<String>>();
+
+            if !missing.is_empty() {
+                return Err(AnsimpleError::MissingVariables(missing.join("\n")));
+            }
+        }
+
+        let scheduler = Scheduler {
+            forks: options.forks,
+            serial: self.serial,
+            strategy: self.strategy.unwrap_or_default(),
+            any_errors_fatal: self.any_errors_fatal.unwrap_or(false),
+            max_fail_percentage: self.max_fail_percentage,
+        };
+        let stage_count = stages.len();
+        let events = options.events.clone();
+        options.results = Results::default();
+        let results = options.results.clone();
+        let play = Arc::new(PlayRun {
+            tasks: self.tasks.clone(),
+            handlers: self.handlers.clone(),
+            stages,
+            templates,
+            options,
+            hostvars,
+            global_config,
+            inventory: host_config.hosts.clone(),
+            health_check: self.health_check.clone(),
+            gather_facts: self.gather_facts.unwrap_or(true),
+            play_number,
+            start,
+            answers: tokio::sync::Mutex::new(HashMap::new()),
+        });
+        let hosts = host_contexts
+            .into_iter()
+            .map(|(host, context, next_stage)| HostRun {
+                host: host.clone(),
+                context,
+                next_stage,
+                gathered: false,
+                notified: Vec::new(),
+            })
+            .collect::<Vec<HostRun>>();
+
+        let checked_play = play.clone();
+        let failures = scheduler
+            .run(
+                hosts,
+                stage_count,
+                move |mut state, index| {
+                    let play = play.clone();
+                    Box::pin(async move {
+                        // Completed before the run was interrupted.
+                        if index < state.next_stage {
+                            return (state, Ok(()));
                         }
 
-                        if let Some(specified_tags) = &specified_tags {
-                            if let Some(task_tags) = &task.tags() {
-                                if task_tags.iter().all(|tag| !specified_tags.contains(tag)) {
-                                    continue;
-                                }
-                            } else {
-                                continue;
+                        if play.gather_facts && !state.gathered {
+                            let gathered =
+                                play.gather_facts(&mut state.host, &mut state.context).await;
+                            if gathered.is_err() {
+                                return (state, gathered);
                             }
+                            state.gathered = true;
                         }
 
-                        let result = task
-                            .kind()
-                            .execute_on_host(&host, &context, &global_config, local_config.as_ref())
-                            .await
-                            .expect("failed to execute task");
-
-                        if let Some(register_key) = task.register() {
-                            context.insert(register_key.to_owned(), &result.register_value());
+                        let mut result = play
+                            .run_stage(&state.host, &mut state.context, index, &mut state.notified)
+                            .await;
+                        if result.is_ok() {
+                            play.save_progress(&state.host, index + 1);
                         }
-                    }
+                        if result.is_ok() && index + 1 == play.stages.len() {
+                            result = play
+                                .run_handlers(&state.host, &mut state.context, &mut state.notified)
+                                .await;
+                        }
+                        (state, result)
+                    })
+                },
+                move |batch| Box::pin(checked_play.clone().check_health(batch)),
+            )
+            .await;
+
+        report.merge(RunReport {
+            hosts: played,
+            failures,
+            results: results.take(),
+        });
+        if let Some(events) = events {
+            events.recap();
+        }
+
+        Ok(report)
+    }
+
+    fn host_context(
+        &self,
+        host: &Host,
+        host_config: &HostConfig,
+        hostvars: &HostVars,
+        file_vars: &IndexMap<String, Value>,
+        extra_vars: &IndexMap<String, Value>,
+        templates: &TemplateRegistry,
+    ) -> Result<Context, AnsimpleError> {
+        let mut host_value = tera::to_value(host)?;
+        if let Value::Object(fields) = &mut host_value {
+            if host.user.is_none() {
+                let user = self.connection_config(&host_config.global_config).user;
+                fields.insert("user".to_owned(), Value::String(user));
+            }
+        }
+
+        let mut context = Context::new();
+        context.insert("host", &host_value);
+        context.insert("hostvars", &hostvars.snapshot());
+        for (key, val) in host_config.vars(host) {
+            context.insert(key, &val);
+        }
+
+        self.insert_vars(&mut context, file_vars, extra_vars, templates)?;
+
+        Ok(context)
+    }
+
+    // Every name in a `notify` must be one of the play's handlers.
+    fn check_notify(&self) -> Result<(), AnsimpleError> {
+        for task in self.tasks.iter().chain(&self.handlers) {
+            let unknown = task.notify().iter().find(|name| {
+                !self
+                    .handlers
+                    .iter()
+                    .any(|handler| handler.to_string() == **name)
+            });
+            if let Some(name) = unknown {
+                return Err(AnsimpleError::Config(format!(
+                    "task '{task}' notifies unknown handler '{name}'"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The connection defaults the play's hosts start from: the inventory's,
+    // then those of `local_config`, then the play's own keywords.
+    fn connection_config(&self, global_config: &GlobalConfig) -> GlobalConfig {
+        let mut config = global_config.clone();
+        if let Some(local) = &self.local_config {
+            config.user = local.user.clone();
+            config.key = local.key.clone();
+            config.port = local.port.or(config.port);
+            config.password = local.password.clone().or(config.password);
+            config.key_passphrase = local.key_passphrase.clone().or(config.key_passphrase);
+            config.proxy_jump = local.proxy_jump.clone().or(config.proxy_jump);
+            config.agent_identity = local.agent_identity.clone().or(config.agent_identity);
+            config.become_root = local.become_root.or(config.become_root);
+            config.become_user = local.become_user.clone().or(config.become_user);
+            config.environment.extend(local.environment.clone());
+            config.upload_cache = local.upload_cache.clone().or(config.upload_cache);
+            config.known_hosts = local.known_hosts.clone().or(config.known_hosts);
+            config.strict_host_key_checking = local
+                .strict_host_key_checking
+                .or(config.strict_host_key_checking);
+        }
+
+        if let Some(user) = &self.remote_user {
+            config.user = user.clone();
+        }
+        config.port = self.port.or(config.port);
+        config.become_root = self.become_root.or(config.become_root);
+        config.become_user = self.become_user.clone().or(config.become_user);
+        config.environment.extend(self.environment.clone());
+
+        config
+    }
+
+    // Renders the play's vars, then those of its vars files, into `context`,
+    // and puts the run's extra vars over them.
+    fn insert_vars(
+        &self,
+        context: &mut Context,
+        file_vars: &IndexMap<String, Value>,
+        extra_vars: &IndexMap<String, Value>,
+        templates: &TemplateRegistry,
+    ) -> Result<(), AnsimpleError> {
+        if let Some(vars) = &self.vars {
+            for (key, val) in vars {
+                let val = templates.render_value(val, context, &format!("vars.{key}"))?;
+                context.insert(key, &val);
+            }
+        }
+
+        for (key, val) in file_vars {
+            let val = templates.render_value(val, context, &format!("vars_files.{key}"))?;
+            context.insert(key, &val);
+        }
+
+        for (key, val) in extra_vars {
+            context.insert(key, val);
+        }
+
+        Ok(())
+    }
+
+    // Restores what `host` registered in an earlier attempt of the run and
+    // returns the first stage it has not completed.
+    fn resume(
+        &self,
+        host: &Host,
+        play_number: Option<usize>,
+        stages: &[Vec<usize>],
+        options: &RunOptions,
+        context: &mut Context,
+        hostvars: &HostVars,
+    ) -> Result<usize, AnsimpleError> {
+        let Some(progress) = options
+            .checkpoint
+            .as_ref()
+            .zip(play_number)
+            .and_then(|(checkpoint, play)| checkpoint.progress(play, &host.address))
+        else {
+            return Ok(0);
+        };
+
+        let done = stages.get(..progress.next_task);
+        let done = done
+            .map(|done| done_sha256(&self.tasks, done))
+            .transpose()?;
+        if done.as_ref() != Some(&progress.done_sha256) {
+            return Err(AnsimpleError::Config(format!(
+                "{host}: the tasks it completed have changed since the run was interrupted"
+            )));
+        }
+
+        for (name, value) in &progress.registered {
+            if !options.extra_vars.contains_key(name) {
+                context.insert(name, value);
+            }
+            hostvars.insert(&host.address, name, value.clone());
+        }
+
+        Ok(progress.next_task)
+    }
+
+    fn load_vars_files(
+        &self,
+        vault: Option<&Vault>,
+    ) -> Result<IndexMap<String, Value>, AnsimpleError> {
+        let mut vars = IndexMap::new();
+        for file in self.vars_files.iter().flatten() {
+            let file_vars: IndexMap<String, Value> = vault::load_vars(file, vault)?;
+            vars.extend(file_vars);
+        }
+
+        Ok(vars)
+    }
+}
+
+struct HostRun {
+    host: Host,
+    context: Context,
+    next_stage: usize,
+    gathered: bool,
+    // The handlers tasks on the host notified, by name.
+    notified: Vec<String>,
+}
+
+struct PlayRun {
+    tasks: Vec<Task>,
+    handlers: Vec<Task>,
+    stages: Vec<Vec<usize>>,
+    templates: TemplateRegistry,
+    options: RunOptions,
+    hostvars: HostVars,
+    // The inventory's, with the play's defaults applied.
+    global_config: GlobalConfig,
+    // Every host of the inventory, for tasks delegated to them.
+    inventory: Vec<Host>,
+    health_check: Option<HealthCheck>,
+    gather_facts: bool,
+    play_number: Option<usize>,
+    // The first task to run, the ones before are skipped.
+    start: usize,
+    // What each task was answered in a `--step` run, by index, so every
+    // host gets the same answer.
+    answers: tokio::sync::Mutex<HashMap<usize, bool>>,
+}
+
+impl PlayRun {
+    // How the task's commands run on `host`: the host overrides the play and
+    // the task the host. Environment values may be templates.
+    fn exec_options(
+        &self,
+        host: &Host,
+        task: &Task,
+        context: &Context,
+    ) -> Result<ExecOptions, AnsimpleError> {
+        let mut environment = IndexMap::new();
+        for (name, value) in self
+            .global_config
+            .environment
+            .iter()
+            .chain(&host.environment)
+            .chain(task.environment())
+        {
+            let location = format!("environment.{name}");
+            let value =
+                self.templates
+                    .render_value(&Value::String(value.clone()), context, &location)?;
+            environment.insert(name.clone(), value.as_str().unwrap_or_default().to_owned());
+        }
+
+        let become_root = task
+            .become_root()
+            .or(host.become_root)
+            .or(self.global_config.become_root)
+            .unwrap_or(false);
+        Ok(ExecOptions {
+            become_root,
+            become_user: task
+                .become_user()
+                .or(host.become_user.as_ref())
+                .or(self.global_config.become_user.as_ref())
+                .filter(|_| become_root)
+                .cloned(),
+            become_password: self
+                .options
+                .credentials
+                .become_password
+                .clone()
+                .filter(|_| become_root),
+            environment,
+        })
+    }
+
+    // Runs the health check on the hosts of a finished batch side by side.
+    async fn check_health(self: Arc<Self>, batch: Vec<HostRun>) -> Vec<AnsimpleError> {
+        if self.health_check.is_none() {
+            return Vec::new();
+        }
+
+        let handles = batch
+            .into_iter()
+            .map(|state| {
+                let play = self.clone();
+                tokio::spawn(async move {
+                    let check = play.health_check.as_ref().expect("health check set");
+                    let (attempts, result) = check
+                        .run(
+                            &state.host,
+                            &state.context,
+                            &play.templates,
+                            &play.options,
+                            &play.global_config,
+                        )
+                        .await;
+                    play.options.emit(Event::HealthCheck {
+                        host: state.host.address.clone(),
+                        attempts,
+                        error: result.as_ref().err().map(|err| {
+                            let message = match err {
+                                AnsimpleError::HealthCheck { message, .. } => message.clone(),
+                                err => err.to_string(),
+                            };
+                            secrets::mask(&message).into_owned()
+                        }),
+                    });
+                    result
                 })
             })
             .collect::<Vec<_>>();
 
-        for handle in task_handles {
-            handle.await.unwrap();
+        let mut failures = Vec::new();
+        for handle in handles {
+            match handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => failures.push(err),
+                Err(err) => failures.push(err.into()),
+            }
+        }
+
+        failures
+    }
+
+    fn save_progress(&self, host: &Host, next_stage: usize) {
+        let Some((checkpoint, play)) = self.options.checkpoint.as_ref().zip(self.play_number)
+        else {
+            return;
+        };
+
+        let saved = done_sha256(&self.tasks, &self.stages[..next_stage])
+            .and_then(|sha256| checkpoint.save(play, &host.address, next_stage, &sha256));
+        if let Err(err) = saved {
+            self.options.emit(Event::Warning {
+                host: host.address.clone(),
+                message: format!("failed to save checkpoint: {err}"),
+            });
+        }
+    }
+
+    // Runs the tasks of a stage side by side, each with its own copy of the
+    // host's context, and merges what they registered back afterwards.
+    async fn run_stage(
+        self: &Arc<Self>,
+        host: &Host,
+        context: &mut Context,
+        stage: usize,
+        notified: &mut Vec<String>,
+    ) -> Result<(), AnsimpleError> {
+        if let [index] = self.stages[stage][..] {
+            let task = &self.tasks[index];
+            if !self.wanted(host, index, context).await? {
+                self.skipped(host, task);
+            } else if self.run_task(host, context, task).await? {
+                notify(notified, task);
+            }
+            return Ok(());
+        }
+
+        let mut wanted = Vec::new();
+        for &index in &self.stages[stage] {
+            if self.wanted(host, index, context).await? {
+                wanted.push(index);
+            } else {
+                self.skipped(host, &self.tasks[index]);
+            }
+        }
+        let handles = wanted
+            .into_iter()
+            .map(|index| {
+                let play = self.clone();
+                let host = host.clone();
+                let mut context = context.clone();
+                let handle = tokio::spawn(async move {
+                    let result = play.run_task(&host, &mut context, &play.tasks[index]).await;
+                    (context, result)
+                });
+                (index, handle)
+            })
+            .collect::<Vec<_>>();
+
+        let mut failure = None;
+        for (index, handle) in handles {
+            let result = match handle.await {
+                Ok((task_context, Ok(changed))) => {
+                    let task = &self.tasks[index];
+                    let registered = task
+                        .register()
+                        .and_then(|key| Some((key, task_context.get(key)?)));
+                    if let Some((key, value)) = registered {
+                        context.insert(key.to_owned(), value);
+                    }
+                    if changed {
+                        notify(notified, task);
+                    }
+                    Ok(())
+                }
+                Ok((_, Err(err))) => Err(err),
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                failure.get_or_insert(err);
+            }
+        }
+
+        failure.map_or(Ok(()), Err)
+    }
+
+    // Whether the task at `index` runs on `host` by where the run starts, its
+    // tags and, in a `--step` run, by what it was answered. Only tasks that
+    // would run are asked about.
+    async fn wanted(
+        &self,
+        host: &Host,
+        index: usize,
+        context: &Context,
+    ) -> Result<bool, AnsimpleError> {
+        let task = &self.tasks[index];
+        if index < self.start || !self.tagged(task) {
+            return Ok(false);
+        }
+        if !self.options.cursor.stepping().await {
+            return Ok(true);
+        }
+        // The condition of a loop holds for each item on its own, and one
+        // that cannot be evaluated fails the task when it runs.
+        if !task.loops() {
+            let scoped = self.task_context(task, context).ok().flatten();
+            if let Ok(false) = task.when(scoped.as_ref().unwrap_or(context), &self.templates) {
+                return Ok(false);
+            }
+        }
+
+        let mut answers = self.answers.lock().await;
+        if let Some(wanted) = answers.get(&index) {
+            return Ok(*wanted);
+        }
+        let name = secrets::mask(&task.to_string()).into_owned();
+        let wanted = match self.options.cursor.confirm(&name).await {
+            Ok(wanted) => wanted,
+            Err(err) => return Err(self.failed(host, name, err, task.no_log())),
+        };
+        answers.insert(index, wanted);
+
+        Ok(wanted)
+    }
+
+    // Whether `task` has one of the tags the run is limited to.
+    fn tagged(&self, task: &Task) -> bool {
+        self.options.tags.as_ref().is_none_or(|specified_tags| {
+            task.tags()
+                .is_some_and(|tags| tags.iter().any(|tag| specified_tags.contains(tag)))
+        })
+    }
+
+    // Reports a task that failed on `host` and returns its error.
+    fn failed(&self, host: &Host, name: String, err: AnsimpleError, no_log: bool) -> AnsimpleError {
+        let options = &self.options;
+        if let Some(audit) = &options.audit {
+            audit.record(AuditEvent::TaskFinished {
+                host: &host.address,
+                task: &name,
+                status: "failed",
+            });
+        }
+
+        let err = if no_log && !err.is_unreachable() {
+            AnsimpleError::Config(NO_LOG_MESSAGE.to_owned())
+        } else {
+            err
+        };
+        let error = secrets::mask(&err.to_string()).into_owned();
+        options.emit(if err.is_unreachable() {
+            Event::HostUnreachable {
+                host: host.address.clone(),
+                task: name.clone(),
+                error,
+            }
+        } else {
+            Event::TaskResult(TaskResultEvent {
+                host: host.address.clone(),
+                task: name.clone(),
+                status: "failed".to_owned(),
+                error: Some(error),
+                result: None,
+                diff: None,
+            })
+        });
+
+        AnsimpleError::Task {
+            host: host.address.clone(),
+            task: name,
+            source: Box::new(err),
         }
     }
+
+    // What the passes of a task that failed did before it, which `failed`
+    // does not record.
+    fn record_effects(
+        &self,
+        host: &Host,
+        results: &[TaskResult],
+        no_log: bool,
+        options: &RunOptions,
+    ) {
+        if let Some(audit) = options.audit.as_ref().filter(|_| !options.check) {
+            for result in results {
+                audit.record_effects(host, result, no_log);
+            }
+        }
+    }
+
+    // Gathers the facts of `host` into its context and its hostvars, shown as
+    // a task of its own. A host they find to run Windows is handled as one
+    // for the rest of the play.
+    async fn gather_facts(
+        &self,
+        host: &mut Host,
+        context: &mut Context,
+    ) -> Result<(), AnsimpleError> {
+        let name = GATHER_FACTS.to_owned();
+        self.options.emit(Event::TaskStarted {
+            host: host.address.clone(),
+            task: name.clone(),
+        });
+        let facts = match Facts::gather(host, &self.options, &self.global_config).await {
+            Ok(facts) => facts,
+            Err(err) => return Err(self.failed(host, name, err, false)),
+        };
+        if facts.system == "Windows" && host.platform.is_none() {
+            host.platform = Some(Platform::Windows);
+            if let Some(Value::Object(mut fields)) = context.get("host").cloned() {
+                fields.insert("platform".to_owned(), tera::to_value(host.platform())?);
+                context.insert("host", &fields);
+            }
+        }
+        let facts = tera::to_value(facts)?;
+
+        context.insert("facts", &facts);
+        self.hostvars.insert(&host.address, "facts", facts);
+        self.options.emit(Event::TaskResult(TaskResultEvent {
+            host: host.address.clone(),
+            task: name,
+            status: "unchanged".to_owned(),
+            error: None,
+            result: None,
+            diff: None,
+        }));
+
+        Ok(())
+    }
+
+    // Runs the handlers notified on `host`, in the order the play lists them.
+    // Handlers may notify the ones after them.
+    async fn run_handlers(
+        &self,
+        host: &Host,
+        context: &mut Context,
+        notified: &mut Vec<String>,
+    ) -> Result<(), AnsimpleError> {
+        for handler in &self.handlers {
+            if notified.contains(&handler.to_string())
+                && self.run_task(host, context, handler).await?
+            {
+                notify(notified, handler);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The context `task` runs in: that of the host with the task's vars
+    // rendered into it, except those the run's extra vars set. `None` for
+    // tasks without vars, which run in the host's.
+    fn task_context(
+        &self,
+        task: &Task,
+        context: &Context,
+    ) -> Result<Option<Context>, AnsimpleError> {
+        if task.vars().is_empty() {
+            return Ok(None);
+        }
+
+        let mut scoped = context.clone();
+        let extra_vars = &self.options.extra_vars;
+        for (key, val) in task
+            .vars()
+            .iter()
+            .filter(|(key, _)| !extra_vars.contains_key(*key))
+        {
+            let location = format!("vars.{key} of task '{task}'");
+            let val = self.templates.render_value(val, &scoped, &location)?;
+            scoped.insert(key, &val);
+        }
+
+        Ok(Some(scoped))
+    }
+
+    // Runs `task` on `host` and returns whether it changed anything.
+    async fn run_task(
+        &self,
+        host: &Host,
+        context: &mut Context,
+        task: &Task,
+    ) -> Result<bool, AnsimpleError> {
+        let options = &self.options;
+        context.insert("hostvars", &self.hostvars.snapshot());
+
+        if !self.tagged(task) {
+            return Ok(self.skipped(host, task));
+        }
+
+        // A task's own limit paces each host by itself, on top of the run's,
+        // and tasks may run for real in a check run.
+        let task_options;
+        let runs = options.check && task.check_mode() == Some(CheckMode::Run);
+        let options = if task.max_bandwidth().is_some() || runs {
+            task_options = RunOptions {
+                throttle: match task.max_bandwidth() {
+                    Some(bandwidth) => options.throttle.and(bandwidth),
+                    None => options.throttle.clone(),
+                },
+                check: options.check && !runs,
+                ..options.clone()
+            };
+            &task_options
+        } else {
+            options
+        };
+
+        let no_log = task.no_log();
+        let mut name = secrets::mask(&task.to_string()).into_owned();
+        let scoped = match self.task_context(task, context) {
+            Ok(scoped) => scoped,
+            Err(err) => return Err(self.failed(host, name, err, no_log)),
+        };
+        let task_context = scoped.as_ref().unwrap_or(context);
+        let items = match task.items(task_context, &self.templates) {
+            Ok(items) => items,
+            Err(err) => return Err(self.failed(host, name, err, no_log)),
+        };
+
+        // Why the task failed, when it carries on regardless.
+        let mut failure = None;
+        let (results, registered) = match items {
+            None => {
+                let rendered = match self.prepare(host, task, task_context) {
+                    Ok(None) => return Ok(self.skipped(host, task)),
+                    Ok(Some(rendered)) => Ok(rendered),
+                    Err(err) => Err(err),
+                };
+                let result = match rendered {
+                    Ok(rendered) => {
+                        name = secrets::mask(&rendered.0.to_string()).into_owned();
+                        options.emit(Event::TaskStarted {
+                            host: host.address.clone(),
+                            task: name.clone(),
+                        });
+                        self.attempt(host, &name, task, rendered, task_context, options)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+
+                match result {
+                    Ok((result, attempts)) => {
+                        let registered = RegisteredResult {
+                            attempts,
+                            ..result.register_value()
+                        };
+                        failure = result.error();
+                        (vec![result], registered)
+                    }
+                    Err(err) if task.ignore_errors() && !err.is_unreachable() => {
+                        failure = Some(err);
+                        (Vec::new(), RegisteredResult::errored())
+                    }
+                    Err(err) => return Err(self.failed(host, name, err, no_log)),
+                }
+            }
+            // The passes of a loop are one task, under the name it was
+            // given, as each pass may render it differently.
+            Some(items) => {
+                options.emit(Event::TaskStarted {
+                    host: host.address.clone(),
+                    task: name.clone(),
+                });
+                let mut results = Vec::new();
+                let mut passes = Vec::new();
+                for item in items {
+                    let mut item_context = task_context.clone();
+                    item_context.insert("item", &item);
+                    let result = match self.prepare(host, task, &item_context) {
+                        Ok(None) => {
+                            passes.push(RegisteredResult {
+                                status: "skipped".to_owned(),
+                                item: Some(item),
+                                ..Default::default()
+                            });
+                            continue;
+                        }
+                        Ok(Some(rendered)) => {
+                            self.attempt(host, &name, task, rendered, &item_context, options)
+                                .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    match result {
+                        Ok((result, attempts)) => {
+                            passes.push(RegisteredResult {
+                                item: Some(item),
+                                attempts,
+                                ..result.register_value()
+                            });
+                            failure = failure.or(result.error());
+                            results.push(result);
+                        }
+                        Err(err) if task.ignore_errors() && !err.is_unreachable() => {
+                            passes.push(RegisteredResult {
+                                item: Some(item),
+                                ..RegisteredResult::errored()
+                            });
+                            failure = failure.or(Some(err));
+                        }
+                        Err(err) => {
+                            self.record_effects(host, &results, no_log, options);
+                            return Err(self.failed(host, name, err, no_log));
+                        }
+                    }
+                    if failure.is_some() && !task.ignore_errors() {
+                        break;
+                    }
+                }
+
+                (results, RegisteredResult::looped(passes))
+            }
+        };
+        if let Some(err) = failure.take_if(|_| !task.ignore_errors()) {
+            self.record_effects(host, &results, no_log, options);
+            return Err(self.failed(host, name, err, no_log));
+        }
+
+        // Nothing a check run reports has happened.
+        if let Some(audit) = options.audit.as_ref().filter(|_| !options.check) {
+            for result in &results {
+                audit.record_result(host, result, no_log);
+            }
+        }
+        let diff = results
+            .iter()
+            .filter_map(TaskResult::diff)
+            .collect::<String>();
+        let error = failure.map(|err| {
+            if no_log {
+                NO_LOG_MESSAGE.to_owned()
+            } else {
+                secrets::mask(&err.to_string()).into_owned()
+            }
+        });
+        options.emit(Event::TaskResult(TaskResultEvent {
+            host: host.address.clone(),
+            task: name,
+            status: match error {
+                Some(_) => "ignored".to_owned(),
+                None => registered.status.clone(),
+            },
+            error,
+            result: (!no_log).then(|| Box::new(registered.clone().masked())),
+            diff: (!no_log && !diff.is_empty()).then(|| secrets::mask(&diff).into_owned()),
+        }));
+
+        if let Some(register_key) = task.register() {
+            let value = tera::to_value(&registered)?;
+            // Extra vars win over what tasks register.
+            if !options.extra_vars.contains_key(register_key) {
+                context.insert(register_key.to_owned(), &value);
+            }
+            self.hostvars.insert(&host.address, register_key, value);
+
+            // Results of no_log tasks are not written to the history.
+            if let Some((checkpoint, play)) = options.checkpoint.as_ref().zip(self.play_number) {
+                if !no_log {
+                    let masked = tera::to_value(registered.clone().masked())?;
+                    if let Err(err) =
+                        checkpoint.save_registered(play, &host.address, register_key, &masked)
+                    {
+                        options.emit(Event::Warning {
+                            host: host.address.clone(),
+                            message: format!("failed to save checkpoint: {err}"),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(registered.changed)
+    }
+
+    // Reports that `task` did not run on `host`. It changed nothing.
+    fn skipped(&self, host: &Host, task: &Task) -> bool {
+        let result = RegisteredResult {
+            status: "skipped".to_owned(),
+            ..Default::default()
+        };
+        self.options.emit(Event::TaskResult(TaskResultEvent {
+            host: host.address.clone(),
+            task: secrets::mask(&task.to_string()).into_owned(),
+            status: result.status.clone(),
+            error: None,
+            result: (!task.no_log()).then(|| Box::new(result)),
+            diff: None,
+        }));
+
+        false
+    }
+
+    // Settles whether a pass of `task` changed anything by its `changed_when`.
+    fn judge(
+        &self,
+        task: &Task,
+        context: &Context,
+        result: TaskResult,
+    ) -> Result<TaskResult, AnsimpleError> {
+        if !matches!(result, TaskResult::Changed(..) | TaskResult::Unchanged(..)) {
+            return Ok(result);
+        }
+
+        match task.changed_when(&result.register_value(), context, &self.templates)? {
+            Some(changed) => Ok(result.with_changed(changed)),
+            None => Ok(result),
+        }
+    }
+
+    // Runs a pass of `task` and settles whether it changed anything, again
+    // after its `delay` while it fails or its `until` does not hold, as often
+    // as its `retries` allow. Nothing runs for real in check runs, so they
+    // try once. With the attempts made when the task retries.
+    async fn attempt(
+        &self,
+        host: &Host,
+        name: &str,
+        task: &Task,
+        (kind, exec, target): (TaskKind, ExecOptions, Host),
+        context: &Context,
+        options: &RunOptions,
+    ) -> Result<(TaskResult, Option<u32>), AnsimpleError> {
+        let retries = if options.check { 0 } else { task.retries() };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut pass = kind.clone();
+            let result = pass
+                .execute_on_host(
+                    &target,
+                    context,
+                    &self.templates,
+                    options,
+                    &self.global_config,
+                    &exec,
+                )
+                .await
+                .and_then(|result| self.judge(task, context, result));
+
+            // Unreachable hosts are left to the connection's own retries.
+            let done = match &result {
+                _ if options.check => true,
+                Ok(result) if result.error().is_none() => {
+                    task.until(&result.register_value(), context, &self.templates)?
+                }
+                Ok(_) => false,
+                Err(err) => err.is_unreachable(),
+            };
+            let attempts = task.retried().then_some(attempt);
+            if done {
+                return result.map(|result| (result, attempts));
+            }
+            if attempt > retries {
+                return match result {
+                    Ok(result) if result.error().is_none() => {
+                        Err(AnsimpleError::UntilFailed(attempt))
+                    }
+                    result => result.map(|result| (result, attempts)),
+                };
+            }
+
+            options.emit(Event::TaskRetrying {
+                host: host.address.clone(),
+                task: name.to_owned(),
+                retries_left: retries + 1 - attempt,
+            });
+            time::sleep(Duration::from_secs(task.delay())).await;
+        }
+    }
+
+    // Renders `task` for a pass in `context`, with the host it runs on.
+    // `None` when its `when` does not hold there.
+    fn prepare(
+        &self,
+        host: &Host,
+        task: &Task,
+        context: &Context,
+    ) -> Result<Option<(TaskKind, ExecOptions, Host)>, AnsimpleError> {
+        if !task.when(context, &self.templates)? {
+            return Ok(None);
+        }
+        let kind = task.kind().render(context, &self.templates)?;
+        let target = self.delegate(host, task, context)?;
+        let exec = self.exec_options(&target, task, context)?;
+
+        Ok(Some((kind, exec, target)))
+    }
+
+    // The host `task` runs on for `host`: the one it is delegated to, reached
+    // over the connection the task asks for. Local commands are POSIX ones.
+    fn delegate(&self, host: &Host, task: &Task, context: &Context) -> Result<Host, AnsimpleError> {
+        let mut target = match task.delegate_to() {
+            None => host.clone(),
+            Some(delegate_to) => {
+                let location = format!("delegate_to of task '{task}'");
+                let rendered = self.templates.render_value(
+                    &Value::String(delegate_to.clone()),
+                    context,
+                    &location,
+                )?;
+                let address = rendered.as_str().unwrap_or_default();
+                match self.inventory.iter().find(|host| host.address == address) {
+                    Some(delegate) => delegate.clone(),
+                    None if LOCALHOST.contains(&address) => Host::local(address),
+                    None => {
+                        return Err(AnsimpleError::Config(format!(
+                            "{location}: `{address}` is not a host of the inventory"
+                        )))
+                    }
+                }
+            }
+        };
+        if let Some(connection) = task.connection() {
+            target.connection = Some(connection);
+        }
+        if target.connection == Some(ConnectionKind::Local) {
+            target.platform = Some(Platform::Posix);
+        }
+
+        Ok(target)
+    }
+}
+
+// Records the handlers `task` notifies, once each.
+fn notify(notified: &mut Vec<String>, task: &Task) {
+    for handler in task.notify() {
+        if !notified.contains(handler) {
+            notified.push(handler.clone());
+        }
+    }
+}
+
+// Identifies the tasks a host completed, so a resumed run can tell whether the
+// playbook changed under them.
+fn done_sha256(tasks: &[Task], stages: &[Vec<usize>]) -> Result<String, AnsimpleError> {
+    let done = stages
+        .iter()
+        .flatten()
+        .map(|index| &tasks[*index])
+        .collect::<Vec<&Task>>();
+    Ok(sha256_hex(&serde_json::to_vec(&done)?))
 }
 
 impl TryFrom<PathBuf> for Playbook {
-    type Error = serde_yaml::Error;
+    type Error = AnsimpleError;
 
     fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
-        let contents = read_file(value).expect("failed to read file");
-        Self::try_from(contents)
+        let contents = fs::read_to_string(&value).map_err(|source| AnsimpleError::Read {
+            path: value.clone(),
+            source,
+        })?;
+        serde_yaml::from_str(&contents).map_err(|source| AnsimpleError::Parse {
+            path: value,
+            source,
+        })
     }
 }
 
@@ -171,8 +1524,3 @@ impl TryFrom<&String> for Playbook {
         serde_yaml::from_str(value)
     }
 }
-
-fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
-    let contents = fs::read_to_string(path)?;
-    Ok(contents)
-}
@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use tera::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::AnsimpleError;
+use crate::inventory::Host;
+use crate::task::find_module;
+
+mod native;
+mod wasm;
+
+use native::Native;
+use wasm::Wasm;
+
+pub const ABI_VERSION: u32 = 1;
+
+// Steps after which a module that keeps asking for operations is failed.
+pub const MAX_STEPS: usize = 1000;
+
+// What a module is told on every step: its arguments, the host, the state it
+// returned last time and the outcome of the operation it asked for.
+#[derive(Debug, Serialize)]
+pub struct Request<'a> {
+    pub module: &'a str,
+    pub args: &'a Value,
+    pub host: &'a Host,
+    pub step: usize,
+    pub state: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<&'a Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Response {
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(default)]
+    pub state: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Exec {
+        command: String,
+    },
+    Read {
+        path: String,
+    },
+    Write {
+        path: String,
+        content: String,
+    },
+    Done {
+        changed: bool,
+        #[serde(default)]
+        output: String,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+// The operations a module can have performed on its host. Each answers with
+// the value passed back to the module as `last`.
+pub trait HostIo {
+    fn exec(&mut self, command: &str) -> Result<Value, AnsimpleError>;
+    fn read(&mut self, path: &str) -> Result<Value, AnsimpleError>;
+    fn write(&mut self, path: &str, content: &str) -> Result<Value, AnsimpleError>;
+}
+
+enum Backend {
+    Native(Native),
+    Wasm(Box<Wasm>),
+}
+
+pub struct Plugin {
+    path: PathBuf,
+    backend: Backend,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+impl Plugin {
+    // `.wasm` files are run by the interpreter, anything else is loaded as a
+    // shared object.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<String>), AnsimpleError> {
+        let path = path.as_ref();
+        let error = |message: String| AnsimpleError::Plugin {
+            path: path.to_owned(),
+            message,
+        };
+
+        let (backend, modules) = if path
+            .extension()
+            .is_some_and(|extension| extension == "wasm")
+        {
+            let bytes = fs::read(path).map_err(|source| AnsimpleError::Read {
+                path: path.to_owned(),
+                source,
+            })?;
+            let (wasm, modules) = Wasm::open(&bytes).map_err(error)?;
+            (Backend::Wasm(Box::new(wasm)), modules)
+        } else {
+            let (native, modules) = Native::open(path).map_err(error)?;
+            (Backend::Native(native), modules)
+        };
+
+        Ok((
+            Self {
+                path: path.to_owned(),
+                backend,
+            },
+            modules,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // WASM modules may use `io` directly through their host functions.
+    pub fn call(&self, request: &Request, io: &mut dyn HostIo) -> Result<Response, AnsimpleError> {
+        let error = |message: String| AnsimpleError::Plugin {
+            path: self.path.clone(),
+            message,
+        };
+
+        let request = serde_json::to_vec(request)?;
+        let response = match &self.backend {
+            Backend::Native(native) => native.call(&request).map_err(error)?,
+            Backend::Wasm(wasm) => wasm.call(&request, io).map_err(|err| match err {
+                wasm::Error::Trap(message) => error(format!("trap: {message}")),
+                wasm::Error::Host(err) => err,
+            })?,
+        };
+
+        serde_json::from_slice(&response).map_err(|err| error(format!("invalid response: {err}")))
+    }
+}
+
+// Maps module names to the plugin providing them.
+#[derive(Debug, Clone, Default)]
+pub struct PluginRegistry {
+    modules: HashMap<String, Arc<Plugin>>,
+}
+
+impl PluginRegistry {
+    pub fn default_dir() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME")?;
+        Some(PathBuf::from(home).join(".ansimple").join("plugins"))
+    }
+
+    // Loads every shared object and WASM module in `dir`; a missing directory
+    // is empty.
+    pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Self, AnsimpleError> {
+        let dir = dir.as_ref();
+        let mut registry = Self::default();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(registry),
+            Err(source) => {
+                return Err(AnsimpleError::Read {
+                    path: dir.to_owned(),
+                    source,
+                })
+            }
+        };
+
+        let mut paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension().is_some_and(|extension| {
+                    extension == "so" || extension == "dylib" || extension == "wasm"
+                })
+            })
+            .collect::<Vec<PathBuf>>();
+        paths.sort();
+
+        for path in paths {
+            registry.load(path)?;
+        }
+
+        Ok(registry)
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), AnsimpleError> {
+        let (plugin, modules) = Plugin::open(path)?;
+        let plugin = Arc::new(plugin);
+        for module in modules {
+            if let Some(existing) = self.modules.get(&module) {
+                return Err(AnsimpleError::Plugin {
+                    path: plugin.path().to_owned(),
+                    message: format!(
+                        "module `{module}` is already provided by {}",
+                        existing.path().display()
+                    ),
+                });
+            }
+            if find_module(&module).is_some() {
+                return Err(AnsimpleError::Plugin {
+                    path: plugin.path().to_owned(),
+                    message: format!("module `{module}` is a registered task module"),
+                });
+            }
+            self.modules.insert(module, plugin.clone());
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, module: &str) -> Option<&Plugin> {
+        self.modules.get(module).map(Arc::as_ref)
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.modules.keys().map(String::as_str)
+    }
+}
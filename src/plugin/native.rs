@@ -0,0 +1,101 @@
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::Path;
+
+use super::ABI_VERSION;
+
+type AbiFn = unsafe extern "C" fn() -> u32;
+type ModulesFn = unsafe extern "C" fn() -> *const c_char;
+type CallFn = unsafe extern "C" fn(*const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+// A shared object exporting the `ansimple_plugin_*` C functions.
+pub struct Native {
+    handle: *mut c_void,
+    call: CallFn,
+    free: FreeFn,
+}
+
+// Plugins must be thread-safe, that is part of the plugin contract.
+unsafe impl Send for Native {}
+unsafe impl Sync for Native {}
+
+impl Native {
+    pub fn open(path: &Path) -> Result<(Self, Vec<String>), String> {
+        let filename =
+            CString::new(path.to_string_lossy().as_bytes()).map_err(|err| err.to_string())?;
+        let handle = unsafe { libc::dlopen(filename.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(dlerror());
+        }
+
+        let symbol = |name: &CStr| {
+            let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+            if symbol.is_null() {
+                Err(format!("missing symbol `{}`", name.to_string_lossy()))
+            } else {
+                Ok(symbol)
+            }
+        };
+        let loaded = (|| {
+            let abi: AbiFn = unsafe { std::mem::transmute(symbol(c"ansimple_plugin_abi")?) };
+            let modules: ModulesFn =
+                unsafe { std::mem::transmute(symbol(c"ansimple_plugin_modules")?) };
+            let call: CallFn = unsafe { std::mem::transmute(symbol(c"ansimple_plugin_call")?) };
+            let free: FreeFn = unsafe { std::mem::transmute(symbol(c"ansimple_plugin_free")?) };
+
+            let version = unsafe { abi() };
+            if version != ABI_VERSION {
+                return Err(format!(
+                    "plugin ABI version {version}, expected {ABI_VERSION}"
+                ));
+            }
+
+            let modules = unsafe { modules() };
+            if modules.is_null() {
+                return Err("plugin lists no modules".to_owned());
+            }
+            let modules: Vec<String> =
+                serde_json::from_slice(unsafe { CStr::from_ptr(modules) }.to_bytes())
+                    .map_err(|err| format!("invalid module list: {err}"))?;
+
+            Ok((call, free, modules))
+        })();
+
+        match loaded {
+            Ok((call, free, modules)) => Ok((Self { handle, call, free }, modules)),
+            Err(err) => {
+                unsafe { libc::dlclose(handle) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn call(&self, request: &[u8]) -> Result<Vec<u8>, String> {
+        let request = CString::new(request).map_err(|err| err.to_string())?;
+        let response = unsafe { (self.call)(request.as_ptr()) };
+        if response.is_null() {
+            return Err("module returned no response".to_owned());
+        }
+
+        let bytes = unsafe { CStr::from_ptr(response) }.to_bytes().to_vec();
+        unsafe { (self.free)(response) };
+        Ok(bytes)
+    }
+}
+
+impl Drop for Native {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.handle) };
+    }
+}
+
+fn dlerror() -> String {
+    let message = unsafe { libc::dlerror() };
+    if message.is_null() {
+        return "failed to load plugin".to_owned();
+    }
+
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
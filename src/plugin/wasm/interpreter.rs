@@ -0,0 +1,776 @@
+use crate::error::AnsimpleError;
+
+use super::module::{ConstExpr, Export, Mode, Module, Op, ValType, NULL_REF};
+
+const PAGE_SIZE: usize = 64 * 1024;
+const MAX_CALL_DEPTH: usize = 512;
+const MAX_STACK: usize = 1 << 20;
+
+// Values a float may truncate to without overflowing, end excluded.
+const I32_RANGE: (f64, f64) = (i32::MIN as f64, -(i32::MIN as f64));
+const U32_RANGE: (f64, f64) = (0.0, u32::MAX as f64 + 1.0);
+const I64_RANGE: (f64, f64) = (i64::MIN as f64, -(i64::MIN as f64));
+const U64_RANGE: (f64, f64) = (0.0, u64::MAX as f64);
+
+#[derive(Debug)]
+pub enum Error {
+    Trap(String),
+    // A host function failed, the error is passed on unchanged.
+    Host(AnsimpleError),
+}
+
+fn trap<T>(message: &str) -> Result<T, Error> {
+    Err(Error::Trap(message.to_owned()))
+}
+
+// Implements the functions a module imports.
+pub trait Host {
+    fn call(
+        &mut self,
+        instance: &mut Instance,
+        import: usize,
+        args: &[u64],
+    ) -> Result<Vec<u64>, Error>;
+}
+
+struct Label {
+    height: usize,
+    arity: usize,
+    target: usize,
+    is_loop: bool,
+}
+
+// A running module: its memory, globals and tables, and the operand stack
+// shared by all frames. Values are kept as raw bits, floats by their bit
+// pattern and references as function indices.
+pub struct Instance<'a> {
+    module: &'a Module,
+    memory: Vec<u8>,
+    max_pages: usize,
+    globals: Vec<u64>,
+    tables: Vec<Vec<u64>>,
+    dropped: Vec<bool>,
+    stack: Vec<u64>,
+    depth: usize,
+    fuel: u64,
+}
+
+impl<'a> Instance<'a> {
+    pub fn new(
+        module: &'a Module,
+        max_pages: usize,
+        fuel: u64,
+        host: &mut dyn Host,
+    ) -> Result<Self, Error> {
+        let pages = module.memory.map_or(0, |memory| memory.min as usize);
+        let max_pages = module
+            .memory
+            .and_then(|memory| memory.max)
+            .map_or(max_pages, |max| max_pages.min(max as usize));
+        if pages > max_pages {
+            return trap("initial memory exceeds the limit");
+        }
+
+        let mut instance = Self {
+            module,
+            memory: vec![0; pages * PAGE_SIZE],
+            max_pages,
+            globals: Vec::with_capacity(module.globals.len()),
+            tables: module
+                .tables
+                .iter()
+                .map(|table| vec![NULL_REF; table.min as usize])
+                .collect(),
+            dropped: vec![false; module.data.len()],
+            stack: Vec::new(),
+            depth: 0,
+            fuel,
+        };
+
+        for global in &module.globals {
+            let value = instance.eval(global.init)?;
+            instance.globals.push(value);
+        }
+        for element in &module.elements {
+            if let Mode::Active { index, offset } = element.mode {
+                let offset = instance.eval(offset)? as u32 as usize;
+                let table = instance
+                    .tables
+                    .get_mut(index as usize)
+                    .ok_or(Error::Trap("unknown table".to_owned()))?;
+                let slots = table
+                    .get_mut(offset..offset + element.items.len())
+                    .ok_or(Error::Trap("element segment out of bounds".to_owned()))?;
+                slots.copy_from_slice(&element.items);
+            }
+        }
+        for (index, data) in module.data.iter().enumerate() {
+            if let Mode::Active { offset, .. } = data.mode {
+                let offset = instance.eval(offset)? as u32 as usize;
+                instance
+                    .memory
+                    .get_mut(offset..offset + data.bytes.len())
+                    .ok_or(Error::Trap("data segment out of bounds".to_owned()))?
+                    .copy_from_slice(&data.bytes);
+                instance.dropped[index] = true;
+            }
+        }
+        if let Some(start) = module.start {
+            instance.call(start as usize, host)?;
+        }
+
+        Ok(instance)
+    }
+
+    pub fn invoke(
+        &mut self,
+        name: &str,
+        args: &[u64],
+        host: &mut dyn Host,
+    ) -> Result<Vec<u64>, Error> {
+        let Some(Export::Func(index)) = self.module.exports.get(name) else {
+            return Err(Error::Trap(format!("missing export `{name}`")));
+        };
+        let ty = self.module.function_type(*index as usize);
+        if ty.params.len() != args.len() {
+            return Err(Error::Trap(format!(
+                "export `{name}` takes {} arguments",
+                ty.params.len()
+            )));
+        }
+
+        let base = self.stack.len();
+        self.stack.extend_from_slice(args);
+        self.call(*index as usize, host)?;
+        Ok(self.stack.split_off(base))
+    }
+
+    pub fn import(&self, index: usize) -> &'a str {
+        &self.module.imports[index].name
+    }
+
+    pub fn memory(&self, address: u32, size: u32) -> Result<&[u8], Error> {
+        let address = address as usize;
+        self.memory
+            .get(address..address + size as usize)
+            .ok_or(Error::Trap("memory access out of bounds".to_owned()))
+    }
+
+    pub fn memory_mut(&mut self, address: u32, size: u32) -> Result<&mut [u8], Error> {
+        let address = address as usize;
+        self.memory
+            .get_mut(address..address + size as usize)
+            .ok_or(Error::Trap("memory access out of bounds".to_owned()))
+    }
+
+    fn eval(&self, expr: ConstExpr) -> Result<u64, Error> {
+        match expr {
+            ConstExpr::Value(value) => Ok(value),
+            ConstExpr::Global(index) => self
+                .globals
+                .get(index as usize)
+                .copied()
+                .ok_or(Error::Trap("unknown global".to_owned())),
+        }
+    }
+
+    fn pop(&mut self) -> Result<u64, Error> {
+        self.stack
+            .pop()
+            .ok_or(Error::Trap("stack underflow".to_owned()))
+    }
+
+    fn unary(&mut self, op: impl FnOnce(u64) -> Result<u64, Error>) -> Result<(), Error> {
+        let a = self.pop()?;
+        self.stack.push(op(a)?);
+        Ok(())
+    }
+
+    fn binary(&mut self, op: impl FnOnce(u64, u64) -> Result<u64, Error>) -> Result<(), Error> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(op(a, b)?);
+        Ok(())
+    }
+
+    // Keeps the top `arity` values and drops everything above `height`
+    // below them.
+    fn unwind(&mut self, height: usize, arity: usize) -> Result<(), Error> {
+        let top = self
+            .stack
+            .len()
+            .checked_sub(arity)
+            .filter(|top| *top >= height)
+            .ok_or(Error::Trap("stack underflow".to_owned()))?;
+        self.stack.drain(height..top);
+        Ok(())
+    }
+
+    // Returns where execution continues, `None` when the branch leaves the
+    // function.
+    fn branch(&mut self, labels: &mut Vec<Label>, depth: u32) -> Result<Option<usize>, Error> {
+        let Some(index) = labels.len().checked_sub(depth as usize + 1) else {
+            return Ok(None);
+        };
+
+        let label = &labels[index];
+        let target = label.target;
+        let truncate = if label.is_loop { index + 1 } else { index };
+        self.unwind(label.height, label.arity)?;
+        labels.truncate(truncate);
+        Ok(Some(target))
+    }
+
+    fn call(&mut self, index: usize, host: &mut dyn Host) -> Result<(), Error> {
+        let module = self.module;
+        let ty = module.function_type(index);
+        let base = self
+            .stack
+            .len()
+            .checked_sub(ty.params.len())
+            .ok_or(Error::Trap("stack underflow".to_owned()))?;
+
+        if index < module.imports.len() {
+            let args = self.stack.split_off(base);
+            let results = host.call(self, index, &args)?;
+            if results.len() != ty.results.len() {
+                return trap("host function returned the wrong number of values");
+            }
+            self.stack.extend(results);
+            return Ok(());
+        }
+
+        if self.depth >= MAX_CALL_DEPTH || self.stack.len() >= MAX_STACK {
+            return trap("call stack exhausted");
+        }
+        self.depth += 1;
+        let result = self.execute(index - module.imports.len(), base, host);
+        self.depth -= 1;
+        result
+    }
+
+    fn execute(&mut self, index: usize, base: usize, host: &mut dyn Host) -> Result<(), Error> {
+        let module = self.module;
+        let function = &module.functions[index];
+        let arity = module.types[function.ty as usize].results.len();
+        let mut locals = self.stack.split_off(base);
+        locals.extend(function.locals.iter().map(|ty| match ty {
+            ValType::FuncRef | ValType::ExternRef => NULL_REF,
+            _ => 0,
+        }));
+
+        let mut labels: Vec<Label> = Vec::new();
+        let mut pc = 0;
+        loop {
+            if self.fuel == 0 {
+                return trap("fuel exhausted");
+            }
+            self.fuel -= 1;
+
+            let Some(op) = function.code.get(pc) else {
+                return trap("fell off the end of a function");
+            };
+            pc += 1;
+            match op {
+                Op::Unreachable => return trap("unreachable executed"),
+                Op::Nop => {}
+                Op::Block {
+                    params,
+                    results,
+                    end,
+                } => labels.push(Label {
+                    height: self.stack.len().saturating_sub(*params),
+                    arity: *results,
+                    target: end + 1,
+                    is_loop: false,
+                }),
+                Op::Loop { params } => labels.push(Label {
+                    height: self.stack.len().saturating_sub(*params),
+                    arity: *params,
+                    target: pc,
+                    is_loop: true,
+                }),
+                Op::If {
+                    params,
+                    results,
+                    else_,
+                    end,
+                } => {
+                    let condition = self.pop()? as u32;
+                    if condition != 0 || else_ != end {
+                        labels.push(Label {
+                            height: self.stack.len().saturating_sub(*params),
+                            arity: *results,
+                            target: end + 1,
+                            is_loop: false,
+                        });
+                    }
+                    if condition == 0 {
+                        pc = else_ + 1;
+                    }
+                }
+                Op::Else { end } => {
+                    labels.pop();
+                    pc = end + 1;
+                }
+                Op::End => {
+                    if labels.pop().is_none() {
+                        return self.unwind(base, arity);
+                    }
+                }
+                Op::Br(depth) => match self.branch(&mut labels, *depth)? {
+                    Some(target) => pc = target,
+                    None => return self.unwind(base, arity),
+                },
+                Op::BrIf(depth) => {
+                    if self.pop()? as u32 != 0 {
+                        match self.branch(&mut labels, *depth)? {
+                            Some(target) => pc = target,
+                            None => return self.unwind(base, arity),
+                        }
+                    }
+                }
+                Op::BrTable(depths, default) => {
+                    let selected = self.pop()? as u32 as usize;
+                    let depth = depths.get(selected).unwrap_or(default);
+                    match self.branch(&mut labels, *depth)? {
+                        Some(target) => pc = target,
+                        None => return self.unwind(base, arity),
+                    }
+                }
+                Op::Return => return self.unwind(base, arity),
+                Op::Call(index) => self.call(*index as usize, host)?,
+                Op::CallIndirect { ty, table } => {
+                    let element = self.pop()? as u32 as usize;
+                    let function = *self
+                        .tables
+                        .get(*table as usize)
+                        .and_then(|table| table.get(element))
+                        .ok_or(Error::Trap("undefined table element".to_owned()))?;
+                    if function == NULL_REF {
+                        return trap("uninitialized table element");
+                    }
+                    let function = function as usize;
+                    if function >= module.function_count()
+                        || module.function_type(function) != &module.types[*ty as usize]
+                    {
+                        return trap("indirect call type mismatch");
+                    }
+                    self.call(function, host)?;
+                }
+                Op::Drop => {
+                    self.pop()?;
+                }
+                Op::Select => {
+                    let condition = self.pop()? as u32;
+                    self.binary(|a, b| Ok(if condition != 0 { a } else { b }))?;
+                }
+                Op::LocalGet(index) => {
+                    let value = *locals
+                        .get(*index as usize)
+                        .ok_or(Error::Trap("unknown local".to_owned()))?;
+                    self.stack.push(value);
+                }
+                Op::LocalSet(index) => {
+                    let value = self.pop()?;
+                    *locals
+                        .get_mut(*index as usize)
+                        .ok_or(Error::Trap("unknown local".to_owned()))? = value;
+                }
+                Op::LocalTee(index) => {
+                    let value = *self
+                        .stack
+                        .last()
+                        .ok_or(Error::Trap("stack underflow".to_owned()))?;
+                    *locals
+                        .get_mut(*index as usize)
+                        .ok_or(Error::Trap("unknown local".to_owned()))? = value;
+                }
+                Op::GlobalGet(index) => {
+                    let value = *self
+                        .globals
+                        .get(*index as usize)
+                        .ok_or(Error::Trap("unknown global".to_owned()))?;
+                    self.stack.push(value);
+                }
+                Op::GlobalSet(index) => {
+                    let value = self.pop()?;
+                    if !module
+                        .globals
+                        .get(*index as usize)
+                        .is_some_and(|global| global.mutable)
+                    {
+                        return trap("global is immutable");
+                    }
+                    self.globals[*index as usize] = value;
+                }
+                Op::TableGet(table) => {
+                    let element = self.pop()? as u32 as usize;
+                    let value = *self
+                        .tables
+                        .get(*table as usize)
+                        .and_then(|table| table.get(element))
+                        .ok_or(Error::Trap("table access out of bounds".to_owned()))?;
+                    self.stack.push(value);
+                }
+                Op::TableSet(table) => {
+                    let value = self.pop()?;
+                    let element = self.pop()? as u32 as usize;
+                    *self
+                        .tables
+                        .get_mut(*table as usize)
+                        .and_then(|table| table.get_mut(element))
+                        .ok_or(Error::Trap("table access out of bounds".to_owned()))? = value;
+                }
+                Op::Load { op, offset } => self.load(*op, *offset)?,
+                Op::Store { op, offset } => self.store(*op, *offset)?,
+                Op::MemorySize => self.stack.push((self.memory.len() / PAGE_SIZE) as u64),
+                Op::MemoryGrow => {
+                    let pages = self.memory.len() / PAGE_SIZE;
+                    let delta = self.pop()? as u32 as usize;
+                    if pages + delta > self.max_pages {
+                        self.stack.push(u64::from(u32::MAX));
+                    } else {
+                        self.memory.resize((pages + delta) * PAGE_SIZE, 0);
+                        self.stack.push(pages as u64);
+                    }
+                }
+                Op::Const(value) => self.stack.push(*value),
+                Op::Numeric(op) => self.numeric(*op)?,
+                Op::TruncSat(op) => self.unary(|a| Ok(trunc_sat(*op, a)))?,
+                Op::MemoryInit(index) => {
+                    let size = self.pop()? as u32 as usize;
+                    let source = self.pop()? as u32 as usize;
+                    let dest = self.pop()? as u32;
+                    let data = module
+                        .data
+                        .get(*index as usize)
+                        .ok_or(Error::Trap("unknown data segment".to_owned()))?;
+                    let bytes: &[u8] = if self.dropped[*index as usize] {
+                        &[]
+                    } else {
+                        &data.bytes
+                    };
+                    let bytes = bytes
+                        .get(source..source + size)
+                        .ok_or(Error::Trap("data segment access out of bounds".to_owned()))?;
+                    self.memory_mut(dest, size as u32)?.copy_from_slice(bytes);
+                }
+                Op::DataDrop(index) => {
+                    *self
+                        .dropped
+                        .get_mut(*index as usize)
+                        .ok_or(Error::Trap("unknown data segment".to_owned()))? = true;
+                }
+                Op::MemoryCopy => {
+                    let size = self.pop()? as u32;
+                    let source = self.pop()? as u32;
+                    let dest = self.pop()? as u32;
+                    self.memory(source, size)?;
+                    self.memory(dest, size)?;
+                    let source = source as usize;
+                    self.memory
+                        .copy_within(source..source + size as usize, dest as usize);
+                }
+                Op::MemoryFill => {
+                    let size = self.pop()? as u32;
+                    let value = self.pop()? as u8;
+                    let dest = self.pop()? as u32;
+                    self.memory_mut(dest, size)?.fill(value);
+                }
+                Op::RefNull => self.stack.push(NULL_REF),
+                Op::RefIsNull => self.unary(|a| Ok(u64::from(a == NULL_REF)))?,
+                Op::RefFunc(index) => self.stack.push(u64::from(*index)),
+            }
+        }
+    }
+
+    fn load(&mut self, op: u8, offset: u32) -> Result<(), Error> {
+        let size = match op {
+            0x2c | 0x2d | 0x30 | 0x31 => 1,
+            0x2e | 0x2f | 0x32 | 0x33 => 2,
+            0x28 | 0x2a | 0x34 | 0x35 => 4,
+            _ => 8,
+        };
+        let address = self.address(offset)?;
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(self.memory(address, size)?);
+        let raw = u64::from_le_bytes(bytes);
+
+        let value = match op {
+            0x2c => from_i32(raw as i8 as i32),
+            0x2e => from_i32(raw as i16 as i32),
+            0x30 => raw as i8 as u64,
+            0x32 => raw as i16 as u64,
+            0x34 => raw as i32 as u64,
+            _ => raw,
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn store(&mut self, op: u8, offset: u32) -> Result<(), Error> {
+        let size = match op {
+            0x3a | 0x3c => 1,
+            0x3b | 0x3d => 2,
+            0x36 | 0x38 | 0x3e => 4,
+            _ => 8,
+        };
+        let value = self.pop()?;
+        let address = self.address(offset)?;
+        self.memory_mut(address, size)?
+            .copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Ok(())
+    }
+
+    fn address(&mut self, offset: u32) -> Result<u32, Error> {
+        let base = self.pop()? as u32;
+        base.checked_add(offset)
+            .ok_or(Error::Trap("memory access out of bounds".to_owned()))
+    }
+
+    fn numeric(&mut self, op: u8) -> Result<(), Error> {
+        match op {
+            // i32 comparisons
+            0x45 => self.unary(|a| Ok(u64::from(a as u32 == 0))),
+            0x46 => self.binary(|a, b| Ok(u64::from(a as u32 == b as u32))),
+            0x47 => self.binary(|a, b| Ok(u64::from(a as u32 != b as u32))),
+            0x48 => self.binary(|a, b| Ok(u64::from((a as i32) < b as i32))),
+            0x49 => self.binary(|a, b| Ok(u64::from((a as u32) < b as u32))),
+            0x4a => self.binary(|a, b| Ok(u64::from(a as i32 > b as i32))),
+            0x4b => self.binary(|a, b| Ok(u64::from(a as u32 > b as u32))),
+            0x4c => self.binary(|a, b| Ok(u64::from(a as i32 <= b as i32))),
+            0x4d => self.binary(|a, b| Ok(u64::from(a as u32 <= b as u32))),
+            0x4e => self.binary(|a, b| Ok(u64::from(a as i32 >= b as i32))),
+            0x4f => self.binary(|a, b| Ok(u64::from(a as u32 >= b as u32))),
+            // i64 comparisons
+            0x50 => self.unary(|a| Ok(u64::from(a == 0))),
+            0x51 => self.binary(|a, b| Ok(u64::from(a == b))),
+            0x52 => self.binary(|a, b| Ok(u64::from(a != b))),
+            0x53 => self.binary(|a, b| Ok(u64::from((a as i64) < b as i64))),
+            0x54 => self.binary(|a, b| Ok(u64::from(a < b))),
+            0x55 => self.binary(|a, b| Ok(u64::from(a as i64 > b as i64))),
+            0x56 => self.binary(|a, b| Ok(u64::from(a > b))),
+            0x57 => self.binary(|a, b| Ok(u64::from(a as i64 <= b as i64))),
+            0x58 => self.binary(|a, b| Ok(u64::from(a <= b))),
+            0x59 => self.binary(|a, b| Ok(u64::from(a as i64 >= b as i64))),
+            0x5a => self.binary(|a, b| Ok(u64::from(a >= b))),
+            // f32 comparisons
+            0x5b => self.binary(|a, b| Ok(u64::from(f32x(a) == f32x(b)))),
+            0x5c => self.binary(|a, b| Ok(u64::from(f32x(a) != f32x(b)))),
+            0x5d => self.binary(|a, b| Ok(u64::from(f32x(a) < f32x(b)))),
+            0x5e => self.binary(|a, b| Ok(u64::from(f32x(a) > f32x(b)))),
+            0x5f => self.binary(|a, b| Ok(u64::from(f32x(a) <= f32x(b)))),
+            0x60 => self.binary(|a, b| Ok(u64::from(f32x(a) >= f32x(b)))),
+            // f64 comparisons
+            0x61 => self.binary(|a, b| Ok(u64::from(f64x(a) == f64x(b)))),
+            0x62 => self.binary(|a, b| Ok(u64::from(f64x(a) != f64x(b)))),
+            0x63 => self.binary(|a, b| Ok(u64::from(f64x(a) < f64x(b)))),
+            0x64 => self.binary(|a, b| Ok(u64::from(f64x(a) > f64x(b)))),
+            0x65 => self.binary(|a, b| Ok(u64::from(f64x(a) <= f64x(b)))),
+            0x66 => self.binary(|a, b| Ok(u64::from(f64x(a) >= f64x(b)))),
+            // i32 arithmetic
+            0x67 => self.unary(|a| Ok(u64::from((a as u32).leading_zeros()))),
+            0x68 => self.unary(|a| Ok(u64::from((a as u32).trailing_zeros()))),
+            0x69 => self.unary(|a| Ok(u64::from((a as u32).count_ones()))),
+            0x6a => self.binary(|a, b| Ok(from_i32((a as i32).wrapping_add(b as i32)))),
+            0x6b => self.binary(|a, b| Ok(from_i32((a as i32).wrapping_sub(b as i32)))),
+            0x6c => self.binary(|a, b| Ok(from_i32((a as i32).wrapping_mul(b as i32)))),
+            0x6d => self.binary(|a, b| match (a as i32, b as i32) {
+                (_, 0) => trap("integer divide by zero"),
+                (i32::MIN, -1) => trap("integer overflow"),
+                (a, b) => Ok(from_i32(a / b)),
+            }),
+            0x6e => self.binary(|a, b| match b as u32 {
+                0 => trap("integer divide by zero"),
+                b => Ok(u64::from(a as u32 / b)),
+            }),
+            0x6f => self.binary(|a, b| match b as i32 {
+                0 => trap("integer divide by zero"),
+                b => Ok(from_i32((a as i32).wrapping_rem(b))),
+            }),
+            0x70 => self.binary(|a, b| match b as u32 {
+                0 => trap("integer divide by zero"),
+                b => Ok(u64::from(a as u32 % b)),
+            }),
+            0x71 => self.binary(|a, b| Ok(u64::from(a as u32 & b as u32))),
+            0x72 => self.binary(|a, b| Ok(u64::from(a as u32 | b as u32))),
+            0x73 => self.binary(|a, b| Ok(u64::from(a as u32 ^ b as u32))),
+            0x74 => self.binary(|a, b| Ok(u64::from((a as u32).wrapping_shl(b as u32)))),
+            0x75 => self.binary(|a, b| Ok(from_i32((a as i32).wrapping_shr(b as u32)))),
+            0x76 => self.binary(|a, b| Ok(u64::from((a as u32).wrapping_shr(b as u32)))),
+            0x77 => self.binary(|a, b| Ok(u64::from((a as u32).rotate_left(b as u32)))),
+            0x78 => self.binary(|a, b| Ok(u64::from((a as u32).rotate_right(b as u32)))),
+            // i64 arithmetic
+            0x79 => self.unary(|a| Ok(u64::from(a.leading_zeros()))),
+            0x7a => self.unary(|a| Ok(u64::from(a.trailing_zeros()))),
+            0x7b => self.unary(|a| Ok(u64::from(a.count_ones()))),
+            0x7c => self.binary(|a, b| Ok(a.wrapping_add(b))),
+            0x7d => self.binary(|a, b| Ok(a.wrapping_sub(b))),
+            0x7e => self.binary(|a, b| Ok(a.wrapping_mul(b))),
+            0x7f => self.binary(|a, b| match (a as i64, b as i64) {
+                (_, 0) => trap("integer divide by zero"),
+                (i64::MIN, -1) => trap("integer overflow"),
+                (a, b) => Ok((a / b) as u64),
+            }),
+            0x80 => self.binary(|a, b| match b {
+                0 => trap("integer divide by zero"),
+                b => Ok(a / b),
+            }),
+            0x81 => self.binary(|a, b| match b as i64 {
+                0 => trap("integer divide by zero"),
+                b => Ok((a as i64).wrapping_rem(b) as u64),
+            }),
+            0x82 => self.binary(|a, b| match b {
+                0 => trap("integer divide by zero"),
+                b => Ok(a % b),
+            }),
+            0x83 => self.binary(|a, b| Ok(a & b)),
+            0x84 => self.binary(|a, b| Ok(a | b)),
+            0x85 => self.binary(|a, b| Ok(a ^ b)),
+            0x86 => self.binary(|a, b| Ok(a.wrapping_shl(b as u32))),
+            0x87 => self.binary(|a, b| Ok((a as i64).wrapping_shr(b as u32) as u64)),
+            0x88 => self.binary(|a, b| Ok(a.wrapping_shr(b as u32))),
+            0x89 => self.binary(|a, b| Ok(a.rotate_left(b as u32))),
+            0x8a => self.binary(|a, b| Ok(a.rotate_right(b as u32))),
+            // f32 arithmetic
+            0x8b => self.unary(|a| Ok(from_f32(f32x(a).abs()))),
+            0x8c => self.unary(|a| Ok(from_f32(-f32x(a)))),
+            0x8d => self.unary(|a| Ok(from_f32(f32x(a).ceil()))),
+            0x8e => self.unary(|a| Ok(from_f32(f32x(a).floor()))),
+            0x8f => self.unary(|a| Ok(from_f32(f32x(a).trunc()))),
+            0x90 => self.unary(|a| Ok(from_f32(f32x(a).round_ties_even()))),
+            0x91 => self.unary(|a| Ok(from_f32(f32x(a).sqrt()))),
+            0x92 => self.binary(|a, b| Ok(from_f32(f32x(a) + f32x(b)))),
+            0x93 => self.binary(|a, b| Ok(from_f32(f32x(a) - f32x(b)))),
+            0x94 => self.binary(|a, b| Ok(from_f32(f32x(a) * f32x(b)))),
+            0x95 => self.binary(|a, b| Ok(from_f32(f32x(a) / f32x(b)))),
+            0x96 => self.binary(|a, b| Ok(from_f32(min(f32x(a).into(), f32x(b).into()) as f32))),
+            0x97 => self.binary(|a, b| Ok(from_f32(max(f32x(a).into(), f32x(b).into()) as f32))),
+            0x98 => self.binary(|a, b| Ok(from_f32(f32x(a).copysign(f32x(b))))),
+            // f64 arithmetic
+            0x99 => self.unary(|a| Ok(from_f64(f64x(a).abs()))),
+            0x9a => self.unary(|a| Ok(from_f64(-f64x(a)))),
+            0x9b => self.unary(|a| Ok(from_f64(f64x(a).ceil()))),
+            0x9c => self.unary(|a| Ok(from_f64(f64x(a).floor()))),
+            0x9d => self.unary(|a| Ok(from_f64(f64x(a).trunc()))),
+            0x9e => self.unary(|a| Ok(from_f64(f64x(a).round_ties_even()))),
+            0x9f => self.unary(|a| Ok(from_f64(f64x(a).sqrt()))),
+            0xa0 => self.binary(|a, b| Ok(from_f64(f64x(a) + f64x(b)))),
+            0xa1 => self.binary(|a, b| Ok(from_f64(f64x(a) - f64x(b)))),
+            0xa2 => self.binary(|a, b| Ok(from_f64(f64x(a) * f64x(b)))),
+            0xa3 => self.binary(|a, b| Ok(from_f64(f64x(a) / f64x(b)))),
+            0xa4 => self.binary(|a, b| Ok(from_f64(min(f64x(a), f64x(b))))),
+            0xa5 => self.binary(|a, b| Ok(from_f64(max(f64x(a), f64x(b))))),
+            0xa6 => self.binary(|a, b| Ok(from_f64(f64x(a).copysign(f64x(b))))),
+            // conversions
+            0xa7 => self.unary(|a| Ok(u64::from(a as u32))),
+            0xa8 => self.unary(|a| Ok(from_i32(trunc(f32x(a).into(), I32_RANGE)? as i32))),
+            0xa9 => self.unary(|a| Ok(u64::from(trunc(f32x(a).into(), U32_RANGE)? as u32))),
+            0xaa => self.unary(|a| Ok(from_i32(trunc(f64x(a), I32_RANGE)? as i32))),
+            0xab => self.unary(|a| Ok(u64::from(trunc(f64x(a), U32_RANGE)? as u32))),
+            0xac => self.unary(|a| Ok(a as i32 as u64)),
+            0xad => self.unary(|a| Ok(u64::from(a as u32))),
+            0xae => self.unary(|a| Ok(trunc(f32x(a).into(), I64_RANGE)? as i64 as u64)),
+            0xaf => self.unary(|a| Ok(trunc(f32x(a).into(), U64_RANGE)? as u64)),
+            0xb0 => self.unary(|a| Ok(trunc(f64x(a), I64_RANGE)? as i64 as u64)),
+            0xb1 => self.unary(|a| Ok(trunc(f64x(a), U64_RANGE)? as u64)),
+            0xb2 => self.unary(|a| Ok(from_f32(a as i32 as f32))),
+            0xb3 => self.unary(|a| Ok(from_f32(a as u32 as f32))),
+            0xb4 => self.unary(|a| Ok(from_f32(a as i64 as f32))),
+            0xb5 => self.unary(|a| Ok(from_f32(a as f32))),
+            0xb6 => self.unary(|a| Ok(from_f32(f64x(a) as f32))),
+            0xb7 => self.unary(|a| Ok(from_f64(f64::from(a as i32)))),
+            0xb8 => self.unary(|a| Ok(from_f64(f64::from(a as u32)))),
+            0xb9 => self.unary(|a| Ok(from_f64(a as i64 as f64))),
+            0xba => self.unary(|a| Ok(from_f64(a as f64))),
+            0xbb => self.unary(|a| Ok(from_f64(f32x(a).into()))),
+            // reinterpretations keep the bits, only the upper half is cleared
+            0xbc | 0xbe => self.unary(|a| Ok(u64::from(a as u32))),
+            0xbd | 0xbf => Ok(()),
+            // sign extension
+            0xc0 => self.unary(|a| Ok(from_i32(a as i8 as i32))),
+            0xc1 => self.unary(|a| Ok(from_i32(a as i16 as i32))),
+            0xc2 => self.unary(|a| Ok(a as i8 as u64)),
+            0xc3 => self.unary(|a| Ok(a as i16 as u64)),
+            0xc4 => self.unary(|a| Ok(a as i32 as u64)),
+            op => Err(Error::Trap(format!("unsupported instruction 0x{op:02x}"))),
+        }
+    }
+}
+
+fn from_i32(value: i32) -> u64 {
+    u64::from(value as u32)
+}
+
+fn f32x(value: u64) -> f32 {
+    f32::from_bits(value as u32)
+}
+
+fn from_f32(value: f32) -> u64 {
+    u64::from(value.to_bits())
+}
+
+fn f64x(value: u64) -> f64 {
+    f64::from_bits(value)
+}
+
+fn from_f64(value: f64) -> u64 {
+    value.to_bits()
+}
+
+// NaN wins and -0 is smaller than 0, unlike `f64::min`.
+fn min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+fn max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+// Truncates towards zero, trapping unless the result is in `range`.
+fn trunc(value: f64, (min, max): (f64, f64)) -> Result<f64, Error> {
+    if value.is_nan() {
+        return trap("invalid conversion to integer");
+    }
+    let value = value.trunc();
+    if value < min || value >= max {
+        return trap("integer overflow");
+    }
+
+    Ok(value)
+}
+
+// Rust's float to integer casts saturate exactly like `trunc_sat`.
+fn trunc_sat(op: u32, value: u64) -> u64 {
+    match op {
+        0 => from_i32(f32x(value) as i32),
+        1 => u64::from(f32x(value) as u32),
+        2 => from_i32(f64x(value) as i32),
+        3 => u64::from(f64x(value) as u32),
+        4 => f32x(value) as i64 as u64,
+        5 => f32x(value) as u64,
+        6 => f64x(value) as i64 as u64,
+        _ => f64x(value) as u64,
+    }
+}
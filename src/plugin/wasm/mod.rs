@@ -0,0 +1,154 @@
+use tera::Value;
+
+mod interpreter;
+mod module;
+
+pub use interpreter::Error;
+
+use super::{HostIo, ABI_VERSION};
+use interpreter::{Host, Instance};
+use module::{Module, ValType};
+
+// Memory a module may grow to, in 64 KiB pages.
+const MAX_PAGES: usize = 1024;
+// Instructions a module may execute in one call, host functions excluded.
+const FUEL: u64 = 1_000_000_000;
+
+// Host functions available to modules under the `ansimple` namespace, with
+// the number of i32 arguments they take. All of them return a packed i64.
+const HOST_FUNCTIONS: &[(&str, usize)] = &[("exec", 2), ("read_file", 2), ("write_file", 4)];
+
+// A WebAssembly module run by the built-in interpreter. It sees nothing but
+// its own memory and the host functions, and every call starts from a fresh
+// instance.
+pub struct Wasm {
+    module: Module,
+}
+
+impl Wasm {
+    pub fn open(bytes: &[u8]) -> Result<(Self, Vec<String>), String> {
+        let module = Module::parse(bytes)?;
+        for import in &module.imports {
+            let ty = &module.types[import.ty as usize];
+            let expected = HOST_FUNCTIONS
+                .iter()
+                .find(|(name, _)| import.module == "ansimple" && *name == import.name)
+                .map(|(_, params)| *params)
+                .ok_or_else(|| format!("unknown import {}.{}", import.module, import.name))?;
+            if ty.params.len() != expected
+                || ty.params.iter().any(|param| *param != ValType::I32)
+                || ty.results != [ValType::I64]
+            {
+                return Err(format!(
+                    "import {}.{} has the wrong signature",
+                    import.module, import.name
+                ));
+            }
+        }
+
+        let wasm = Self { module };
+        let mut host = Bridge { io: None };
+        let trap = |err: Error| match err {
+            Error::Trap(message) => format!("trap: {message}"),
+            Error::Host(err) => err.to_string(),
+        };
+        let mut instance = Instance::new(&wasm.module, MAX_PAGES, FUEL, &mut host).map_err(trap)?;
+
+        let version = instance
+            .invoke("ansimple_plugin_abi", &[], &mut host)
+            .map_err(trap)?;
+        if version != [u64::from(ABI_VERSION)] {
+            return Err(format!(
+                "plugin ABI version {}, expected {ABI_VERSION}",
+                version.first().copied().unwrap_or_default()
+            ));
+        }
+
+        let packed = instance
+            .invoke("ansimple_plugin_modules", &[], &mut host)
+            .map_err(trap)?;
+        let modules = read(&instance, &packed).map_err(trap)?;
+        let modules = serde_json::from_slice(&modules)
+            .map_err(|err| format!("invalid module list: {err}"))?;
+
+        Ok((wasm, modules))
+    }
+
+    pub fn call(&self, request: &[u8], io: &mut dyn HostIo) -> Result<Vec<u8>, Error> {
+        let mut host = Bridge { io: Some(io) };
+        let mut instance = Instance::new(&self.module, MAX_PAGES, FUEL, &mut host)?;
+        let address = write(&mut instance, &mut host, request)?;
+        let packed = instance.invoke(
+            "ansimple_plugin_call",
+            &[u64::from(address), request.len() as u64],
+            &mut host,
+        )?;
+
+        read(&instance, &packed)
+    }
+}
+
+// Performs the host functions a module calls over the connection.
+struct Bridge<'a> {
+    io: Option<&'a mut dyn HostIo>,
+}
+
+impl Host for Bridge<'_> {
+    fn call(
+        &mut self,
+        instance: &mut Instance,
+        import: usize,
+        args: &[u64],
+    ) -> Result<Vec<u64>, Error> {
+        let string = |instance: &Instance, index: usize| {
+            let bytes = instance.memory(args[index] as u32, args[index + 1] as u32)?;
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| Error::Trap("host function argument is not UTF-8".to_owned()))
+        };
+
+        let Some(io) = self.io.as_mut() else {
+            return Err(Error::Trap(
+                "host functions are not available while loading".to_owned(),
+            ));
+        };
+        let result: Value = match instance.import(import) {
+            "exec" => io.exec(&string(instance, 0)?),
+            "read_file" => io.read(&string(instance, 0)?),
+            _ => io.write(&string(instance, 0)?, &string(instance, 2)?),
+        }
+        .map_err(Error::Host)?;
+
+        let bytes = serde_json::to_vec(&result).map_err(|err| Error::Trap(err.to_string()))?;
+        let address = write(instance, self, &bytes)?;
+        Ok(vec![pack(address, bytes.len())])
+    }
+}
+
+// Copies `bytes` into memory the module allocates for them.
+fn write(instance: &mut Instance, host: &mut dyn Host, bytes: &[u8]) -> Result<u32, Error> {
+    let size = u32::try_from(bytes.len())
+        .map_err(|_| Error::Trap("value does not fit in memory".to_owned()))?;
+    let address = instance.invoke("ansimple_plugin_alloc", &[u64::from(size)], host)?;
+    let address = address.first().copied().unwrap_or_default() as u32;
+    instance.memory_mut(address, size)?.copy_from_slice(bytes);
+
+    Ok(address)
+}
+
+// Values cross the boundary as an address in the upper and a length in the
+// lower half of an i64.
+fn pack(address: u32, size: usize) -> u64 {
+    (u64::from(address) << 32) | size as u64
+}
+
+fn read(instance: &Instance, packed: &[u64]) -> Result<Vec<u8>, Error> {
+    let [packed] = packed else {
+        return Err(Error::Trap(
+            "expected a packed address and length".to_owned(),
+        ));
+    };
+
+    Ok(instance
+        .memory((packed >> 32) as u32, *packed as u32)?
+        .to_vec())
+}
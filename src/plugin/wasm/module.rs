@@ -0,0 +1,710 @@
+use std::collections::HashMap;
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: u32 = 1;
+
+// Guards against modules that declare absurd sizes.
+const MAX_LOCALS: usize = 50_000;
+const MAX_TABLE_SIZE: u32 = 100_000;
+
+// The value of a null reference on the operand stack.
+pub const NULL_REF: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    FuncRef,
+    ExternRef,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+#[derive(Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub ty: u32,
+    pub locals: Vec<ValType>,
+    pub code: Vec<Op>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConstExpr {
+    Value(u64),
+    Global(u32),
+}
+
+#[derive(Debug)]
+pub struct Global {
+    pub mutable: bool,
+    pub init: ConstExpr,
+}
+
+#[derive(Debug)]
+pub enum Mode {
+    Passive,
+    Active { index: u32, offset: ConstExpr },
+    Declarative,
+}
+
+#[derive(Debug)]
+pub struct Element {
+    pub mode: Mode,
+    pub items: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct Data {
+    pub mode: Mode,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Export {
+    Func(u32),
+    Table(u32),
+    Memory(u32),
+    Global(u32),
+}
+
+// Instructions with their immediates decoded and block ends resolved to
+// positions in the function body.
+#[derive(Debug, Clone)]
+pub enum Op {
+    Unreachable,
+    Nop,
+    Block {
+        params: usize,
+        results: usize,
+        end: usize,
+    },
+    Loop {
+        params: usize,
+    },
+    If {
+        params: usize,
+        results: usize,
+        else_: usize,
+        end: usize,
+    },
+    Else {
+        end: usize,
+    },
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Box<[u32]>, u32),
+    Return,
+    Call(u32),
+    CallIndirect {
+        ty: u32,
+        table: u32,
+    },
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    TableGet(u32),
+    TableSet(u32),
+    Load {
+        op: u8,
+        offset: u32,
+    },
+    Store {
+        op: u8,
+        offset: u32,
+    },
+    MemorySize,
+    MemoryGrow,
+    Const(u64),
+    Numeric(u8),
+    TruncSat(u32),
+    MemoryInit(u32),
+    DataDrop(u32),
+    MemoryCopy,
+    MemoryFill,
+    RefNull,
+    RefIsNull,
+    RefFunc(u32),
+}
+
+#[derive(Debug, Default)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    pub tables: Vec<Limits>,
+    pub memory: Option<Limits>,
+    pub globals: Vec<Global>,
+    pub exports: HashMap<String, Export>,
+    pub start: Option<u32>,
+    pub elements: Vec<Element>,
+    pub data: Vec<Data>,
+}
+
+impl Module {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.bytes(4)? != MAGIC {
+            return Err("not a WebAssembly module".to_owned());
+        }
+        let version = u32::from_le_bytes(reader.bytes(4)?.try_into().expect("four bytes"));
+        if version != VERSION {
+            return Err(format!("unsupported WebAssembly version {version}"));
+        }
+
+        let mut module = Self::default();
+        let mut function_types = Vec::new();
+        while !reader.is_empty() {
+            let id = reader.byte()?;
+            let size = reader.u32()? as usize;
+            let mut section = Reader::new(reader.bytes(size)?);
+            match id {
+                0 => {}
+                1 => module.types = section.vec(read_func_type)?,
+                2 => module.imports = section.vec(read_import)?,
+                3 => function_types = section.vec(Reader::u32)?,
+                4 => module.tables = section.vec(read_table)?,
+                5 => {
+                    let mut memories = section.vec(read_limits)?;
+                    if memories.len() > 1 {
+                        return Err("multiple memories are not supported".to_owned());
+                    }
+                    module.memory = memories.pop();
+                }
+                6 => module.globals = section.vec(read_global)?,
+                7 => {
+                    for (name, export) in section.vec(read_export)? {
+                        module.exports.insert(name, export);
+                    }
+                }
+                8 => module.start = Some(section.u32()?),
+                9 => module.elements = section.vec(read_element)?,
+                10 => {
+                    let bodies = section.vec(|reader| {
+                        let size = reader.u32()? as usize;
+                        Ok(reader.bytes(size)?.to_vec())
+                    })?;
+                    if bodies.len() != function_types.len() {
+                        return Err("function and code section sizes differ".to_owned());
+                    }
+                    for (ty, body) in function_types.iter().zip(bodies) {
+                        let function = read_function(&module, *ty, &body)?;
+                        module.functions.push(function);
+                    }
+                }
+                11 => module.data = section.vec(read_data)?,
+                12 => {
+                    section.u32()?;
+                }
+                _ => return Err(format!("unknown section {id}")),
+            }
+        }
+
+        if module.functions.len() != function_types.len() {
+            return Err("missing code section".to_owned());
+        }
+        for ty in module
+            .imports
+            .iter()
+            .map(|import| import.ty)
+            .chain(function_types)
+        {
+            if ty as usize >= module.types.len() {
+                return Err(format!("unknown type {ty}"));
+            }
+        }
+        let functions = module.function_count();
+        let calls = module.functions.iter().flat_map(|function| {
+            function.code.iter().filter_map(|op| match op {
+                Op::Call(index) | Op::RefFunc(index) => Some(*index),
+                _ => None,
+            })
+        });
+        for index in calls.chain(module.start) {
+            if index as usize >= functions {
+                return Err(format!("unknown function {index}"));
+            }
+        }
+
+        Ok(module)
+    }
+
+    pub fn function_count(&self) -> usize {
+        self.imports.len() + self.functions.len()
+    }
+
+    pub fn function_type(&self, index: usize) -> &FuncType {
+        let ty = match index.checked_sub(self.imports.len()) {
+            None => self.imports[index].ty,
+            Some(index) => self.functions[index].ty,
+        };
+        &self.types[ty as usize]
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or("unexpected end of module")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn peek(&self) -> Result<u8, String> {
+        self.bytes
+            .get(self.position)
+            .copied()
+            .ok_or_else(|| "unexpected end of module".to_owned())
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("unexpected end of module")?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64, String> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits.div_ceil(7) * 7 {
+                return Err("integer too long".to_owned());
+            }
+            result |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    result |= u64::MAX << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.leb(32, false)? as u32)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(self.leb(32, true)? as i32)
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        Ok(self.leb(64, true)? as i64)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let size = self.u32()? as usize;
+        String::from_utf8(self.bytes(size)?.to_vec()).map_err(|_| "invalid name".to_owned())
+    }
+
+    fn vec<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let count = self.u32()? as usize;
+        // Every item takes at least one byte.
+        if count > self.bytes.len() - self.position {
+            return Err("unexpected end of module".to_owned());
+        }
+        (0..count).map(|_| item(self)).collect()
+    }
+}
+
+fn read_val_type(reader: &mut Reader) -> Result<ValType, String> {
+    match reader.byte()? {
+        0x7f => Ok(ValType::I32),
+        0x7e => Ok(ValType::I64),
+        0x7d => Ok(ValType::F32),
+        0x7c => Ok(ValType::F64),
+        0x70 => Ok(ValType::FuncRef),
+        0x6f => Ok(ValType::ExternRef),
+        byte => Err(format!("unsupported value type 0x{byte:02x}")),
+    }
+}
+
+fn read_func_type(reader: &mut Reader) -> Result<FuncType, String> {
+    if reader.byte()? != 0x60 {
+        return Err("invalid function type".to_owned());
+    }
+
+    Ok(FuncType {
+        params: reader.vec(read_val_type)?,
+        results: reader.vec(read_val_type)?,
+    })
+}
+
+fn read_import(reader: &mut Reader) -> Result<Import, String> {
+    let module = reader.name()?;
+    let name = reader.name()?;
+    match reader.byte()? {
+        0 => Ok(Import {
+            module,
+            name,
+            ty: reader.u32()?,
+        }),
+        _ => Err(format!(
+            "import {module}.{name}: only function imports are supported"
+        )),
+    }
+}
+
+fn read_limits(reader: &mut Reader) -> Result<Limits, String> {
+    match reader.byte()? {
+        0 => Ok(Limits {
+            min: reader.u32()?,
+            max: None,
+        }),
+        1 => Ok(Limits {
+            min: reader.u32()?,
+            max: Some(reader.u32()?),
+        }),
+        _ => Err("unsupported limits".to_owned()),
+    }
+}
+
+fn read_table(reader: &mut Reader) -> Result<Limits, String> {
+    read_val_type(reader)?;
+    let limits = read_limits(reader)?;
+    if limits.min > MAX_TABLE_SIZE {
+        return Err(format!("table of {} elements is too large", limits.min));
+    }
+
+    Ok(limits)
+}
+
+fn read_global(reader: &mut Reader) -> Result<Global, String> {
+    read_val_type(reader)?;
+    let mutable = reader.byte()? == 1;
+    Ok(Global {
+        mutable,
+        init: read_const_expr(reader)?,
+    })
+}
+
+fn read_const_expr(reader: &mut Reader) -> Result<ConstExpr, String> {
+    let expr = match reader.byte()? {
+        0x41 => ConstExpr::Value(reader.i32()? as u32 as u64),
+        0x42 => ConstExpr::Value(reader.i64()? as u64),
+        0x43 => ConstExpr::Value(u64::from(u32::from_le_bytes(
+            reader.bytes(4)?.try_into().expect("four bytes"),
+        ))),
+        0x44 => ConstExpr::Value(u64::from_le_bytes(
+            reader.bytes(8)?.try_into().expect("eight bytes"),
+        )),
+        0x23 => ConstExpr::Global(reader.u32()?),
+        0xd0 => {
+            reader.byte()?;
+            ConstExpr::Value(NULL_REF)
+        }
+        0xd2 => ConstExpr::Value(u64::from(reader.u32()?)),
+        byte => return Err(format!("unsupported constant expression 0x{byte:02x}")),
+    };
+    if reader.byte()? != 0x0b {
+        return Err("unsupported constant expression".to_owned());
+    }
+
+    Ok(expr)
+}
+
+fn read_export(reader: &mut Reader) -> Result<(String, Export), String> {
+    let name = reader.name()?;
+    let export = match reader.byte()? {
+        0 => Export::Func(reader.u32()?),
+        1 => Export::Table(reader.u32()?),
+        2 => Export::Memory(reader.u32()?),
+        3 => Export::Global(reader.u32()?),
+        kind => return Err(format!("unknown export kind {kind}")),
+    };
+
+    Ok((name, export))
+}
+
+fn read_element(reader: &mut Reader) -> Result<Element, String> {
+    let flags = reader.u32()?;
+    if flags > 7 {
+        return Err(format!("unsupported element segment {flags}"));
+    }
+
+    let mode = if flags & 1 == 0 {
+        let index = if flags & 2 != 0 { reader.u32()? } else { 0 };
+        Mode::Active {
+            index,
+            offset: read_const_expr(reader)?,
+        }
+    } else if flags & 2 == 0 {
+        Mode::Passive
+    } else {
+        Mode::Declarative
+    };
+    // Segments other than the plain active one declare their element type.
+    if flags & 3 != 0 {
+        reader.byte()?;
+    }
+
+    let items = if flags & 4 == 0 {
+        reader.vec(|reader| Ok(u64::from(reader.u32()?)))?
+    } else {
+        reader.vec(|reader| match read_const_expr(reader)? {
+            ConstExpr::Value(value) => Ok(value),
+            ConstExpr::Global(_) => Err("unsupported element expression".to_owned()),
+        })?
+    };
+
+    Ok(Element { mode, items })
+}
+
+fn read_data(reader: &mut Reader) -> Result<Data, String> {
+    let mode = match reader.u32()? {
+        0 => Mode::Active {
+            index: 0,
+            offset: read_const_expr(reader)?,
+        },
+        1 => Mode::Passive,
+        2 => Mode::Active {
+            index: reader.u32()?,
+            offset: read_const_expr(reader)?,
+        },
+        flags => return Err(format!("unsupported data segment {flags}")),
+    };
+    let size = reader.u32()? as usize;
+
+    Ok(Data {
+        mode,
+        bytes: reader.bytes(size)?.to_vec(),
+    })
+}
+
+fn read_block_type(module: &Module, reader: &mut Reader) -> Result<(usize, usize), String> {
+    match reader.peek()? {
+        0x40 => {
+            reader.byte()?;
+            Ok((0, 0))
+        }
+        0x6f..=0x7f => {
+            read_val_type(reader)?;
+            Ok((0, 1))
+        }
+        _ => {
+            let index = reader.leb(33, true)? as usize;
+            let ty = module
+                .types
+                .get(index)
+                .ok_or_else(|| format!("unknown block type {index}"))?;
+            Ok((ty.params.len(), ty.results.len()))
+        }
+    }
+}
+
+fn read_function(module: &Module, ty: u32, body: &[u8]) -> Result<Function, String> {
+    let mut reader = Reader::new(body);
+    let mut locals = Vec::new();
+    for _ in 0..reader.u32()? {
+        let count = reader.u32()? as usize;
+        let ty = read_val_type(&mut reader)?;
+        if locals.len() + count > MAX_LOCALS {
+            return Err("too many locals".to_owned());
+        }
+        locals.extend(std::iter::repeat_n(ty, count));
+    }
+
+    let mut code = Vec::new();
+    // Positions of the blocks that are still open.
+    let mut open = Vec::new();
+    loop {
+        let position = code.len();
+        let op = match reader.byte()? {
+            0x00 => Op::Unreachable,
+            0x01 => Op::Nop,
+            0x02 => {
+                let (params, results) = read_block_type(module, &mut reader)?;
+                open.push(position);
+                Op::Block {
+                    params,
+                    results,
+                    end: 0,
+                }
+            }
+            0x03 => {
+                let (params, _) = read_block_type(module, &mut reader)?;
+                open.push(position);
+                Op::Loop { params }
+            }
+            0x04 => {
+                let (params, results) = read_block_type(module, &mut reader)?;
+                open.push(position);
+                Op::If {
+                    params,
+                    results,
+                    else_: 0,
+                    end: 0,
+                }
+            }
+            0x05 => {
+                let start = *open.last().ok_or("unexpected else")?;
+                match &mut code[start] {
+                    Op::If { else_, .. } if *else_ == 0 => *else_ = position,
+                    _ => return Err("unexpected else".to_owned()),
+                }
+                Op::Else { end: 0 }
+            }
+            0x0b => {
+                let Some(start) = open.pop() else {
+                    code.push(Op::End);
+                    break;
+                };
+                let mut else_position = None;
+                match &mut code[start] {
+                    Op::Block { end, .. } => *end = position,
+                    Op::If { else_, end, .. } => {
+                        *end = position;
+                        if *else_ == 0 {
+                            *else_ = position;
+                        } else {
+                            else_position = Some(*else_);
+                        }
+                    }
+                    _ => {}
+                }
+                if let Some(Op::Else { end }) = else_position.map(|index| &mut code[index]) {
+                    *end = position;
+                }
+                Op::End
+            }
+            0x0c => Op::Br(reader.u32()?),
+            0x0d => Op::BrIf(reader.u32()?),
+            0x0e => {
+                let labels = reader.vec(Reader::u32)?;
+                Op::BrTable(labels.into_boxed_slice(), reader.u32()?)
+            }
+            0x0f => Op::Return,
+            0x10 => Op::Call(reader.u32()?),
+            0x11 => {
+                let ty = reader.u32()?;
+                if ty as usize >= module.types.len() {
+                    return Err(format!("unknown type {ty}"));
+                }
+                Op::CallIndirect {
+                    ty,
+                    table: reader.u32()?,
+                }
+            }
+            0x1a => Op::Drop,
+            0x1b => Op::Select,
+            0x1c => {
+                reader.vec(read_val_type)?;
+                Op::Select
+            }
+            0x20 => Op::LocalGet(reader.u32()?),
+            0x21 => Op::LocalSet(reader.u32()?),
+            0x22 => Op::LocalTee(reader.u32()?),
+            0x23 => Op::GlobalGet(reader.u32()?),
+            0x24 => Op::GlobalSet(reader.u32()?),
+            0x25 => Op::TableGet(reader.u32()?),
+            0x26 => Op::TableSet(reader.u32()?),
+            op @ 0x28..=0x3e => {
+                if reader.u32()? & 0x40 != 0 {
+                    return Err("multiple memories are not supported".to_owned());
+                }
+                let offset = reader.u32()?;
+                if op <= 0x35 {
+                    Op::Load { op, offset }
+                } else {
+                    Op::Store { op, offset }
+                }
+            }
+            0x3f => {
+                reader.byte()?;
+                Op::MemorySize
+            }
+            0x40 => {
+                reader.byte()?;
+                Op::MemoryGrow
+            }
+            0x41 => Op::Const(reader.i32()? as u32 as u64),
+            0x42 => Op::Const(reader.i64()? as u64),
+            0x43 => Op::Const(u64::from(u32::from_le_bytes(
+                reader.bytes(4)?.try_into().expect("four bytes"),
+            ))),
+            0x44 => Op::Const(u64::from_le_bytes(
+                reader.bytes(8)?.try_into().expect("eight bytes"),
+            )),
+            op @ 0x45..=0xc4 => Op::Numeric(op),
+            0xd0 => {
+                reader.byte()?;
+                Op::RefNull
+            }
+            0xd1 => Op::RefIsNull,
+            0xd2 => Op::RefFunc(reader.u32()?),
+            0xfc => match reader.u32()? {
+                op @ 0..=7 => Op::TruncSat(op),
+                8 => {
+                    let index = reader.u32()?;
+                    reader.byte()?;
+                    Op::MemoryInit(index)
+                }
+                9 => Op::DataDrop(reader.u32()?),
+                10 => {
+                    reader.bytes(2)?;
+                    Op::MemoryCopy
+                }
+                11 => {
+                    reader.byte()?;
+                    Op::MemoryFill
+                }
+                op => return Err(format!("unsupported instruction 0xfc {op}")),
+            },
+            op => return Err(format!("unsupported instruction 0x{op:02x}")),
+        };
+        code.push(op);
+    }
+
+    if !reader.is_empty() {
+        return Err("trailing bytes after function body".to_owned());
+    }
+
+    Ok(Function { ty, locals, code })
+}
@@ -0,0 +1,217 @@
+use std::io::{self, IsTerminal, Write};
+
+use indexmap::IndexMap;
+
+use crate::events::{Event, HostStats, TaskResultEvent};
+
+// Turns run events into output for people or for other programs.
+pub trait Reporter: Send {
+    fn report(&mut self, event: &Event);
+}
+
+// One line per task and host, with statuses and diffs colored when printing
+// to a terminal and `NO_COLOR` is not set. Failures go to stderr.
+pub struct HumanReporter {
+    color: bool,
+}
+
+impl HumanReporter {
+    pub fn new() -> Self {
+        Self {
+            color: io::stdout().is_terminal()
+                && io::stderr().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    fn status(&self, status: &str) -> String {
+        let color = match status {
+            "changed" => "33",
+            "failed" | "unreachable" | "retrying" => "31",
+            "skipped" | "ignored" => "36",
+            _ => "32",
+        };
+        self.paint(color, &status.to_uppercase())
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color && !color.is_empty() {
+            format!("\x1b[{color}m{text}\x1b[0m")
+        } else {
+            text.to_owned()
+        }
+    }
+
+    // A line of counts per host, like Ansible's. Hosts are red when anything
+    // failed on them and yellow when anything changed.
+    fn recap(&self, hosts: &IndexMap<String, HostStats>) {
+        let width = hosts.keys().map(String::len).max().unwrap_or(0).max(20);
+        println!();
+        println!("PLAY RECAP");
+        for (host, stats) in hosts {
+            let color = if stats.failed > 0 || stats.unreachable > 0 {
+                "31"
+            } else if stats.changed > 0 {
+                "33"
+            } else {
+                "32"
+            };
+            let counts = [
+                ("ok", stats.ok, "32"),
+                ("changed", stats.changed, "33"),
+                ("unreachable", stats.unreachable, "31"),
+                ("failed", stats.failed, "31"),
+                ("skipped", stats.skipped, "36"),
+                ("ignored", stats.ignored, "35"),
+            ]
+            .map(|(name, count, color)| {
+                let text = format!("{name}={count}");
+                let padding = " ".repeat((name.len() + 5).saturating_sub(text.len()));
+                self.paint(if count > 0 { color } else { "" }, &text) + &padding
+            });
+            println!(
+                "{} : {}",
+                self.paint(color, &format!("{host:<width$}")),
+                counts.join(" ").trim_end()
+            );
+        }
+    }
+
+    // Added lines in green and removed ones in red.
+    fn diff(&self, diff: &str) {
+        if !self.color {
+            print!("{diff}");
+            return;
+        }
+        for line in diff.lines() {
+            let color = match line.as_bytes().first() {
+                _ if line.starts_with("---") || line.starts_with("+++") => "1",
+                Some(b'+') => "32",
+                Some(b'-') => "31",
+                Some(b'@') => "36",
+                _ => "",
+            };
+            println!("{}", self.paint(color, line));
+        }
+    }
+}
+
+impl Default for HumanReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reporter for HumanReporter {
+    fn report(&mut self, event: &Event) {
+        match event {
+            Event::TaskStarted { host, task } => println!("{task}: {host} - START"),
+            Event::TaskResult(TaskResultEvent {
+                host,
+                task,
+                status,
+                error: Some(error),
+                ..
+            }) => eprintln!("{task}: {host} - {}: {error}", self.status(status)),
+            Event::TaskResult(TaskResultEvent {
+                host,
+                task,
+                status,
+                diff,
+                ..
+            }) => {
+                println!("{task}: {host} - {}", self.status(status));
+                if let Some(diff) = diff {
+                    self.diff(diff);
+                }
+            }
+            Event::TransferProgress {
+                host,
+                task,
+                path,
+                bytes,
+                total,
+            } => println!(
+                "{task}: {host} - {path}: {} of {} ({}%)",
+                format_bytes(*bytes as f64),
+                format_bytes(*total as f64),
+                bytes * 100 / total.max(&1)
+            ),
+            Event::TransferFinished {
+                host,
+                task,
+                path,
+                bytes,
+                seconds,
+            } => println!(
+                "{task}: {host} - {path}: {} in {seconds:.1}s ({}/s)",
+                format_bytes(*bytes as f64),
+                format_bytes(*bytes as f64 / seconds.max(0.001))
+            ),
+            Event::HostUnreachable { host, task, error } => {
+                eprintln!("{task}: {host} - {}: {error}", self.status("unreachable"))
+            }
+            Event::TaskRetrying {
+                host,
+                task,
+                retries_left,
+            } => println!(
+                "{task}: {host} - {} ({retries_left} retries left)",
+                self.status("retrying")
+            ),
+            Event::HealthCheck {
+                host,
+                attempts,
+                error: None,
+            } => println!(
+                "health check: {host} - {} after {attempts} attempt(s)",
+                self.status("ok")
+            ),
+            Event::HealthCheck {
+                host,
+                attempts,
+                error: Some(error),
+            } => eprintln!(
+                "health check: {host} - {} after {attempts} attempt(s): {error}",
+                self.status("failed")
+            ),
+            Event::Recap { hosts } => self.recap(hosts),
+            Event::Warning { host, message } => eprintln!("warning: {host}: {message}"),
+            Event::PlayStarted { .. } => {}
+        }
+    }
+}
+
+// Every event as a line of JSON, with the per-host `recap` of each play, for
+// CI pipelines and other programs to read.
+pub struct JsonReporter<W> {
+    out: W,
+}
+
+impl<W: Write + Send> JsonReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write + Send> Reporter for JsonReporter<W> {
+    fn report(&mut self, event: &Event) {
+        // A reader that went away must not fail the run.
+        let _ = serde_json::to_writer(&mut self.out, event)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(self.out))
+            .and_then(|_| self.out.flush());
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return format!("{value:.1} {unit}");
+        }
+        value /= 1024.0;
+    }
+
+    format!("{value:.1} TiB")
+}
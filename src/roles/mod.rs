@@ -0,0 +1,225 @@
+use indexmap::IndexMap;
+use serde::Deserialize;
+use tera::Value;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::AnsimpleError;
+use crate::task::{include_tasks, Task};
+use crate::vault::{self, Vault};
+
+// Where roles are looked up, next to the playbook or requirements file.
+pub const ROLES_DIR: &str = "roles";
+
+// The roles and task libraries a project pulls from git, as listed in its
+// `requirements.yml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Requirements {
+    #[serde(default)]
+    pub roles: Vec<Requirement>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Requirement {
+    // The name it is installed under, by default that of the repository.
+    pub name: Option<String>,
+    // Anything `git clone` takes.
+    pub src: String,
+    // A tag, branch or commit; without one, the default branch.
+    pub version: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Installed {
+    pub name: String,
+    pub version: Option<String>,
+    pub commit: String,
+    // False when the checkout already had the version.
+    pub fetched: bool,
+}
+
+// The tasks of a role, to run before those of the play, its handlers, and the
+// defaults of its variables, which the play's own vars override.
+#[derive(Debug)]
+pub struct Role {
+    pub tasks: Vec<Task>,
+    pub handlers: Vec<Task>,
+    pub defaults: IndexMap<String, Value>,
+}
+
+impl Requirements {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AnsimpleError> {
+        vault::load(path, None)
+    }
+}
+
+impl Requirement {
+    pub fn name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => {
+                let src = self.src.trim_end_matches('/');
+                let base = src.rsplit(['/', ':']).next().unwrap_or(src);
+                base.strip_suffix(".git").unwrap_or(base)
+            }
+        }
+    }
+
+    // Clones the requirement into `roles_path`, or moves an existing checkout
+    // to its version. Checkouts already at the version are left alone, unless
+    // `update` asks to fetch again, which is how branches move forward.
+    pub fn install(&self, roles_path: &Path, update: bool) -> Result<Installed, AnsimpleError> {
+        let name = self.name();
+        let fail = |message: String| AnsimpleError::Install {
+            role: name.to_owned(),
+            message,
+        };
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(fail(format!("`{name}` is not a usable directory name")));
+        }
+        let dest = roles_path.join(name);
+
+        // Whatever else is in the way may be someone's work, so it stays.
+        if dest.exists()
+            && (!dest.join(".git").exists()
+                || git(&dest, &["remote", "get-url", "origin"]).ok().as_deref() != Some(&self.src))
+        {
+            return Err(fail(format!(
+                "{} is not a checkout of {}, move it away to install there",
+                dest.display(),
+                self.src
+            )));
+        }
+
+        let mut fetched = false;
+        if !dest.exists() {
+            // Cloned aside first, so a failed clone leaves nothing behind.
+            fs::create_dir_all(roles_path)
+                .map_err(|err| fail(format!("{}: {err}", roles_path.display())))?;
+            let staging = roles_path.join(format!(".{name}.partial"));
+            if staging.exists() {
+                fs::remove_dir_all(&staging)
+                    .map_err(|err| fail(format!("{}: {err}", staging.display())))?;
+            }
+            let staged = staging.to_string_lossy();
+            git(Path::new("."), &["clone", "--quiet", &self.src, &staged]).map_err(fail)?;
+            fs::rename(&staging, &dest)
+                .map_err(|err| fail(format!("{}: {err}", dest.display())))?;
+            fetched = true;
+        }
+
+        let commit = match &self.version {
+            Some(version) => {
+                let wanted = resolve(&dest, version);
+                let head = git(&dest, &["rev-parse", "HEAD"]).map_err(fail)?;
+                if update || wanted.as_deref() != Some(&head) {
+                    if !fetched {
+                        git(&dest, &["fetch", "--quiet", "--tags", "--force", "origin"])
+                            .map_err(fail)?;
+                        fetched = true;
+                    }
+                    let commit = resolve(&dest, version)
+                        .ok_or_else(|| fail(format!("no version `{version}` in {}", self.src)))?;
+                    git(&dest, &["checkout", "--quiet", "--detach", &commit]).map_err(fail)?;
+                }
+                git(&dest, &["rev-parse", "HEAD"]).map_err(fail)?
+            }
+            None => {
+                if update && !fetched {
+                    git(&dest, &["pull", "--quiet", "--ff-only"]).map_err(fail)?;
+                    fetched = true;
+                }
+                git(&dest, &["rev-parse", "HEAD"]).map_err(fail)?
+            }
+        };
+
+        Ok(Installed {
+            name: name.to_owned(),
+            version: self.version.clone(),
+            commit,
+            fetched,
+        })
+    }
+}
+
+// The commit `version` stands for in the checkout at `dir`, preferring the
+// remote's branches over stale local ones.
+fn resolve(dir: &Path, version: &str) -> Option<String> {
+    [format!("origin/{version}"), version.to_owned()]
+        .iter()
+        .find_map(|candidate| {
+            git(
+                dir,
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("{candidate}^{{commit}}"),
+                ],
+            )
+            .ok()
+        })
+}
+
+// Runs git in `dir` and returns what it printed, trimmed. It is never allowed
+// to prompt, as nobody may be there to answer.
+fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .map_err(|err| format!("running git: {err}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            stderr.trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+// Loads role `name` from the roles directory next to `playbook_dir`: its
+// `tasks/main.yml` and, when there are, its `handlers/main.yml` and
+// `defaults/main.yml`.
+pub fn load(playbook_dir: &Path, name: &str, vault: Option<&Vault>) -> Result<Role, AnsimpleError> {
+    let dir = playbook_dir.join(ROLES_DIR).join(name);
+    let tasks_file = dir.join("tasks").join("main.yml");
+    if !tasks_file.exists() {
+        return Err(AnsimpleError::Config(format!(
+            "role `{name}` has no {}, is it installed? Run `ansimple install`",
+            tasks_file.display()
+        )));
+    }
+
+    let defaults_file = dir.join("defaults").join("main.yml");
+    let defaults = if defaults_file.exists() {
+        vault::load_vars::<Option<IndexMap<String, Value>>, _>(&defaults_file, vault)?
+            .unwrap_or_default()
+    } else {
+        IndexMap::new()
+    };
+
+    let handlers_file = dir.join("handlers").join("main.yml");
+    let handlers = if handlers_file.exists() {
+        let handlers =
+            vault::load::<Option<Vec<Task>>, _>(&handlers_file, vault)?.unwrap_or_default();
+        include_tasks(handlers, &handlers_file, vault)?
+    } else {
+        Vec::new()
+    };
+
+    let tasks = vault::load::<Option<Vec<Task>>, _>(&tasks_file, vault)?.unwrap_or_default();
+    Ok(Role {
+        tasks: include_tasks(tasks, &tasks_file, vault)?,
+        handlers,
+        defaults,
+    })
+}
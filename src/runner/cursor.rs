@@ -0,0 +1,88 @@
+use tokio::sync::Mutex;
+
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use crate::error::AnsimpleError;
+use crate::task::Task;
+
+// Where a run starts and whether it asks before each task, shared by every
+// host and play of the run.
+#[derive(Debug, Clone, Default)]
+pub struct TaskCursor(Arc<Mutex<Cursor>>);
+
+#[derive(Debug, Default)]
+struct Cursor {
+    // The task the run starts at, until a play has it.
+    start_at: Option<String>,
+    // Tasks are confirmed one at a time, until the answer is to continue.
+    step: bool,
+}
+
+impl TaskCursor {
+    pub fn new(start_at: Option<String>, step: bool) -> Self {
+        Self(Arc::new(Mutex::new(Cursor { start_at, step })))
+    }
+
+    // The index of the first of `tasks` to run. Plays before the one with the
+    // task the run starts at run none of theirs and get `None`.
+    pub async fn start(&self, tasks: &[Task]) -> Option<usize> {
+        let mut cursor = self.0.lock().await;
+        let Some(name) = &cursor.start_at else {
+            return Some(0);
+        };
+        let index = tasks.iter().position(|task| task.to_string() == *name)?;
+        cursor.start_at = None;
+
+        Some(index)
+    }
+
+    // The task the run was to start at when no play had it.
+    pub async fn missed(&self) -> Option<String> {
+        self.0.lock().await.start_at.clone()
+    }
+
+    pub async fn stepping(&self) -> bool {
+        self.0.lock().await.step
+    }
+
+    // Whether to run `task`, asked on the terminal in a `--step` run. Hosts
+    // reaching tasks at once are asked about one after another.
+    pub async fn confirm(&self, task: &str) -> Result<bool, AnsimpleError> {
+        let mut cursor = self.0.lock().await;
+        if !cursor.step {
+            return Ok(true);
+        }
+
+        let prompt = format!("run task '{task}'? [y]es, [n]o, [c]ontinue without asking: ");
+        loop {
+            let prompt = prompt.clone();
+            let answer = tokio::task::spawn_blocking(move || ask(&prompt)).await??;
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                "c" | "continue" => {
+                    cursor.step = false;
+                    return Ok(true);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn ask(prompt: &str) -> io::Result<String> {
+    let mut stderr = io::stderr();
+    write!(stderr, "{prompt}")?;
+    stderr.flush()?;
+
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "no answer to --step on standard input",
+        ));
+    }
+
+    Ok(line)
+}
@@ -0,0 +1,234 @@
+use indexmap::IndexMap;
+use tera::Value;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::agent::AgentPool;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::connection::{HostKeyChecking, SessionPool, Workers};
+use crate::credentials::Credentials;
+use crate::error::AnsimpleError;
+use crate::events::{Event, EventSender, TaskResultEvent};
+use crate::history::Checkpoint;
+use crate::inventory::{HostConfig, Limit};
+use crate::manifest::UploadCache;
+use crate::playbook::Playbook;
+use crate::plugin::PluginRegistry;
+use crate::task::RegisteredResult;
+use crate::throttle::Throttle;
+use crate::vault::Vault;
+
+mod cursor;
+
+pub use cursor::TaskCursor;
+
+// Runs this process started, so that runs started within the same second
+// still get ids of their own.
+static RUNS: AtomicU64 = AtomicU64::new(0);
+
+// Identifies a run in the audit log and the run history.
+pub fn new_run_id() -> String {
+    format!(
+        "{}-{}-{}",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    // The id to run under, as when continuing an earlier run. Each run gets
+    // a new one otherwise.
+    pub run_id: Option<String>,
+    pub tags: Option<Vec<String>>,
+    // Plays only run on the hosts it allows.
+    pub limit: Option<Limit>,
+    pub cursor: TaskCursor,
+    pub vault: Option<Vault>,
+    pub credentials: Credentials,
+    pub audit: Option<AuditLog>,
+    pub events: Option<EventSender>,
+    pub forks: Option<usize>,
+    pub plugins: PluginRegistry,
+    pub checkpoint: Option<Checkpoint>,
+    pub agent: Option<AgentPool>,
+    // Where to reach hosts instead of port 22 of their address, by address.
+    pub connect_to: HashMap<String, SocketAddr>,
+    pub throttle: Throttle,
+    pub uploads: UploadCache,
+    // Reports what tasks would change without changing it.
+    pub check: bool,
+    // Shows how file tasks change the files they write.
+    pub diff: bool,
+    // Set for the whole run, over the vars of hosts, plays and tasks.
+    pub extra_vars: IndexMap<String, Value>,
+    pub sessions: SessionPool,
+    pub workers: Workers,
+    // Over `strict_host_key_checking` of the inventory.
+    pub host_key_checking: Option<HostKeyChecking>,
+    // The results of the play being run, for its report.
+    pub results: Results,
+}
+
+impl RunOptions {
+    pub fn emit(&self, event: Event) {
+        if let Event::TaskResult(result) = &event {
+            self.results.push(result.clone());
+        }
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+    }
+}
+
+// Collects the result of every task a play ran, on every host.
+#[derive(Debug, Clone, Default)]
+pub struct Results(Arc<Mutex<Vec<TaskResultEvent>>>);
+
+impl Results {
+    pub fn push(&self, result: TaskResultEvent) {
+        self.0.lock().expect("results lock poisoned").push(result);
+    }
+
+    pub fn take(&self) -> Vec<TaskResultEvent> {
+        std::mem::take(&mut *self.0.lock().expect("results lock poisoned"))
+    }
+}
+
+// What became of the hosts of a run. Hosts that fail end up here rather than
+// failing the run, so they do not keep the others from their plays.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    // Every host a play ran on, in the order they were first seen.
+    pub hosts: Vec<String>,
+    // The failure of each host that failed, and what aborted a play.
+    pub failures: Vec<AnsimpleError>,
+    // Every task that ran or was skipped on a host, in the order they
+    // finished, as the `TaskResult` events tell of them.
+    pub results: Vec<TaskResultEvent>,
+}
+
+impl RunReport {
+    pub fn failed(&self, host: &str) -> bool {
+        self.failures
+            .iter()
+            .any(|failure| failure.host() == Some(host))
+    }
+
+    // A play was aborted, which ends the run.
+    pub fn aborted(&self) -> bool {
+        self.failures
+            .iter()
+            .any(|failure| matches!(failure, AnsimpleError::Aborted(_)))
+    }
+
+    // What `task` registered on `host` the last time it ran there. `None`
+    // as well for `no_log` tasks.
+    pub fn registered(&self, host: &str, task: &str) -> Option<&RegisteredResult> {
+        self.results
+            .iter()
+            .rev()
+            .find(|result| result.host == host && result.task == task)
+            .and_then(|result| result.result.as_deref())
+    }
+
+    pub fn merge(&mut self, other: RunReport) {
+        for host in other.hosts {
+            if !self.hosts.contains(&host) {
+                self.hosts.push(host);
+            }
+        }
+        self.failures.extend(other.failures);
+        self.results.extend(other.results);
+    }
+
+    // Fails with a summary of the failures, if there were any.
+    pub fn into_result(self) -> Result<(), AnsimpleError> {
+        if self.failures.is_empty() {
+            Ok(())
+        } else {
+            Err(AnsimpleError::HostsFailed(self.failures))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Runner {
+    inventory: HostConfig,
+    options: RunOptions,
+}
+
+impl Runner {
+    pub fn new(inventory: HostConfig, mut options: RunOptions) -> Self {
+        if let Some(bandwidth) = inventory.global_config.max_bandwidth {
+            options.throttle = options.throttle.and(bandwidth);
+        }
+
+        Self { inventory, options }
+    }
+
+    pub fn inventory(&self) -> &HostConfig {
+        &self.inventory
+    }
+
+    pub fn options(&self) -> &RunOptions {
+        &self.options
+    }
+
+    pub async fn run(&self, playbook: &mut Playbook) -> Result<RunReport, AnsimpleError> {
+        self.run_plays(std::slice::from_mut(playbook)).await
+    }
+
+    // Runs the plays one after another. Hosts that fail sit out the plays
+    // after, and a play that cannot start ends the run with its error.
+    pub async fn run_plays(&self, plays: &mut [Playbook]) -> Result<RunReport, AnsimpleError> {
+        let Self { inventory, options } = self.for_run();
+        let mut result = Playbook::process_plays(plays, inventory, options.clone()).await;
+        if let (Ok(_), Some(task)) = (&result, options.cursor.missed().await) {
+            result = Err(AnsimpleError::Config(format!(
+                "no play has a task named '{task}' to start at"
+            )));
+        }
+
+        // What a play that could not finish did.
+        if let Some(events) = &options.events {
+            events.recap();
+        }
+
+        result
+    }
+
+    pub async fn run_file<P: AsRef<Path>>(&self, path: P) -> Result<RunReport, AnsimpleError> {
+        let path = path.as_ref();
+        let runner = self.for_run();
+        if let Some(audit) = &runner.options.audit {
+            audit.record(AuditEvent::RunStarted { playbook: path });
+        }
+
+        let mut plays = Playbook::load_plays(path, runner.options.vault.as_ref())?;
+        let result = runner.run_plays(&mut plays).await;
+
+        if let Some(audit) = &runner.options.audit {
+            audit.record(AuditEvent::RunFinished);
+        }
+
+        result
+    }
+
+    // The runner with the id of the run it is about to start, which the
+    // audit log records it under.
+    fn for_run(&self) -> Self {
+        let mut runner = self.clone();
+        let run_id = runner.options.run_id.get_or_insert_with(new_run_id);
+        if let Some(audit) = &mut runner.options.audit {
+            *audit = audit.for_run(run_id);
+        }
+
+        runner
+    }
+}
@@ -0,0 +1,313 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::error::AnsimpleError;
+use crate::schema::{self, Generator, Schema};
+
+// Runs task `index` for one host, handing the host's state back afterwards.
+pub type Step<S> = Pin<Box<dyn Future<Output = (S, Result<(), AnsimpleError>)> + Send>>;
+
+// Checks the hosts that got through a batch and returns their failures.
+pub type Check = Pin<Box<dyn Future<Output = Vec<AnsimpleError>> + Send>>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    // Every host of a batch finishes a task before any host starts the next.
+    Linear,
+    // Every host works through its tasks as fast as it can.
+    #[default]
+    Free,
+}
+
+impl Schema for Strategy {
+    fn schema(_: &mut Generator) -> serde_json::Value {
+        schema::names(&["linear", "free"])
+    }
+}
+
+// How many hosts a batch has: a number of them, or a percentage of the
+// play's hosts, rounded down but at least one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Serial {
+    Hosts(usize),
+    Percent(f64),
+}
+
+impl Serial {
+    pub fn batch_size(&self, hosts: usize) -> usize {
+        match *self {
+            Serial::Hosts(0) => usize::MAX,
+            Serial::Hosts(count) => count,
+            Serial::Percent(percent) => ((hosts as f64 * percent / 100.0) as usize).max(1),
+        }
+    }
+}
+
+impl Serialize for Serial {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Serial::Hosts(count) => serializer.serialize_u64(*count as u64),
+            Serial::Percent(percent) => serializer.serialize_str(&format!("{percent}%")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Serial {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Hosts(usize),
+            Text(String),
+        }
+
+        match Written::deserialize(deserializer)? {
+            Written::Hosts(count) => Ok(Serial::Hosts(count)),
+            Written::Text(text) => text
+                .trim()
+                .strip_suffix('%')
+                .and_then(|percent| percent.trim().parse::<f64>().ok())
+                .filter(|percent| *percent > 0.0 && *percent <= 100.0)
+                .map(Serial::Percent)
+                .ok_or_else(|| {
+                    de::Error::custom(format!(
+                        "`{text}` is neither a number of hosts nor a percentage such as \"25%\""
+                    ))
+                }),
+        }
+    }
+}
+
+impl Schema for Serial {
+    fn schema(_: &mut Generator) -> serde_json::Value {
+        json!({
+            "oneOf": [
+                { "type": "integer", "minimum": 0 },
+                { "type": "string", "pattern": "^[0-9]+(\\.[0-9]+)?%$" },
+            ]
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    pub forks: Option<usize>,
+    pub serial: Option<Serial>,
+    pub strategy: Strategy,
+    pub any_errors_fatal: bool,
+    pub max_fail_percentage: Option<f64>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    size: usize,
+    failed: AtomicUsize,
+    abort: Mutex<Option<String>>,
+}
+
+impl Batch {
+    fn aborted(&self) -> bool {
+        self.abort.lock().expect("abort lock poisoned").is_some()
+    }
+}
+
+impl Scheduler {
+    // Runs `tasks` steps on every host, batch by batch, and returns the
+    // failures. Hosts stop at their first failing task. The hosts that got
+    // through a batch are handed to `check`, and any failure it finds ends
+    // the rollout before the next batch.
+    pub async fn run<S, F, C>(
+        &self,
+        hosts: Vec<S>,
+        tasks: usize,
+        step: F,
+        check: C,
+    ) -> Vec<AnsimpleError>
+    where
+        S: Send + 'static,
+        F: Fn(S, usize) -> Step<S> + Send + Sync + 'static,
+        C: Fn(Vec<S>) -> Check,
+    {
+        let step = Arc::new(step);
+        let forks = Arc::new(Semaphore::new(
+            self.forks.unwrap_or(Semaphore::MAX_PERMITS).max(1),
+        ));
+        let batch_size = self
+            .serial
+            .map_or(usize::MAX, |serial| serial.batch_size(hosts.len()));
+
+        let mut failures = Vec::new();
+        let mut hosts = hosts.into_iter().peekable();
+        while hosts.peek().is_some() {
+            let members = hosts.by_ref().take(batch_size).collect::<Vec<S>>();
+            let batch = Arc::new(Batch {
+                size: members.len(),
+                failed: AtomicUsize::new(0),
+                abort: Mutex::new(None),
+            });
+
+            let (batch_failures, passed) = match self.strategy {
+                Strategy::Linear => self.run_linear(members, tasks, &step, &forks, &batch).await,
+                Strategy::Free => self.run_free(members, tasks, &step, &forks, &batch).await,
+            };
+            failures.extend(batch_failures);
+
+            let failed = batch.failed.load(Ordering::SeqCst);
+            let mut abort = batch.abort.lock().expect("abort lock poisoned").take();
+            if abort.is_none() && failed == batch.size && hosts.peek().is_some() {
+                abort = Some("every host of the batch failed".to_owned());
+            }
+            if let Some(reason) = abort {
+                failures.push(AnsimpleError::Aborted(reason));
+                break;
+            }
+
+            if passed.is_empty() {
+                continue;
+            }
+            let checked = passed.len();
+            let unhealthy = check(passed).await;
+            if !unhealthy.is_empty() {
+                let count = unhealthy.len();
+                failures.extend(unhealthy);
+                if hosts.peek().is_some() {
+                    failures.push(AnsimpleError::Aborted(format!(
+                        "the health check failed on {count} of {checked} host(s) of the batch"
+                    )));
+                    break;
+                }
+            }
+        }
+
+        failures
+    }
+
+    async fn run_linear<S, F>(
+        &self,
+        members: Vec<S>,
+        tasks: usize,
+        step: &Arc<F>,
+        forks: &Arc<Semaphore>,
+        batch: &Arc<Batch>,
+    ) -> (Vec<AnsimpleError>, Vec<S>)
+    where
+        S: Send + 'static,
+        F: Fn(S, usize) -> Step<S> + Send + Sync + 'static,
+    {
+        let mut failures = Vec::new();
+        let mut live = members;
+        for index in 0..tasks {
+            if live.is_empty() || batch.aborted() {
+                break;
+            }
+
+            let handles = live
+                .into_iter()
+                .map(|host| {
+                    let step = step.clone();
+                    let forks = forks.clone();
+                    task::spawn(async move {
+                        let _permit = forks.acquire_owned().await.expect("forks closed");
+                        step(host, index).await
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            live = Vec::new();
+            for handle in handles {
+                match handle.await {
+                    Ok((host, Ok(()))) => live.push(host),
+                    Ok((_, Err(err))) => failures.push(self.record_failure(batch, err)),
+                    Err(err) => failures.push(self.record_failure(batch, err.into())),
+                }
+            }
+        }
+
+        (failures, live)
+    }
+
+    async fn run_free<S, F>(
+        &self,
+        members: Vec<S>,
+        tasks: usize,
+        step: &Arc<F>,
+        forks: &Arc<Semaphore>,
+        batch: &Arc<Batch>,
+    ) -> (Vec<AnsimpleError>, Vec<S>)
+    where
+        S: Send + 'static,
+        F: Fn(S, usize) -> Step<S> + Send + Sync + 'static,
+    {
+        let handles = members
+            .into_iter()
+            .map(|mut host| {
+                let scheduler = self.clone();
+                let step = step.clone();
+                let forks = forks.clone();
+                let batch = batch.clone();
+                task::spawn(async move {
+                    let _permit = forks.acquire_owned().await.expect("forks closed");
+                    for index in 0..tasks {
+                        if batch.aborted() {
+                            break;
+                        }
+
+                        let (next, result) = step(host, index).await;
+                        if let Err(err) = result {
+                            return Err(scheduler.record_failure(&batch, err));
+                        }
+                        host = next;
+                    }
+
+                    Ok(host)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut failures = Vec::new();
+        let mut passed = Vec::new();
+        for handle in handles {
+            match handle.await {
+                Ok(Ok(host)) => passed.push(host),
+                Ok(Err(err)) => failures.push(err),
+                Err(err) => failures.push(self.record_failure(batch, err.into())),
+            }
+        }
+
+        (failures, passed)
+    }
+
+    fn record_failure(&self, batch: &Batch, err: AnsimpleError) -> AnsimpleError {
+        let failed = batch.failed.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(reason) = self.abort_reason(failed, batch.size) {
+            batch
+                .abort
+                .lock()
+                .expect("abort lock poisoned")
+                .get_or_insert(reason);
+        }
+
+        err
+    }
+
+    fn abort_reason(&self, failed: usize, size: usize) -> Option<String> {
+        if self.any_errors_fatal {
+            return Some("a host failed and any_errors_fatal is set".to_owned());
+        }
+
+        match self.max_fail_percentage {
+            Some(max) if failed as f64 * 100.0 / size as f64 > max => Some(format!(
+                "{failed} of {size} host(s) failed, more than max_fail_percentage {max}%"
+            )),
+            _ => None,
+        }
+    }
+}
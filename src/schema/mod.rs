@@ -0,0 +1,175 @@
+use indexmap::IndexMap;
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::inventory::HostConfig;
+use crate::playbook::Playbook;
+
+const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+// The JSON Schema of what a type accepts when read from YAML, for editors to
+// complete and check the files ansimple reads. Types that implement it spell
+// their fields the way serde does.
+pub trait Schema {
+    // Named schemas are added to the definitions once and referred to.
+    fn name() -> Option<&'static str> {
+        None
+    }
+
+    fn schema(generator: &mut Generator) -> Value;
+}
+
+#[derive(Debug, Default)]
+pub struct Generator {
+    definitions: Map<String, Value>,
+}
+
+// An object schema, built field by field.
+pub struct Object<'a> {
+    generator: &'a mut Generator,
+    properties: Map<String, Value>,
+    required: Vec<String>,
+}
+
+impl Generator {
+    pub fn subschema<T: Schema>(&mut self) -> Value {
+        let Some(name) = T::name() else {
+            return T::schema(self);
+        };
+        if !self.definitions.contains_key(name) {
+            // Claimed first, so types that contain themselves terminate.
+            self.definitions.insert(name.to_owned(), Value::Bool(true));
+            let schema = T::schema(self);
+            self.definitions.insert(name.to_owned(), schema);
+        }
+
+        json!({ "$ref": format!("#/definitions/{name}") })
+    }
+
+    pub fn object(&mut self) -> Object<'_> {
+        Object {
+            generator: self,
+            properties: Map::new(),
+            required: Vec::new(),
+        }
+    }
+
+    // `schema` as a document of its own, with everything it refers to.
+    fn document(mut self, title: &str, schema: Value) -> Value {
+        let mut document = Map::new();
+        document.insert("$schema".to_owned(), DRAFT.into());
+        document.insert("title".to_owned(), title.into());
+        if let Value::Object(schema) = schema {
+            document.extend(schema);
+        }
+        document.insert(
+            "definitions".to_owned(),
+            Value::Object(std::mem::take(&mut self.definitions)),
+        );
+
+        Value::Object(document)
+    }
+}
+
+impl Object<'_> {
+    pub fn required<T: Schema>(self, name: &str) -> Self {
+        let schema = self.generator.subschema::<T>();
+        self.property(name, schema, true)
+    }
+
+    pub fn optional<T: Schema>(self, name: &str) -> Self {
+        let schema = self.generator.subschema::<T>();
+        self.property(name, schema, false)
+    }
+
+    pub fn property(mut self, name: &str, schema: Value, required: bool) -> Self {
+        if required {
+            self.required.push(name.to_owned());
+        }
+        self.properties.insert(name.to_owned(), schema);
+        self
+    }
+
+    // Unknown keys are rejected, as everywhere ansimple reads strictly.
+    pub fn build(self) -> Value {
+        json!({
+            "type": "object",
+            "properties": self.properties,
+            "required": self.required,
+            "additionalProperties": false,
+        })
+    }
+}
+
+// A string that is one of `names`, as unit enum variants are written.
+pub fn names(names: &[&str]) -> Value {
+    json!({ "type": "string", "enum": names })
+}
+
+// A playbook file holds a play or a list of plays.
+pub fn playbook() -> Value {
+    let mut generator = Generator::default();
+    let play = generator.subschema::<Playbook>();
+    let schema = json!({
+        "oneOf": [play, { "type": "array", "items": play }],
+    });
+
+    generator.document("ansimple playbook", schema)
+}
+
+pub fn host_config() -> Value {
+    let mut generator = Generator::default();
+    let schema = generator.subschema::<HostConfig>();
+
+    generator.document("ansimple host config", schema)
+}
+
+macro_rules! primitive {
+    ($($type:ty => $schema:tt),* $(,)?) => {
+        $(impl Schema for $type {
+            fn schema(_: &mut Generator) -> Value {
+                json!($schema)
+            }
+        })*
+    };
+}
+
+primitive! {
+    String => { "type": "string" },
+    PathBuf => { "type": "string" },
+    bool => { "type": "boolean" },
+    i32 => { "type": "integer" },
+    u16 => { "type": "integer", "minimum": 0, "maximum": 65535 },
+    u32 => { "type": "integer", "minimum": 0 },
+    u64 => { "type": "integer", "minimum": 0 },
+    usize => { "type": "integer", "minimum": 0 },
+    f64 => { "type": "number" },
+    // Variables and plugin arguments can be anything.
+    Value => true,
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn schema(generator: &mut Generator) -> Value {
+        generator.subschema::<T>()
+    }
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema(generator: &mut Generator) -> Value {
+        json!({ "type": "array", "items": generator.subschema::<T>() })
+    }
+}
+
+impl<T: Schema> Schema for HashMap<String, T> {
+    fn schema(generator: &mut Generator) -> Value {
+        json!({ "type": "object", "additionalProperties": generator.subschema::<T>() })
+    }
+}
+
+impl<T: Schema> Schema for IndexMap<String, T> {
+    fn schema(generator: &mut Generator) -> Value {
+        json!({ "type": "object", "additionalProperties": generator.subschema::<T>() })
+    }
+}
@@ -0,0 +1,55 @@
+use std::borrow::Cow;
+use std::sync::RwLock;
+
+pub const MASK: &str = "****";
+
+static DESIGNATED: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static TRACKED: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+// Names may end in `*` to designate every variable with that prefix. Names
+// designated before stay designated.
+pub fn designate(names: Vec<String>) {
+    let mut designated = DESIGNATED.write().expect("secrets lock poisoned");
+    for name in names {
+        if !designated.contains(&name) {
+            designated.push(name);
+        }
+    }
+}
+
+pub fn is_designated(name: &str) -> bool {
+    let designated = DESIGNATED.read().expect("secrets lock poisoned");
+    designated
+        .iter()
+        .any(|designated| match designated.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == designated,
+        })
+}
+
+pub fn track(value: &str) {
+    if value.is_empty() {
+        return;
+    }
+
+    let mut tracked = TRACKED.write().expect("secrets lock poisoned");
+    if !tracked.iter().any(|secret| secret == value) {
+        tracked.push(value.to_owned());
+        // mask longer secrets first so one containing another is fully hidden
+        tracked.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+}
+
+pub fn mask(text: &str) -> Cow<'_, str> {
+    let tracked = TRACKED.read().expect("secrets lock poisoned");
+    if !tracked.iter().any(|secret| text.contains(secret.as_str())) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut masked = text.to_owned();
+    for secret in tracked.iter() {
+        masked = masked.replace(secret.as_str(), MASK);
+    }
+
+    Cow::Owned(masked)
+}
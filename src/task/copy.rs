@@ -0,0 +1,294 @@
+use chrono::Local;
+use serde::de::value::{StringDeserializer, U64Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tera::Value;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::file::Mode;
+use super::holds;
+use crate::connection::{self, quote, Connection, FileKind};
+use crate::error::AnsimpleError;
+use crate::manifest::Placed;
+use crate::schema::{Generator, Schema};
+
+// The mode copied files get: octal bits, or `preserve` for those of the file
+// they are a copy of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMode {
+    Preserve,
+    Bits(Mode),
+}
+
+impl Serialize for CopyMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            CopyMode::Preserve => serializer.serialize_str("preserve"),
+            CopyMode::Bits(mode) => mode.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CopyMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Number(u64),
+            Text(String),
+        }
+
+        match Written::deserialize(deserializer)? {
+            Written::Text(text) if text == "preserve" => Ok(CopyMode::Preserve),
+            Written::Text(text) => {
+                Mode::deserialize(StringDeserializer::new(text)).map(CopyMode::Bits)
+            }
+            Written::Number(number) => {
+                Mode::deserialize(U64Deserializer::new(number)).map(CopyMode::Bits)
+            }
+        }
+    }
+}
+
+impl Schema for CopyMode {
+    fn schema(generator: &mut Generator) -> Value {
+        json!({ "oneOf": [{ "const": "preserve" }, generator.subschema::<Mode>()] })
+    }
+}
+
+impl CopyMode {
+    // The bits for a copy of `src`, whose own are found by `of_src`.
+    pub fn bits(
+        self,
+        of_src: impl FnOnce() -> Result<u32, AnsimpleError>,
+    ) -> Result<u32, AnsimpleError> {
+        match self {
+            CopyMode::Bits(Mode(bits)) => Ok(bits),
+            CopyMode::Preserve => of_src(),
+        }
+    }
+}
+
+// The permission bits of a local file.
+pub fn local_mode(path: &Path) -> Result<u32, AnsimpleError> {
+    fs::metadata(path)
+        .map(|metadata| metadata.mode() & 0o7777)
+        .map_err(|source| AnsimpleError::Read {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+// Gives `path` on the host `mode` unless it has it already. Returns whether
+// it did, or would have in a check run.
+pub fn chmod(
+    connection: &mut dyn Connection,
+    path: &Path,
+    mode: u32,
+    check: bool,
+) -> Result<bool, AnsimpleError> {
+    if connection.stat(path)?.is_some_and(|stat| stat.mode == mode) {
+        return Ok(false);
+    }
+    if !check {
+        run(
+            connection,
+            &format!("chmod {mode:04o} -- {}", quote(&path.to_string_lossy())),
+        )?;
+    }
+
+    Ok(true)
+}
+
+// Copies `path` on the host to a file next to it named for when it was
+// replaced, such as `app.conf.2024-05-01@12:30:00~`. `None` when there is
+// nothing to keep.
+pub fn backup(
+    connection: &mut dyn Connection,
+    path: &Path,
+) -> Result<Option<String>, AnsimpleError> {
+    if !connection.exists(path)? {
+        return Ok(None);
+    }
+    let backup = format!(
+        "{}.{}~",
+        path.display(),
+        Local::now().format("%Y-%m-%d@%H:%M:%S")
+    );
+    connection.copy(path, Path::new(&backup))?;
+
+    Ok(Some(backup))
+}
+
+pub struct Tree {
+    pub mode: Option<CopyMode>,
+    pub backup: bool,
+    pub check: bool,
+}
+
+// What copying a tree did: whether anything changed, the bytes sent and a
+// SHA-256 over the checksums of all files, as for trees sent with tar.
+pub struct Copied {
+    pub changed: bool,
+    pub bytes: u64,
+    pub checksum: String,
+}
+
+impl Tree {
+    // Copies the local directory `src` into `dest` on the host file by file.
+    // Only files that differ are sent, so copying a tree again changes
+    // nothing. Symlinks are made again as they are.
+    pub fn copy(
+        &self,
+        connection: &mut dyn Connection,
+        src: &Path,
+        dest: &Path,
+    ) -> Result<Copied, AnsimpleError> {
+        let mut entries = Vec::new();
+        walk(src, Path::new(""), &mut entries).map_err(|source| AnsimpleError::Read {
+            path: src.to_owned(),
+            source,
+        })?;
+
+        let mut copied = Copied {
+            changed: self.directory(connection, dest)?,
+            bytes: 0,
+            checksum: String::new(),
+        };
+        let mut manifest = Sha256::new();
+        for (name, local) in entries {
+            let path = src.join(&name);
+            let remote = dest.join(&name);
+            let changed = match local {
+                Entry::Directory => self.directory(connection, &remote)?,
+                Entry::Symlink(target) => self.symlink(connection, &remote, &target)?,
+                Entry::File => {
+                    let content = Placed::of(&path)?;
+                    manifest.update(format!("{}  {}\n", content.sha256, name.display()));
+                    let sent = !holds(connection, &remote, &content)?;
+                    if sent && !self.check {
+                        if self.backup {
+                            backup(connection, &remote)?;
+                        }
+                        copied.bytes +=
+                            connection::upload(connection, &path, &remote, &mut |_| {})?.0;
+                    }
+                    let moded = match self.mode {
+                        Some(mode) => {
+                            let mode = mode.bits(|| local_mode(&path))?;
+                            chmod(connection, &remote, mode, self.check)?
+                        }
+                        None => false,
+                    };
+                    sent || moded
+                }
+            };
+            copied.changed |= changed;
+        }
+        copied.checksum = format!("{:x}", manifest.finalize());
+
+        Ok(copied)
+    }
+
+    fn directory(
+        &self,
+        connection: &mut dyn Connection,
+        path: &Path,
+    ) -> Result<bool, AnsimpleError> {
+        match connection.stat(path)? {
+            Some(stat) if stat.kind == FileKind::Directory => Ok(false),
+            Some(_) => Err(AnsimpleError::Config(format!(
+                "{} exists and is not a directory",
+                path.display()
+            ))),
+            None => {
+                if !self.check {
+                    run(
+                        connection,
+                        &format!("mkdir -p -- {}", quote(&path.to_string_lossy())),
+                    )?;
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    fn symlink(
+        &self,
+        connection: &mut dyn Connection,
+        path: &Path,
+        target: &str,
+    ) -> Result<bool, AnsimpleError> {
+        if let Some(stat) = connection.stat(path)? {
+            if stat.kind
+                == (FileKind::Symlink {
+                    target: target.to_owned(),
+                })
+            {
+                return Ok(false);
+            }
+        }
+        if !self.check {
+            run(
+                connection,
+                &format!(
+                    "ln -sfn -- {} {}",
+                    quote(target),
+                    quote(&path.to_string_lossy())
+                ),
+            )?;
+        }
+
+        Ok(true)
+    }
+}
+
+enum Entry {
+    Directory,
+    File,
+    Symlink(String),
+}
+
+// Everything under `dir`, by its path below the tree's root, parents before
+// what they hold and in name order. Sockets, fifos and devices have nothing
+// to copy.
+fn walk(dir: &Path, prefix: &Path, entries: &mut Vec<(PathBuf, Entry)>) -> std::io::Result<()> {
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    names.sort();
+
+    for name in names {
+        let path = dir.join(&name);
+        let relative = prefix.join(&name);
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(&path)?.to_string_lossy().into_owned();
+            entries.push((relative, Entry::Symlink(target)));
+        } else if file_type.is_dir() {
+            entries.push((relative.clone(), Entry::Directory));
+            walk(&path, &relative, entries)?;
+        } else if file_type.is_file() {
+            entries.push((relative, Entry::File));
+        }
+    }
+
+    Ok(())
+}
+
+fn run(connection: &mut dyn Connection, command: &str) -> Result<(), AnsimpleError> {
+    let (_, stderr, rc) = connection.exec(command)?;
+    if rc != 0 {
+        return Err(AnsimpleError::Command {
+            command: command.to_owned(),
+            rc,
+            stderr: stderr.trim().to_owned(),
+        });
+    }
+
+    Ok(())
+}
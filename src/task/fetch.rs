@@ -0,0 +1,124 @@
+use sha2::{Digest, Sha256};
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use super::sha256_hex;
+use crate::connection::{partial_path, Connection};
+use crate::error::AnsimpleError;
+use crate::inventory::Host;
+use crate::platform::Platform;
+
+// Where `src` of `host` is put: `dest/<host>/<src>`, or `dest` itself when
+// `flat`, with the name of `src` added when `dest` ends with a slash.
+pub fn local_path(
+    host: &Host,
+    src: &str,
+    dest: &str,
+    flat: bool,
+) -> Result<PathBuf, AnsimpleError> {
+    let src = match host.platform() {
+        Platform::Posix => src.to_owned(),
+        Platform::Windows => src.replace('\\', "/"),
+    };
+    let mut parts = Vec::new();
+    for component in Path::new(&src).components() {
+        match component {
+            // Drive letters are kept as a directory of their own.
+            Component::Normal(part) => {
+                parts.push(part.to_string_lossy().trim_end_matches(':').to_owned())
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => {
+                return Err(AnsimpleError::Config(format!(
+                    "`{src}` leaves its directory, fetch it by a path without `..`"
+                )))
+            }
+        }
+    }
+    let Some(name) = parts.last().filter(|name| !name.is_empty()) else {
+        return Err(AnsimpleError::Config(format!(
+            "`{src}` names no file to fetch"
+        )));
+    };
+
+    let mut path = PathBuf::from(dest);
+    if !flat {
+        path.push(&host.address);
+        path.extend(parts.iter().filter(|part| !part.is_empty()));
+    } else if dest.ends_with('/') {
+        path.push(name);
+    }
+
+    Ok(path)
+}
+
+// Downloads `src` from the host to `local` unless that already holds it, or
+// only finds out whether it does in a check run. Returns whether it changed,
+// the SHA-256 of `src` and the number of bytes downloaded.
+pub fn fetch(
+    connection: &mut dyn Connection,
+    src: &Path,
+    local: &Path,
+    check: bool,
+) -> Result<(bool, String, u64), AnsimpleError> {
+    let Some(checksum) = connection.checksum(src)? else {
+        return Err(AnsimpleError::Transfer(format!(
+            "{} does not exist on the host",
+            src.display()
+        )));
+    };
+    if local_checksum(local)?.as_ref() == Some(&checksum) {
+        return Ok((false, checksum, 0));
+    }
+    if check {
+        return Ok((true, checksum, 0));
+    }
+
+    let contents = connection.read(src)?;
+    if sha256_hex(&contents) != checksum {
+        return Err(AnsimpleError::Transfer(format!(
+            "{} changed while it was fetched",
+            src.display()
+        )));
+    }
+
+    // Like uploads, the file only takes its name once it is complete.
+    let write_error = |source| AnsimpleError::Write {
+        path: local.to_owned(),
+        source,
+    };
+    if let Some(parent) = local
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).map_err(write_error)?;
+    }
+    let partial = partial_path(local);
+    fs::write(&partial, &contents).map_err(write_error)?;
+    fs::rename(&partial, local).map_err(write_error)?;
+
+    Ok((true, checksum, contents.len() as u64))
+}
+
+// `None` when there is no such file yet.
+fn local_checksum(path: &Path) -> Result<Option<String>, AnsimpleError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => {
+            return Err(AnsimpleError::Read {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|source| AnsimpleError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
@@ -0,0 +1,228 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use tera::Value;
+
+use std::path::Path;
+
+use crate::connection::{quote, Connection, FileKind, FileStat};
+use crate::error::AnsimpleError;
+use crate::schema::{self, Generator, Schema};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    // Already there, only its mode and ownership are managed.
+    #[default]
+    File,
+    // Created empty when missing.
+    Touch,
+    Directory,
+    // Removed, directories with all they hold.
+    Absent,
+    // A symlink to `src`.
+    Link,
+}
+
+impl Schema for FileState {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["file", "touch", "directory", "absent", "link"])
+    }
+}
+
+// Permission bits, written in octal. An unquoted `0644` reads as the number
+// 644, which means the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode(pub u32);
+
+impl Serialize for Mode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Number(u64),
+            Text(String),
+        }
+
+        let text = match Written::deserialize(deserializer)? {
+            Written::Number(number) => number.to_string(),
+            Written::Text(text) => text,
+        };
+        let digits = text.trim().trim_start_matches("0o");
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(Mode(mode)),
+            _ => Err(de::Error::custom(format!(
+                "`{text}` is not an octal mode such as \"0644\""
+            ))),
+        }
+    }
+}
+
+impl Schema for Mode {
+    fn schema(_: &mut Generator) -> Value {
+        json!({
+            "oneOf": [
+                { "type": "string", "pattern": "^(0o)?[0-7]{1,4}$" },
+                { "type": "integer", "minimum": 0 },
+            ]
+        })
+    }
+}
+
+pub struct Wanted<'a> {
+    pub state: FileState,
+    pub src: Option<&'a str>,
+    pub mode: Option<Mode>,
+    pub owner: Option<&'a str>,
+    pub group: Option<&'a str>,
+}
+
+// Brings `path` to the wanted state, or only finds out whether it is there in
+// a check run. Returns whether anything changed and what was done.
+pub fn ensure(
+    connection: &mut dyn Connection,
+    path: &Path,
+    wanted: &Wanted,
+    check: bool,
+) -> Result<(bool, String), AnsimpleError> {
+    let quoted = quote(&path.to_string_lossy());
+    let current = connection.stat(path)?;
+
+    let create = match (wanted.state, &current) {
+        (FileState::Absent, None) => return Ok((false, String::new())),
+        (FileState::Absent, Some(_)) => {
+            return run(connection, vec![format!("rm -rf -- {quoted}")], check)
+        }
+        (FileState::File, None) => {
+            return Err(AnsimpleError::Config(format!(
+                "{} does not exist, `state: touch` creates it",
+                path.display()
+            )))
+        }
+        (FileState::Touch, None) => Some(format!("touch -- {quoted}")),
+        (FileState::Directory, None) => Some(format!("mkdir -p -- {quoted}")),
+        (FileState::Directory, Some(stat)) if stat.kind != FileKind::Directory => {
+            return Err(AnsimpleError::Config(format!(
+                "{} exists and is not a directory",
+                path.display()
+            )))
+        }
+        (FileState::Link, current) => {
+            let src = wanted.src.ok_or_else(|| {
+                AnsimpleError::Config("`state: link` needs the `src` to link to".to_owned())
+            })?;
+            match current {
+                Some(FileStat {
+                    kind: FileKind::Symlink { target },
+                    ..
+                }) if target == src => None,
+                None
+                | Some(FileStat {
+                    kind: FileKind::Symlink { .. },
+                    ..
+                }) => Some(format!("ln -sfn -- {} {quoted}", quote(src))),
+                Some(_) => {
+                    return Err(AnsimpleError::Config(format!(
+                        "{} exists and is not a symlink",
+                        path.display()
+                    )))
+                }
+            }
+        }
+        (FileState::File | FileState::Touch | FileState::Directory, Some(_)) => None,
+    };
+
+    // What a new file or link looks like is only known once it is there.
+    let (created, stat) = match create {
+        Some(command) if check => return Ok((true, command)),
+        Some(command) => {
+            run(connection, vec![command.clone()], false)?;
+            let stat = connection.stat(path)?.ok_or_else(|| {
+                AnsimpleError::Config(format!("{} was not created", path.display()))
+            })?;
+            (Some(command), stat)
+        }
+        None => (None, current.expect("only missing files are created")),
+    };
+
+    // The mode of a symlink is that of what it points to, and its owner is
+    // changed rather than that of the target.
+    let link = wanted.state == FileState::Link;
+    let mut commands = Vec::new();
+    if let Some(Mode(mode)) = wanted.mode.filter(|_| !link) {
+        if stat.mode != mode {
+            commands.push(format!("chmod {mode:04o} -- {quoted}"));
+        }
+    }
+    let no_follow = if link { "-h " } else { "" };
+    if let Some(owner) = wanted.owner {
+        if stat.uid != id(connection, owner, false)? {
+            commands.push(format!("chown {no_follow}{} -- {quoted}", quote(owner)));
+        }
+    }
+    if let Some(group) = wanted.group {
+        if stat.gid != id(connection, group, true)? {
+            commands.push(format!("chgrp {no_follow}{} -- {quoted}", quote(group)));
+        }
+    }
+
+    let changed = created.is_some() || !commands.is_empty();
+    let (_, done) = run(connection, commands, check)?;
+    let done = created
+        .into_iter()
+        .chain(Some(done).filter(|done| !done.is_empty()));
+    Ok((changed, done.collect::<Vec<_>>().join("\n")))
+}
+
+// Runs `commands` one after the other, unless in a check run. Returns
+// whether there were any and what they were.
+fn run(
+    connection: &mut dyn Connection,
+    commands: Vec<String>,
+    check: bool,
+) -> Result<(bool, String), AnsimpleError> {
+    if !check {
+        for command in &commands {
+            let (_, stderr, rc) = connection.exec(command)?;
+            if rc != 0 {
+                return Err(AnsimpleError::Command {
+                    command: command.clone(),
+                    rc,
+                    stderr: stderr.trim().to_owned(),
+                });
+            }
+        }
+    }
+
+    Ok((!commands.is_empty(), commands.join("\n")))
+}
+
+// The numeric id of a user or group on the host, which may be given by it.
+fn id(connection: &mut dyn Connection, name: &str, group: bool) -> Result<u32, AnsimpleError> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let quoted = quote(name);
+    let command = if group {
+        format!(
+            "{{ getent group {quoted} 2>/dev/null || grep \"^\"{quoted}: /etc/group; }} | cut -d: -f3"
+        )
+    } else {
+        format!("id -u {quoted}")
+    };
+    let (stdout, _, _) = connection.exec(&command)?;
+
+    stdout
+        .lines()
+        .next()
+        .and_then(|id| id.trim().parse().ok())
+        .ok_or_else(|| {
+            let kind = if group { "group" } else { "user" };
+            AnsimpleError::Config(format!("no {kind} `{name}` on the host"))
+        })
+}
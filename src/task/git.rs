@@ -0,0 +1,243 @@
+use crate::connection::{quote, Connection};
+use crate::error::AnsimpleError;
+
+// Where a checkout was before the task and where it is after, or would be
+// in a check run.
+pub struct Checkout {
+    pub changed: bool,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub output: String,
+}
+
+// Clones `repo` into `dest` when it is not there, and otherwise fetches it and
+// checks out `version`: a branch, a tag or a commit, the remote's default
+// branch when not given. Branches are checked out as they are on the remote.
+// Local changes fail the task unless `force` discards them. Only moving HEAD
+// is a change; with `check` nothing is fetched or checked out.
+pub fn ensure(
+    connection: &mut dyn Connection,
+    repo: &str,
+    dest: &str,
+    version: Option<&str>,
+    force: bool,
+    check: bool,
+) -> Result<Checkout, AnsimpleError> {
+    if connection.exec("command -v git")?.2 != 0 {
+        return Err(AnsimpleError::Config(
+            "git is not installed on the host".to_owned(),
+        ));
+    }
+    let git = format!("git -C {}", quote(dest));
+    let mut output = String::new();
+
+    // Git would otherwise find the checkout of a directory `dest` is in.
+    let checkout = format!("{dest}/.git");
+    let before = if connection.exec(&format!("test -e {}", quote(&checkout)))?.2 == 0 {
+        commit(
+            connection,
+            &format!("{git} rev-parse --verify --quiet HEAD"),
+        )?
+    } else {
+        let (entries, _, rc) = connection.exec(&format!("ls -A {}", quote(dest)))?;
+        if rc == 0 && !entries.trim().is_empty() {
+            return Err(AnsimpleError::Config(format!(
+                "{dest} exists and is not a git checkout"
+            )));
+        }
+        None
+    };
+    let Some(before) = before else {
+        let after = match check {
+            true => remote_commit(connection, "git", &quote(repo), version)?,
+            false => {
+                run(
+                    connection,
+                    &format!("git clone --quiet {} {}", quote(repo), quote(dest)),
+                    &mut output,
+                )?;
+                if let Some(version) = version {
+                    run(
+                        connection,
+                        &format!("{git} checkout --quiet {}", quote(version)),
+                        &mut output,
+                    )?;
+                }
+                commit(connection, &format!("{git} rev-parse HEAD"))?
+            }
+        };
+        return Ok(Checkout {
+            changed: true,
+            before: None,
+            after,
+            output,
+        });
+    };
+
+    let (modified, _, _) =
+        connection.exec(&format!("{git} status --porcelain --untracked-files=no"))?;
+    if !modified.trim().is_empty() && !force {
+        return Err(AnsimpleError::Config(format!(
+            "{dest} has local changes, set `force: true` to discard them"
+        )));
+    }
+
+    if check {
+        let after = remote_commit(connection, &git, "origin", version)?;
+        let after = match (after, version) {
+            (Some(after), _) => Some(after),
+            // Commits are not listed by the remote, but may be here already.
+            (None, Some(version)) => commit(
+                connection,
+                &format!(
+                    "{git} rev-parse --verify --quiet {}",
+                    quote(&format!("{version}^{{commit}}"))
+                ),
+            )?,
+            (None, None) => None,
+        };
+        return Ok(Checkout {
+            changed: after.as_deref() != Some(before.as_str()),
+            before: Some(before),
+            after,
+            output,
+        });
+    }
+
+    run(
+        connection,
+        &format!("{git} fetch --quiet --tags --force origin"),
+        &mut output,
+    )?;
+    // Branches are followed as the remote has them, anything else is checked
+    // out as it is.
+    let branch = match version {
+        Some(version) => commit(
+            connection,
+            &format!(
+                "{git} rev-parse --verify --quiet {}",
+                quote(&format!("refs/remotes/origin/{version}"))
+            ),
+        )?
+        .map(|_| version.to_owned()),
+        None => Some(default_branch(connection, &git, dest)?),
+    };
+    let (target, checkout) = match branch {
+        Some(branch) => {
+            let remote = format!("origin/{branch}");
+            (
+                format!("{remote}^{{commit}}"),
+                format!("-B {} {}", quote(&branch), quote(&remote)),
+            )
+        }
+        None => {
+            let target = format!("{}^{{commit}}", version.unwrap_or("HEAD"));
+            let checkout = format!("--detach {}", quote(&target));
+            (target, checkout)
+        }
+    };
+    let after = commit(
+        connection,
+        &format!("{git} rev-parse --verify --quiet {}", quote(&target)),
+    )?
+    .ok_or_else(|| {
+        AnsimpleError::Config(format!(
+            "`{}` is no branch, tag or commit of {repo}",
+            version.unwrap_or("HEAD")
+        ))
+    })?;
+
+    // The branch is made to point where the remote's does even when HEAD is
+    // there already, which moves nothing.
+    let force = if force { " --force" } else { "" };
+    run(
+        connection,
+        &format!("{git} checkout --quiet{force} {checkout}"),
+        &mut output,
+    )?;
+
+    Ok(Checkout {
+        changed: after != before,
+        before: Some(before),
+        after: Some(after),
+        output,
+    })
+}
+
+// The branch `origin/HEAD` points to, as the clone found it.
+fn default_branch(
+    connection: &mut dyn Connection,
+    git: &str,
+    dest: &str,
+) -> Result<String, AnsimpleError> {
+    let (head, _, rc) = connection.exec(&format!(
+        "{git} symbolic-ref --short refs/remotes/origin/HEAD"
+    ))?;
+    match head.trim().strip_prefix("origin/") {
+        Some(branch) if rc == 0 => Ok(branch.to_owned()),
+        _ => Err(AnsimpleError::Config(format!(
+            "the remote of {dest} has no default branch, give a `version`"
+        ))),
+    }
+}
+
+// The commit `command` printed, `None` when it failed.
+fn commit(connection: &mut dyn Connection, command: &str) -> Result<Option<String>, AnsimpleError> {
+    let (stdout, _, rc) = connection.exec(command)?;
+    Ok(Some(stdout.trim().to_owned()).filter(|commit| rc == 0 && !commit.is_empty()))
+}
+
+// Where `version` of `remote` is, found without fetching it, with `git` run
+// in the checkout for a remote of its own. Tags are peeled to their commit;
+// commits themselves are not listed and give `None`.
+fn remote_commit(
+    connection: &mut dyn Connection,
+    git: &str,
+    remote: &str,
+    version: Option<&str>,
+) -> Result<Option<String>, AnsimpleError> {
+    let refs = match version {
+        Some(version) => format!(
+            "{} {} {}",
+            quote(&format!("refs/heads/{version}")),
+            quote(&format!("refs/tags/{version}")),
+            quote(&format!("refs/tags/{version}^{{}}"))
+        ),
+        None => "HEAD".to_owned(),
+    };
+    let (stdout, stderr, rc) = connection.exec(&format!("{git} ls-remote {remote} {refs}"))?;
+    if rc != 0 {
+        return Err(AnsimpleError::Command {
+            command: format!("{git} ls-remote {remote}"),
+            rc,
+            stderr: stderr.trim().to_owned(),
+        });
+    }
+    let listed = stdout
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect::<Vec<_>>();
+    let peeled = listed.iter().find(|(_, name)| name.ends_with("^{}"));
+
+    Ok(peeled
+        .or(listed.first())
+        .map(|(commit, _)| (*commit).to_owned()))
+}
+
+fn run(
+    connection: &mut dyn Connection,
+    command: &str,
+    output: &mut String,
+) -> Result<(), AnsimpleError> {
+    let (stdout, stderr, rc) = connection.exec(command)?;
+    if rc != 0 {
+        return Err(AnsimpleError::Command {
+            command: command.to_owned(),
+            rc,
+            stderr: stderr.trim().to_owned(),
+        });
+    }
+    output.push_str(&stdout);
+
+    Ok(())
+}
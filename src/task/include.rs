@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{Task, TaskKind};
+use crate::error::AnsimpleError;
+use crate::template;
+use crate::vault::{self, Vault};
+
+// Puts the tasks of the files `include_tasks` entries name in their place, so
+// they run with the play's hosts and context like its own. Files are relative
+// to `path`, the one `tasks` were read from, and an entry's `tags` and `when`
+// go to every task it includes.
+pub fn include_tasks(
+    tasks: Vec<Task>,
+    path: &Path,
+    vault: Option<&Vault>,
+) -> Result<Vec<Task>, AnsimpleError> {
+    expand(tasks, path, vault, &mut vec![canonical(path)])
+}
+
+fn expand(
+    tasks: Vec<Task>,
+    path: &Path,
+    vault: Option<&Vault>,
+    including: &mut Vec<PathBuf>,
+) -> Result<Vec<Task>, AnsimpleError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut expanded = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let TaskKind::IncludeTasks { file } = &task.kind else {
+            expanded.push(task);
+            continue;
+        };
+        let file = dir.join(file);
+        let canonical = canonical(&file);
+        if including.contains(&canonical) {
+            return Err(AnsimpleError::Config(format!(
+                "{} includes itself through {}",
+                file.display(),
+                path.display()
+            )));
+        }
+
+        let included = vault::load::<Option<Vec<Task>>, _>(&file, vault)?.unwrap_or_default();
+        including.push(canonical);
+        let included = expand(included, &file, vault, including)?;
+        including.pop();
+
+        for mut included in included {
+            if let Some(tags) = &task.options.tags {
+                included.add_tags(tags);
+            }
+            if let Some(outer) = &task.options.when {
+                included.options.when = Some(match included.options.when.take() {
+                    Some(inner) => format!(
+                        "({}) and ({})",
+                        template::expression(outer),
+                        template::expression(&inner)
+                    ),
+                    None => outer.clone(),
+                });
+            }
+            expanded.push(included);
+        }
+    }
+
+    Ok(expanded)
+}
+
+// The same file by however many paths it is reached.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
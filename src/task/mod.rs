@@ -1,32 +1,292 @@
-use serde::{Deserialize, Serialize};
-use ssh2::Session;
-use tera::{Context, Tera};
+use indexmap::IndexMap;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tera::{Context, Value};
 
 use std::collections::HashMap;
-use std::error::Error;
 use std::fmt::Display;
 use std::fs;
-use std::io::prelude::*;
-use std::net::TcpStream;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use crate::playbook::{GlobalConfig, Host};
+use crate::change::ChangeDetector;
+use crate::connection::{self, Archive, Connection, ConnectionKind, ExecOptions};
+use crate::diff;
+use crate::error::AnsimpleError;
+use crate::events::Event;
+use crate::inventory::{GlobalConfig, Host};
+use crate::manifest::{Cached, Placed};
+use crate::platform::Platform;
+use crate::plugin::{self, Action, HostIo, Plugin, Request};
+use crate::runner::RunOptions;
+use crate::schema::{self, Generator, Object, Schema};
+use crate::secrets;
+use crate::template::{self, TemplateRegistry};
+use crate::throttle::Bandwidth;
 
 #[derive(Debug)]
 pub enum TaskResult {
     Changed(Host, TaskKind),
     Unchanged(Host, TaskKind),
-    _Failed(Host, TaskKind),
+    // Not run, as a check run cannot tell what it would do.
+    Skipped(Host, TaskKind),
+    // A command that exited with an error.
+    Failed(Host, TaskKind),
+}
+
+mod copy;
+mod fetch;
+mod file;
+mod git;
+mod include;
+mod module;
+mod package;
+mod service;
+
+pub use copy::CopyMode;
+pub use file::{FileState, Mode};
+pub use include::include_tasks;
+pub use module::{find_module, module_names, register_module, TaskModule};
+pub use package::PackageState;
+pub use service::ServiceState;
+
+const UPLOAD_ATTEMPTS: usize = 3;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const TOO_LARGE: &str = "files too large to diff";
+
+pub const NO_LOG_MESSAGE: &str = "the output has been hidden due to `no_log: true`";
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RegisteredResult {
+    pub status: String,
+    pub changed: bool,
+    pub failed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rc: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout_lines: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr_lines: Option<Vec<String>>,
+    // The file a file task wrote, or would have, and the SHA-256 of what it
+    // holds after.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    // The item of one pass of a loop.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item: Option<Value>,
+    // What each pass of a loop registered, in order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<RegisteredResult>>,
+    // Where a copy kept the file it replaced.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_file: Option<String>,
+    // The commit a git checkout was at before the task and is at after.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    // How many times a task with `retries` or `until` ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+}
+
+impl RegisteredResult {
+    pub fn masked(mut self) -> Self {
+        let mask = |text: &mut String| *text = secrets::mask(text).into_owned();
+        for text in [&mut self.stdout, &mut self.stderr].into_iter().flatten() {
+            mask(text);
+        }
+        for lines in [&mut self.stdout_lines, &mut self.stderr_lines]
+            .into_iter()
+            .flatten()
+        {
+            lines.iter_mut().for_each(mask);
+        }
+        if let Some(results) = self.results.take() {
+            self.results = Some(results.into_iter().map(Self::masked).collect());
+        }
+
+        self
+    }
+
+    // What a task registers when it failed without a result of its own.
+    pub fn errored() -> Self {
+        Self {
+            status: "failed".to_owned(),
+            failed: true,
+            ..Default::default()
+        }
+    }
+
+    // What a task that looped registers: failed when any pass failed, changed
+    // when any pass changed, and skipped when none ran.
+    pub fn looped(results: Vec<RegisteredResult>) -> Self {
+        let changed = results.iter().any(|result| result.changed);
+        let status = if changed {
+            "changed"
+        } else if results.iter().all(|result| result.status == "skipped") {
+            "skipped"
+        } else {
+            "unchanged"
+        };
+
+        let failed = results.iter().any(|result| result.failed);
+        Self {
+            status: if failed { "failed" } else { status }.to_owned(),
+            changed,
+            failed,
+            results: Some(results),
+            ..Default::default()
+        }
+    }
 }
 
 impl TaskResult {
-    pub fn register_value(&self) -> String {
+    pub fn status(&self) -> &'static str {
         match self {
             TaskResult::Changed(_, _) => "changed",
             TaskResult::Unchanged(_, _) => "unchanged",
-            TaskResult::_Failed(_, _) => "failed",
+            TaskResult::Skipped(_, _) => "skipped",
+            TaskResult::Failed(_, _) => "failed",
+        }
+    }
+
+    // The result as the task's `changed_when` has it, for tasks that ran.
+    pub fn with_changed(self, changed: bool) -> Self {
+        match self {
+            TaskResult::Changed(host, kind) | TaskResult::Unchanged(host, kind) if changed => {
+                TaskResult::Changed(host, kind)
+            }
+            TaskResult::Changed(host, kind) | TaskResult::Unchanged(host, kind) => {
+                TaskResult::Unchanged(host, kind)
+            }
+            result => result,
+        }
+    }
+
+    // Why the task failed.
+    pub fn error(&self) -> Option<AnsimpleError> {
+        match self {
+            TaskResult::Failed(
+                _,
+                TaskKind::Shell {
+                    command,
+                    stderr,
+                    rc,
+                    ..
+                },
+            ) => Some(AnsimpleError::Command {
+                command: command.clone(),
+                rc: rc.unwrap_or(-1),
+                stderr: stderr.trim().to_owned(),
+            }),
+            TaskResult::Failed(_, kind) => Some(AnsimpleError::Config(format!("{kind} failed"))),
+            _ => None,
+        }
+    }
+
+    pub fn register_value(&self) -> RegisteredResult {
+        let (TaskResult::Changed(host, kind)
+        | TaskResult::Unchanged(host, kind)
+        | TaskResult::Skipped(host, kind)
+        | TaskResult::Failed(host, kind)) = self;
+
+        let mut registered = RegisteredResult {
+            status: self.status().to_string(),
+            changed: matches!(self, TaskResult::Changed(_, _)),
+            failed: matches!(self, TaskResult::Failed(_, _)),
+            ..Default::default()
+        };
+
+        if let TaskKind::Shell {
+            result, stderr, rc, ..
+        } = kind
+        {
+            registered.rc = *rc;
+            registered.stdout_lines = Some(result.lines().map(str::to_owned).collect());
+            registered.stdout = Some(result.clone());
+            registered.stderr_lines = Some(stderr.lines().map(str::to_owned).collect());
+            registered.stderr = Some(stderr.clone());
+        }
+
+        if let TaskKind::Plugin { result, .. }
+        | TaskKind::Package { result, .. }
+        | TaskKind::Service { result, .. }
+        | TaskKind::Git { result, .. } = kind
+        {
+            registered.stdout_lines = Some(result.lines().map(str::to_owned).collect());
+            registered.stdout = Some(result.clone());
+        }
+
+        if let TaskKind::Copy { dest, result, .. }
+        | TaskKind::Template { dest, result, .. }
+        | TaskKind::SearchReplace {
+            path: dest, result, ..
+        } = kind
+        {
+            registered.dest = Some(dest.clone());
+            registered.checksum = Some(result.clone()).filter(|checksum| !checksum.is_empty());
+        }
+
+        if let TaskKind::Copy { backup_file, .. } = kind {
+            registered.backup_file = backup_file.clone();
+        }
+
+        if let TaskKind::File { path, .. } = kind {
+            registered.dest = Some(path.clone());
+        }
+
+        if let TaskKind::Git {
+            dest,
+            before,
+            after,
+            ..
+        } = kind
+        {
+            registered.dest = Some(dest.clone());
+            registered.before = before.clone();
+            registered.after = after.clone();
+        }
+
+        // Where the fetched file is on this machine.
+        if let TaskKind::Fetch {
+            src,
+            dest,
+            flat,
+            result,
+            ..
+        } = kind
+        {
+            registered.dest = fetch::local_path(host, src, dest, flat.unwrap_or(false))
+                .ok()
+                .map(|path| path.display().to_string());
+            registered.checksum = Some(result.clone()).filter(|checksum| !checksum.is_empty());
+        }
+
+        registered
+    }
+
+    // How the file the task wrote, or would have, changed, when the run
+    // shows diffs.
+    pub fn diff(&self) -> Option<&str> {
+        let (TaskResult::Changed(_, kind)
+        | TaskResult::Unchanged(_, kind)
+        | TaskResult::Skipped(_, kind)
+        | TaskResult::Failed(_, kind)) = self;
+
+        match kind {
+            TaskKind::Copy { diff, .. }
+            | TaskKind::Template { diff, .. }
+            | TaskKind::SearchReplace { diff, .. } => diff.as_deref(),
+            _ => None,
         }
-        .to_string()
     }
 }
 
@@ -35,22 +295,270 @@ impl Display for TaskResult {
         match self {
             TaskResult::Changed(host, kind) => write!(f, "{kind}: {host} - CHANGED"),
             TaskResult::Unchanged(host, kind) => write!(f, "{kind}: {host} - UNCHANGED"),
-            TaskResult::_Failed(host, kind) => write!(f, "{kind}: {host} - FAILED"),
+            TaskResult::Skipped(host, kind) => write!(f, "{kind}: {host} - SKIPPED"),
+            TaskResult::Failed(host, kind) => write!(f, "{kind}: {host} - FAILED"),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
-#[serde(rename_all = "snake_case")]
+#[derive(Debug, Clone, Serialize)]
 pub struct Task {
     #[serde(flatten)]
     kind: TaskKind,
+    #[serde(flatten)]
+    options: TaskOptions,
+}
+
+// Everything about a task besides what it does.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct TaskOptions {
     #[serde(skip_serializing_if = "Option::is_none")]
     tags: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     register: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     when: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_log: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depends_on: Option<Vec<String>>,
+    // Handlers to run at the end of the play when the task changed something.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notify: Vec<String>,
+    // Applies to each host's transfers on its own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_bandwidth: Option<Bandwidth>,
+    #[serde(rename = "become", skip_serializing_if = "Option::is_none")]
+    become_root: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    become_user: Option<String>,
+    // Runs the task on another host of the inventory, or on this machine
+    // for `localhost`, in the context of the host it is for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delegate_to: Option<String>,
+    // Over that of the host the task runs on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection: Option<ConnectionKind>,
+    // On top of the environment of the play and the host.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    environment: IndexMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_mode: Option<CheckMode>,
+    // Over the play's vars, for this task only.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    vars: IndexMap<String, Value>,
+    // Runs the task once for each item, as `item`.
+    #[serde(
+        rename = "loop",
+        alias = "with_items",
+        skip_serializing_if = "Option::is_none"
+    )]
+    items: Option<Loop>,
+    // Carries on with the play when the task fails, with the failure in what
+    // it registers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_errors: Option<bool>,
+    // Whether the task changed anything, in place of what it says itself.
+    // Its registered result is in scope under its `register` name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changed_when: Option<Condition>,
+    // Runs the task again while it fails or, with `until`, until the
+    // condition holds over its registered result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retries: Option<u32>,
+    // Seconds between attempts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<String>,
+}
+
+// Serde cannot deny unknown fields next to a flattened enum, so the kind is
+// picked out of the task by hand and the rest checked on its own. Errors name
+// the task and are raised while its mapping is being read, so the parser
+// still points at where it is.
+impl<'de> Deserialize<'de> for Task {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(TaskVisitor)
+    }
+}
+
+struct TaskVisitor;
+
+impl<'de> de::Visitor<'de> for TaskVisitor {
+    type Value = Task;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a task")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Task, A::Error> {
+        let mut fields =
+            serde_yaml::Mapping::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        if let Some(file) = fields.remove("import_tasks") {
+            fields.insert("include_tasks".into(), file);
+        }
+        let modules = module_names();
+        let kinds = fields
+            .keys()
+            .filter_map(serde_yaml::Value::as_str)
+            .filter(|key| TaskKind::NAMES.contains(key) || modules.iter().any(|name| name == key))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let kind = match kinds.as_slice() {
+            [kind] => kind.clone(),
+            [] => {
+                let names = TaskKind::NAMES
+                    .iter()
+                    .copied()
+                    .chain(modules.iter().map(String::as_str))
+                    .collect::<Vec<_>>();
+                return Err(de::Error::custom(format!(
+                    "a task needs one of `{}`",
+                    names.join("`, `")
+                )));
+            }
+            kinds => {
+                return Err(de::Error::custom(format!(
+                    "a task has one kind, not `{}`",
+                    kinds.join("` and `")
+                )))
+            }
+        };
+
+        let module = modules.contains(&kind);
+        let (mut kind, mut arguments) = fields.remove_entry(&kind).expect("kind found above");
+        // Included tasks only take the entry's tags and condition, as the
+        // file is read when the play is.
+        if kind == "include_tasks" {
+            if let Some(file) = arguments.as_str() {
+                let mut include = serde_yaml::Mapping::new();
+                include.insert("file".into(), file.into());
+                arguments = include.into();
+            }
+            let option = fields
+                .keys()
+                .filter_map(serde_yaml::Value::as_str)
+                .find(|option| !["tags", "when"].contains(option));
+            if let Some(option) = option {
+                return Err(de::Error::custom(format!(
+                    "`include_tasks` takes only `tags` and `when`, not `{option}`"
+                )));
+            }
+        }
+        let location = match arguments.get("name").and_then(serde_yaml::Value::as_str) {
+            Some(name) => format!("task '{name}'"),
+            None => format!("{} task", kind.as_str().unwrap_or_default()),
+        };
+        // Task modules are run by `plugin` tasks, with everything but the
+        // name as their arguments.
+        if module {
+            let mut args = match arguments {
+                serde_yaml::Value::Mapping(args) => args,
+                serde_yaml::Value::Null => serde_yaml::Mapping::new(),
+                _ => {
+                    return Err(de::Error::custom(format!(
+                        "{location}: the arguments of `{}` are a mapping",
+                        kind.as_str().unwrap_or_default()
+                    )))
+                }
+            };
+            let mut plugin = serde_yaml::Mapping::new();
+            if let Some(name) = args.remove("name") {
+                plugin.insert("name".into(), name);
+            }
+            plugin.insert("module".into(), kind);
+            plugin.insert("args".into(), args.into());
+            (kind, arguments) = ("plugin".into(), plugin.into());
+        }
+        let mut task_kind = serde_yaml::Mapping::new();
+        task_kind.insert(kind, arguments);
+        let invalid = |err: serde_yaml::Error| de::Error::custom(format!("{location}: {err}"));
+
+        Ok(Task {
+            kind: serde_yaml::with::singleton_map::deserialize(serde_yaml::Value::from(task_kind))
+                .map_err(invalid)?,
+            options: serde_yaml::from_value(fields.into()).map_err(invalid)?,
+        })
+    }
+}
+
+// One object per task kind, with the kind's arguments under its key next to
+// the task options.
+impl Schema for Task {
+    fn name() -> Option<&'static str> {
+        Some("Task")
+    }
+
+    fn schema(generator: &mut Generator) -> Value {
+        let mut kinds = TaskKind::NAMES
+            .iter()
+            .filter(|kind| **kind != "include_tasks")
+            .map(|kind| {
+                let arguments = TaskKind::arguments(kind, generator);
+                TaskOptions::properties(generator.object().property(kind, arguments, true)).build()
+            })
+            .collect::<Vec<_>>();
+        let file = json!({
+            "oneOf": [
+                { "type": "string" },
+                generator.object().required::<PathBuf>("file").build(),
+            ]
+        });
+        for kind in ["include_tasks", "import_tasks"] {
+            kinds.push(
+                generator
+                    .object()
+                    .property(kind, file.clone(), true)
+                    .optional::<Vec<String>>("tags")
+                    .optional::<String>("when")
+                    .build(),
+            );
+        }
+        for name in module_names() {
+            let module = find_module(&name).expect("module names are registered");
+            let mut arguments = module.schema();
+            arguments["properties"]["name"] = json!({ "type": "string" });
+            match &mut arguments["required"] {
+                Value::Array(required) => required.push("name".into()),
+                required => *required = json!(["name"]),
+            }
+            kinds.push(
+                TaskOptions::properties(generator.object().property(&name, arguments, true))
+                    .build(),
+            );
+        }
+
+        json!({ "oneOf": kinds })
+    }
+}
+
+impl TaskOptions {
+    fn properties(object: Object) -> Object {
+        object
+            .optional::<Vec<String>>("tags")
+            .optional::<String>("register")
+            .optional::<String>("when")
+            .optional::<bool>("no_log")
+            .optional::<Vec<String>>("depends_on")
+            .optional::<Vec<String>>("notify")
+            .optional::<Bandwidth>("max_bandwidth")
+            .optional::<bool>("become")
+            .optional::<String>("become_user")
+            .optional::<String>("delegate_to")
+            .optional::<ConnectionKind>("connection")
+            .optional::<IndexMap<String, String>>("environment")
+            .optional::<CheckMode>("check_mode")
+            .optional::<IndexMap<String, Value>>("vars")
+            .optional::<Loop>("loop")
+            .optional::<Loop>("with_items")
+            .optional::<bool>("ignore_errors")
+            .optional::<Condition>("changed_when")
+            .optional::<u32>("retries")
+            .optional::<u64>("delay")
+            .optional::<String>("until")
+    }
 }
 
 impl Display for Task {
@@ -60,50 +568,322 @@ impl Display for Task {
 }
 
 impl Task {
-    pub fn when(&self, _vars: &Context) -> bool {
-        true
+    // Whether the task runs, by its `when` condition.
+    pub fn when(
+        &self,
+        context: &Context,
+        templates: &TemplateRegistry,
+    ) -> Result<bool, AnsimpleError> {
+        match &self.options.when {
+            Some(condition) => {
+                templates.evaluate(condition, context, &format!("when of task '{}'", self.kind))
+            }
+            None => Ok(true),
+        }
+    }
+
+    pub fn loops(&self) -> bool {
+        self.options.items.is_some()
     }
 
     pub fn tags(&self) -> Option<&Vec<String>> {
-        self.tags.as_ref()
+        self.options.tags.as_ref()
+    }
+
+    // Tags the task also has, from what included it.
+    pub fn add_tags(&mut self, tags: &[String]) {
+        let own = self.options.tags.get_or_insert_with(Vec::new);
+        for tag in tags {
+            if !own.contains(tag) {
+                own.push(tag.clone());
+            }
+        }
     }
 
-    pub fn kind(&mut self) -> &mut TaskKind {
-        &mut self.kind
+    pub fn kind(&self) -> &TaskKind {
+        &self.kind
     }
 
     pub fn register(&self) -> Option<&String> {
-        self.register.as_ref()
+        self.options.register.as_ref()
+    }
+
+    pub fn no_log(&self) -> bool {
+        self.options.no_log.unwrap_or(false)
+    }
+
+    pub fn depends_on(&self) -> Option<&Vec<String>> {
+        self.options.depends_on.as_ref()
+    }
+
+    pub fn notify(&self) -> &[String] {
+        &self.options.notify
+    }
+
+    pub fn max_bandwidth(&self) -> Option<Bandwidth> {
+        self.options.max_bandwidth
+    }
+
+    pub fn become_root(&self) -> Option<bool> {
+        self.options.become_root
+    }
+
+    pub fn become_user(&self) -> Option<&String> {
+        self.options.become_user.as_ref()
+    }
+
+    pub fn delegate_to(&self) -> Option<&String> {
+        self.options.delegate_to.as_ref()
+    }
+
+    pub fn connection(&self) -> Option<ConnectionKind> {
+        self.options.connection
+    }
+
+    pub fn environment(&self) -> &IndexMap<String, String> {
+        &self.options.environment
+    }
+
+    pub fn check_mode(&self) -> Option<CheckMode> {
+        self.options.check_mode
+    }
+
+    pub fn vars(&self) -> &IndexMap<String, Value> {
+        &self.options.vars
+    }
+
+    pub fn ignore_errors(&self) -> bool {
+        self.options.ignore_errors.unwrap_or(false)
+    }
+
+    // Whether the task changed anything by its `changed_when`, given what it
+    // registered. `None` for tasks that leave it to the result.
+    pub fn changed_when(
+        &self,
+        registered: &RegisteredResult,
+        context: &Context,
+        templates: &TemplateRegistry,
+    ) -> Result<Option<bool>, AnsimpleError> {
+        let condition = match &self.options.changed_when {
+            None => return Ok(None),
+            Some(Condition::Literal(changed)) => return Ok(Some(*changed)),
+            Some(Condition::Expression(condition)) => condition,
+        };
+        let mut context = context.clone();
+        if let Some(register) = self.register() {
+            context.insert(register.to_owned(), registered);
+        }
+
+        templates
+            .evaluate(
+                condition,
+                &context,
+                &format!("changed_when of task '{}'", self.kind),
+            )
+            .map(Some)
+    }
+
+    // Attempts after the first one, three by default with an `until`.
+    pub fn retries(&self) -> u32 {
+        match (self.options.retries, &self.options.until) {
+            (Some(retries), _) => retries,
+            (None, Some(_)) => 3,
+            (None, None) => 0,
+        }
+    }
+
+    pub fn delay(&self) -> u64 {
+        self.options.delay.unwrap_or(5)
+    }
+
+    // Whether the task retries at all, so its result counts the attempts.
+    pub fn retried(&self) -> bool {
+        self.options.retries.is_some() || self.options.until.is_some()
+    }
+
+    // Whether an attempt that did not fail is the last one, by the task's
+    // `until` condition. Its registered result is in scope as for
+    // `changed_when`.
+    pub fn until(
+        &self,
+        registered: &RegisteredResult,
+        context: &Context,
+        templates: &TemplateRegistry,
+    ) -> Result<bool, AnsimpleError> {
+        let Some(condition) = &self.options.until else {
+            return Ok(true);
+        };
+        let mut context = context.clone();
+        if let Some(register) = self.register() {
+            context.insert(register.to_owned(), registered);
+        }
+
+        templates.evaluate(
+            condition,
+            &context,
+            &format!("until of task '{}'", self.kind),
+        )
+    }
+
+    // The items of the task's loop, `None` for tasks that do not loop.
+    pub fn items(
+        &self,
+        context: &Context,
+        templates: &TemplateRegistry,
+    ) -> Result<Option<Vec<Value>>, AnsimpleError> {
+        let location = format!("loop of task '{}'", self.kind);
+        let items = match &self.options.items {
+            None => return Ok(None),
+            Some(Loop::Items(items)) => {
+                templates.render_value(&Value::Array(items.clone()), context, &location)?
+            }
+            Some(Loop::Expression(expression)) => {
+                templates.value_of(expression, context, &location)?
+            }
+        };
+
+        match items {
+            Value::Array(items) => Ok(Some(items)),
+            items => Err(AnsimpleError::Template(format!(
+                "{location}: expected a list, got {items}"
+            ))),
+        }
+    }
+
+    // What can be told to be wrong with the task without a host: templates
+    // and conditions that do not compile and local files that are not there.
+    pub fn problems(&self, templates_dir: Option<&Path>) -> Vec<AnsimpleError> {
+        let location = format!("task '{}'", self.kind);
+        let options = &self.options;
+        let mut checks = Vec::new();
+        if let Some(when) = &options.when {
+            checks.push(template::check_condition(
+                when,
+                &format!("when of {location}"),
+            ));
+        }
+        if let Some(Condition::Expression(condition)) = &options.changed_when {
+            checks.push(template::check_condition(
+                condition,
+                &format!("changed_when of {location}"),
+            ));
+        }
+        if let Some(until) = &options.until {
+            checks.push(template::check_condition(
+                until,
+                &format!("until of {location}"),
+            ));
+        }
+        match &options.items {
+            Some(Loop::Expression(expression)) => checks.push(template::check_expression(
+                expression,
+                &format!("loop of {location}"),
+            )),
+            Some(Loop::Items(items)) => checks.push(template::check_value(
+                &Value::Array(items.clone()),
+                &format!("loop of {location}"),
+            )),
+            None => {}
+        }
+        for (key, value) in &options.vars {
+            checks.push(template::check_value(
+                value,
+                &format!("vars.{key} of {location}"),
+            ));
+        }
+        for (name, value) in &options.environment {
+            checks.push(template::check_value(
+                &Value::String(value.clone()),
+                &format!("environment.{name} of {location}"),
+            ));
+        }
+        if let Some(delegate_to) = &options.delegate_to {
+            checks.push(template::check_value(
+                &Value::String(delegate_to.clone()),
+                &format!("delegate_to of {location}"),
+            ));
+        }
+        checks.push(
+            tera::to_value(&self.kind)
+                .map_err(AnsimpleError::from)
+                .and_then(|arguments| template::check_value(&arguments, &location)),
+        );
+
+        match &self.kind {
+            TaskKind::Copy {
+                src, remote_src, ..
+            } if *remote_src != Some(true) && !is_templated(src) => checks.push(
+                fs::metadata(src)
+                    .map(|_| ())
+                    .map_err(|source| AnsimpleError::Read {
+                        path: PathBuf::from(src),
+                        source,
+                    }),
+            ),
+            TaskKind::Template { src, jinja2, .. } if !is_templated(src) => checks.push(
+                template::check_file(src, jinja2.unwrap_or(false), templates_dir),
+            ),
+            _ => {}
+        }
+
+        checks.into_iter().filter_map(Result::err).collect()
+    }
+
+    pub fn template_source(&self) -> Option<(&str, bool)> {
+        match &self.kind {
+            TaskKind::Template { src, jinja2, .. } if !is_templated(src) => {
+                Some((src, jinja2.unwrap_or(false)))
+            }
+            _ => None,
+        }
     }
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum TaskKind {
     Shell {
         name: String,
         command: String,
+        creates: Option<String>,
+        removes: Option<String>,
+        unless: Option<String>,
 
         #[serde(skip_serializing, skip_deserializing)]
         result: String,
+        #[serde(skip_serializing, skip_deserializing)]
+        stderr: String,
+        #[serde(skip_serializing, skip_deserializing)]
+        rc: Option<i32>,
     },
     Copy {
         name: String,
         src: String,
         dest: String,
         remote_src: Option<bool>,
+        transfer: Option<Transfer>,
+        mode: Option<CopyMode>,
+        // Keeps the file a copy replaces next to it.
+        backup: Option<bool>,
 
         #[serde(skip_serializing, skip_deserializing)]
         result: String,
+        #[serde(skip_serializing, skip_deserializing)]
+        diff: Option<String>,
+        #[serde(skip_serializing, skip_deserializing)]
+        backup_file: Option<String>,
     },
     Template {
         name: String,
         src: String,
         dest: String,
         variables: HashMap<String, String>,
+        jinja2: Option<bool>,
 
         #[serde(skip_serializing, skip_deserializing)]
         result: String,
+        #[serde(skip_serializing, skip_deserializing)]
+        diff: Option<String>,
     },
     SearchReplace {
         name: String,
@@ -113,7 +893,140 @@ pub enum TaskKind {
 
         #[serde(skip_serializing, skip_deserializing)]
         result: String,
+        #[serde(skip_serializing, skip_deserializing)]
+        diff: Option<String>,
+    },
+    // Downloads a file of the host to this machine.
+    Fetch {
+        name: String,
+        src: String,
+        dest: String,
+        flat: Option<bool>,
+
+        #[serde(skip_serializing, skip_deserializing)]
+        result: String,
+    },
+    // The type, mode and ownership of a path.
+    File {
+        name: String,
+        path: String,
+        state: Option<FileState>,
+        // What a link points to.
+        src: Option<String>,
+        mode: Option<Mode>,
+        owner: Option<String>,
+        group: Option<String>,
+
+        #[serde(skip_serializing, skip_deserializing)]
+        result: String,
+    },
+    Plugin {
+        name: String,
+        module: String,
+        #[serde(default)]
+        args: Value,
+
+        #[serde(skip_serializing, skip_deserializing)]
+        result: String,
+    },
+    Package {
+        name: String,
+        packages: Vec<String>,
+        state: Option<PackageState>,
+
+        #[serde(skip_serializing, skip_deserializing)]
+        result: String,
+    },
+    Service {
+        name: String,
+        service: String,
+        state: Option<ServiceState>,
+        enabled: Option<bool>,
+        daemon_reload: Option<bool>,
+
+        #[serde(skip_serializing, skip_deserializing)]
+        result: String,
+    },
+    Git {
+        name: String,
+        repo: String,
+        dest: String,
+        // A branch, tag or commit, the remote's default branch when not set.
+        version: Option<String>,
+        // Discards local changes to the checkout.
+        force: Option<bool>,
+
+        #[serde(skip_serializing, skip_deserializing)]
+        result: String,
+        #[serde(skip_serializing, skip_deserializing)]
+        before: Option<String>,
+        #[serde(skip_serializing, skip_deserializing)]
+        after: Option<String>,
     },
+    // The tasks of another file, which take its place when the play is read.
+    IncludeTasks {
+        file: PathBuf,
+    },
+}
+
+// How `copy` gets local files onto the host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    // One file over SFTP.
+    #[default]
+    Sftp,
+    // A whole directory as one tar stream, unpacked by the host's `tar`.
+    Tar,
+}
+
+impl Schema for Transfer {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["sftp", "tar"])
+    }
+}
+
+// What a task does in a check run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckMode {
+    // Runs for real, for tasks that only look and whose results later
+    // tasks need.
+    Run,
+}
+
+impl Schema for CheckMode {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["run"])
+    }
+}
+
+// What a task loops over: a list, or an expression that gives one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Loop {
+    Items(Vec<Value>),
+    Expression(String),
+}
+
+impl Schema for Loop {
+    fn schema(_: &mut Generator) -> Value {
+        json!({ "oneOf": [{ "type": "array" }, { "type": "string" }] })
+    }
+}
+
+// A condition, which may also be a plain `true` or `false`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Condition {
+    Literal(bool),
+    Expression(String),
+}
+
+impl Schema for Condition {
+    fn schema(_: &mut Generator) -> Value {
+        json!({ "oneOf": [{ "type": "boolean" }, { "type": "string" }] })
+    }
 }
 
 impl Display for TaskKind {
@@ -122,7 +1035,14 @@ impl Display for TaskKind {
             TaskKind::Shell { name, .. }
             | TaskKind::Copy { name, .. }
             | TaskKind::Template { name, .. }
-            | TaskKind::SearchReplace { name, .. } => name,
+            | TaskKind::SearchReplace { name, .. }
+            | TaskKind::Fetch { name, .. }
+            | TaskKind::File { name, .. }
+            | TaskKind::Plugin { name, .. }
+            | TaskKind::Package { name, .. }
+            | TaskKind::Service { name, .. }
+            | TaskKind::Git { name, .. } => name,
+            TaskKind::IncludeTasks { file } => return write!(f, "include {}", file.display()),
         };
 
         write!(f, "{name}")
@@ -130,59 +1050,491 @@ impl Display for TaskKind {
 }
 
 impl TaskKind {
+    // The keys naming each kind in a task, as serde spells the variants.
+    pub const NAMES: &'static [&'static str] = &[
+        "shell",
+        "copy",
+        "template",
+        "search_replace",
+        "fetch",
+        "file",
+        "plugin",
+        "package",
+        "service",
+        "git",
+        "include_tasks",
+    ];
+
+    fn arguments(kind: &str, generator: &mut Generator) -> Value {
+        let object = generator.object().required::<String>("name");
+        match kind {
+            "shell" => object
+                .required::<String>("command")
+                .optional::<String>("creates")
+                .optional::<String>("removes")
+                .optional::<String>("unless"),
+            "copy" => object
+                .required::<String>("src")
+                .required::<String>("dest")
+                .optional::<bool>("remote_src")
+                .optional::<Transfer>("transfer")
+                .optional::<CopyMode>("mode")
+                .optional::<bool>("backup"),
+            "template" => object
+                .required::<String>("src")
+                .required::<String>("dest")
+                .required::<HashMap<String, String>>("variables")
+                .optional::<bool>("jinja2"),
+            "search_replace" => object
+                .required::<String>("path")
+                .required::<String>("search")
+                .required::<String>("replace"),
+            "fetch" => object
+                .required::<String>("src")
+                .required::<String>("dest")
+                .optional::<bool>("flat"),
+            "file" => object
+                .required::<String>("path")
+                .optional::<FileState>("state")
+                .optional::<String>("src")
+                .optional::<Mode>("mode")
+                .optional::<String>("owner")
+                .optional::<String>("group"),
+            "plugin" => object
+                .required::<String>("module")
+                .optional::<Value>("args"),
+            "package" => object
+                .required::<Vec<String>>("packages")
+                .optional::<PackageState>("state"),
+            "service" => object
+                .required::<String>("service")
+                .optional::<ServiceState>("state")
+                .optional::<bool>("enabled")
+                .optional::<bool>("daemon_reload"),
+            "git" => object
+                .required::<String>("repo")
+                .required::<String>("dest")
+                .optional::<String>("version")
+                .optional::<bool>("force"),
+            kind => unreachable!("task kind `{kind}` has no schema"),
+        }
+        .build()
+    }
+
+    pub fn render(
+        &self,
+        context: &Context,
+        templates: &TemplateRegistry,
+    ) -> Result<TaskKind, AnsimpleError> {
+        let location = format!("task '{self}'");
+        let value = tera::to_value(self)?;
+        let rendered = templates.render_value(&value, context, &location)?;
+        tera::from_value(rendered).map_err(|err| {
+            AnsimpleError::Template(format!("invalid arguments in {location}: {err}"))
+        })
+    }
+
+    // The desired state known before touching the host; tasks whose desired
+    // content depends on the remote file consult their own detector later.
+    pub fn change_detector(&self) -> ChangeDetector {
+        match self {
+            TaskKind::Shell {
+                creates: Some(path),
+                ..
+            } => ChangeDetector::Stat {
+                path: PathBuf::from(path),
+                exists: true,
+            },
+            TaskKind::Shell {
+                removes: Some(path),
+                ..
+            } => ChangeDetector::Stat {
+                path: PathBuf::from(path),
+                exists: false,
+            },
+            TaskKind::Shell {
+                unless: Some(command),
+                ..
+            } => ChangeDetector::Probe {
+                command: command.clone(),
+            },
+            TaskKind::Shell { .. }
+            | TaskKind::Copy { .. }
+            | TaskKind::Template { .. }
+            | TaskKind::SearchReplace { .. }
+            | TaskKind::Fetch { .. }
+            | TaskKind::File { .. }
+            | TaskKind::Plugin { .. }
+            | TaskKind::Package { .. }
+            | TaskKind::Service { .. }
+            | TaskKind::Git { .. }
+            | TaskKind::IncludeTasks { .. } => ChangeDetector::Always,
+        }
+    }
+
+    // Runs the task on `host` on one of the run's workers, as the SSH calls
+    // block.
     pub async fn execute_on_host(
         &mut self,
         host: &Host,
         context: &Context,
+        templates: &TemplateRegistry,
+        options: &RunOptions,
+        global_config: &GlobalConfig,
+        exec: &ExecOptions,
+    ) -> Result<TaskResult, AnsimpleError> {
+        let mut kind = self.clone();
+        let (host, context, templates, options, global_config, exec) = (
+            host.clone(),
+            context.clone(),
+            templates.clone(),
+            options.clone(),
+            global_config.clone(),
+            exec.clone(),
+        );
+        let workers = options.workers.clone();
+        let (kind, result) = workers
+            .run(move || {
+                let result =
+                    kind.execute(&host, &context, &templates, &options, &global_config, &exec);
+                (kind, result)
+            })
+            .await?;
+        *self = kind;
+
+        result
+    }
+
+    fn execute(
+        &mut self,
+        host: &Host,
+        context: &Context,
+        templates: &TemplateRegistry,
+        options: &RunOptions,
         global_config: &GlobalConfig,
-        _local_config: Option<&GlobalConfig>,
-    ) -> Result<TaskResult, Box<dyn Error>> {
-        println!("{self}: {host} - START");
-        let user = host.user.as_ref().unwrap_or(&global_config.user);
-        let key = host.key.as_ref().unwrap_or(&global_config.key);
-        let tcp = TcpStream::connect(format!("{}:22", host.address)).unwrap();
-        let mut session = Session::new().unwrap();
-        session.set_tcp_stream(tcp);
-        session.handshake().unwrap();
-        session.userauth_agent(user)?;
+        exec: &ExecOptions,
+    ) -> Result<TaskResult, AnsimpleError> {
+        let task_name = secrets::mask(&self.to_string()).into_owned();
+        let open = || {
+            let connection = connection::open(host, options, global_config)?;
+            exec.clone().wrap(connection, host.platform())
+        };
+        let mut connection = open()?;
 
-        if !session.authenticated() {
-            session.userauth_pubkey_file(user, None, Path::new(&key), None)?;
+        if !self.change_detector().needs_change(connection.as_mut())? {
+            return Ok(TaskResult::Unchanged(host.clone(), self.clone()));
+        }
+        // What commands and plugins would do is up to them. Task modules
+        // are asked instead.
+        let skipped = match self {
+            Self::Shell { .. } => true,
+            Self::Plugin { module, .. } => find_module(module).is_none(),
+            _ => false,
+        };
+        if options.check && skipped {
+            return Ok(TaskResult::Skipped(host.clone(), self.clone()));
         }
 
         let result = match self {
             Self::Shell {
                 command,
                 ref mut result,
+                ref mut stderr,
+                ref mut rc,
                 ..
             } => {
-                let mut channel = session.channel_session()?;
-                channel.exec(command)?;
-                channel.read_to_string(result)?;
+                let (stdout, errors, status) = connection.exec(command)?;
+                *result = stdout;
+                *stderr = errors;
+                *rc = Some(status);
 
-                TaskResult::Changed(host.clone(), self.clone())
+                if status == 0 {
+                    TaskResult::Changed(host.clone(), self.clone())
+                } else {
+                    TaskResult::Failed(host.clone(), self.clone())
+                }
             }
             Self::Copy {
                 src,
                 dest,
                 remote_src,
+                transfer,
+                mode,
+                backup,
+                ref mut result,
+                diff: ref mut shown,
+                ref mut backup_file,
                 ..
             } => {
-                let sftp = session.sftp()?;
                 let src = PathBuf::from(src.clone());
-                let dest = PathBuf::from(dest.clone());
+                let dest_path = PathBuf::from(dest.clone());
+                let (mode, backup) = (*mode, backup.unwrap_or(false));
 
-                if let Some(true) = remote_src {
-                    let mut remote_file = sftp.open(&src)?;
-                    let mut contents = Vec::new();
-                    remote_file.read_to_end(&mut contents)?;
-                    let mut remote_dest = sftp.create(&dest)?;
-                    remote_dest.write_all(&contents)?;
-                } else {
-                    let contents = fs::read(src)?;
-                    let mut remote_file = sftp.create(&dest)?;
-                    remote_file.write_all(&contents)?;
+                let transfer = transfer.unwrap_or_default();
+                if *remote_src == Some(true) && transfer != Transfer::Sftp {
+                    return Err(AnsimpleError::Config(
+                        "`transfer` only applies to local files, not `remote_src`".to_owned(),
+                    ));
+                }
+                if transfer == Transfer::Tar && host.platform() == Platform::Windows {
+                    return Err(AnsimpleError::Config(
+                        "`transfer: tar` needs a POSIX host".to_owned(),
+                    ));
+                }
+                if mode.is_some() && host.platform() == Platform::Windows {
+                    return Err(AnsimpleError::Config(
+                        "`mode` needs a POSIX host".to_owned(),
+                    ));
                 }
+                if backup && transfer == Transfer::Tar {
+                    return Err(AnsimpleError::Config(
+                        "`backup` keeps the files a copy replaces, which `transfer: tar` does not know"
+                            .to_owned(),
+                    ));
+                }
+
+                let started = Instant::now();
+                let (bytes, checksum) = if let Some(true) = remote_src {
+                    let bits = match mode {
+                        Some(mode) => Some(mode.bits(|| {
+                            connection.stat(&src)?.map(|stat| stat.mode).ok_or_else(|| {
+                                AnsimpleError::Config(format!("{} does not exist", src.display()))
+                            })
+                        })?),
+                        None => None,
+                    };
+                    if let Some(checksum) = connection.checksum(&src)? {
+                        let detector = ChangeDetector::Checksum {
+                            path: dest_path.clone(),
+                            checksum: checksum.clone(),
+                        };
+                        let changed = detector.needs_change(connection.as_mut())?;
+                        if changed && options.diff {
+                            let size = connection.size(&src)?.unwrap_or_default();
+                            *shown = Some(file_diff(
+                                connection.as_mut(),
+                                &dest_path,
+                                size,
+                                |connection| connection.read(&src),
+                            )?);
+                        }
+                        if !changed || options.check {
+                            let moded = match bits {
+                                Some(bits) => copy::chmod(
+                                    connection.as_mut(),
+                                    &dest_path,
+                                    bits,
+                                    options.check,
+                                )?,
+                                None => false,
+                            };
+                            *result = checksum;
+                            return Ok(self.outcome(host, changed || moded));
+                        }
+                    }
+                    if backup {
+                        *backup_file = copy::backup(connection.as_mut(), &dest_path)?;
+                    }
+                    let copied = connection.copy(&src, &dest_path)?;
+                    if let Some(bits) = bits {
+                        copy::chmod(connection.as_mut(), &dest_path, bits, false)?;
+                    }
+                    copied
+                } else {
+                    let metadata = fs::metadata(&src).map_err(|source| AnsimpleError::Read {
+                        path: src.clone(),
+                        source,
+                    })?;
+                    let tree = metadata.is_dir();
+                    if tree && transfer == Transfer::Sftp {
+                        if host.platform() == Platform::Windows {
+                            return Err(AnsimpleError::Config(format!(
+                                "{} is a directory, which is only copied to POSIX hosts",
+                                src.display()
+                            )));
+                        }
+                        let copied = copy::Tree {
+                            mode,
+                            backup,
+                            check: options.check,
+                        }
+                        .copy(connection.as_mut(), &src, &dest_path)?;
+                        if copied.bytes > 0 {
+                            options.emit(Event::TransferFinished {
+                                host: host.address.clone(),
+                                task: task_name,
+                                path: dest.clone(),
+                                bytes: copied.bytes,
+                                seconds: started.elapsed().as_secs_f64(),
+                            });
+                        }
+                        *result = copied.checksum;
+                        return Ok(self.outcome(host, copied.changed));
+                    }
+                    // The mode of a single file, those of trees are set as
+                    // they are unpacked.
+                    let bits = match mode {
+                        Some(mode) if !tree => Some(mode.bits(|| copy::local_mode(&src))?),
+                        _ => None,
+                    };
+                    let mut archive = match (transfer, tree) {
+                        (Transfer::Sftp, _) => None,
+                        (Transfer::Tar, true) => Some(Archive::new(&src)?),
+                        (Transfer::Tar, false) => {
+                            return Err(AnsimpleError::Config(format!(
+                                "`transfer: tar` copies directories, {} is not one",
+                                src.display()
+                            )))
+                        }
+                    };
+                    let total = archive
+                        .as_ref()
+                        .map_or(metadata.len(), |archive| archive.size());
+                    // Content the host already has is not sent again.
+                    let cached = match (&global_config.upload_cache, &archive) {
+                        (Some(manifest), None) => {
+                            let content = Placed::of(&src)?;
+                            let found = options.uploads.find(
+                                host,
+                                manifest,
+                                connection.as_mut(),
+                                &dest_path,
+                                &content,
+                            )?;
+                            Some((manifest, content, found))
+                        }
+                        _ => None,
+                    };
+                    // A destination that already has the content is left
+                    // alone. Trees are always sent.
+                    let wanted = match &cached {
+                        Some((_, content, _)) => Some(content.clone()),
+                        None if archive.is_none() => Some(Placed::of(&src)?),
+                        None => None,
+                    };
+                    let unchanged = match (&cached, &wanted) {
+                        (Some((_, _, Cached::Here)), _) => true,
+                        (_, Some(content)) => holds(connection.as_mut(), &dest_path, content)?,
+                        (_, None) => false,
+                    };
+                    if !unchanged && options.diff && archive.is_none() {
+                        *shown = Some(file_diff(connection.as_mut(), &dest_path, total, |_| {
+                            fs::read(&src).map_err(|source| AnsimpleError::Read {
+                                path: src.clone(),
+                                source,
+                            })
+                        })?);
+                    }
+                    if unchanged || options.check {
+                        let moded = match bits {
+                            Some(bits) => {
+                                copy::chmod(connection.as_mut(), &dest_path, bits, options.check)?
+                            }
+                            None => false,
+                        };
+                        *result = wanted.map(|content| content.sha256).unwrap_or_default();
+                        return Ok(self.outcome(host, !unchanged || moded));
+                    }
+                    if backup {
+                        *backup_file = copy::backup(connection.as_mut(), &dest_path)?;
+                    }
+                    let copied = match &cached {
+                        // Should the copy turn out different after all, the
+                        // file is uploaded like any other.
+                        Some((_, content, Cached::At(path))) => {
+                            Some(connection.copy(path, &dest_path)?)
+                                .filter(|(_, checksum)| *checksum == content.sha256)
+                        }
+                        _ => None,
+                    };
+
+                    let (bytes, checksum) = match copied {
+                        Some(copied) => copied,
+                        None => {
+                            let mut reported: Option<Instant> = None;
+                            let mut progress = |bytes: u64| {
+                                // The first report is where the upload starts, which
+                                // is only news when it resumes.
+                                let due = reported
+                                    .map_or(bytes > 0, |at| at.elapsed() >= PROGRESS_INTERVAL);
+                                if reported.is_none() || due {
+                                    reported = Some(Instant::now());
+                                }
+                                if due && bytes < total {
+                                    options.emit(Event::TransferProgress {
+                                        host: host.address.clone(),
+                                        task: task_name.clone(),
+                                        path: dest.clone(),
+                                        bytes,
+                                        total,
+                                    });
+                                }
+                            };
+
+                            // A connection that drops mid-transfer is replaced, and
+                            // the upload continues where it stopped.
+                            let mut attempt = 1;
+                            loop {
+                                let uploaded = match transfer {
+                                    Transfer::Sftp => connection::upload(
+                                        connection.as_mut(),
+                                        &src,
+                                        &dest_path,
+                                        &mut progress,
+                                    ),
+                                    // A stream cut short is sent again from the start,
+                                    // from a fresh look at the tree.
+                                    Transfer::Tar => match archive.take() {
+                                        Some(archive) => Ok(archive),
+                                        None => Archive::new(&src),
+                                    }
+                                    .and_then(|archive| {
+                                        connection::upload_tree(
+                                            connection.as_mut(),
+                                            archive,
+                                            &dest_path,
+                                            mode,
+                                            &mut progress,
+                                        )
+                                    }),
+                                };
+                                match uploaded {
+                                    Err(err) if err.is_transport() && attempt < UPLOAD_ATTEMPTS => {
+                                        attempt += 1;
+                                        connection = open()?;
+                                    }
+                                    result => break result?,
+                                }
+                            }
+                        }
+                    };
+                    if let Some((manifest, _, _)) = cached {
+                        let content = Placed {
+                            sha256: checksum.clone(),
+                            size: total,
+                        };
+                        options.uploads.record(
+                            host,
+                            manifest,
+                            connection.as_mut(),
+                            &dest_path,
+                            content,
+                        )?;
+                    }
+                    if let Some(bits) = bits {
+                        copy::chmod(connection.as_mut(), &dest_path, bits, false)?;
+                    }
+                    (bytes, checksum)
+                };
+                *result = checksum;
+
+                options.emit(Event::TransferFinished {
+                    host: host.address.clone(),
+                    task: task_name,
+                    path: dest.clone(),
+                    bytes,
+                    seconds: started.elapsed().as_secs_f64(),
+                });
 
                 TaskResult::Changed(host.clone(), self.clone())
             }
@@ -191,63 +1543,380 @@ impl TaskKind {
                 src,
                 dest,
                 variables,
+                jinja2,
+                ref mut result,
+                diff: ref mut shown,
                 ..
             } => {
                 let dest = PathBuf::from(dest.clone());
-                let template = read_file(src)?;
 
                 let mut context = context.clone();
                 for (key, val) in variables.iter() {
                     context.insert(key, val);
                 }
 
-                let rendered_template = render_template(&template, &context)?;
-                let mut remote_file = session.sftp()?.create(&dest)?;
-                remote_file.write_all(rendered_template.as_bytes())?;
+                let rendered_template = templates.render(src, jinja2.unwrap_or(false), &context)?;
+                let rendered_template = host.platform().text(&rendered_template);
+                *result = sha256_hex(rendered_template.as_bytes());
 
-                TaskResult::Changed(host.clone(), self.clone())
+                let detector = ChangeDetector::Checksum {
+                    path: dest.clone(),
+                    checksum: result.clone(),
+                };
+                let changed = detector.needs_change(connection.as_mut())?;
+                if changed && options.diff {
+                    let rendered = rendered_template.as_bytes();
+                    *shown = Some(file_diff(
+                        connection.as_mut(),
+                        &dest,
+                        rendered.len() as u64,
+                        |_| Ok(rendered.to_vec()),
+                    )?);
+                }
+                if changed && !options.check {
+                    connection.write(&dest, &mut rendered_template.as_bytes())?;
+                }
+
+                self.outcome(host, changed)
             }
 
             Self::SearchReplace {
                 path,
                 search,
                 replace,
+                ref mut result,
+                diff: ref mut shown,
                 ..
             } => {
                 let path = PathBuf::from(path.clone());
-                let sftp = session.sftp()?;
-                let mut remote_file = sftp.open(&path)?;
-                let mut contents = String::new();
-                remote_file.read_to_string(&mut contents)?;
+                let contents = String::from_utf8(connection.read(&path)?)?;
 
                 let re = regex::Regex::new(search.as_str())?;
-                let new_contents = re.replace_all(&contents, replace.clone());
+                // Replacements spanning lines keep the host's line endings.
+                let new_contents = re.replace_all(&contents, host.platform().text(replace));
+                *result = sha256_hex(new_contents.as_bytes());
 
-                let mut remote_file = sftp.create(&path)?;
-                remote_file.write_all(new_contents.as_bytes())?;
+                let detector = ChangeDetector::Checksum {
+                    path: path.clone(),
+                    checksum: result.clone(),
+                };
+                let changed = detector.needs_change(connection.as_mut())?;
+                if changed && options.diff {
+                    let name = path.display().to_string();
+                    *shown = Some(
+                        if contents.len().max(new_contents.len()) as u64 > diff::MAX_SIZE {
+                            diff::summary(&name, TOO_LARGE)
+                        } else {
+                            diff::render(&name, Some(contents.as_bytes()), new_contents.as_bytes())
+                        },
+                    );
+                }
+                if changed && !options.check {
+                    connection.write(&path, &mut new_contents.as_bytes())?;
+                }
 
-                if contents == new_contents {
-                    TaskResult::Unchanged(host.clone(), self.clone())
-                } else {
-                    TaskResult::Changed(host.clone(), self.clone())
+                self.outcome(host, changed)
+            }
+
+            Self::Fetch {
+                src,
+                dest,
+                flat,
+                ref mut result,
+                ..
+            } => {
+                let local = fetch::local_path(host, src, dest, flat.unwrap_or(false))?;
+                let started = Instant::now();
+                let (changed, checksum, bytes) =
+                    fetch::fetch(connection.as_mut(), Path::new(src), &local, options.check)?;
+                *result = checksum;
+                if bytes > 0 {
+                    options.emit(Event::TransferFinished {
+                        host: host.address.clone(),
+                        task: task_name,
+                        path: src.clone(),
+                        bytes,
+                        seconds: started.elapsed().as_secs_f64(),
+                    });
+                }
+
+                self.outcome(host, changed)
+            }
+
+            Self::File {
+                path,
+                state,
+                src,
+                mode,
+                owner,
+                group,
+                ref mut result,
+                ..
+            } => {
+                if host.platform() == Platform::Windows {
+                    return Err(AnsimpleError::Config(
+                        "`file` needs a POSIX host".to_owned(),
+                    ));
                 }
+                let wanted = file::Wanted {
+                    state: state.unwrap_or_default(),
+                    src: src.as_deref(),
+                    mode: *mode,
+                    owner: owner.as_deref(),
+                    group: group.as_deref(),
+                };
+                let (changed, done) =
+                    file::ensure(connection.as_mut(), Path::new(path), &wanted, options.check)?;
+                *result = done;
+
+                self.outcome(host, changed)
+            }
+
+            Self::Plugin {
+                module,
+                args,
+                ref mut result,
+                ..
+            } => {
+                let (changed, output) = match find_module(module) {
+                    Some(module) if options.check => (
+                        module.check(args, host, connection.as_mut())?,
+                        String::new(),
+                    ),
+                    Some(module) => module.execute(args, host, connection.as_mut())?,
+                    None => {
+                        let plugin = options.plugins.get(module).ok_or_else(|| {
+                            AnsimpleError::Config(format!("no plugin provides module `{module}`"))
+                        })?;
+                        run_plugin(plugin, module, args, host, connection.as_mut())?
+                    }
+                };
+                *result = output;
+
+                self.outcome(host, changed)
+            }
+
+            Self::Package {
+                packages,
+                state,
+                ref mut result,
+                ..
+            } => {
+                if host.platform() == Platform::Windows {
+                    return Err(AnsimpleError::Config(
+                        "`package` needs a POSIX host".to_owned(),
+                    ));
+                }
+                let (changed, output) = package::ensure(
+                    connection.as_mut(),
+                    packages,
+                    state.unwrap_or_default(),
+                    options.check,
+                )?;
+                *result = output;
+
+                self.outcome(host, changed)
+            }
+
+            Self::Service {
+                service,
+                state,
+                enabled,
+                daemon_reload,
+                ref mut result,
+                ..
+            } => {
+                if host.platform() == Platform::Windows {
+                    return Err(AnsimpleError::Config(
+                        "`service` needs a POSIX host".to_owned(),
+                    ));
+                }
+                let (changed, output) = service::ensure(
+                    connection.as_mut(),
+                    service,
+                    *state,
+                    *enabled,
+                    daemon_reload.unwrap_or(false),
+                    options.check,
+                )?;
+                *result = output;
+
+                self.outcome(host, changed)
+            }
+
+            Self::Git {
+                repo,
+                dest,
+                version,
+                force,
+                ref mut result,
+                ref mut before,
+                ref mut after,
+                ..
+            } => {
+                if host.platform() == Platform::Windows {
+                    return Err(AnsimpleError::Config("`git` needs a POSIX host".to_owned()));
+                }
+                let checkout = git::ensure(
+                    connection.as_mut(),
+                    repo,
+                    dest,
+                    version.as_deref(),
+                    force.unwrap_or(false),
+                    options.check,
+                )?;
+                *result = checkout.output;
+                *before = checkout.before;
+                *after = checkout.after;
+
+                self.outcome(host, checkout.changed)
+            }
+            // Playbooks put the included tasks in its place when read.
+            Self::IncludeTasks { file } => {
+                return Err(AnsimpleError::Config(format!(
+                    "{} was not included when the play was read",
+                    file.display()
+                )))
             }
         };
 
-        println!("{result}");
         Ok(result)
     }
 }
 
-fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
-    let contents = fs::read_to_string(path)?;
-    Ok(contents)
+impl TaskKind {
+    // How the task went, by whether it changed something or, in a check
+    // run, would have.
+    fn outcome(&self, host: &Host, changed: bool) -> TaskResult {
+        if changed {
+            TaskResult::Changed(host.clone(), self.clone())
+        } else {
+            TaskResult::Unchanged(host.clone(), self.clone())
+        }
+    }
 }
 
-fn render_template(template: &str, context: &Context) -> Result<String, Box<dyn Error>> {
-    let mut tera = Tera::default();
-    tera.add_raw_template("template", template)?;
+struct ConnectionIo<'a>(&'a mut dyn Connection);
+
+impl HostIo for ConnectionIo<'_> {
+    fn exec(&mut self, command: &str) -> Result<Value, AnsimpleError> {
+        let (stdout, stderr, rc) = self.0.exec(command)?;
+        Ok(json!({ "rc": rc, "stdout": stdout, "stderr": stderr }))
+    }
+
+    fn read(&mut self, path: &str) -> Result<Value, AnsimpleError> {
+        let path = Path::new(path);
+        if !self.0.exists(path)? {
+            return Ok(json!({ "exists": false }));
+        }
+
+        let content = String::from_utf8(self.0.read(path)?)?;
+        Ok(json!({ "exists": true, "content": content }))
+    }
+
+    fn write(&mut self, path: &str, content: &str) -> Result<Value, AnsimpleError> {
+        self.0.write(Path::new(path), &mut content.as_bytes())?;
+        Ok(json!({ "written": true }))
+    }
+}
+
+// Drives a plugin module step by step, performing the remote operations it
+// asks for until it reports that it is done.
+fn run_plugin(
+    plugin: &Plugin,
+    module: &str,
+    args: &Value,
+    host: &Host,
+    connection: &mut dyn Connection,
+) -> Result<(bool, String), AnsimpleError> {
+    let mut io = ConnectionIo(connection);
+    let mut state = Value::Null;
+    let mut last = None;
+    for step in 0..plugin::MAX_STEPS {
+        let response = plugin.call(
+            &Request {
+                module,
+                args,
+                host,
+                step,
+                state: &state,
+                last: last.as_ref(),
+            },
+            &mut io,
+        )?;
+        state = response.state;
+
+        last = Some(match response.action {
+            Action::Exec { command } => io.exec(&command)?,
+            Action::Read { path } => io.read(&path)?,
+            Action::Write { path, content } => io.write(&path, &content)?,
+            Action::Done { changed, output } => return Ok((changed, output)),
+            Action::Failed { message } => {
+                return Err(AnsimpleError::Plugin {
+                    path: plugin.path().to_owned(),
+                    message: format!("module `{module}` failed: {message}"),
+                })
+            }
+        });
+    }
+
+    Err(AnsimpleError::Plugin {
+        path: plugin.path().to_owned(),
+        message: format!(
+            "module `{module}` did not finish within {} steps",
+            plugin::MAX_STEPS
+        ),
+    })
+}
+
+// What `--diff` shows of `path` on the host getting `size` bytes, which are
+// only read when both sides are small enough to diff.
+fn file_diff(
+    connection: &mut dyn Connection,
+    path: &Path,
+    size: u64,
+    after: impl FnOnce(&mut dyn Connection) -> Result<Vec<u8>, AnsimpleError>,
+) -> Result<String, AnsimpleError> {
+    let name = path.display().to_string();
+    let before = connection.size(path)?;
+    if size.max(before.unwrap_or_default()) > diff::MAX_SIZE {
+        return Ok(diff::summary(&name, TOO_LARGE));
+    }
+    let after = after(connection)?;
+    let before = match before {
+        Some(_) => Some(connection.read(path)?),
+        None => None,
+    };
+
+    Ok(diff::render(&name, before.as_deref(), &after))
+}
+
+// Whether `path` on the host already has `content`. Files of another size
+// are not read to find out.
+fn holds(
+    connection: &mut dyn Connection,
+    path: &Path,
+    content: &Placed,
+) -> Result<bool, AnsimpleError> {
+    if connection.size(path)? != Some(content.size) {
+        return Ok(false);
+    }
+    let detector = ChangeDetector::Checksum {
+        path: path.to_owned(),
+        checksum: content.sha256.clone(),
+    };
+
+    Ok(!detector.needs_change(connection)?)
+}
+
+pub fn sha256_hex(contents: &[u8]) -> String {
+    hex(&Sha256::digest(contents))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
 
-    let rendered_template = tera.render("template", context)?;
-    Ok(rendered_template)
+fn is_templated(value: &str) -> bool {
+    value.contains("{{") || value.contains("{%")
 }
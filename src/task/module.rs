@@ -0,0 +1,90 @@
+use serde_json::json;
+use tera::Value;
+
+use std::sync::{Arc, RwLock};
+
+use super::TaskKind;
+use crate::connection::Connection;
+use crate::error::AnsimpleError;
+use crate::inventory::Host;
+
+// A task kind of its own, used in playbooks under its name like the built-in
+// ones:
+//
+//   - cron:
+//       name: rotate logs
+//       job: logrotate /etc/logrotate.conf
+//
+// The task's `name` is taken out of the arguments, the rest are templated and
+// passed to the module. It works on the host's connection itself.
+pub trait TaskModule: Send + Sync {
+    // The key naming the module in a task.
+    fn name(&self) -> &str;
+
+    // Whether executing the module would change the host, for check runs.
+    // Nothing on the host may change.
+    fn check(
+        &self,
+        args: &Value,
+        host: &Host,
+        connection: &mut dyn Connection,
+    ) -> Result<bool, AnsimpleError>;
+
+    // Brings the host to what `args` ask for. Returns whether it changed
+    // anything and the output that becomes the registered `stdout`.
+    fn execute(
+        &self,
+        args: &Value,
+        host: &Host,
+        connection: &mut dyn Connection,
+    ) -> Result<(bool, String), AnsimpleError>;
+
+    // What `ansimple schema playbook` accepts for the arguments, next to the
+    // task's `name`.
+    fn schema(&self) -> Value {
+        json!({ "type": "object" })
+    }
+}
+
+// Modules are known by the time playbooks are read, which is when a task's
+// kind is told by its key.
+static MODULES: RwLock<Vec<Arc<dyn TaskModule>>> = RwLock::new(Vec::new());
+
+// Makes `module` a task kind for every playbook read after. Its name cannot
+// be that of a built-in kind or of a module registered before.
+pub fn register_module<M: TaskModule + 'static>(module: M) -> Result<(), AnsimpleError> {
+    let name = module.name();
+    if TaskKind::NAMES.contains(&name) {
+        return Err(AnsimpleError::Config(format!(
+            "`{name}` is a built-in task kind"
+        )));
+    }
+
+    let mut modules = MODULES.write().expect("modules lock poisoned");
+    if modules.iter().any(|registered| registered.name() == name) {
+        return Err(AnsimpleError::Config(format!(
+            "task module `{name}` is already registered"
+        )));
+    }
+    modules.push(Arc::new(module));
+
+    Ok(())
+}
+
+pub fn find_module(name: &str) -> Option<Arc<dyn TaskModule>> {
+    MODULES
+        .read()
+        .expect("modules lock poisoned")
+        .iter()
+        .find(|module| module.name() == name)
+        .cloned()
+}
+
+pub fn module_names() -> Vec<String> {
+    MODULES
+        .read()
+        .expect("modules lock poisoned")
+        .iter()
+        .map(|module| module.name().to_owned())
+        .collect()
+}
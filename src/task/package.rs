@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use tera::Value;
+
+use crate::connection::{quote, Connection};
+use crate::error::AnsimpleError;
+use crate::schema::{self, Generator, Schema};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageState {
+    // Installed, in whatever version.
+    #[default]
+    Present,
+    Absent,
+    // Installed and upgraded to the newest version the repositories have.
+    Latest,
+}
+
+impl Schema for PackageState {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["present", "absent", "latest"])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Manager {
+    Apt,
+    Dnf,
+    Yum,
+    Pacman,
+    Apk,
+}
+
+impl Manager {
+    // In the order they are looked for, dnf before the yum it replaced.
+    const ALL: [Manager; 5] = [Self::Apt, Self::Dnf, Self::Yum, Self::Pacman, Self::Apk];
+
+    fn binary(self) -> &'static str {
+        match self {
+            Self::Apt => "apt-get",
+            Self::Dnf => "dnf",
+            Self::Yum => "yum",
+            Self::Pacman => "pacman",
+            Self::Apk => "apk",
+        }
+    }
+
+    fn detect(connection: &mut dyn Connection) -> Result<Self, AnsimpleError> {
+        let binaries = Self::ALL.map(Self::binary).join(" ");
+        let command = format!(
+            "for m in {binaries}; do command -v $m >/dev/null 2>&1 && {{ echo $m; exit 0; }}; done; exit 1"
+        );
+        let (stdout, _, _) = connection.exec(&command)?;
+        Self::ALL
+            .into_iter()
+            .find(|manager| manager.binary() == stdout.trim())
+            .ok_or_else(|| {
+                AnsimpleError::Config(format!("no package manager found, looked for {binaries}"))
+            })
+    }
+
+    // Exits with 0 when `package` is installed.
+    fn installed(self, package: &str) -> String {
+        let package = quote(package);
+        match self {
+            Self::Apt => format!(
+                "dpkg-query -W -f='${{Status}}' {package} 2>/dev/null | grep -q 'ok installed'"
+            ),
+            Self::Dnf | Self::Yum => format!("rpm -q {package} >/dev/null 2>&1"),
+            Self::Pacman => format!("pacman -Q {package} >/dev/null 2>&1"),
+            Self::Apk => format!("apk info -e {package} >/dev/null 2>&1"),
+        }
+    }
+
+    // Exits with 0 when the installed `package` has a newer version.
+    fn outdated(self, package: &str) -> String {
+        let package = quote(package);
+        match self {
+            Self::Apt => format!("apt-get -s install --only-upgrade {package} | grep -q '^Inst '"),
+            // check-update exits with 100 when there are updates.
+            Self::Dnf | Self::Yum => {
+                format!(
+                    "{} -q check-update {package} >/dev/null; [ $? -eq 100 ]",
+                    self.binary()
+                )
+            }
+            Self::Pacman => format!("pacman -Qu {package} >/dev/null 2>&1"),
+            Self::Apk => format!("apk list -u {package} 2>/dev/null | grep -q ."),
+        }
+    }
+
+    fn install(self, packages: &[&String]) -> String {
+        let packages = join(packages);
+        match self {
+            Self::Apt => {
+                format!("DEBIAN_FRONTEND=noninteractive apt-get install -y -q {packages}")
+            }
+            Self::Dnf | Self::Yum => format!("{} install -y -q {packages}", self.binary()),
+            Self::Pacman => format!("pacman -S --noconfirm --needed {packages}"),
+            Self::Apk => format!("apk add -q {packages}"),
+        }
+    }
+
+    fn upgrade(self, packages: &[&String]) -> String {
+        let packages = join(packages);
+        match self {
+            Self::Apt => format!(
+                "DEBIAN_FRONTEND=noninteractive apt-get install -y -q --only-upgrade {packages}"
+            ),
+            Self::Dnf | Self::Yum => format!("{} upgrade -y -q {packages}", self.binary()),
+            Self::Pacman => format!("pacman -S --noconfirm {packages}"),
+            Self::Apk => format!("apk add -q -u {packages}"),
+        }
+    }
+
+    fn remove(self, packages: &[&String]) -> String {
+        let packages = join(packages);
+        match self {
+            Self::Apt => format!("DEBIAN_FRONTEND=noninteractive apt-get remove -y -q {packages}"),
+            Self::Dnf | Self::Yum => format!("{} remove -y -q {packages}", self.binary()),
+            Self::Pacman => format!("pacman -R --noconfirm {packages}"),
+            Self::Apk => format!("apk del -q {packages}"),
+        }
+    }
+}
+
+// Brings `packages` into `state` with the host's package manager, or with
+// `check` only finds out whether it would. Returns whether anything was (or
+// would be) done and what the package manager printed.
+pub fn ensure(
+    connection: &mut dyn Connection,
+    packages: &[String],
+    state: PackageState,
+    check: bool,
+) -> Result<(bool, String), AnsimpleError> {
+    let manager = Manager::detect(connection)?;
+    let mut installed = Vec::new();
+    let mut missing = Vec::new();
+    for package in packages {
+        if connection.exec(&manager.installed(package))?.2 == 0 {
+            installed.push(package);
+        } else {
+            missing.push(package);
+        }
+    }
+
+    let mut commands = Vec::new();
+    match state {
+        PackageState::Present if !missing.is_empty() => commands.push(manager.install(&missing)),
+        PackageState::Absent if !installed.is_empty() => commands.push(manager.remove(&installed)),
+        PackageState::Latest => {
+            let mut outdated = Vec::new();
+            for package in installed {
+                if connection.exec(&manager.outdated(package))?.2 == 0 {
+                    outdated.push(package);
+                }
+            }
+            if !missing.is_empty() {
+                commands.push(manager.install(&missing));
+            }
+            if !outdated.is_empty() {
+                commands.push(manager.upgrade(&outdated));
+            }
+        }
+        PackageState::Present | PackageState::Absent => {}
+    }
+
+    if check {
+        return Ok((!commands.is_empty(), String::new()));
+    }
+    let mut output = String::new();
+    for command in &commands {
+        let (stdout, stderr, rc) = connection.exec(command)?;
+        if rc != 0 {
+            return Err(AnsimpleError::Command {
+                command: command.clone(),
+                rc,
+                stderr: stderr.trim().to_owned(),
+            });
+        }
+        output.push_str(&stdout);
+    }
+
+    Ok((!commands.is_empty(), output))
+}
+
+fn join(packages: &[&String]) -> String {
+    packages
+        .iter()
+        .map(|package| quote(package))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
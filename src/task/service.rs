@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use tera::Value;
+
+use crate::connection::{quote, Connection};
+use crate::error::AnsimpleError;
+use crate::schema::{self, Generator, Schema};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceState {
+    Started,
+    Stopped,
+    // Always done, so they always report a change.
+    Restarted,
+    Reloaded,
+}
+
+impl Schema for ServiceState {
+    fn schema(_: &mut Generator) -> Value {
+        schema::names(&["started", "stopped", "restarted", "reloaded"])
+    }
+}
+
+// What the host manages its services with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Manager {
+    Systemd,
+    // The `service` command of SysV init and OpenRC, with `chkconfig`,
+    // `rc-update` or `update-rc.d` for what starts at boot.
+    Service,
+}
+
+impl Manager {
+    fn detect(connection: &mut dyn Connection) -> Result<Self, AnsimpleError> {
+        let command = "if command -v systemctl >/dev/null 2>&1 && [ -d /run/systemd/system ]; \
+                       then echo systemd; elif command -v service >/dev/null 2>&1; \
+                       then echo service; fi";
+        match connection.exec(command)?.0.trim() {
+            "systemd" => Ok(Self::Systemd),
+            "service" => Ok(Self::Service),
+            _ => Err(AnsimpleError::Config(
+                "neither systemctl nor service found".to_owned(),
+            )),
+        }
+    }
+
+    // Exits with 0 when `service` is running.
+    fn active(self, service: &str) -> String {
+        match self {
+            Self::Systemd => format!("systemctl is-active --quiet {service}"),
+            Self::Service => format!("service {service} status >/dev/null 2>&1"),
+        }
+    }
+
+    // Exits with 0 when `service` starts at boot.
+    fn enabled(self, service: &str) -> String {
+        match self {
+            Self::Systemd => format!("systemctl is-enabled --quiet {service}"),
+            Self::Service => format!(
+                "if command -v chkconfig >/dev/null 2>&1; then chkconfig {service}; \
+                 elif command -v rc-update >/dev/null 2>&1; then rc-update show default | grep -qw {service}; \
+                 else ls /etc/rc[2345].d/S??{service} >/dev/null 2>&1; fi"
+            ),
+        }
+    }
+
+    fn action(self, service: &str, action: &str) -> String {
+        match self {
+            Self::Systemd => format!("systemctl {action} {service}"),
+            Self::Service => format!("service {service} {action}"),
+        }
+    }
+
+    fn enable(self, service: &str, enable: bool) -> String {
+        match (self, enable) {
+            (Self::Systemd, true) => format!("systemctl enable {service}"),
+            (Self::Systemd, false) => format!("systemctl disable {service}"),
+            (Self::Service, true) => format!(
+                "if command -v chkconfig >/dev/null 2>&1; then chkconfig {service} on; \
+                 elif command -v rc-update >/dev/null 2>&1; then rc-update add {service} default; \
+                 else update-rc.d {service} enable; fi"
+            ),
+            (Self::Service, false) => format!(
+                "if command -v chkconfig >/dev/null 2>&1; then chkconfig {service} off; \
+                 elif command -v rc-update >/dev/null 2>&1; then rc-update del {service} default; \
+                 else update-rc.d {service} disable; fi"
+            ),
+        }
+    }
+}
+
+// Brings `service` into `state` and makes it start at boot or not, checking
+// first what is already so; with `check` it only checks. Returns whether
+// anything changed (or would) and what the commands printed.
+pub fn ensure(
+    connection: &mut dyn Connection,
+    service: &str,
+    state: Option<ServiceState>,
+    enabled: Option<bool>,
+    daemon_reload: bool,
+    check: bool,
+) -> Result<(bool, String), AnsimpleError> {
+    if state.is_none() && enabled.is_none() {
+        return Err(AnsimpleError::Config(
+            "`service` needs a `state` or `enabled`".to_owned(),
+        ));
+    }
+    let manager = Manager::detect(connection)?;
+    let service = quote(service);
+    let mut output = String::new();
+
+    // Picks up changed unit files, which by itself changes nothing.
+    if daemon_reload && manager == Manager::Systemd && !check {
+        run(connection, "systemctl daemon-reload", &mut output)?;
+    }
+
+    let mut commands = Vec::new();
+    match state {
+        Some(ServiceState::Started) if !succeeds(connection, &manager.active(&service))? => {
+            commands.push(manager.action(&service, "start"))
+        }
+        Some(ServiceState::Stopped) if succeeds(connection, &manager.active(&service))? => {
+            commands.push(manager.action(&service, "stop"))
+        }
+        Some(ServiceState::Restarted) => commands.push(manager.action(&service, "restart")),
+        Some(ServiceState::Reloaded) => commands.push(manager.action(&service, "reload")),
+        Some(ServiceState::Started | ServiceState::Stopped) | None => {}
+    }
+    if let Some(enable) = enabled {
+        if succeeds(connection, &manager.enabled(&service))? != enable {
+            commands.push(manager.enable(&service, enable));
+        }
+    }
+
+    if check {
+        return Ok((!commands.is_empty(), output));
+    }
+    for command in &commands {
+        run(connection, command, &mut output)?;
+    }
+
+    Ok((!commands.is_empty(), output))
+}
+
+fn succeeds(connection: &mut dyn Connection, command: &str) -> Result<bool, AnsimpleError> {
+    Ok(connection.exec(command)?.2 == 0)
+}
+
+fn run(
+    connection: &mut dyn Connection,
+    command: &str,
+    output: &mut String,
+) -> Result<(), AnsimpleError> {
+    let (stdout, stderr, rc) = connection.exec(command)?;
+    if rc != 0 {
+        return Err(AnsimpleError::Command {
+            command: command.to_owned(),
+            rc,
+            stderr: stderr.trim().to_owned(),
+        });
+    }
+    output.push_str(&stdout);
+
+    Ok(())
+}
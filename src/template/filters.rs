@@ -0,0 +1,242 @@
+use regex::Regex;
+use tera::{Tera, Value};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::encoding;
+
+pub fn register(tera: &mut Tera) {
+    tera.register_filter("to_json", to_json);
+    tera.register_filter("to_yaml", to_yaml);
+    tera.register_filter("b64encode", b64encode);
+    tera.register_filter("b64decode", b64decode);
+    tera.register_filter("regex_replace", regex_replace);
+    tera.register_filter("ipaddr", ipaddr);
+    tera.register_filter("mandatory", mandatory);
+    tera.register_filter("bool", bool);
+}
+
+// Ansible's loose booleans, for `when: flag | bool` with flags given as
+// strings such as `yes`.
+fn bool(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let truth = match value {
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(text) => matches!(
+            text.trim().to_lowercase().as_str(),
+            "yes" | "y" | "true" | "on" | "1"
+        ),
+        Value::Null => false,
+        Value::Array(_) | Value::Object(_) => {
+            return Err(tera::Error::msg(
+                "bool: expected a string, number or boolean",
+            ))
+        }
+    };
+
+    Ok(Value::Bool(truth))
+}
+
+// Undefined values never reach a filter, they are reported before rendering.
+fn mandatory(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(value.clone())
+}
+
+fn to_json(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let json = match args.get("indent").and_then(Value::as_u64) {
+        Some(indent) => {
+            let indent = " ".repeat(indent as usize);
+            let mut buf = Vec::new();
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+            let mut serializer = serde_json::Serializer::with_formatter(&mut buf, formatter);
+            serde::Serialize::serialize(value, &mut serializer)
+                .map_err(|err| tera::Error::msg(format!("to_json: {err}")))?;
+            String::from_utf8(buf).map_err(|err| tera::Error::msg(format!("to_json: {err}")))?
+        }
+        None => serde_json::to_string(value)
+            .map_err(|err| tera::Error::msg(format!("to_json: {err}")))?,
+    };
+
+    Ok(Value::String(json))
+}
+
+fn to_yaml(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let yaml =
+        serde_yaml::to_string(value).map_err(|err| tera::Error::msg(format!("to_yaml: {err}")))?;
+
+    Ok(Value::String(yaml.trim_end().to_owned()))
+}
+
+fn b64encode(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let input = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("b64encode: value must be a string"))?;
+
+    Ok(Value::String(encoding::b64encode(input)))
+}
+
+fn b64decode(value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let input = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("b64decode: value must be a string"))?;
+    let decoded = encoding::b64decode(input)
+        .ok_or_else(|| tera::Error::msg("b64decode: invalid base64 input"))?;
+    let decoded = String::from_utf8(decoded)
+        .map_err(|_| tera::Error::msg("b64decode: decoded value is not valid utf8"))?;
+
+    Ok(Value::String(decoded))
+}
+
+fn regex_replace(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let input = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("regex_replace: value must be a string"))?;
+    let pattern = args
+        .get("pattern")
+        .and_then(Value::as_str)
+        .ok_or_else(|| tera::Error::msg("regex_replace: missing `pattern` argument"))?;
+    let replace = args.get("replace").and_then(Value::as_str).unwrap_or("");
+
+    let re =
+        Regex::new(pattern).map_err(|err| tera::Error::msg(format!("regex_replace: {err}")))?;
+
+    Ok(Value::String(re.replace_all(input, replace).into_owned()))
+}
+
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(input: &str) -> Option<Self> {
+        let (addr, prefix) = match input.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (input.parse::<IpAddr>().ok()?, None),
+        };
+
+        let width = width(&addr);
+        let prefix = prefix.unwrap_or(width);
+        if prefix > width {
+            return None;
+        }
+
+        Some(Self { addr, prefix })
+    }
+
+    fn width(&self) -> u32 {
+        width(&self.addr)
+    }
+
+    fn mask(&self) -> u128 {
+        let width = self.width();
+        if self.prefix == 0 {
+            0
+        } else {
+            (u128::MAX << (128 - self.prefix)) >> (128 - width)
+        }
+    }
+
+    fn network(&self) -> u128 {
+        to_bits(&self.addr) & self.mask()
+    }
+
+    fn broadcast(&self) -> u128 {
+        let all = u128::MAX >> (128 - self.width());
+        self.network() | (all & !self.mask())
+    }
+
+    fn size(&self) -> u128 {
+        1u128
+            .checked_shl(self.width() - self.prefix)
+            .unwrap_or(u128::MAX)
+    }
+
+    fn ip(&self, bits: u128) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+        }
+    }
+}
+
+fn width(addr: &IpAddr) -> u32 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn to_bits(addr: &IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u128::from(u32::from(*addr)),
+        IpAddr::V6(addr) => u128::from(*addr),
+    }
+}
+
+fn ipaddr(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let input = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("ipaddr: value must be a string"))?;
+    let Some(cidr) = Cidr::parse(input) else {
+        return Ok(Value::Bool(false));
+    };
+
+    let query = match args.get("query") {
+        None => return Ok(value.clone()),
+        Some(query) => query,
+    };
+
+    if let Some(nth) = query.as_u64() {
+        let nth = u128::from(nth);
+        if nth >= cidr.size() {
+            return Ok(Value::Bool(false));
+        }
+
+        let addr = cidr.ip(cidr.network() + nth);
+        return Ok(Value::String(format!("{addr}/{}", cidr.prefix)));
+    }
+
+    let query = query
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("ipaddr: `query` must be a string or an integer"))?;
+
+    let result = match query {
+        "address" => Value::String(cidr.addr.to_string()),
+        "host" => Value::String(format!("{}/{}", cidr.addr, cidr.prefix)),
+        "network" => Value::String(cidr.ip(cidr.network()).to_string()),
+        "net" => Value::String(format!("{}/{}", cidr.ip(cidr.network()), cidr.prefix)),
+        "netmask" => Value::String(cidr.ip(cidr.mask()).to_string()),
+        "broadcast" => Value::String(cidr.ip(cidr.broadcast()).to_string()),
+        "prefix" => Value::from(cidr.prefix),
+        "size" => Value::from(cidr.size() as u64),
+        "first_usable" => {
+            let first = if cidr.size() > 2 {
+                cidr.network() + 1
+            } else {
+                cidr.network()
+            };
+            Value::String(cidr.ip(first).to_string())
+        }
+        "last_usable" => {
+            let last = if cidr.size() > 2 {
+                cidr.broadcast() - 1
+            } else {
+                cidr.broadcast()
+            };
+            Value::String(cidr.ip(last).to_string())
+        }
+        "ipv4" => match cidr.addr {
+            IpAddr::V4(_) => value.clone(),
+            IpAddr::V6(_) => Value::Bool(false),
+        },
+        "ipv6" => match cidr.addr {
+            IpAddr::V4(_) => Value::Bool(false),
+            IpAddr::V6(_) => value.clone(),
+        },
+        query => return Err(tera::Error::msg(format!("ipaddr: unknown query `{query}`"))),
+    };
+
+    Ok(result)
+}
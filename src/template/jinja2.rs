@@ -0,0 +1,258 @@
+use regex::{Captures, Regex};
+
+use std::sync::OnceLock;
+
+// Maps a Jinja2 filter to its Tera name and the keyword names of its
+// positional arguments. An empty keyword drops the argument.
+const FILTERS: &[(&str, &str, &[&str])] = &[
+    ("d", "default", &["value"]),
+    ("default", "default", &["value"]),
+    ("join", "join", &["sep"]),
+    ("replace", "replace", &["from", "to"]),
+    ("round", "round", &["precision", "method"]),
+    ("truncate", "truncate", &["length", "", "end"]),
+    ("indent", "indent", &["prefix", "first", "blank"]),
+    ("split", "split", &["pat"]),
+    ("int", "int", &["default", "base"]),
+    ("float", "float", &["default"]),
+    ("count", "length", &[]),
+    ("e", "escape", &[]),
+    ("tojson", "json_encode", &[]),
+    ("to_nice_json", "to_json", &[]),
+    ("to_nice_yaml", "to_yaml", &[]),
+    ("regex_replace", "regex_replace", &["pattern", "replace"]),
+    ("ipaddr", "ipaddr", &["query"]),
+];
+
+// Jinja2 filters that have no Tera equivalent because Tera values are
+// already materialized.
+const DROPPED_FILTERS: &[&str] = &["list"];
+
+const TESTS: &[(&str, &str)] = &[
+    ("mapping", "object"),
+    ("sequence", "iterable"),
+    ("match", "matching"),
+    ("search", "matching"),
+];
+
+pub fn translate(template: &str) -> String {
+    let mut translated = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let (text, tail) = rest.split_at(start);
+        translated.push_str(text);
+
+        let close = match tail.get(..2) {
+            Some("{{") => "}}",
+            Some("{%") => "%}",
+            Some("{#") => "#}",
+            _ => {
+                translated.push('{');
+                rest = &tail[1..];
+                continue;
+            }
+        };
+
+        let Some(end) = find_close(&tail[2..], close) else {
+            translated.push_str(tail);
+            return translated;
+        };
+
+        let inner = &tail[2..2 + end];
+        rest = &tail[2 + end + 2..];
+
+        match close {
+            "#}" => {
+                translated.push_str("{#");
+                translated.push_str(inner);
+                translated.push_str("#}");
+            }
+            "}}" => {
+                translated.push_str("{{");
+                translated.push_str(&translate_expression(inner));
+                translated.push_str("}}");
+            }
+            _ => {
+                let (inner, keep_newline) = strip_block_markers(inner);
+
+                if inner.trim().trim_matches('-').trim() == "raw" {
+                    if let Some(raw_end) = rest.find("endraw") {
+                        if let Some(block_end) = rest[raw_end..].find("%}") {
+                            let block = &rest[..raw_end + block_end + 2];
+                            translated.push_str("{%");
+                            translated.push_str(&inner);
+                            translated.push_str("%}");
+                            translated.push_str(block);
+                            rest = &rest[raw_end + block_end + 2..];
+                            continue;
+                        }
+                    }
+                }
+
+                translated.push_str("{%");
+                translated.push_str(&translate_expression(&inner));
+                translated.push_str("%}");
+
+                // ansible renders templates with `trim_blocks` enabled
+                if !keep_newline && !inner.ends_with('-') {
+                    if let Some(stripped) = rest.strip_prefix('\n') {
+                        rest = stripped;
+                    } else if let Some(stripped) = rest.strip_prefix("\r\n") {
+                        rest = stripped;
+                    }
+                }
+            }
+        }
+    }
+
+    translated.push_str(rest);
+    translated
+}
+
+fn find_close(input: &str, close: &str) -> Option<usize> {
+    let mut quote = None;
+    let bytes = input.as_bytes();
+
+    for (i, b) in bytes.iter().enumerate() {
+        match quote {
+            Some(q) if *b == q => quote = None,
+            Some(_) => {}
+            None if *b == b'\'' || *b == b'"' => quote = Some(*b),
+            None if input[i..].starts_with(close) => return Some(i),
+            None => {}
+        }
+    }
+
+    None
+}
+
+fn strip_block_markers(inner: &str) -> (String, bool) {
+    let mut inner = inner.strip_prefix('+').unwrap_or(inner).to_owned();
+    let keep_newline = inner.ends_with('+');
+    if keep_newline {
+        inner.pop();
+    }
+
+    (inner, keep_newline)
+}
+
+fn translate_expression(expression: &str) -> String {
+    let (code, literals) = protect_literals(expression);
+
+    let code = code.replace(".items()", "").replace(".iteritems()", "");
+    let code = translate_tests(&code);
+    let code = translate_filters(&code);
+
+    restore_literals(&code, &literals)
+}
+
+fn protect_literals(expression: &str) -> (String, Vec<String>) {
+    let mut code = String::with_capacity(expression.len());
+    let mut literals = Vec::new();
+    let mut chars = expression.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\'' && c != '"' {
+            code.push(c);
+            continue;
+        }
+
+        let mut literal = String::from(c);
+        for next in chars.by_ref() {
+            literal.push(next);
+            if next == c {
+                break;
+            }
+        }
+
+        code.push_str(&format!("\u{0}{}\u{0}", literals.len()));
+        literals.push(literal);
+    }
+
+    (code, literals)
+}
+
+fn restore_literals(code: &str, literals: &[String]) -> String {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let re = PLACEHOLDER.get_or_init(|| Regex::new("\u{0}(\\d+)\u{0}").expect("invalid regex"));
+
+    re.replace_all(code, |caps: &Captures| {
+        let index = caps[1].parse::<usize>().expect("placeholder index");
+        literals[index].clone()
+    })
+    .into_owned()
+}
+
+fn translate_tests(code: &str) -> String {
+    static TEST: OnceLock<Regex> = OnceLock::new();
+    let re =
+        TEST.get_or_init(|| Regex::new(r"\bis(\s+not)?\s+(\w+)\b").expect("invalid test regex"));
+
+    re.replace_all(code, |caps: &Captures| {
+        let negation = caps.get(1).map_or("", |m| m.as_str());
+        let name = &caps[2];
+        let name = TESTS
+            .iter()
+            .find(|(jinja, _)| *jinja == name)
+            .map_or(name, |(_, tera)| tera);
+
+        format!("is{negation} {name}")
+    })
+    .into_owned()
+}
+
+fn translate_filters(code: &str) -> String {
+    static FILTER: OnceLock<Regex> = OnceLock::new();
+    let re = FILTER
+        .get_or_init(|| Regex::new(r"\|\s*(\w+)(\s*\(([^()]*)\))?").expect("invalid filter regex"));
+
+    re.replace_all(code, |caps: &Captures| {
+        let name = &caps[1];
+        if DROPPED_FILTERS.contains(&name) {
+            return String::new();
+        }
+
+        let Some((_, tera_name, params)) = FILTERS.iter().find(|(jinja, _, _)| *jinja == name)
+        else {
+            return caps[0].to_owned();
+        };
+
+        let Some(args) = caps.get(3) else {
+            return format!("| {tera_name}");
+        };
+
+        let args = args
+            .as_str()
+            .split(',')
+            .map(str::trim)
+            .filter(|arg| !arg.is_empty())
+            .enumerate()
+            .filter_map(|(i, arg)| {
+                if is_keyword_arg(arg) {
+                    return Some(arg.to_owned());
+                }
+
+                match params.get(i) {
+                    Some(&"") | None => None,
+                    Some(&"prefix") if arg.chars().all(|c| c.is_ascii_digit()) => {
+                        let width = arg.parse::<usize>().unwrap_or(4);
+                        Some(format!("prefix=\"{}\"", " ".repeat(width)))
+                    }
+                    Some(param) => Some(format!("{param}={arg}")),
+                }
+            })
+            .collect::<Vec<String>>();
+
+        format!("| {tera_name}({})", args.join(", "))
+    })
+    .into_owned()
+}
+
+fn is_keyword_arg(arg: &str) -> bool {
+    let Some((key, rest)) = arg.split_once('=') else {
+        return false;
+    };
+
+    !rest.starts_with('=') && key.trim().chars().all(|c| c.is_alphanumeric() || c == '_')
+}
@@ -0,0 +1,270 @@
+use rand::Rng;
+use regex::Regex;
+use tera::{Tera, Value};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+use crate::secrets;
+
+const DEFAULT_PASSWORD_LENGTH: usize = 20;
+const ASCII_LETTERS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const PUNCTUATION: &str = "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+
+pub fn register(tera: &mut Tera) {
+    tera.register_function("lookup", lookup);
+}
+
+// Tera functions only accept keyword arguments, so `lookup('env', 'HOME')`
+// is rewritten to `lookup(kind='env', term='HOME')` before compiling.
+pub fn rewrite_calls(template: &str) -> Cow<'_, str> {
+    static LOOKUP_CALL: OnceLock<Regex> = OnceLock::new();
+    let re = LOOKUP_CALL.get_or_init(|| {
+        Regex::new(r#"\blookup\(\s*(?:'(\w+)'|"(\w+)")\s*,\s*"#).expect("invalid lookup regex")
+    });
+
+    re.replace_all(template, |caps: &regex::Captures| {
+        let kind = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .map_or("", |m| m.as_str());
+        format!("lookup(kind=\"{kind}\", term=")
+    })
+}
+
+fn lookup(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let kind = args
+        .get("kind")
+        .and_then(Value::as_str)
+        .ok_or_else(|| tera::Error::msg("lookup: missing lookup kind"))?;
+    let term = args
+        .get("term")
+        .and_then(Value::as_str)
+        .ok_or_else(|| tera::Error::msg(format!("lookup({kind}): missing term")))?;
+
+    let value = match kind {
+        "env" => lookup_env(term),
+        "file" => lookup_file(term)?,
+        "pipe" => lookup_pipe(term)?,
+        "password" => lookup_password(term)?,
+        "aws_secret" | "aws_ssm" => lookup_aws(kind, term)?,
+        kind => return Err(tera::Error::msg(format!("lookup: unknown lookup `{kind}`"))),
+    };
+
+    Ok(Value::String(value))
+}
+
+fn lookup_env(name: &str) -> String {
+    let value = std::env::var(name).unwrap_or_default();
+    if secrets::is_designated(name) {
+        secrets::track(&value);
+    }
+
+    value
+}
+
+fn lookup_file(path: &str) -> tera::Result<String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| tera::Error::msg(format!("lookup(file): {path}: {err}")))?;
+
+    Ok(contents.trim_end_matches('\n').to_owned())
+}
+
+fn lookup_pipe(command: &str) -> tera::Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|err| tera::Error::msg(format!("lookup(pipe): {command}: {err}")))?;
+
+    if !output.status.success() {
+        return Err(tera::Error::msg(format!(
+            "lookup(pipe): `{command}` exited with {}",
+            output.status
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.trim_end_matches('\n').to_owned())
+}
+
+fn lookup_password(term: &str) -> tera::Result<String> {
+    let mut parts = term.split_whitespace();
+    let path = parts
+        .next()
+        .ok_or_else(|| tera::Error::msg("lookup(password): missing path"))?;
+
+    let mut length = DEFAULT_PASSWORD_LENGTH;
+    let mut chars = format!("{ASCII_LETTERS}{DIGITS}.,:-_");
+    for option in parts {
+        match option.split_once('=') {
+            Some(("length", val)) => {
+                length = val.parse().map_err(|_| {
+                    tera::Error::msg(format!("lookup(password): invalid length `{val}`"))
+                })?;
+            }
+            Some(("chars", val)) => {
+                chars = val
+                    .split(',')
+                    .map(|set| match set {
+                        "ascii_letters" => ASCII_LETTERS,
+                        "digits" => DIGITS,
+                        "punctuation" => PUNCTUATION,
+                        set => set,
+                    })
+                    .collect();
+            }
+            _ => {
+                return Err(tera::Error::msg(format!(
+                    "lookup(password): unknown option `{option}`"
+                )))
+            }
+        }
+    }
+
+    let path = Path::new(path);
+    if path.exists() {
+        return lookup_file(&path.to_string_lossy());
+    }
+
+    let chars = chars.chars().collect::<Vec<char>>();
+    if chars.is_empty() {
+        return Err(tera::Error::msg("lookup(password): empty character set"));
+    }
+
+    let mut rng = rand::thread_rng();
+    let password = (0..length)
+        .map(|_| chars[rng.gen_range(0..chars.len())])
+        .collect::<String>();
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|err| tera::Error::msg(format!("lookup(password): {err}")))?;
+    }
+    // Only its owner may read the password, and one another host wrote in
+    // the meantime is the one to use.
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            return lookup_file(&path.to_string_lossy())
+        }
+        Err(err) => return Err(tera::Error::msg(format!("lookup(password): {err}"))),
+    };
+    writeln!(file, "{password}")
+        .map_err(|err| tera::Error::msg(format!("lookup(password): {err}")))?;
+
+    Ok(password)
+}
+
+// Secrets are fetched through the aws cli so the standard credential chain
+// (env, profiles, SSO, instance metadata) applies, and cached for the run.
+fn lookup_aws(kind: &str, term: &str) -> tera::Result<String> {
+    static CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    let mut parts = term.split_whitespace();
+    let name = parts
+        .next()
+        .ok_or_else(|| tera::Error::msg(format!("lookup({kind}): missing name")))?;
+
+    let mut region = None;
+    let mut profile = None;
+    let mut key = None;
+    for option in parts {
+        match option.split_once('=') {
+            Some(("region", val)) => region = Some(val),
+            Some(("profile", val)) => profile = Some(val),
+            Some(("key", val)) if kind == "aws_secret" => key = Some(val),
+            _ => {
+                return Err(tera::Error::msg(format!(
+                    "lookup({kind}): unknown option `{option}`"
+                )))
+            }
+        }
+    }
+
+    let cache_key = format!("{kind} {name} {region:?} {profile:?}");
+    let cached = cache
+        .lock()
+        .expect("aws lookup cache poisoned")
+        .get(&cache_key)
+        .cloned();
+
+    let value = match cached {
+        Some(value) => value,
+        None => {
+            let mut command = Command::new("aws");
+            match kind {
+                "aws_secret" => command.args([
+                    "secretsmanager",
+                    "get-secret-value",
+                    "--secret-id",
+                    name,
+                    "--query",
+                    "SecretString",
+                ]),
+                _ => command.args([
+                    "ssm",
+                    "get-parameter",
+                    "--with-decryption",
+                    "--name",
+                    name,
+                    "--query",
+                    "Parameter.Value",
+                ]),
+            };
+            command.args(["--output", "text"]);
+            if let Some(region) = region {
+                command.args(["--region", region]);
+            }
+            if let Some(profile) = profile {
+                command.args(["--profile", profile]);
+            }
+
+            let output = command
+                .output()
+                .map_err(|err| tera::Error::msg(format!("lookup({kind}): {name}: {err}")))?;
+            if !output.status.success() {
+                return Err(tera::Error::msg(format!(
+                    "lookup({kind}): {name}: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+
+            let value = String::from_utf8_lossy(&output.stdout)
+                .trim_end_matches('\n')
+                .to_owned();
+            cache
+                .lock()
+                .expect("aws lookup cache poisoned")
+                .insert(cache_key, value.clone());
+            value
+        }
+    };
+
+    let Some(key) = key else {
+        return Ok(value);
+    };
+
+    let secret: Value = serde_json::from_str(&value)
+        .map_err(|_| tera::Error::msg(format!("lookup({kind}): {name} is not a JSON secret")))?;
+    match secret.get(key) {
+        Some(Value::String(val)) => Ok(val.clone()),
+        Some(val) => Ok(val.to_string()),
+        None => Err(tera::Error::msg(format!(
+            "lookup({kind}): {name} has no key `{key}`"
+        ))),
+    }
+}
@@ -0,0 +1,379 @@
+use tera::{Context, Template, Tera, Value};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::AnsimpleError;
+
+mod filters;
+mod jinja2;
+mod lookups;
+mod strict;
+
+const INLINE_TEMPLATE: &str = "__inline__";
+
+pub fn new_tera() -> Tera {
+    let mut tera = Tera::default();
+    // Templates are registered by their path, config files ending in `.xml`
+    // or `.html` are not to be escaped for a browser.
+    tera.autoescape_on(vec![]);
+    filters::register(&mut tera);
+    lookups::register(&mut tera);
+    tera
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TemplateRegistry {
+    tera: Arc<Tera>,
+    // Templates of `jinja2: true` tasks, and those of the templates
+    // directory translated for them to include.
+    jinja2: Arc<Tera>,
+    strict_vars: bool,
+}
+
+impl TemplateRegistry {
+    // Compiles the templates of `sources` with those under `dir`, which they
+    // include, import and extend by their path in it.
+    pub fn new<'a, I>(
+        sources: I,
+        dir: Option<&Path>,
+        strict_vars: bool,
+    ) -> Result<Self, AnsimpleError>
+    where
+        I: IntoIterator<Item = (&'a str, bool)>,
+    {
+        let included = match dir {
+            Some(dir) => read_dir(dir, dir)?,
+            None => Vec::new(),
+        };
+        let mut templates = [false, true].map(|jinja2| parsed(&included, jinja2));
+        for (src, jinja2) in sources {
+            let templates = &mut templates[usize::from(jinja2)];
+            if templates.iter().any(|(existing, _)| existing == src) {
+                continue;
+            }
+
+            let template = read_template(src)?;
+            templates.push((src.to_owned(), prepare(&template, jinja2)));
+        }
+
+        let [tera, jinja2] = templates.map(|templates| {
+            let mut tera = new_tera();
+            tera.add_raw_templates(templates)?;
+            Ok::<_, AnsimpleError>(Arc::new(tera))
+        });
+
+        Ok(Self {
+            tera: tera?,
+            jinja2: jinja2?,
+            strict_vars,
+        })
+    }
+
+    // Templates whose `src` is itself a template are compiled as they are
+    // rendered, next to the others.
+    pub fn render(
+        &self,
+        src: &str,
+        jinja2: bool,
+        context: &Context,
+    ) -> Result<String, AnsimpleError> {
+        let tera = if jinja2 { &self.jinja2 } else { &self.tera };
+        if tera.get_template_names().any(|existing| existing == src) {
+            return self.render_compiled(tera, src, src, context);
+        }
+
+        let template = read_template(src)?;
+        let mut tera = Tera::clone(tera);
+        tera.add_raw_template(src, &prepare(&template, jinja2))?;
+        self.render_compiled(&tera, src, src, context)
+    }
+
+    pub fn render_str(
+        &self,
+        template: &str,
+        context: &Context,
+        location: &str,
+    ) -> Result<String, AnsimpleError> {
+        let mut tera = new_tera();
+        tera.add_raw_template(INLINE_TEMPLATE, &prepare(template, false))?;
+        self.render_compiled(&tera, INLINE_TEMPLATE, location, context)
+    }
+
+    // Evaluates a condition such as `result.changed and port > 80` the way
+    // `{% if %}` does. It may also be written inside `{{ }}`.
+    pub fn evaluate(
+        &self,
+        condition: &str,
+        context: &Context,
+        location: &str,
+    ) -> Result<bool, AnsimpleError> {
+        let condition = expression(condition);
+        let template = format!("{{% if {condition} %}}true{{% else %}}false{{% endif %}}");
+
+        Ok(self.render_expression(&template, context, location)? == "true")
+    }
+
+    // The value of an expression such as `packages | reverse`, which may also
+    // be written inside `{{ }}`.
+    pub fn value_of(
+        &self,
+        expression: &str,
+        context: &Context,
+        location: &str,
+    ) -> Result<Value, AnsimpleError> {
+        let template = format!("{{{{ {} | json_encode() }}}}", self::expression(expression));
+        let rendered = self.render_expression(&template, context, location)?;
+
+        Ok(serde_json::from_str(&rendered)?)
+    }
+
+    // Renders a template built around an expression. Tera names the inline
+    // template, the expression's location says more.
+    fn render_expression(
+        &self,
+        template: &str,
+        context: &Context,
+        location: &str,
+    ) -> Result<String, AnsimpleError> {
+        self.render_str(template, context, location)
+            .map_err(|err| match err {
+                AnsimpleError::Template(message) => {
+                    let prefix = format!("Failed to render '{INLINE_TEMPLATE}': ");
+                    let suffix = format!(" while rendering '{INLINE_TEMPLATE}'");
+                    match message.strip_prefix(&prefix) {
+                        Some(message) => {
+                            let message = message.strip_suffix(&suffix).unwrap_or(message);
+                            AnsimpleError::Template(format!("{location}: {message}"))
+                        }
+                        None => AnsimpleError::Template(message),
+                    }
+                }
+                err => err,
+            })
+    }
+
+    pub fn render_value(
+        &self,
+        value: &Value,
+        context: &Context,
+        location: &str,
+    ) -> Result<Value, AnsimpleError> {
+        let rendered = match value {
+            Value::String(template) if template.contains("{{") || template.contains("{%") => {
+                Value::String(self.render_str(template, context, location)?)
+            }
+            Value::Array(values) => Value::Array(
+                values
+                    .iter()
+                    .map(|value| self.render_value(value, context, location))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(values) => Value::Object(
+                values
+                    .iter()
+                    .map(|(key, value)| {
+                        let location = format!("{location}.{key}");
+                        Ok((key.clone(), self.render_value(value, context, &location)?))
+                    })
+                    .collect::<Result<_, AnsimpleError>>()?,
+            ),
+            value => value.clone(),
+        };
+
+        Ok(rendered)
+    }
+
+    fn render_compiled(
+        &self,
+        tera: &Tera,
+        name: &str,
+        location: &str,
+        context: &Context,
+    ) -> Result<String, AnsimpleError> {
+        let template = tera.get_template(name)?;
+        let (kind, undefined) = if self.strict_vars {
+            (
+                "undefined",
+                strict::undefined_variables(&template.ast, context),
+            )
+        } else {
+            (
+                "undefined mandatory",
+                strict::undefined_mandatory_variables(&template.ast, context),
+            )
+        };
+
+        if !undefined.is_empty() {
+            return Err(AnsimpleError::Template(format!(
+                "{kind} variable{} {} in {location}",
+                if undefined.len() == 1 { "" } else { "s" },
+                format_names(&undefined)
+            )));
+        }
+
+        Ok(tera.render(name, context)?)
+    }
+}
+
+// Whether `template` compiles, for checking playbooks without running them.
+pub fn check_str(template: &str, location: &str) -> Result<(), AnsimpleError> {
+    new_tera()
+        .add_raw_template(INLINE_TEMPLATE, &prepare(template, false))
+        .map_err(|err| {
+            // Tera names the inline template, the location says more.
+            let message = AnsimpleError::from(err).to_string();
+            let prefix = format!("Failed to parse '{INLINE_TEMPLATE}': ");
+            let message = message.strip_prefix(&prefix).unwrap_or(&message);
+            AnsimpleError::Template(format!("{location}: {}", message.trim_start()))
+        })
+}
+
+// Like `check_str`, for the templates in a value as
+// `TemplateRegistry::render_value` renders them.
+pub fn check_value(value: &Value, location: &str) -> Result<(), AnsimpleError> {
+    match value {
+        Value::String(template) if template.contains("{{") || template.contains("{%") => {
+            check_str(template, location)
+        }
+        Value::Array(values) => values
+            .iter()
+            .try_for_each(|value| check_value(value, location)),
+        Value::Object(values) => values
+            .iter()
+            .try_for_each(|(key, value)| check_value(value, &format!("{location}.{key}"))),
+        _ => Ok(()),
+    }
+}
+
+// Like `check_str`, for what `TemplateRegistry::evaluate` takes.
+pub fn check_condition(condition: &str, location: &str) -> Result<(), AnsimpleError> {
+    let condition = expression(condition);
+    check_str(
+        &format!("{{% if {condition} %}}true{{% else %}}false{{% endif %}}"),
+        location,
+    )
+}
+
+// Like `check_str`, for what `TemplateRegistry::value_of` takes.
+pub fn check_expression(text: &str, location: &str) -> Result<(), AnsimpleError> {
+    check_str(
+        &format!("{{{{ {} | json_encode() }}}}", expression(text)),
+        location,
+    )
+}
+
+// Whether the template file at `src` is there and compiles, with the
+// templates of `dir` it may include.
+pub fn check_file(src: &str, jinja2: bool, dir: Option<&Path>) -> Result<(), AnsimpleError> {
+    TemplateRegistry::new([(src, jinja2)], dir, false).map(|_| ())
+}
+
+// An expression without the `{{ }}` it may be written inside.
+pub fn expression(text: &str) -> &str {
+    let text = text.trim();
+    text.strip_prefix("{{")
+        .and_then(|text| text.strip_suffix("}}"))
+        .unwrap_or(text)
+}
+
+pub fn missing_variables<'a, I>(context: &Context, names: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let context = context.clone().into_json();
+    names
+        .into_iter()
+        .filter(|name| !strict::is_defined(&context, name))
+        .cloned()
+        .collect()
+}
+
+pub fn format_names(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| format!("`{name}`"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn read_template(src: &str) -> Result<String, AnsimpleError> {
+    fs::read_to_string(src).map_err(|source| AnsimpleError::Read {
+        path: PathBuf::from(src),
+        source,
+    })
+}
+
+// The files under `dir`, named by their path in `root`. Those that are not
+// text are not templates.
+fn read_dir(root: &Path, dir: &Path) -> Result<Vec<(String, String)>, AnsimpleError> {
+    let read_error = |source| AnsimpleError::Read {
+        path: dir.to_owned(),
+        source,
+    };
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(read_error)?;
+    entries.sort();
+
+    let mut templates = Vec::new();
+    for path in entries {
+        if path.is_dir() {
+            templates.extend(read_dir(root, &path)?);
+            continue;
+        }
+        let template = match fs::read_to_string(&path) {
+            Ok(template) => template,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => continue,
+            Err(source) => return Err(AnsimpleError::Read { path, source }),
+        };
+        let name = path.strip_prefix(root).unwrap_or(&path);
+        templates.push((name.to_string_lossy().into_owned(), template));
+    }
+
+    Ok(templates)
+}
+
+// The templates of a templates directory that parse as Tera or, with
+// `jinja2`, Jinja2 ones. The others are likely in the other syntax and are
+// left out, and so are those extending one left out.
+fn parsed(templates: &[(String, String)], jinja2: bool) -> Vec<(String, String)> {
+    let mut parsed = templates
+        .iter()
+        .filter_map(|(name, template)| {
+            let template = prepare(template, jinja2);
+            let parent = Template::new(name, None, &template).ok()?.parent;
+            Some((name.clone(), template, parent))
+        })
+        .collect::<Vec<_>>();
+    loop {
+        let names = parsed
+            .iter()
+            .map(|(name, ..)| name.clone())
+            .collect::<Vec<_>>();
+        let before = parsed.len();
+        parsed.retain(|(_, _, parent)| parent.as_ref().is_none_or(|parent| names.contains(parent)));
+        if parsed.len() == before {
+            break;
+        }
+    }
+
+    parsed
+        .into_iter()
+        .map(|(name, template, _)| (name, template))
+        .collect()
+}
+
+fn prepare(template: &str, jinja2: bool) -> String {
+    if jinja2 {
+        lookups::rewrite_calls(&jinja2::translate(template)).into_owned()
+    } else {
+        lookups::rewrite_calls(template).into_owned()
+    }
+}
@@ -0,0 +1,259 @@
+use tera::ast::{Expr, ExprVal, Node};
+use tera::{Context, Value};
+
+use std::cell::OnceCell;
+use std::collections::HashSet;
+
+const BUILTIN_NAMES: &[&str] = &["loop", "__tera_context", "self", "super"];
+
+pub fn undefined_variables(ast: &[Node], context: &Context) -> Vec<String> {
+    check(ast, context, false)
+}
+
+pub fn undefined_mandatory_variables(ast: &[Node], context: &Context) -> Vec<String> {
+    check(ast, context, true)
+}
+
+pub fn is_defined(context: &Value, ident: &str) -> bool {
+    tera::dotted_pointer(context, &pointer(ident)).is_some()
+}
+
+fn check(ast: &[Node], context: &Context, mandatory_only: bool) -> Vec<String> {
+    let mut checker = Checker {
+        context,
+        json: OnceCell::new(),
+        mandatory_only,
+        active: !mandatory_only,
+        guarded: HashSet::new(),
+        scope: Vec::new(),
+        undefined: Vec::new(),
+    };
+
+    checker.collect_guards(ast);
+    checker.nodes(ast);
+    checker.undefined
+}
+
+struct Checker<'a> {
+    context: &'a Context,
+    json: OnceCell<Value>,
+    mandatory_only: bool,
+    active: bool,
+    guarded: HashSet<String>,
+    scope: Vec<String>,
+    undefined: Vec<String>,
+}
+
+impl Checker<'_> {
+    // Variables tested with `is defined` / `is undefined` are allowed to be
+    // missing anywhere in the template.
+    fn collect_guards(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::If(condition, _) => {
+                    for (_, expr, body) in &condition.conditions {
+                        self.collect_expr_guards(expr);
+                        self.collect_guards(body);
+                    }
+                    if let Some((_, body)) = &condition.otherwise {
+                        self.collect_guards(body);
+                    }
+                }
+                Node::Forloop(_, forloop, _) => {
+                    self.collect_guards(&forloop.body);
+                    if let Some(body) = &forloop.empty_body {
+                        self.collect_guards(body);
+                    }
+                }
+                Node::Block(_, block, _) => self.collect_guards(&block.body),
+                Node::FilterSection(_, section, _) => self.collect_guards(&section.body),
+                _ => {}
+            }
+        }
+    }
+
+    fn collect_expr_guards(&mut self, expr: &Expr) {
+        match &expr.val {
+            ExprVal::Test(test) if test.name == "defined" || test.name == "undefined" => {
+                self.guarded.insert(test.ident.clone());
+                self.guarded.insert(root(&test.ident).to_owned());
+            }
+            ExprVal::Logic(logic) => {
+                self.collect_expr_guards(&logic.lhs);
+                self.collect_expr_guards(&logic.rhs);
+            }
+            _ => {}
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        let depth = self.scope.len();
+
+        for node in nodes {
+            match node {
+                Node::VariableBlock(_, expr) => self.expr(expr),
+                Node::Set(_, set) => {
+                    self.expr(&set.value);
+                    self.scope.push(set.key.clone());
+                }
+                Node::Forloop(_, forloop, _) => {
+                    self.expr(&forloop.container);
+
+                    let depth = self.scope.len();
+                    self.scope.push(forloop.value.clone());
+                    if let Some(key) = &forloop.key {
+                        self.scope.push(key.clone());
+                    }
+                    self.nodes(&forloop.body);
+                    self.scope.truncate(depth);
+
+                    if let Some(body) = &forloop.empty_body {
+                        self.nodes(body);
+                    }
+                }
+                Node::If(condition, _) => {
+                    for (_, expr, body) in &condition.conditions {
+                        self.expr(expr);
+                        self.nodes(body);
+                    }
+                    if let Some((_, body)) = &condition.otherwise {
+                        self.nodes(body);
+                    }
+                }
+                Node::FilterSection(_, section, _) => {
+                    for arg in section.filter.args.values() {
+                        self.expr(arg);
+                    }
+                    self.nodes(&section.body);
+                }
+                Node::Block(_, block, _) => self.nodes(&block.body),
+                Node::MacroDefinition(_, definition, _) => {
+                    let depth = self.scope.len();
+                    self.scope.extend(definition.args.keys().cloned());
+                    self.nodes(&definition.body);
+                    self.scope.truncate(depth);
+                }
+                _ => {}
+            }
+        }
+
+        self.scope.truncate(depth);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        for filter in &expr.filters {
+            for arg in filter.args.values() {
+                self.expr(arg);
+            }
+        }
+
+        if expr.has_default_filter() {
+            return;
+        }
+
+        let active = self.active;
+        if self.mandatory_only {
+            self.active = expr.filters.iter().any(|filter| filter.name == "mandatory");
+        }
+        self.expr_val(&expr.val);
+        self.active = active;
+    }
+
+    fn expr_val(&mut self, val: &ExprVal) {
+        match val {
+            ExprVal::Ident(ident) => self.ident(ident),
+            ExprVal::Math(math) => {
+                self.expr(&math.lhs);
+                self.expr(&math.rhs);
+            }
+            ExprVal::Logic(logic) => {
+                self.expr(&logic.lhs);
+                self.expr(&logic.rhs);
+            }
+            ExprVal::Test(test) => {
+                if test.name != "defined" && test.name != "undefined" {
+                    self.ident(&test.ident);
+                }
+                for arg in &test.args {
+                    self.expr(arg);
+                }
+            }
+            ExprVal::MacroCall(call) => {
+                for arg in call.args.values() {
+                    self.expr(arg);
+                }
+            }
+            ExprVal::FunctionCall(call) => {
+                for arg in call.args.values() {
+                    self.expr(arg);
+                }
+            }
+            ExprVal::Array(values) => {
+                for value in values {
+                    self.expr(value);
+                }
+            }
+            ExprVal::StringConcat(concat) => {
+                for value in &concat.values {
+                    self.expr_val(value);
+                }
+            }
+            ExprVal::In(contains) => {
+                self.expr(&contains.lhs);
+                self.expr(&contains.rhs);
+            }
+            ExprVal::String(_) | ExprVal::Int(_) | ExprVal::Float(_) | ExprVal::Bool(_) => {}
+        }
+    }
+
+    fn ident(&mut self, ident: &str) {
+        let root = root(ident);
+        if !self.active
+            || BUILTIN_NAMES.contains(&root)
+            || self.scope.iter().any(|name| name == root)
+            || self.guarded.contains(root)
+            || self.guarded.contains(ident)
+        {
+            return;
+        }
+
+        let context = self.json.get_or_init(|| self.context.clone().into_json());
+        if !is_defined(context, ident) && !self.undefined.iter().any(|name| name == ident) {
+            self.undefined.push(ident.to_owned());
+        }
+    }
+}
+
+fn root(ident: &str) -> &str {
+    let end = ident.find(['.', '[']).unwrap_or(ident.len());
+    &ident[..end]
+}
+
+// Turns `a.b[0]["c"]` into the `a.b.0."c"` form understood by
+// `tera::dotted_pointer`, stopping at the first dynamic subscript.
+fn pointer(ident: &str) -> String {
+    let mut pointer = String::with_capacity(ident.len());
+    let mut rest = ident;
+
+    while let Some(start) = rest.find('[') {
+        pointer.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find(']') else {
+            return pointer;
+        };
+
+        let subscript = &rest[start + 1..start + end];
+        let is_literal = subscript.chars().all(|c| c.is_ascii_digit())
+            || subscript.starts_with('"')
+            || subscript.starts_with('\'');
+        if !is_literal {
+            return pointer;
+        }
+
+        pointer.push('.');
+        pointer.push_str(subscript);
+        rest = &rest[start + end + 1..];
+    }
+
+    pointer.push_str(rest);
+    pointer
+}
@@ -0,0 +1,212 @@
+use openssl_sys as ffi;
+use sha2::{Digest, Sha256};
+
+use std::io;
+use std::os::raw::c_int;
+use std::ptr;
+
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const HMAC_BLOCK_LEN: usize = 64;
+
+struct PKey(*mut ffi::EVP_PKEY);
+
+// The keys are only read after they were created, which OpenSSL allows from
+// several threads at once.
+unsafe impl Send for PKey {}
+unsafe impl Sync for PKey {}
+
+impl PKey {
+    fn generate(kind: c_int) -> io::Result<Self> {
+        let secret: [u8; KEY_LEN] = rand::random();
+        let key = unsafe {
+            ffi::EVP_PKEY_new_raw_private_key(kind, ptr::null_mut(), secret.as_ptr(), KEY_LEN)
+        };
+        if key.is_null() {
+            return Err(openssl_error());
+        }
+
+        Ok(Self(key))
+    }
+
+    fn public_key(&self) -> io::Result<[u8; KEY_LEN]> {
+        let mut public = [0u8; KEY_LEN];
+        let mut len = KEY_LEN;
+        check(unsafe { ffi::EVP_PKEY_get_raw_public_key(self.0, public.as_mut_ptr(), &mut len) })?;
+        Ok(public)
+    }
+}
+
+impl Drop for PKey {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_PKEY_free(self.0) }
+    }
+}
+
+// The ssh-ed25519 host key of a mock host.
+pub struct HostKey(PKey);
+
+impl HostKey {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self(PKey::generate(ffi::EVP_PKEY_ED25519)?))
+    }
+
+    pub fn public_key(&self) -> io::Result<[u8; KEY_LEN]> {
+        self.0.public_key()
+    }
+
+    pub fn sign(&self, data: &[u8]) -> io::Result<[u8; SIGNATURE_LEN]> {
+        let mut signature = [0u8; SIGNATURE_LEN];
+        let mut len = SIGNATURE_LEN;
+        unsafe {
+            let ctx = ffi::EVP_MD_CTX_new();
+            if ctx.is_null() {
+                return Err(openssl_error());
+            }
+            let signed = check(ffi::EVP_DigestSignInit(
+                ctx,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null_mut(),
+                (self.0).0,
+            ))
+            .and_then(|()| {
+                check(ffi::EVP_DigestSign(
+                    ctx,
+                    signature.as_mut_ptr(),
+                    &mut len,
+                    data.as_ptr(),
+                    data.len(),
+                ))
+            });
+            ffi::EVP_MD_CTX_free(ctx);
+            signed?;
+        }
+
+        Ok(signature)
+    }
+}
+
+// One side of a curve25519-sha256 key exchange.
+pub struct Exchange(PKey);
+
+impl Exchange {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self(PKey::generate(ffi::EVP_PKEY_X25519)?))
+    }
+
+    pub fn public_key(&self) -> io::Result<[u8; KEY_LEN]> {
+        self.0.public_key()
+    }
+
+    pub fn shared_secret(&self, peer: &[u8]) -> io::Result<[u8; KEY_LEN]> {
+        let peer = unsafe {
+            ffi::EVP_PKEY_new_raw_public_key(
+                ffi::EVP_PKEY_X25519,
+                ptr::null_mut(),
+                peer.as_ptr(),
+                peer.len(),
+            )
+        };
+        if peer.is_null() {
+            return Err(openssl_error());
+        }
+        let peer = PKey(peer);
+
+        let mut secret = [0u8; KEY_LEN];
+        let mut len = KEY_LEN;
+        unsafe {
+            let ctx = ffi::EVP_PKEY_CTX_new((self.0).0, ptr::null_mut());
+            if ctx.is_null() {
+                return Err(openssl_error());
+            }
+            let derived = check(ffi::EVP_PKEY_derive_init(ctx))
+                .and_then(|()| check(ffi::EVP_PKEY_derive_set_peer(ctx, peer.0)))
+                .and_then(|()| check(ffi::EVP_PKEY_derive(ctx, secret.as_mut_ptr(), &mut len)));
+            ffi::EVP_PKEY_CTX_free(ctx);
+            derived?;
+        }
+
+        Ok(secret)
+    }
+}
+
+// aes128-ctr or aes256-ctr, by the length of the key.
+pub struct Cipher(*mut ffi::EVP_CIPHER_CTX);
+
+unsafe impl Send for Cipher {}
+
+impl Cipher {
+    pub fn new(key: &[u8], iv: &[u8]) -> io::Result<Self> {
+        let cipher = match key.len() {
+            16 => unsafe { ffi::EVP_aes_128_ctr() },
+            _ => unsafe { ffi::EVP_aes_256_ctr() },
+        };
+        let ctx = unsafe { ffi::EVP_CIPHER_CTX_new() };
+        if ctx.is_null() {
+            return Err(openssl_error());
+        }
+        let cipher_ctx = Self(ctx);
+        check(unsafe {
+            ffi::EVP_EncryptInit_ex(ctx, cipher, ptr::null_mut(), key.as_ptr(), iv.as_ptr())
+        })?;
+
+        Ok(cipher_ctx)
+    }
+
+    // Counter mode works the same in both directions.
+    pub fn apply(&mut self, data: &mut [u8]) -> io::Result<()> {
+        let mut out = vec![0u8; data.len()];
+        let mut len: c_int = 0;
+        check(unsafe {
+            ffi::EVP_EncryptUpdate(
+                self.0,
+                out.as_mut_ptr(),
+                &mut len,
+                data.as_ptr(),
+                data.len() as c_int,
+            )
+        })?;
+        data.copy_from_slice(&out[..len as usize]);
+
+        Ok(())
+    }
+}
+
+impl Drop for Cipher {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_CIPHER_CTX_free(self.0) }
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut block = [0u8; HMAC_BLOCK_LEN];
+    if key.len() > HMAC_BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+
+    outer.finalize().into()
+}
+
+fn check(ret: c_int) -> io::Result<()> {
+    if ret == 1 {
+        Ok(())
+    } else {
+        Err(openssl_error())
+    }
+}
+
+fn openssl_error() -> io::Error {
+    io::Error::other("openssl call failed")
+}
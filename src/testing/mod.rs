@@ -0,0 +1,285 @@
+mod crypto;
+mod server;
+mod sftp;
+
+use indexmap::IndexMap;
+use regex::Regex;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::connection::HostKeyChecking;
+use crate::credentials::{Credentials, Password};
+use crate::encoding;
+use crate::error::AnsimpleError;
+use crate::events::{self, Event, TaskResultEvent};
+use crate::inventory::{GlobalConfig, Host, HostConfig};
+use crate::runner::{RunOptions, RunReport, Runner};
+
+pub use server::{CommandStub, MockConfig, MockHost};
+
+// A playbook test: the playbook runs against mock hosts, then the assertions
+// are checked against what it left on them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub playbook: PathBuf,
+    // Hosts and their vars. Without it the hosts below are the inventory.
+    #[serde(default)]
+    pub inventory: Option<PathBuf>,
+    #[serde(default)]
+    pub hosts: IndexMap<String, MockConfig>,
+    // Runs the playbook a second time, which must not change anything.
+    #[serde(default = "default_idempotent")]
+    pub idempotent: bool,
+    #[serde(default, rename = "assert")]
+    pub assertions: Vec<Assertion>,
+}
+
+fn default_idempotent() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Assertion {
+    pub host: String,
+    // With none of the checks below, the file only has to exist.
+    pub file: Option<String>,
+    pub exists: Option<bool>,
+    pub content: Option<String>,
+    pub contains: Option<String>,
+    // A command matching the pattern ran on the host, or did not.
+    pub ran: Option<String>,
+    pub not_ran: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    pub failures: Vec<String>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl TestCase {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AnsimpleError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|source| AnsimpleError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        serde_yaml::from_str(&contents).map_err(|source| AnsimpleError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    // Errors are for tests that cannot run at all, a playbook that fails is a
+    // failure in the report.
+    pub async fn run(&self) -> Result<TestReport, AnsimpleError> {
+        let mut inventory = match &self.inventory {
+            Some(path) => HostConfig::load(path, None)?,
+            None => HostConfig {
+                global_config: GlobalConfig {
+                    user: "test".to_owned(),
+                    key: String::new(),
+                    port: None,
+                    password: None,
+                    key_passphrase: None,
+                    proxy_jump: None,
+                    agent_identity: None,
+                    become_root: None,
+                    become_user: None,
+                    environment: IndexMap::new(),
+                    max_bandwidth: None,
+                    upload_cache: None,
+                    known_hosts: None,
+                    strict_host_key_checking: None,
+                },
+                hosts: Vec::new(),
+                groups: IndexMap::new(),
+                group_vars: IndexMap::new(),
+                host_vars: HashMap::new(),
+            },
+        };
+        for name in self.hosts.keys() {
+            if !inventory.hosts.iter().any(|host| &host.address == name) {
+                inventory.hosts.push(Host {
+                    address: name.clone(),
+                    user: None,
+                    key: None,
+                    port: None,
+                    password: None,
+                    key_passphrase: None,
+                    proxy_jump: None,
+                    connection: None,
+                    agent_identity: None,
+                    become_root: None,
+                    become_user: None,
+                    environment: IndexMap::new(),
+                    vars: HashMap::new(),
+                    platform: None,
+                });
+            }
+        }
+
+        let mut mocks = IndexMap::new();
+        for host in &inventory.hosts {
+            let config = self.hosts.get(&host.address).cloned().unwrap_or_default();
+            mocks.insert(host.address.clone(), MockHost::start(&config)?);
+        }
+        let peers = mocks
+            .iter()
+            .map(|(name, mock)| (name.clone(), mock.address()))
+            .collect::<HashMap<_, _>>();
+        for mock in mocks.values() {
+            mock.reach(peers.clone());
+        }
+
+        // Keys in the inventory are unlikely to exist here, the mock hosts
+        // take any password.
+        let password: [u8; 16] = rand::random();
+        let options = RunOptions {
+            credentials: Credentials {
+                ssh_password: Some(Password::new(encoding::b64encode(password))),
+                become_password: None,
+            },
+            connect_to: mocks
+                .iter()
+                .map(|(name, mock)| (name.clone(), mock.address()))
+                .collect(),
+            // Mock hosts get a new key every run.
+            host_key_checking: Some(HostKeyChecking::No),
+            ..RunOptions::default()
+        };
+
+        let mut failures = Vec::new();
+        let (result, _) = self.run_playbook(&inventory, &options).await;
+        if let Err(err) = result {
+            failures.push(format!("run failed: {err}"));
+        } else if self.idempotent {
+            let (result, results) = self.run_playbook(&inventory, &options).await;
+            match result {
+                Err(err) => failures.push(format!("second run failed: {err}")),
+                Ok(()) => failures.extend(
+                    results
+                        .iter()
+                        .filter(|result| result.status == "changed")
+                        .map(|result| {
+                            format!(
+                                "not idempotent: '{}' changed {} on the second run",
+                                result.task, result.host
+                            )
+                        }),
+                ),
+            }
+        }
+
+        for assertion in &self.assertions {
+            failures.extend(assertion.check(&mocks)?);
+        }
+
+        Ok(TestReport { failures })
+    }
+
+    async fn run_playbook(
+        &self,
+        inventory: &HostConfig,
+        options: &RunOptions,
+    ) -> (Result<(), AnsimpleError>, Vec<TaskResultEvent>) {
+        let (sender, mut receiver) = events::channel();
+        let collector = tokio::spawn(async move {
+            let mut results = Vec::new();
+            while let Some(event) = receiver.recv().await {
+                if let Event::TaskResult(result) = event {
+                    results.push(result);
+                }
+            }
+            results
+        });
+
+        let runner = Runner::new(
+            inventory.clone(),
+            RunOptions {
+                events: Some(sender),
+                ..options.clone()
+            },
+        );
+        let result = runner
+            .run_file(&self.playbook)
+            .await
+            .and_then(RunReport::into_result);
+        // The collector finishes once the last sender is gone.
+        drop(runner);
+
+        (result, collector.await.unwrap_or_default())
+    }
+}
+
+impl Assertion {
+    fn check(&self, mocks: &IndexMap<String, MockHost>) -> Result<Vec<String>, AnsimpleError> {
+        let Some(mock) = mocks.get(&self.host) else {
+            return Ok(vec![format!("{}: not a host of the test", self.host)]);
+        };
+        let mut failures = Vec::new();
+
+        if let Some(file) = &self.file {
+            let contents = fs::read(mock.path(file)).ok();
+            let exists = self
+                .exists
+                .or((self.content.is_none() && self.contains.is_none()).then_some(true));
+            match (exists, &contents) {
+                (Some(true), None) => {
+                    failures.push(format!("{}: {file} does not exist", self.host))
+                }
+                (Some(false), Some(_)) => failures.push(format!("{}: {file} exists", self.host)),
+                _ => {}
+            }
+
+            let text = contents.map(|contents| String::from_utf8_lossy(&contents).into_owned());
+            if let Some(content) = &self.content {
+                if text.as_ref() != Some(content) {
+                    failures.push(format!(
+                        "{}: {file} is {}, expected {content:?}",
+                        self.host,
+                        text.as_ref()
+                            .map_or("missing".to_owned(), |text| format!("{text:?}"))
+                    ));
+                }
+            }
+            if let Some(contains) = &self.contains {
+                if !text.as_ref().is_some_and(|text| text.contains(contains)) {
+                    failures.push(format!(
+                        "{}: {file} does not contain {contains:?}",
+                        self.host
+                    ));
+                }
+            }
+        }
+
+        let commands = mock.commands();
+        if let Some(pattern) = &self.ran {
+            let regex = Regex::new(pattern)?;
+            if !commands.iter().any(|command| regex.is_match(command)) {
+                failures.push(format!(
+                    "{}: no command matching `{pattern}` ran",
+                    self.host
+                ));
+            }
+        }
+        if let Some(pattern) = &self.not_ran {
+            let regex = Regex::new(pattern)?;
+            if let Some(command) = commands.iter().find(|command| regex.is_match(command)) {
+                failures.push(format!("{}: `{command}` ran", self.host));
+            }
+        }
+
+        Ok(failures)
+    }
+}
@@ -0,0 +1,991 @@
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::crypto::{hmac_sha256, Cipher, Exchange, HostKey};
+use super::sftp::Sftp;
+use crate::error::AnsimpleError;
+
+const VERSION: &str = "SSH-2.0-ansimple_mock";
+const MAC_LEN: usize = 32;
+const MAX_PACKET: u32 = 256 * 1024;
+// The window granted to clients, topped up once half of it is used.
+const WINDOW: u32 = 2 * 1024 * 1024;
+
+const MSG_DISCONNECT: u8 = 1;
+const MSG_IGNORE: u8 = 2;
+const MSG_UNIMPLEMENTED: u8 = 3;
+const MSG_DEBUG: u8 = 4;
+const MSG_SERVICE_REQUEST: u8 = 5;
+const MSG_SERVICE_ACCEPT: u8 = 6;
+const MSG_KEXINIT: u8 = 20;
+const MSG_NEWKEYS: u8 = 21;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+const MSG_USERAUTH_REQUEST: u8 = 50;
+const MSG_USERAUTH_FAILURE: u8 = 51;
+const MSG_USERAUTH_SUCCESS: u8 = 52;
+const MSG_USERAUTH_PK_OK: u8 = 60;
+const MSG_GLOBAL_REQUEST: u8 = 80;
+const MSG_REQUEST_FAILURE: u8 = 82;
+const MSG_CHANNEL_OPEN: u8 = 90;
+const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+const MSG_CHANNEL_DATA: u8 = 94;
+const MSG_CHANNEL_EXTENDED_DATA: u8 = 95;
+const MSG_CHANNEL_EOF: u8 = 96;
+const MSG_CHANNEL_CLOSE: u8 = 97;
+const MSG_CHANNEL_REQUEST: u8 = 98;
+const MSG_CHANNEL_SUCCESS: u8 = 99;
+const MSG_CHANNEL_FAILURE: u8 = 100;
+
+const KEX_ALGORITHMS: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const CIPHERS: &[&str] = &["aes128-ctr", "aes256-ctr"];
+
+// A command a mock host answers without running anything.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandStub {
+    // Matched anywhere in the command.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    #[serde(default)]
+    pub rc: i32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MockConfig {
+    // Files to create before the run, by path on the host.
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+    // Checked in order, the first matching one answers the command.
+    #[serde(default)]
+    pub commands: Vec<CommandStub>,
+    // Runs commands without a stub with `/bin/sh` in the host's directory
+    // instead of answering them with an empty success.
+    #[serde(default)]
+    pub exec: bool,
+}
+
+// An SSH server on a local port whose filesystem is a temporary directory.
+// It accepts any user, key and password. Commands are recorded and answered
+// by stubs, so a playbook can run against it without touching this machine.
+pub struct MockHost {
+    address: SocketAddr,
+    state: Arc<State>,
+}
+
+struct State {
+    root: PathBuf,
+    host_key: HostKey,
+    stubs: Vec<(Regex, CommandStub)>,
+    exec: bool,
+    commands: Mutex<Vec<String>>,
+    // The other hosts of the test, reachable through this one by name.
+    peers: Mutex<HashMap<String, SocketAddr>>,
+    stopped: AtomicBool,
+}
+
+impl MockHost {
+    pub fn start(config: &MockConfig) -> Result<Self, AnsimpleError> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "ansimple-mock-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&root)?;
+
+        let stubs = config
+            .commands
+            .iter()
+            .map(|stub| Ok((Regex::new(&stub.pattern)?, stub.clone())))
+            .collect::<Result<Vec<_>, AnsimpleError>>()?;
+        let state = Arc::new(State {
+            root,
+            host_key: HostKey::generate()?,
+            stubs,
+            exec: config.exec,
+            commands: Mutex::new(Vec::new()),
+            peers: Mutex::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+        });
+        for (path, content) in &config.files {
+            let path = state.resolve(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let accepting = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = accepting.clone();
+                    thread::spawn(move || {
+                        // A client going away is no concern of the host.
+                        let _ = serve(stream, state);
+                    });
+                }
+            }
+        });
+
+        Ok(Self { address, state })
+    }
+
+    // Where to connect to reach this host.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // The directory that is `/` on this host.
+    pub fn root(&self) -> &Path {
+        &self.state.root
+    }
+
+    // The local path of `path` on this host.
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.state.resolve(path)
+    }
+
+    // Lets clients that use this host as a jump host reach `peers`.
+    pub fn reach(&self, peers: HashMap<String, SocketAddr>) {
+        *self.state.peers.lock().expect("peers lock poisoned") = peers;
+    }
+
+    // Every command run on this host so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.state
+            .commands
+            .lock()
+            .expect("command log lock poisoned")
+            .clone()
+    }
+}
+
+impl Drop for MockHost {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // Wakes the accepting thread up so it sees the host stopped.
+        let _ = TcpStream::connect(self.address);
+        let _ = fs::remove_dir_all(&self.state.root);
+    }
+}
+
+impl State {
+    // Paths never leave the root, `..` stops there.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        self.root.join(normalize(path))
+    }
+}
+
+// `path` relative to the root of the host, taking relative paths as relative
+// to the root.
+pub fn normalize(path: &str) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    normalized
+}
+
+// Builds the payload of a packet.
+#[derive(Default)]
+pub struct Packet(pub Vec<u8>);
+
+impl Packet {
+    pub fn new(kind: u8) -> Self {
+        Self(vec![kind])
+    }
+
+    pub fn byte(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn string<T: AsRef<[u8]>>(self, value: T) -> Self {
+        let value = value.as_ref();
+        let mut packet = self.u32(value.len() as u32);
+        packet.0.extend_from_slice(value);
+        packet
+    }
+
+    pub fn mpint(self, value: &[u8]) -> Self {
+        let value = &value[value.iter().take_while(|byte| **byte == 0).count()..];
+        if value.first().is_some_and(|byte| byte & 0x80 != 0) {
+            let mut padded = vec![0];
+            padded.extend_from_slice(value);
+            self.string(padded)
+        } else {
+            self.string(value)
+        }
+    }
+}
+
+// Reads the fields of a packet, failing on truncated ones.
+pub struct Fields<'a>(pub &'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated packet",
+            ));
+        }
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    pub fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.byte()? != 0)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(
+            self.take(8)?.try_into().expect("8 bytes"),
+        ))
+    }
+
+    pub fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn text(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.string()?).into_owned())
+    }
+}
+
+struct Keys {
+    cipher: Cipher,
+    mac: Vec<u8>,
+}
+
+struct Incoming {
+    stream: BufReader<TcpStream>,
+    keys: Option<Keys>,
+    sequence: u32,
+}
+
+impl Incoming {
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let block = if self.keys.is_some() { 16 } else { 8 };
+        let mut packet = vec![0u8; block];
+        self.stream.read_exact(&mut packet)?;
+        if let Some(keys) = &mut self.keys {
+            keys.cipher.apply(&mut packet)?;
+        }
+
+        let len = u32::from_be_bytes(packet[..4].try_into().expect("4 bytes")) as usize;
+        if !(block - 4..=MAX_PACKET as usize + 1024).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad packet length",
+            ));
+        }
+        let mut rest = vec![0u8; len + 4 - block];
+        self.stream.read_exact(&mut rest)?;
+        if let Some(keys) = &mut self.keys {
+            keys.cipher.apply(&mut rest)?;
+            packet.extend_from_slice(&rest);
+
+            let mut mac = [0u8; MAC_LEN];
+            self.stream.read_exact(&mut mac)?;
+            if hmac_sha256(&keys.mac, &[&self.sequence.to_be_bytes(), &packet]) != mac {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad packet mac"));
+            }
+        } else {
+            packet.extend_from_slice(&rest);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let padding = packet[4] as usize;
+        if padding + 1 > len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad padding"));
+        }
+        Ok(packet[5..4 + len - padding].to_vec())
+    }
+}
+
+struct Outgoing {
+    stream: TcpStream,
+    keys: Option<Keys>,
+    sequence: u32,
+}
+
+impl Outgoing {
+    fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let block = if self.keys.is_some() { 16 } else { 8 };
+        let mut padding = block - (payload.len() + 5) % block;
+        if padding < 4 {
+            padding += block;
+        }
+
+        let mut packet = Vec::with_capacity(payload.len() + padding + 5 + MAC_LEN);
+        packet.extend_from_slice(&((payload.len() + padding + 1) as u32).to_be_bytes());
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        packet.extend((0..padding).map(|_| rand::random::<u8>()));
+
+        if let Some(keys) = &mut self.keys {
+            let mac = hmac_sha256(&keys.mac, &[&self.sequence.to_be_bytes(), &packet]);
+            keys.cipher.apply(&mut packet)?;
+            packet.extend_from_slice(&mac);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+
+        self.stream.write_all(&packet)
+    }
+}
+
+// The sending half of a connection, shared by the threads of its channels.
+#[derive(Clone)]
+pub struct Sender(Arc<Mutex<Outgoing>>);
+
+impl Sender {
+    pub fn send(&self, packet: Packet) -> io::Result<()> {
+        self.0
+            .lock()
+            .expect("connection lock poisoned")
+            .send(&packet.0)
+    }
+}
+
+// A session channel as seen from its own threads.
+pub struct Channel {
+    remote: u32,
+    sender: Sender,
+    // How much the client still accepts.
+    window: Mutex<u32>,
+    window_changed: Condvar,
+    max_packet: u32,
+    closed: AtomicBool,
+}
+
+impl Channel {
+    // Waits for the client's window where needed.
+    pub fn data(&self, mut data: &[u8], extended: bool) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = {
+                let mut window = self.window.lock().expect("window lock poisoned");
+                while *window == 0 && !self.closed.load(Ordering::SeqCst) {
+                    window = self
+                        .window_changed
+                        .wait(window)
+                        .expect("window lock poisoned");
+                }
+                if self.closed.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                let len = data
+                    .len()
+                    .min(*window as usize)
+                    .min(self.max_packet as usize - 64)
+                    .min(32 * 1024);
+                *window -= len as u32;
+                len
+            };
+
+            let (chunk, rest) = data.split_at(len);
+            let packet = if extended {
+                Packet::new(MSG_CHANNEL_EXTENDED_DATA)
+                    .u32(self.remote)
+                    .u32(1)
+                    .string(chunk)
+            } else {
+                Packet::new(MSG_CHANNEL_DATA).u32(self.remote).string(chunk)
+            };
+            self.sender.send(packet)?;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    fn adjust(&self, bytes: u32) {
+        let mut window = self.window.lock().expect("window lock poisoned");
+        *window = window.saturating_add(bytes);
+        self.window_changed.notify_all();
+    }
+
+    fn finish(&self, status: i32) -> io::Result<()> {
+        self.sender
+            .send(Packet::new(MSG_CHANNEL_EOF).u32(self.remote))?;
+        self.sender.send(
+            Packet::new(MSG_CHANNEL_REQUEST)
+                .u32(self.remote)
+                .string("exit-status")
+                .byte(0)
+                .u32(status as u32),
+        )?;
+        self.close()
+    }
+
+    pub fn close(&self) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.window_changed.notify_all();
+        self.sender
+            .send(Packet::new(MSG_CHANNEL_CLOSE).u32(self.remote))
+    }
+}
+
+// What the connection thread knows about a channel.
+struct Open {
+    channel: Arc<Channel>,
+    env: Vec<(String, String)>,
+    // Where data from the client goes.
+    input: Option<mpsc::Sender<Option<Vec<u8>>>>,
+    // Received since the window was last topped up.
+    consumed: u32,
+}
+
+fn serve(stream: TcpStream, state: Arc<State>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    writer.write_all(format!("{VERSION}\r\n").as_bytes())?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let client_version = loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.starts_with("SSH-") {
+            break line.trim_end().to_owned();
+        }
+    };
+
+    let mut connection = Connection {
+        state,
+        incoming: Incoming {
+            stream: reader,
+            keys: None,
+            sequence: 0,
+        },
+        sender: Sender(Arc::new(Mutex::new(Outgoing {
+            stream: writer,
+            keys: None,
+            sequence: 0,
+        }))),
+        client_version,
+        session_id: None,
+        channels: HashMap::new(),
+        next_channel: 0,
+    };
+    let result = connection.run();
+    for open in connection.channels.values() {
+        open.channel.closed.store(true, Ordering::SeqCst);
+        open.channel.window_changed.notify_all();
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+struct Connection {
+    state: Arc<State>,
+    incoming: Incoming,
+    sender: Sender,
+    client_version: String,
+    session_id: Option<Vec<u8>>,
+    channels: HashMap<u32, Open>,
+    next_channel: u32,
+}
+
+impl Connection {
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let payload = match self.incoming.receive() {
+                Ok(payload) => payload,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let Some(&kind) = payload.first() else {
+                continue;
+            };
+            let mut fields = Fields(&payload[1..]);
+
+            match kind {
+                MSG_DISCONNECT => return Ok(()),
+                MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED => {}
+                MSG_KEXINIT => self.exchange_keys(&payload)?,
+                MSG_SERVICE_REQUEST => {
+                    let service = fields.string()?;
+                    self.sender
+                        .send(Packet::new(MSG_SERVICE_ACCEPT).string(service))?;
+                }
+                MSG_USERAUTH_REQUEST => self.authenticate(fields)?,
+                MSG_GLOBAL_REQUEST => {
+                    fields.string()?;
+                    if fields.bool()? {
+                        self.sender.send(Packet::new(MSG_REQUEST_FAILURE))?;
+                    }
+                }
+                MSG_CHANNEL_OPEN => self.open_channel(fields)?,
+                MSG_CHANNEL_WINDOW_ADJUST => {
+                    let local = fields.u32()?;
+                    let bytes = fields.u32()?;
+                    if let Some(open) = self.channels.get(&local) {
+                        open.channel.adjust(bytes);
+                    }
+                }
+                MSG_CHANNEL_DATA => {
+                    let local = fields.u32()?;
+                    let data = fields.string()?;
+                    if let Some(open) = self.channels.get_mut(&local) {
+                        if let Some(input) = &open.input {
+                            let _ = input.send(Some(data.to_vec()));
+                        }
+                        open.consumed += data.len() as u32;
+                        if open.consumed >= WINDOW / 2 {
+                            self.sender.send(
+                                Packet::new(MSG_CHANNEL_WINDOW_ADJUST)
+                                    .u32(open.channel.remote)
+                                    .u32(open.consumed),
+                            )?;
+                            open.consumed = 0;
+                        }
+                    }
+                }
+                MSG_CHANNEL_EXTENDED_DATA => {}
+                MSG_CHANNEL_EOF => {
+                    let local = fields.u32()?;
+                    if let Some(input) = self
+                        .channels
+                        .get_mut(&local)
+                        .and_then(|open| open.input.take())
+                    {
+                        let _ = input.send(None);
+                    }
+                }
+                MSG_CHANNEL_CLOSE => {
+                    let local = fields.u32()?;
+                    if let Some(open) = self.channels.remove(&local) {
+                        open.channel.close()?;
+                    }
+                }
+                MSG_CHANNEL_REQUEST => self.channel_request(fields)?,
+                _ => {
+                    let sequence = self.incoming.sequence.wrapping_sub(1);
+                    self.sender
+                        .send(Packet::new(MSG_UNIMPLEMENTED).u32(sequence))?;
+                }
+            }
+        }
+    }
+
+    fn exchange_keys(&mut self, client_kexinit: &[u8]) -> io::Result<()> {
+        let mut server_kexinit = Packet::new(MSG_KEXINIT);
+        server_kexinit
+            .0
+            .extend((0..16).map(|_| rand::random::<u8>()));
+        let server_kexinit = server_kexinit
+            .string(KEX_ALGORITHMS.join(","))
+            .string("ssh-ed25519")
+            .string(CIPHERS.join(","))
+            .string(CIPHERS.join(","))
+            .string("hmac-sha2-256")
+            .string("hmac-sha2-256")
+            .string("none")
+            .string("none")
+            .string("")
+            .string("")
+            .byte(0)
+            .u32(0);
+        self.sender.send(Packet(server_kexinit.0.clone()))?;
+
+        let mut fields = Fields(client_kexinit.get(17..).unwrap_or_default());
+        let mut lists = Vec::new();
+        for _ in 0..10 {
+            lists.push(fields.text()?);
+        }
+        let choose = |list: &str, supported: &[&str]| {
+            list.split(',')
+                .find(|name| supported.contains(name))
+                .map(str::to_owned)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("no common algorithm in {list}"),
+                    )
+                })
+        };
+        choose(&lists[0], KEX_ALGORITHMS)?;
+        let cipher_in = choose(&lists[2], CIPHERS)?;
+        let cipher_out = choose(&lists[3], CIPHERS)?;
+
+        let client_public = loop {
+            let payload = self.incoming.receive()?;
+            if payload.first() == Some(&MSG_KEX_ECDH_INIT) {
+                break Fields(&payload[1..]).string()?.to_vec();
+            }
+        };
+        let exchange = Exchange::generate()?;
+        let server_public = exchange.public_key()?;
+        let secret = exchange.shared_secret(&client_public)?;
+        let host_key = Packet::default()
+            .string("ssh-ed25519")
+            .string(self.state.host_key.public_key()?)
+            .0;
+
+        let hash: Vec<u8> = Sha256::digest(
+            Packet::default()
+                .string(&self.client_version)
+                .string(VERSION)
+                .string(client_kexinit)
+                .string(&server_kexinit.0)
+                .string(&host_key)
+                .string(&client_public)
+                .string(server_public)
+                .mpint(&secret)
+                .0,
+        )
+        .to_vec();
+        let session_id = self.session_id.get_or_insert_with(|| hash.clone()).clone();
+        let signature = Packet::default()
+            .string("ssh-ed25519")
+            .string(self.state.host_key.sign(&hash)?)
+            .0;
+        self.sender.send(
+            Packet::new(MSG_KEX_ECDH_REPLY)
+                .string(&host_key)
+                .string(server_public)
+                .string(&signature),
+        )?;
+
+        let secret = Packet::default().mpint(&secret).0;
+        let derive = |letter: u8, len: usize| {
+            let mut key =
+                Sha256::digest([&secret[..], &hash, &[letter], &session_id].concat()).to_vec();
+            while key.len() < len {
+                let more = Sha256::digest([&secret[..], &hash, &key].concat());
+                key.extend_from_slice(&more);
+            }
+            key.truncate(len);
+            key
+        };
+        let key_len = |cipher: &str| if cipher == "aes128-ctr" { 16 } else { 32 };
+
+        // Keys change right after NEWKEYS, in each direction on its own.
+        let mut outgoing = self.sender.0.lock().expect("connection lock poisoned");
+        outgoing.send(&[MSG_NEWKEYS])?;
+        outgoing.keys = Some(Keys {
+            cipher: Cipher::new(&derive(b'D', key_len(&cipher_out)), &derive(b'B', 16))?,
+            mac: derive(b'F', MAC_LEN),
+        });
+        drop(outgoing);
+
+        while self.incoming.receive()?.first() != Some(&MSG_NEWKEYS) {}
+        self.incoming.keys = Some(Keys {
+            cipher: Cipher::new(&derive(b'C', key_len(&cipher_in)), &derive(b'A', 16))?,
+            mac: derive(b'E', MAC_LEN),
+        });
+
+        Ok(())
+    }
+
+    fn authenticate(&mut self, mut fields: Fields) -> io::Result<()> {
+        fields.string()?;
+        fields.string()?;
+        let reply = match fields.string()? {
+            b"password" => Packet::new(MSG_USERAUTH_SUCCESS),
+            b"publickey" => {
+                let signed = fields.bool()?;
+                let algorithm = fields.string()?;
+                let key = fields.string()?;
+                if signed {
+                    Packet::new(MSG_USERAUTH_SUCCESS)
+                } else {
+                    Packet::new(MSG_USERAUTH_PK_OK)
+                        .string(algorithm)
+                        .string(key)
+                }
+            }
+            _ => Packet::new(MSG_USERAUTH_FAILURE)
+                .string("publickey,password")
+                .byte(0),
+        };
+        self.sender.send(reply)
+    }
+
+    fn open_channel(&mut self, mut fields: Fields) -> io::Result<()> {
+        let kind = fields.string()?;
+        let remote = fields.u32()?;
+        let window = fields.u32()?;
+        let max_packet = fields.u32()?.max(1024);
+        // Forwarded connections only go to the other hosts of the test.
+        let forwarded = match kind {
+            b"session" => None,
+            b"direct-tcpip" => {
+                let peer = fields.text()?;
+                let address = self
+                    .state
+                    .peers
+                    .lock()
+                    .expect("peers lock poisoned")
+                    .get(&peer)
+                    .copied();
+                match address.map(TcpStream::connect) {
+                    Some(Ok(stream)) => Some(stream),
+                    _ => {
+                        return self.sender.send(
+                            Packet::new(MSG_CHANNEL_OPEN_FAILURE)
+                                .u32(remote)
+                                .u32(2)
+                                .string(format!("cannot reach {peer}"))
+                                .string(""),
+                        )
+                    }
+                }
+            }
+            _ => {
+                return self.sender.send(
+                    Packet::new(MSG_CHANNEL_OPEN_FAILURE)
+                        .u32(remote)
+                        .u32(3)
+                        .string("only session and direct-tcpip channels are supported")
+                        .string(""),
+                )
+            }
+        };
+
+        let local = self.next_channel;
+        self.next_channel += 1;
+        let channel = Arc::new(Channel {
+            remote,
+            sender: self.sender.clone(),
+            window: Mutex::new(window),
+            window_changed: Condvar::new(),
+            max_packet,
+            closed: AtomicBool::new(false),
+        });
+        let input = match forwarded {
+            Some(stream) => Some(forward(stream, channel.clone())?),
+            None => None,
+        };
+        self.channels.insert(
+            local,
+            Open {
+                channel,
+                env: Vec::new(),
+                input,
+                consumed: 0,
+            },
+        );
+        self.sender.send(
+            Packet::new(MSG_CHANNEL_OPEN_CONFIRMATION)
+                .u32(remote)
+                .u32(local)
+                .u32(WINDOW)
+                .u32(MAX_PACKET),
+        )
+    }
+
+    fn channel_request(&mut self, mut fields: Fields) -> io::Result<()> {
+        let local = fields.u32()?;
+        let request = fields.string()?.to_vec();
+        let want_reply = fields.bool()?;
+        let Some(open) = self.channels.get_mut(&local) else {
+            return Ok(());
+        };
+        let remote = open.channel.remote;
+
+        let accepted = match &request[..] {
+            b"env" => {
+                let name = fields.text()?;
+                let value = fields.text()?;
+                open.env.push((name, value));
+                true
+            }
+            b"exec" if open.input.is_none() => {
+                let command = fields.text()?;
+                let (input, receiver) = mpsc::channel();
+                open.input = Some(input);
+                if want_reply {
+                    self.sender
+                        .send(Packet::new(MSG_CHANNEL_SUCCESS).u32(remote))?;
+                }
+                let channel = open.channel.clone();
+                let env = open.env.clone();
+                let state = self.state.clone();
+                thread::spawn(move || {
+                    let status = exec(&state, &channel, &command, &env, receiver).unwrap_or(255);
+                    let _ = channel.finish(status);
+                });
+                return Ok(());
+            }
+            b"subsystem" if open.input.is_none() && fields.string()? == b"sftp" => {
+                let (input, receiver) = mpsc::channel();
+                open.input = Some(input);
+                let channel = open.channel.clone();
+                let state = self.state.clone();
+                thread::spawn(move || {
+                    let _ = Sftp::new(&state.root, &channel).serve(receiver);
+                    let _ = channel.close();
+                });
+                true
+            }
+            _ => false,
+        };
+
+        if want_reply {
+            let kind = if accepted {
+                MSG_CHANNEL_SUCCESS
+            } else {
+                MSG_CHANNEL_FAILURE
+            };
+            self.sender.send(Packet::new(kind).u32(remote))?;
+        }
+        Ok(())
+    }
+}
+
+// Answers `command` from the stubs or, when the host runs commands, with
+// `/bin/sh` in the host's directory.
+fn exec(
+    state: &State,
+    channel: &Channel,
+    command: &str,
+    env: &[(String, String)],
+    input: mpsc::Receiver<Option<Vec<u8>>>,
+) -> io::Result<i32> {
+    state
+        .commands
+        .lock()
+        .expect("command log lock poisoned")
+        .push(command.to_owned());
+
+    if let Some((_, stub)) = state
+        .stubs
+        .iter()
+        .find(|(pattern, _)| pattern.is_match(command))
+    {
+        channel.data(stub.stdout.as_bytes(), false)?;
+        channel.data(stub.stderr.as_bytes(), true)?;
+        return Ok(stub.rc);
+    }
+    if !state.exec {
+        return Ok(0);
+    }
+
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(&state.root)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .env("HOME", &state.root)
+        .env("ANSIMPLE_MOCK_ROOT", &state.root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take();
+    let feeder = thread::spawn(move || {
+        while let Ok(Some(data)) = input.recv() {
+            if let Some(writer) = &mut stdin {
+                if writer.write_all(&data).is_err() {
+                    stdin = None;
+                }
+            }
+        }
+    });
+
+    let mut stdout = child.stdout.take().expect("piped stdout");
+    let mut stderr = child.stderr.take().expect("piped stderr");
+    thread::scope(|scope| {
+        let errors = scope.spawn(|| pump(&mut stderr, channel, true));
+        pump(&mut stdout, channel, false)?;
+        errors.join().expect("stderr thread panicked")
+    })?;
+
+    let status = child.wait()?;
+    drop(feeder);
+    Ok(status.code().unwrap_or(255))
+}
+
+// Relays between `stream` and `channel` until either closes. Returns where
+// the client's data goes.
+fn forward(stream: TcpStream, channel: Arc<Channel>) -> io::Result<mpsc::Sender<Option<Vec<u8>>>> {
+    let (input, receiver) = mpsc::channel::<Option<Vec<u8>>>();
+    let mut writer = stream.try_clone()?;
+    thread::spawn(move || {
+        while let Ok(Some(data)) = receiver.recv() {
+            if writer.write_all(&data).is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Write);
+    });
+    thread::spawn(move || {
+        let mut reader = stream;
+        let _ = pump(&mut reader, &channel, false);
+        let _ = channel
+            .sender
+            .send(Packet::new(MSG_CHANNEL_EOF).u32(channel.remote));
+        let _ = channel.close();
+    });
+
+    Ok(input)
+}
+
+fn pump(reader: &mut dyn Read, channel: &Channel, extended: bool) -> io::Result<()> {
+    let mut buffer = [0u8; 32 * 1024];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(()),
+            read => channel.data(&buffer[..read], extended)?,
+        }
+    }
+}
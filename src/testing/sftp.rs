@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+
+use super::server::{normalize, Channel, Fields, Packet};
+
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_LSTAT: u8 = 7;
+const FXP_FSTAT: u8 = 8;
+const FXP_SETSTAT: u8 = 9;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_RMDIR: u8 = 15;
+const FXP_REALPATH: u8 = 16;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_READLINK: u8 = 19;
+const FXP_SYMLINK: u8 = 20;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+const FXP_EXTENDED: u8 = 200;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const FX_FAILURE: u32 = 4;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const PFLAG_READ: u32 = 0x1;
+const PFLAG_WRITE: u32 = 0x2;
+const PFLAG_APPEND: u32 = 0x4;
+const PFLAG_CREAT: u32 = 0x8;
+const PFLAG_TRUNC: u32 = 0x10;
+const PFLAG_EXCL: u32 = 0x20;
+
+const READDIR_BATCH: usize = 50;
+
+enum Handle {
+    File(File),
+    Dir(Vec<(String, Metadata)>),
+}
+
+#[derive(Default)]
+struct Attributes {
+    size: Option<u64>,
+    permissions: Option<u32>,
+}
+
+// SFTP version 3 with every path inside the root of a mock host.
+pub struct Sftp<'a> {
+    root: &'a Path,
+    channel: &'a Channel,
+    handles: HashMap<Vec<u8>, Handle>,
+    next_handle: u64,
+}
+
+impl<'a> Sftp<'a> {
+    pub fn new(root: &'a Path, channel: &'a Channel) -> Self {
+        Self {
+            root,
+            channel,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    // Answers requests until the client closes the channel.
+    pub fn serve(mut self, input: Receiver<Option<Vec<u8>>>) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        while let Ok(Some(data)) = input.recv() {
+            buffer.extend_from_slice(&data);
+            while buffer.len() >= 4 {
+                let len = u32::from_be_bytes(buffer[..4].try_into().expect("4 bytes")) as usize;
+                if buffer.len() < len + 4 {
+                    break;
+                }
+                let packet: Vec<u8> = buffer.drain(..len + 4).skip(4).collect();
+                self.dispatch(&packet)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&mut self, packet: &[u8]) -> io::Result<()> {
+        let mut fields = Fields(packet);
+        let kind = fields.byte()?;
+        if kind == FXP_INIT {
+            return self.reply(Packet::new(FXP_VERSION).u32(3));
+        }
+
+        let id = fields.u32()?;
+        let reply = match self.handle(kind, id, fields) {
+            Ok(reply) => reply,
+            Err(err) => {
+                let code = match err.kind() {
+                    io::ErrorKind::NotFound => FX_NO_SUCH_FILE,
+                    io::ErrorKind::PermissionDenied => FX_PERMISSION_DENIED,
+                    io::ErrorKind::Unsupported => FX_OP_UNSUPPORTED,
+                    _ => FX_FAILURE,
+                };
+                status(id, code, &err.to_string())
+            }
+        };
+        self.reply(reply)
+    }
+
+    fn handle(&mut self, kind: u8, id: u32, mut fields: Fields) -> io::Result<Packet> {
+        match kind {
+            FXP_OPEN => {
+                let path = self.path(&mut fields)?;
+                let flags = fields.u32()?;
+                let attributes = read_attributes(&mut fields)?;
+                let file = OpenOptions::new()
+                    .read(flags & PFLAG_READ != 0)
+                    .write(flags & PFLAG_WRITE != 0 && flags & PFLAG_APPEND == 0)
+                    .append(flags & PFLAG_APPEND != 0)
+                    .create(flags & PFLAG_CREAT != 0 && flags & PFLAG_EXCL == 0)
+                    .create_new(flags & PFLAG_CREAT != 0 && flags & PFLAG_EXCL != 0)
+                    .truncate(flags & PFLAG_TRUNC != 0)
+                    .mode(attributes.permissions.unwrap_or(0o644) & 0o7777)
+                    .open(path)?;
+                Ok(self.open(id, Handle::File(file)))
+            }
+            FXP_CLOSE => {
+                self.handles.remove(fields.string()?);
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_READ => {
+                let file = self.file(&mut fields)?;
+                let offset = fields.u64()?;
+                let mut data = vec![0u8; fields.u32()?.min(256 * 1024) as usize];
+                let read = file.read_at(&mut data, offset)?;
+                if read == 0 {
+                    return Ok(status(id, FX_EOF, "end of file"));
+                }
+                Ok(Packet::new(FXP_DATA).u32(id).string(&data[..read]))
+            }
+            FXP_WRITE => {
+                let file = self.file(&mut fields)?;
+                let offset = fields.u64()?;
+                file.write_all_at(fields.string()?, offset)?;
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_STAT => {
+                let metadata = fs::metadata(self.path(&mut fields)?)?;
+                Ok(attributes(Packet::new(FXP_ATTRS).u32(id), &metadata))
+            }
+            FXP_LSTAT => {
+                let metadata = fs::symlink_metadata(self.path(&mut fields)?)?;
+                Ok(attributes(Packet::new(FXP_ATTRS).u32(id), &metadata))
+            }
+            FXP_FSTAT => {
+                let metadata = self.file(&mut fields)?.metadata()?;
+                Ok(attributes(Packet::new(FXP_ATTRS).u32(id), &metadata))
+            }
+            FXP_SETSTAT => {
+                let path = self.path(&mut fields)?;
+                let attributes = read_attributes(&mut fields)?;
+                if let Some(permissions) = attributes.permissions {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(permissions & 0o7777))?;
+                }
+                if let Some(size) = attributes.size {
+                    OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+                }
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_FSETSTAT => {
+                let file = self.file(&mut fields)?;
+                let attributes = read_attributes(&mut fields)?;
+                if let Some(permissions) = attributes.permissions {
+                    file.set_permissions(fs::Permissions::from_mode(permissions & 0o7777))?;
+                }
+                if let Some(size) = attributes.size {
+                    file.set_len(size)?;
+                }
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_OPENDIR => {
+                let path = self.path(&mut fields)?;
+                let mut entries = vec![
+                    (".".to_owned(), fs::metadata(&path)?),
+                    ("..".to_owned(), fs::metadata(&path)?),
+                ];
+                for entry in fs::read_dir(&path)? {
+                    let entry = entry?;
+                    entries.push((
+                        entry.file_name().to_string_lossy().into_owned(),
+                        fs::symlink_metadata(entry.path())?,
+                    ));
+                }
+                entries[2..].sort_by(|a, b| a.0.cmp(&b.0));
+                entries.reverse();
+                Ok(self.open(id, Handle::Dir(entries)))
+            }
+            FXP_READDIR => {
+                let Some(Handle::Dir(entries)) = self.handles.get_mut(fields.string()?) else {
+                    return Err(io::Error::other("not a directory handle"));
+                };
+                if entries.is_empty() {
+                    return Ok(status(id, FX_EOF, "end of directory"));
+                }
+                let batch = entries.len().min(READDIR_BATCH);
+                let mut reply = Packet::new(FXP_NAME).u32(id).u32(batch as u32);
+                for _ in 0..batch {
+                    let (name, metadata) = entries.pop().expect("entries left");
+                    reply = attributes(reply.string(&name).string(&name), &metadata);
+                }
+                Ok(reply)
+            }
+            FXP_REMOVE => {
+                fs::remove_file(self.path(&mut fields)?)?;
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_MKDIR => {
+                let path = self.path(&mut fields)?;
+                fs::create_dir(&path)?;
+                if let Some(permissions) = read_attributes(&mut fields)?.permissions {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(permissions & 0o7777))?;
+                }
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_RMDIR => {
+                fs::remove_dir(self.path(&mut fields)?)?;
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_REALPATH => {
+                let path = format!("/{}", normalize(&fields.text()?).display());
+                Ok(name(id, &path))
+            }
+            FXP_RENAME => {
+                let old = self.path(&mut fields)?;
+                let new = self.path(&mut fields)?;
+                // Like OpenSSH, plain renames never replace a file.
+                if fs::symlink_metadata(&new).is_ok() {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+                }
+                fs::rename(old, new)?;
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_READLINK => {
+                let target = fs::read_link(self.path(&mut fields)?)?;
+                Ok(name(id, &target.to_string_lossy()))
+            }
+            FXP_SYMLINK => {
+                // OpenSSH sends the target first, then the link.
+                let target = fields.text()?;
+                let link = self.path(&mut fields)?;
+                std::os::unix::fs::symlink(target, link)?;
+                Ok(status(id, FX_OK, ""))
+            }
+            FXP_EXTENDED if fields.string()? == b"posix-rename@openssh.com" => {
+                let old = self.path(&mut fields)?;
+                let new = self.path(&mut fields)?;
+                fs::rename(old, new)?;
+                Ok(status(id, FX_OK, ""))
+            }
+            _ => Ok(status(id, FX_OP_UNSUPPORTED, "unsupported request")),
+        }
+    }
+
+    fn reply(&self, packet: Packet) -> io::Result<()> {
+        let mut framed = (packet.0.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&packet.0);
+        self.channel.data(&framed, false)
+    }
+
+    fn open(&mut self, id: u32, handle: Handle) -> Packet {
+        self.next_handle += 1;
+        let key = self.next_handle.to_string().into_bytes();
+        self.handles.insert(key.clone(), handle);
+        Packet::new(FXP_HANDLE).u32(id).string(key)
+    }
+
+    fn file(&self, fields: &mut Fields) -> io::Result<&File> {
+        match self.handles.get(fields.string()?) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => Err(io::Error::other("not a file handle")),
+        }
+    }
+
+    fn path(&self, fields: &mut Fields) -> io::Result<PathBuf> {
+        Ok(self.root.join(normalize(&fields.text()?)))
+    }
+}
+
+fn status(id: u32, code: u32, message: &str) -> Packet {
+    Packet::new(FXP_STATUS)
+        .u32(id)
+        .u32(code)
+        .string(message)
+        .string("")
+}
+
+fn name(id: u32, path: &str) -> Packet {
+    Packet::new(FXP_NAME)
+        .u32(id)
+        .u32(1)
+        .string(path)
+        .string(path)
+        .u32(0)
+}
+
+fn attributes(packet: Packet, metadata: &Metadata) -> Packet {
+    packet
+        .u32(ATTR_SIZE | ATTR_UIDGID | ATTR_PERMISSIONS | ATTR_ACMODTIME)
+        .u64(metadata.len())
+        .u32(metadata.uid())
+        .u32(metadata.gid())
+        .u32(metadata.mode())
+        .u32(metadata.atime() as u32)
+        .u32(metadata.mtime() as u32)
+}
+
+// Ownership and times are read but left alone, the files of a mock host all
+// belong to whoever runs the tests.
+fn read_attributes(fields: &mut Fields) -> io::Result<Attributes> {
+    let flags = fields.u32()?;
+    let mut attributes = Attributes::default();
+    if flags & ATTR_SIZE != 0 {
+        attributes.size = Some(fields.u64()?);
+    }
+    if flags & ATTR_UIDGID != 0 {
+        fields.u32()?;
+        fields.u32()?;
+    }
+    if flags & ATTR_PERMISSIONS != 0 {
+        attributes.permissions = Some(fields.u32()?);
+    }
+    if flags & ATTR_ACMODTIME != 0 {
+        fields.u32()?;
+        fields.u32()?;
+    }
+    if flags & ATTR_EXTENDED != 0 {
+        for _ in 0..fields.u32()? {
+            fields.string()?;
+            fields.string()?;
+        }
+    }
+
+    Ok(attributes)
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::schema::{Generator, Schema};
+
+// Bytes per second, written as a number of bytes or with a `K`, `M` or `G`
+// suffix for KiB, MiB or GiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "BandwidthSpec", into = "u64")]
+pub struct Bandwidth(u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BandwidthSpec {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<BandwidthSpec> for Bandwidth {
+    type Error = String;
+
+    fn try_from(value: BandwidthSpec) -> Result<Self, Self::Error> {
+        match value {
+            BandwidthSpec::Bytes(bytes) => Self::new(bytes),
+            BandwidthSpec::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<Bandwidth> for u64 {
+    fn from(value: Bandwidth) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((at, 'k' | 'K')) => (&s[..at], 1024.0),
+            Some((at, 'm' | 'M')) => (&s[..at], 1024.0 * 1024.0),
+            Some((at, 'g' | 'G')) => (&s[..at], 1024.0 * 1024.0 * 1024.0),
+            _ => (s, 1.0),
+        };
+        let number: f64 = number
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid bandwidth `{s}`, expected e.g. 512K or 10M"))?;
+        Self::new((number * multiplier) as u64)
+    }
+}
+
+impl Display for Bandwidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} B/s", self.0)
+    }
+}
+
+impl Schema for Bandwidth {
+    fn schema(_: &mut Generator) -> serde_json::Value {
+        json!({
+            "oneOf": [
+                { "type": "integer", "minimum": 1 },
+                { "type": "string", "pattern": "^\\s*[0-9.]+\\s*[kKmMgG]?\\s*$" },
+            ],
+        })
+    }
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_second: u64) -> Result<Self, String> {
+        if bytes_per_second == 0 {
+            return Err("bandwidth must be more than 0 bytes per second".to_owned());
+        }
+
+        Ok(Self(bytes_per_second))
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.0
+    }
+}
+
+// Paces transfers to stay within every limit it holds. Clones share their
+// limits, so a limit in a throttle handed to every host caps them together.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    limits: Vec<Arc<Limit>>,
+}
+
+#[derive(Debug)]
+struct Limit {
+    bandwidth: Bandwidth,
+    // When what was taken so far has gone through at the limit.
+    due: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        Self::default().and(bandwidth)
+    }
+
+    // This throttle with one more limit of its own.
+    pub fn and(&self, bandwidth: Bandwidth) -> Self {
+        let mut limits = self.limits.clone();
+        limits.push(Arc::new(Limit {
+            bandwidth,
+            due: Mutex::new(Instant::now()),
+        }));
+
+        Self { limits }
+    }
+
+    // Blocks until `bytes` more fit into every limit.
+    pub fn take(&self, bytes: usize) {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for limit in &self.limits {
+            let mut due = limit.due.lock().expect("throttle lock poisoned");
+            // Time spent idle is not saved up for a burst later.
+            *due = (*due).max(now)
+                + Duration::from_secs_f64(bytes as f64 / limit.bandwidth.bytes_per_second() as f64);
+            wait = wait.max(*due - now);
+        }
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    pub fn reader<R: Read>(&self, inner: R) -> Throttled<R> {
+        Throttled {
+            inner,
+            throttle: self.clone(),
+        }
+    }
+}
+
+pub struct Throttled<R> {
+    inner: R,
+    throttle: Throttle,
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.throttle.take(read);
+        Ok(read)
+    }
+}
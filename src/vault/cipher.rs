@@ -0,0 +1,166 @@
+use openssl_sys as ffi;
+
+use std::os::raw::c_int;
+use std::ptr;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+const PBKDF2_ITERATIONS: c_int = 100_000;
+
+pub fn derive_key(password: &[u8], salt: &[u8]) -> Option<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    let ok = unsafe {
+        ffi::PKCS5_PBKDF2_HMAC(
+            password.as_ptr().cast(),
+            password.len() as c_int,
+            salt.as_ptr(),
+            salt.len() as c_int,
+            PBKDF2_ITERATIONS,
+            ffi::EVP_sha256(),
+            KEY_LEN as c_int,
+            key.as_mut_ptr(),
+        )
+    };
+
+    (ok == 1).then_some(key)
+}
+
+pub fn random_bytes(len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let ok = unsafe { ffi::RAND_bytes(buf.as_mut_ptr(), len as c_int) };
+    (ok == 1).then_some(buf)
+}
+
+struct CipherCtx(*mut ffi::EVP_CIPHER_CTX);
+
+impl CipherCtx {
+    fn new() -> Option<Self> {
+        let ctx = unsafe { ffi::EVP_CIPHER_CTX_new() };
+        (!ctx.is_null()).then_some(Self(ctx))
+    }
+}
+
+impl Drop for CipherCtx {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_CIPHER_CTX_free(self.0) }
+    }
+}
+
+// Returns the ciphertext with the authentication tag appended.
+pub fn encrypt(key: &[u8; KEY_LEN], nonce: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+    let ctx = CipherCtx::new()?;
+    let mut out = vec![0u8; plaintext.len() + TAG_LEN];
+    let mut len: c_int = 0;
+
+    unsafe {
+        check(ffi::EVP_EncryptInit_ex(
+            ctx.0,
+            ffi::EVP_aes_256_gcm(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+        ))?;
+        check(ffi::EVP_CIPHER_CTX_ctrl(
+            ctx.0,
+            ffi::EVP_CTRL_GCM_SET_IVLEN,
+            nonce.len() as c_int,
+            ptr::null_mut(),
+        ))?;
+        check(ffi::EVP_EncryptInit_ex(
+            ctx.0,
+            ptr::null(),
+            ptr::null_mut(),
+            key.as_ptr(),
+            nonce.as_ptr(),
+        ))?;
+        check(ffi::EVP_EncryptUpdate(
+            ctx.0,
+            out.as_mut_ptr(),
+            &mut len,
+            plaintext.as_ptr(),
+            plaintext.len() as c_int,
+        ))?;
+        let mut written = len as usize;
+        check(ffi::EVP_EncryptFinal_ex(
+            ctx.0,
+            out.as_mut_ptr().add(written),
+            &mut len,
+        ))?;
+        written += len as usize;
+        check(ffi::EVP_CIPHER_CTX_ctrl(
+            ctx.0,
+            ffi::EVP_CTRL_GCM_GET_TAG,
+            TAG_LEN as c_int,
+            out.as_mut_ptr().add(written).cast(),
+        ))?;
+        out.truncate(written + TAG_LEN);
+    }
+
+    Some(out)
+}
+
+// Expects the authentication tag appended to the ciphertext, returns `None`
+// when the tag does not verify.
+pub fn decrypt(key: &[u8; KEY_LEN], nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    if ciphertext.len() < TAG_LEN {
+        return None;
+    }
+
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+    let mut tag = tag.to_vec();
+    let ctx = CipherCtx::new()?;
+    let mut out = vec![0u8; ciphertext.len() + TAG_LEN];
+    let mut len: c_int = 0;
+
+    unsafe {
+        check(ffi::EVP_DecryptInit_ex(
+            ctx.0,
+            ffi::EVP_aes_256_gcm(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+        ))?;
+        check(ffi::EVP_CIPHER_CTX_ctrl(
+            ctx.0,
+            ffi::EVP_CTRL_GCM_SET_IVLEN,
+            nonce.len() as c_int,
+            ptr::null_mut(),
+        ))?;
+        check(ffi::EVP_DecryptInit_ex(
+            ctx.0,
+            ptr::null(),
+            ptr::null_mut(),
+            key.as_ptr(),
+            nonce.as_ptr(),
+        ))?;
+        check(ffi::EVP_DecryptUpdate(
+            ctx.0,
+            out.as_mut_ptr(),
+            &mut len,
+            ciphertext.as_ptr(),
+            ciphertext.len() as c_int,
+        ))?;
+        let mut written = len as usize;
+        check(ffi::EVP_CIPHER_CTX_ctrl(
+            ctx.0,
+            ffi::EVP_CTRL_GCM_SET_TAG,
+            TAG_LEN as c_int,
+            tag.as_mut_ptr().cast(),
+        ))?;
+        check(ffi::EVP_DecryptFinal_ex(
+            ctx.0,
+            out.as_mut_ptr().add(written),
+            &mut len,
+        ))?;
+        written += len as usize;
+        out.truncate(written);
+    }
+
+    Some(out)
+}
+
+fn check(ret: c_int) -> Option<()> {
+    (ret == 1).then_some(())
+}
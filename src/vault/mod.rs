@@ -0,0 +1,242 @@
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+use thiserror::Error;
+
+use std::fs;
+use std::path::Path;
+
+mod cipher;
+
+use crate::encoding;
+use crate::error::AnsimpleError;
+use crate::secrets;
+
+pub const HEADER: &str = "$ANSIMPLE_VAULT;1.0;AES256GCM";
+pub const TAG: &str = "vault";
+
+const SALT_LEN: usize = 16;
+// Base64 characters per line of encrypted content.
+const LINE_LEN: usize = 76;
+
+#[derive(Debug, Error)]
+pub enum VaultError {
+    #[error("encrypted content found but no vault password was given")]
+    MissingPassword,
+    #[error("malformed vault content")]
+    Malformed,
+    #[error("failed to decrypt vault content, wrong password?")]
+    Decrypt,
+    #[error("failed to encrypt vault content")]
+    Encrypt,
+}
+
+#[derive(Debug, Clone)]
+pub struct Vault {
+    password: String,
+}
+
+impl Vault {
+    pub fn new(password: String) -> Self {
+        Self { password }
+    }
+
+    pub fn from_password_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let password = fs::read_to_string(path)?;
+        Ok(Self::new(
+            password.trim_end_matches(['\r', '\n']).to_owned(),
+        ))
+    }
+
+    pub fn decrypt(&self, contents: &str) -> Result<Vec<u8>, VaultError> {
+        let payload = contents
+            .trim_start()
+            .strip_prefix(HEADER)
+            .ok_or(VaultError::Malformed)?;
+        let payload = encoding::b64decode(payload).ok_or(VaultError::Malformed)?;
+        if payload.len() < SALT_LEN + cipher::NONCE_LEN + cipher::TAG_LEN {
+            return Err(VaultError::Malformed);
+        }
+
+        let (salt, rest) = payload.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(cipher::NONCE_LEN);
+        let key = cipher::derive_key(self.password.as_bytes(), salt).ok_or(VaultError::Decrypt)?;
+
+        cipher::decrypt(&key, nonce, ciphertext).ok_or(VaultError::Decrypt)
+    }
+
+    // The header and the base64 of a fresh salt, nonce and the ciphertext,
+    // in lines short enough to diff.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String, VaultError> {
+        let salt = cipher::random_bytes(SALT_LEN).ok_or(VaultError::Encrypt)?;
+        let nonce = cipher::random_bytes(cipher::NONCE_LEN).ok_or(VaultError::Encrypt)?;
+        let key = cipher::derive_key(self.password.as_bytes(), &salt).ok_or(VaultError::Encrypt)?;
+        let ciphertext = cipher::encrypt(&key, &nonce, plaintext).ok_or(VaultError::Encrypt)?;
+
+        let payload = encoding::b64encode([salt, nonce, ciphertext].concat());
+        let mut encrypted = format!("{HEADER}\n");
+        for line in payload.as_bytes().chunks(LINE_LEN) {
+            encrypted.push_str(&String::from_utf8_lossy(line));
+            encrypted.push('\n');
+        }
+
+        Ok(encrypted)
+    }
+
+    // `value` as an inline `!vault` scalar, to paste into a YAML mapping.
+    pub fn encrypt_value(&self, value: &str) -> Result<String, VaultError> {
+        let encrypted = self.encrypt(value.as_bytes())?;
+        let mut tagged = format!("!{TAG} |\n");
+        for line in encrypted.lines() {
+            tagged.push_str(&format!("  {line}\n"));
+        }
+
+        Ok(tagged)
+    }
+}
+
+pub fn is_encrypted(contents: &str) -> bool {
+    contents.trim_start().starts_with(HEADER)
+}
+
+// Encrypts the file at `path` in place.
+pub fn encrypt_file<P: AsRef<Path>>(path: P, vault: &Vault) -> Result<(), AnsimpleError> {
+    let path = path.as_ref();
+    let contents = fs::read(path).map_err(|source| AnsimpleError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    if is_encrypted(&String::from_utf8_lossy(&contents)) {
+        return Err(AnsimpleError::Config(format!(
+            "{} is already encrypted",
+            path.display()
+        )));
+    }
+
+    write(path, vault.encrypt(&contents)?.as_bytes())
+}
+
+// Decrypts the file at `path` in place.
+pub fn decrypt_file<P: AsRef<Path>>(path: P, vault: &Vault) -> Result<(), AnsimpleError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path).map_err(|source| AnsimpleError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    if !is_encrypted(&contents) {
+        return Err(AnsimpleError::Config(format!(
+            "{} is not encrypted",
+            path.display()
+        )));
+    }
+
+    write(path, &vault.decrypt(&contents)?)
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), AnsimpleError> {
+    fs::write(path, contents).map_err(|source| AnsimpleError::Write {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+pub fn load<T: DeserializeOwned, P: AsRef<Path>>(
+    path: P,
+    vault: Option<&Vault>,
+) -> Result<T, AnsimpleError> {
+    let contents = read_to_string(&path, vault)?;
+    from_str(&contents, vault).map_err(|err| match err {
+        AnsimpleError::Yaml(source) => AnsimpleError::Parse {
+            path: path.as_ref().to_owned(),
+            source,
+        },
+        err => err,
+    })
+}
+
+// Like `load`, for files of variables. Each string of an encrypted one is a
+// secret, masked wherever it is printed like `!vault` scalars are.
+pub fn load_vars<T: DeserializeOwned, P: AsRef<Path>>(
+    path: P,
+    vault: Option<&Vault>,
+) -> Result<T, AnsimpleError> {
+    let path = path.as_ref();
+    let encrypted = fs::read_to_string(path).is_ok_and(|contents| is_encrypted(&contents));
+    let value: Value = load(path, vault)?;
+    if encrypted {
+        track(&value);
+    }
+
+    serde_yaml::from_value(value).map_err(|source| AnsimpleError::Parse {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+pub fn read_to_string<P: AsRef<Path>>(
+    path: P,
+    vault: Option<&Vault>,
+) -> Result<String, AnsimpleError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path).map_err(|source| AnsimpleError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    if !is_encrypted(&contents) {
+        return Ok(contents);
+    }
+
+    let vault = vault.ok_or(VaultError::MissingPassword)?;
+    Ok(String::from_utf8(vault.decrypt(&contents)?)?)
+}
+
+// Deserializes YAML that may contain inline `!vault |` scalars, decrypting
+// them before handing the document to `T`.
+pub fn from_str<T: DeserializeOwned>(
+    contents: &str,
+    vault: Option<&Vault>,
+) -> Result<T, AnsimpleError> {
+    if !contents.contains(&format!("!{TAG}")) {
+        return Ok(serde_yaml::from_str(contents)?);
+    }
+
+    let mut value: Value = serde_yaml::from_str(contents)?;
+    decrypt_tagged(&mut value, vault)?;
+    Ok(serde_yaml::from_value(value)?)
+}
+
+fn decrypt_tagged(value: &mut Value, vault: Option<&Vault>) -> Result<(), AnsimpleError> {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == TAG => {
+            let contents = tagged.value.as_str().ok_or(VaultError::Malformed)?;
+            let vault = vault.ok_or(VaultError::MissingPassword)?;
+            let plaintext = String::from_utf8(vault.decrypt(contents)?)?;
+            secrets::track(&plaintext);
+            *value = Value::String(plaintext);
+        }
+        Value::Tagged(tagged) => decrypt_tagged(&mut tagged.value, vault)?,
+        Value::Sequence(values) => {
+            for value in values {
+                decrypt_tagged(value, vault)?;
+            }
+        }
+        Value::Mapping(values) => {
+            for (_, value) in values.iter_mut() {
+                decrypt_tagged(value, vault)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Masks the strings of a decrypted document wherever they are printed.
+fn track(value: &Value) {
+    match value {
+        Value::String(secret) => secrets::track(secret),
+        Value::Tagged(tagged) => track(&tagged.value),
+        Value::Sequence(values) => values.iter().for_each(track),
+        Value::Mapping(values) => values.values().for_each(track),
+        _ => {}
+    }
+}